@@ -121,6 +121,7 @@ impl HPET
 		s.write_reg(HPETReg::ISR as usize, s.read_reg(HPETReg::ISR as usize));
 		
 		s.oneshot(0, s.current() + 100*1000 );
+		::time::handle_tick();
 	}
 	
 	fn read_reg(&self, reg: usize) -> u64 {
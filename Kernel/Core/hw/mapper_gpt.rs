@@ -0,0 +1,270 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/hw/mapper_gpt.rs
+/// GUID Partition Table logical volume mapper
+use prelude::*;
+use lib::byteorder::{ByteOrder,LittleEndian};
+use lib::crc32::crc32;
+use metadevs::storage;
+
+module_define!{MapperGPT, [Storage], init}
+
+static S_MAPPER: Mapper = Mapper;
+
+fn init()
+{
+	storage::register_mapper(&S_MAPPER);
+}
+
+/// Sanity limit on the size of the partition entry array (the spec minimum is 16KiB)
+const MAX_TABLE_SIZE: usize = 1024*1024;
+
+struct Mapper;
+
+#[derive(Debug)]
+struct Header
+{
+	my_lba: u64,
+	alternate_lba: u64,
+	first_usable_lba: u64,
+	last_usable_lba: u64,
+	entries_lba: u64,
+	num_entries: u32,
+	entry_size: u32,
+	entries_crc32: u32,
+}
+
+#[derive(Debug)]
+struct Entry
+{
+	type_guid: [u8; 16],
+	lba_start: u64,
+	lba_end: u64,
+	attributes: u64,
+	name: String,
+}
+
+impl storage::Mapper for Mapper
+{
+	fn name(&self) -> &str { "gpt" }
+
+	fn handles_pv(&self, pv: &storage::PhysicalVolume) -> Result<usize,storage::IoError> {
+		if pv.blocksize() < 512 {
+			return Ok(0);
+		}
+		let bs = pv.blocksize();
+
+		let mut block = vec![0u8; bs];
+		try!(pv.read(0, 0, 1, &mut block).wait());
+		if !has_protective_mbr(&block) {
+			return Ok(0);
+		}
+
+		match try!(read_header(pv))
+		{
+		Some(_) => Ok(2),
+		None => {
+			log_warning!("PV '{}' has a protective MBR, but no valid GPT header", pv.name());
+			Ok(0)
+			},
+		}
+	}
+
+	fn enum_volumes(&self, pv: &storage::PhysicalVolume, new_volume_cb: &mut FnMut(String, u64, u64)) -> Result<(),storage::IoError> {
+		let hdr = match try!(read_header(pv))
+			{
+			Some(h) => h,
+			None => return Err( storage::IoError::InvalidParameter ),
+			};
+		log_debug!("{:?}", hdr);
+
+		// - The header used for the entries must be the one describing the table that was read
+		let (hdr, table) = match try!(read_table(pv, &hdr))
+			{
+			Some(t) => (hdr, t),
+			None => {
+				// Primary table is bad, fall back to the table referenced by the backup header
+				log_warning!("PV '{}' GPT partition array CRC mismatch, trying backup", pv.name());
+				match try!(read_header_at(pv, hdr.alternate_lba))
+				{
+				Some(alt) => match try!(read_table(pv, &alt))
+					{
+					Some(t) => (alt, t),
+					None => return Err( storage::IoError::Unknown("GPT partition arrays corrupted") ),
+					},
+				None => return Err( storage::IoError::Unknown("GPT partition array corrupted") ),
+				}
+				},
+			};
+
+		// Names of existing volumes, partition names must be unique to be used
+		let mut used_names: Vec<String> = storage::enum_lvs().into_iter().map(|(_,n)| n).collect();
+		for i in 0 .. hdr.num_entries as usize
+		{
+			let ofs = i * hdr.entry_size as usize;
+			let info = match Entry::read( &table[ofs .. ofs + hdr.entry_size as usize] )
+				{
+				Some(v) => v,
+				None => continue,
+				};
+			log_debug!("{:?}", info);
+			if info.lba_end < info.lba_start || info.lba_start < hdr.first_usable_lba || info.lba_end > hdr.last_usable_lba {
+				log_warning!("PV '{}' GPT entry #{} out of range ({:#x}--{:#x})", pv.name(), i, info.lba_start, info.lba_end);
+				continue ;
+			}
+
+			let name = if is_valid_name(&info.name) && !used_names.iter().any(|n| *n == info.name) {
+					info.name.clone()
+				}
+				else {
+					format!("{}p{}", pv.name(), i)
+				};
+			used_names.push( name.clone() );
+			new_volume_cb( name, info.lba_start, info.lba_end - info.lba_start + 1 );
+		}
+
+		Ok( () )
+	}
+}
+
+/// Check for a MBR with a GPT protective partition (type 0xEE)
+fn has_protective_mbr(block: &[u8]) -> bool
+{
+	if !(block[0x1FE] == 0x55 && block[0x1FF] == 0xAA) {
+		return false;
+	}
+	(0 .. 4).any(|i| block[0x1BE + i*16 + 4] == 0xEE)
+}
+
+/// Read the primary header, falling back to the backup (at the end of the disk) if the primary is corrupted
+fn read_header(pv: &storage::PhysicalVolume) -> Result<Option<Header>,storage::IoError>
+{
+	if let Some(h) = try!(read_header_at(pv, 1)) {
+		return Ok( Some(h) );
+	}
+	log_warning!("PV '{}' primary GPT header invalid, trying backup", pv.name());
+	match pv.capacity()
+	{
+	Some(cap) if cap > 1 => read_header_at(pv, cap - 1),
+	_ => Ok(None),
+	}
+}
+
+/// Read and validate a header at the specified LBA
+fn read_header_at(pv: &storage::PhysicalVolume, lba: u64) -> Result<Option<Header>,storage::IoError>
+{
+	let mut block = vec![0u8; pv.blocksize()];
+	try!(pv.read(0, lba, 1, &mut block).wait());
+
+	if &block[0 .. 8] != b"EFI PART" {
+		return Ok(None);
+	}
+	let header_size = LittleEndian::read_u32(&block[12..]) as usize;
+	if header_size < 92 || header_size > block.len() {
+		log_notice!("GPT header at {:#x} has a bad size ({})", lba, header_size);
+		return Ok(None);
+	}
+	// - The header checksum is calculated with the checksum field zeroed
+	let header_crc = LittleEndian::read_u32(&block[16..]);
+	LittleEndian::write_u32(&mut block[16..], 0);
+	if crc32(&block[.. header_size]) != header_crc {
+		log_notice!("GPT header at {:#x} CRC mismatch", lba);
+		return Ok(None);
+	}
+
+	let hdr = Header {
+		my_lba: LittleEndian::read_u64(&block[24..]),
+		alternate_lba: LittleEndian::read_u64(&block[32..]),
+		first_usable_lba: LittleEndian::read_u64(&block[40..]),
+		last_usable_lba: LittleEndian::read_u64(&block[48..]),
+		entries_lba: LittleEndian::read_u64(&block[72..]),
+		num_entries: LittleEndian::read_u32(&block[80..]),
+		entry_size: LittleEndian::read_u32(&block[84..]),
+		entries_crc32: LittleEndian::read_u32(&block[88..]),
+		};
+	if hdr.my_lba != lba {
+		log_notice!("GPT header at {:#x} has a mismatched location ({:#x})", lba, hdr.my_lba);
+		return Ok(None);
+	}
+	if hdr.entry_size < 128 || hdr.entry_size % 8 != 0 || hdr.num_entries as usize * hdr.entry_size as usize > MAX_TABLE_SIZE {
+		log_notice!("GPT header at {:#x} has a bad table format ({} x {})", lba, hdr.num_entries, hdr.entry_size);
+		return Ok(None);
+	}
+	Ok( Some(hdr) )
+}
+
+/// Read the partition entry array referenced by a header (returns None if the CRC is bad)
+fn read_table(pv: &storage::PhysicalVolume, hdr: &Header) -> Result<Option<Vec<u8>>,storage::IoError>
+{
+	let bs = pv.blocksize();
+	let len = hdr.num_entries as usize * hdr.entry_size as usize;
+	let mut table = vec![0u8; (len + bs - 1) / bs * bs];
+	for (i, blk) in table.chunks_mut(bs).enumerate()
+	{
+		try!(pv.read(0, hdr.entries_lba + i as u64, 1, blk).wait());
+	}
+	table.truncate(len);
+
+	if crc32(&table) != hdr.entries_crc32 {
+		Ok(None)
+	}
+	else {
+		Ok(Some(table))
+	}
+}
+
+/// Returns true if a partition name is usable as a volume name
+fn is_valid_name(name: &str) -> bool
+{
+	name.len() > 0 && name.bytes().all(|c| match c
+		{
+		b'a' ... b'z' | b'A' ... b'Z' | b'0' ... b'9' => true,
+		b'-' | b'_' | b'.' => true,
+		_ => false,
+		})
+}
+
+impl Entry
+{
+	fn read(data: &[u8]) -> Option<Entry>
+	{
+		assert!(data.len() >= 128);
+		let mut type_guid = [0; 16];
+		type_guid.copy_from_slice(&data[0 .. 16]);
+		if type_guid == [0; 16] {
+			return None;
+		}
+
+		Some(Entry {
+			type_guid: type_guid,
+			lba_start: LittleEndian::read_u64(&data[32..]),
+			lba_end: LittleEndian::read_u64(&data[40..]),
+			attributes: LittleEndian::read_u64(&data[48..]),
+			name: decode_name(&data[56 .. 128]),
+			})
+	}
+}
+
+/// Decode a NUL-terminated UTF-16LE partition name
+fn decode_name(data: &[u8]) -> String
+{
+	let mut rv = String::new();
+	let mut units = data.chunks(2).map(|c| LittleEndian::read_u16(c)).take_while(|&c| c != 0);
+	while let Some(u) = units.next()
+	{
+		let cp = match u
+			{
+			0xD800 ... 0xDBFF => match units.next()
+				{
+				Some(l @ 0xDC00 ... 0xDFFF) => 0x10000 + (((u as u32) - 0xD800) << 10) + ((l as u32) - 0xDC00),
+				_ => 0xFFFD,
+				},
+			0xDC00 ... 0xDFFF => 0xFFFD,
+			_ => u as u32,
+			};
+		let mut buf = [0; 4];
+		rv.push_str( ::core::char::from_u32(cp).unwrap_or('\u{FFFD}').encode_utf8(&mut buf) );
+	}
+	rv
+}
@@ -18,6 +18,9 @@ fn init()
 
 struct Mapper;
 
+/// Maximum number of logical partitions (limits the EBR walk in case of a loop)
+const MAX_LOGICAL_PARTITIONS: usize = 64;
+
 #[derive(Debug)]
 struct Entry
 {
@@ -65,14 +68,20 @@ impl storage::Mapper for Mapper
 		// the "unique ID" (according to the osdev.org wiki) might just be the tail of the MBR code
 		//let uid = &block[0x1b4 .. 0x1be];
 		
+		let mut extended = None;
 		for i in 0 .. 4 {
 			let ofs = 0x1BE + i*16;
 			
 			if let Some(info) = Entry::read( &block[ofs .. ofs + 16] )
 			{
 				log_debug!("{:?}", info);
-				if info.system_id == 0x5 || info.system_id == 0xF {
-					todo!("Extended partition");
+				if info.is_extended() {
+					if extended.is_some() {
+						log_warning!("PV '{}' has multiple extended partitions, ignoring #{}", pv.name(), i);
+					}
+					else {
+						extended = Some(info.lba_start);
+					}
 				}
 				else {
 					new_volume_cb( format!("{}p{}", pv.name(), i), info.lba_start, info.lba_count );
@@ -80,10 +89,47 @@ impl storage::Mapper for Mapper
 			}
 		}
 		
+		// Logical partitions are numbered after the four primary slots
+		if let Some(ext_base) = extended {
+			try!(enum_logical(pv, ext_base, &mut |idx, base, count| new_volume_cb( format!("{}p{}", pv.name(), 4 + idx), base, count )));
+		}
+		
 		Ok( () )
 	}
 }
 
+/// Walk the chain of Extended Boot Records starting at `ext_base`
+///
+/// Each EBR contains the logical partition (relative to the EBR) and a link to the next EBR (relative to `ext_base`)
+fn enum_logical(pv: &storage::PhysicalVolume, ext_base: u64, cb: &mut FnMut(usize, u64, u64)) -> Result<(),storage::IoError>
+{
+	// SAFE: Plain old data
+	let mut block: [u8; 512] = unsafe { ::core::mem::zeroed() };
+	let mut ebr_lba = ext_base;
+	for idx in 0 .. MAX_LOGICAL_PARTITIONS
+	{
+		try!( pv.read(0, ebr_lba, 1, &mut block).wait() );
+		if !(block[510] == 0x55 && block[511] == 0xAA) {
+			log_warning!("PV '{}' EBR at {:#x} has a bad signature", pv.name(), ebr_lba);
+			return Ok( () );
+		}
+		
+		if let Some(info) = Entry::read( &block[0x1BE .. 0x1BE + 16] )
+		{
+			log_debug!("EBR {:#x}: {:?}", ebr_lba, info);
+			cb(idx, ebr_lba + info.lba_start, info.lba_count);
+		}
+		
+		ebr_lba = match Entry::read( &block[0x1CE .. 0x1CE + 16] )
+			{
+			Some(ref next) if next.is_extended() && next.lba_start != 0 => ext_base + next.lba_start,
+			_ => return Ok( () ),
+			};
+	}
+	log_warning!("PV '{}' has too many logical partitions (or a loop in the EBR chain), stopping at {}", pv.name(), MAX_LOGICAL_PARTITIONS);
+	Ok( () )
+}
+
 impl Entry
 {
 	fn read(data: &[u8]) -> Option<Entry>
@@ -98,8 +144,19 @@ impl Entry
 			return None;
 		}
 		
+		if data[0] & 1 != 0 && (data[1] != 0x14 || data[5] != 0xEB) {
+			log_warning!("Partition entry has the 48-bit flag set, but invalid signature bytes ({:#x},{:#x})", data[1], data[5]);
+			return None;
+		}
+		
 		let (base, len) = if data[0] & 1 != 0 {
-				todo!("non-standard 48-bit LBA");
+				// Non-standard 48-bit LBA: the (otherwise unused) CHS fields hold the high 16 bits of the start and length
+				// - Bytes 1 and 5 hold the signature (0x14 and 0xEB), checked above
+				let base_lo = (&data[8..]).read_u32::<LittleEndian>().unwrap() as u64;
+				let len_lo = (&data[12..]).read_u32::<LittleEndian>().unwrap() as u64;
+				let base_hi = (&data[2..]).read_u16::<LittleEndian>().unwrap() as u64;
+				let len_hi = (&data[6..]).read_u16::<LittleEndian>().unwrap() as u64;
+				(base_hi << 32 | base_lo, len_hi << 32 | len_lo)
 			}
 			else {
 				let base = (&data[8..]).read_u32::<LittleEndian>().unwrap() as u64;
@@ -114,5 +171,14 @@ impl Entry
 			lba_count: len,
 			})
 	}
+
+	/// Returns true if this entry points to an extended partition (containing an EBR chain)
+	fn is_extended(&self) -> bool {
+		match self.system_id
+		{
+		0x05 | 0x0F | 0x85 => true,
+		_ => false,
+		}
+	}
 }
 
@@ -0,0 +1,268 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/hw/mapper_md.rs
+/// Linux software RAID (md, version 1.x superblock) array mapper
+use prelude::*;
+use lib::byteorder::{ByteOrder,LittleEndian};
+use metadevs::storage;
+
+module_define!{MapperMD, [Storage], init}
+
+static S_MAPPER: Mapper = Mapper;
+
+fn init()
+{
+	storage::register_mapper(&S_MAPPER);
+}
+
+const MD_MAGIC: u32 = 0xa92b4efc;
+/// Size of the fixed portion of the superblock (followed by the `dev_roles` array)
+const SB_FIXED_SIZE: usize = 256;
+/// Maximum size of the superblock (including the roles array)
+const SB_MAX_SIZE: usize = 4096;
+/// All md offsets/sizes are in 512 byte sectors
+const SECTOR_SIZE: u64 = 512;
+
+struct Mapper;
+
+#[derive(Debug)]
+struct Superblock
+{
+	/// Location of the superblock (in sectors)
+	sector: u64,
+	/// Number of entries in the `dev_roles` array
+	max_dev: usize,
+	set_uuid: [u8; 16],
+	set_name: String,
+	level: i32,
+	chunk_sectors: u32,
+	raid_disks: u32,
+	data_offset: u64,
+	data_size: u64,
+	/// Update counter (members that missed updates have lower values)
+	events: u64,
+	/// Role of this device (slot number, or 0xFFFF=spare, 0xFFFE=faulty)
+	role: u16,
+}
+
+impl storage::Mapper for Mapper
+{
+	fn name(&self) -> &str { "md" }
+
+	fn handles_pv(&self, pv: &storage::PhysicalVolume) -> Result<usize,storage::IoError> {
+		match try!(find_superblock(pv))
+		{
+		Some(sb) => {
+			log_debug!("PV '{}' is md member: {:?}", pv.name(), sb);
+			// Only bind if the array format is supported, otherwise let the partition mappers have it
+			if array_layout(&sb, pv.blocksize()).is_some() {
+				Ok(3)
+			}
+			else {
+				log_notice!("PV '{}' is a member of an unsupported md array (level {})", pv.name(), sb.level);
+				Ok(0)
+			}
+			},
+		None => Ok(0),
+		}
+	}
+
+	fn enum_volumes(&self, _pv: &storage::PhysicalVolume, _new_volume_cb: &mut FnMut(String, u64, u64)) -> Result<(),storage::IoError> {
+		// No single-volume LVs, all exposed via `enum_array_members`
+		Ok( () )
+	}
+
+	fn enum_array_members(&self, pv: &storage::PhysicalVolume, new_member_cb: &mut FnMut(storage::ArrayMember)) -> Result<(),storage::IoError> {
+		let sb = match try!(find_superblock(pv))
+			{
+			Some(v) => v,
+			None => return Err( storage::IoError::InvalidParameter ),
+			};
+		let layout = match array_layout(&sb, pv.blocksize())
+			{
+			Some(v) => v,
+			None => return Err( storage::IoError::InvalidParameter ),
+			};
+		if sb.role as u32 >= sb.raid_disks {
+			log_notice!("PV '{}' is a spare/faulty member of md array '{}', ignoring", pv.name(), sb.set_name);
+			return Ok( () );
+		}
+
+		let bs = pv.blocksize() as u64;
+		new_member_cb(storage::ArrayMember {
+			array_id: sb.set_uuid,
+			name: volume_name(&sb),
+			layout: layout,
+			member_count: sb.raid_disks as usize,
+			slot: sb.role as usize,
+			first_block: sb.data_offset * SECTOR_SIZE / bs,
+			block_count: sb.data_size * SECTOR_SIZE / bs,
+			events: sb.events,
+			});
+		Ok( () )
+	}
+
+	fn set_array_events(&self, pv: &storage::PhysicalVolume, events: u64) -> Result<(),storage::IoError> {
+		let sb = match try!(find_superblock(pv))
+			{
+			Some(v) => v,
+			None => return Err( storage::IoError::InvalidParameter ),
+			};
+		let bs = pv.blocksize();
+		let first_block = sb.sector * SECTOR_SIZE / bs as u64;
+		let sb_len = SB_FIXED_SIZE + sb.max_dev * 2;
+
+		let mut block = vec![0u8; SB_MAX_SIZE];
+		for (i, blk) in block.chunks_mut(bs).enumerate()
+		{
+			try!(pv.read(0, first_block + i as u64, 1, blk).wait());
+		}
+		LittleEndian::write_u64(&mut block[200..], events);
+		let csum = calc_checksum(&block[.. sb_len]);
+		LittleEndian::write_u32(&mut block[216..], csum);
+		// - Only the blocks covering the superblock need to be written back
+		for (i, blk) in block[.. (sb_len + bs - 1) / bs * bs].chunks(bs).enumerate()
+		{
+			try!(pv.write(0, first_block + i as u64, 1, blk).wait());
+		}
+		log_debug!("PV '{}' md array '{}' events {} -> {}", pv.name(), sb.set_name, sb.events, events);
+		Ok( () )
+	}
+}
+
+/// Convert the array level into a storage layout (None if unsupported)
+fn array_layout(sb: &Superblock, block_size: usize) -> Option<storage::ArrayLayout>
+{
+	let bs = block_size as u64;
+	// All offsets must be representable in device blocks
+	if (sb.data_offset * SECTOR_SIZE) % bs != 0 || (sb.chunk_sectors as u64 * SECTOR_SIZE) % bs != 0 {
+		return None;
+	}
+	match sb.level
+	{
+	-1 => Some(storage::ArrayLayout::Concatenated),
+	0 if sb.chunk_sectors > 0 => Some(storage::ArrayLayout::Striped( (sb.chunk_sectors as u64 * SECTOR_SIZE / bs) as usize )),
+	1 => Some(storage::ArrayLayout::Mirrored),
+	_ => None,
+	}
+}
+
+/// Obtain the LV name from the array name (which is formatted as "host:name")
+fn volume_name(sb: &Superblock) -> String
+{
+	let name = match sb.set_name.rfind(':')
+		{
+		Some(p) => &sb.set_name[p+1 ..],
+		None => &sb.set_name[..],
+		};
+	let is_valid = name.len() > 0 && name.bytes().all(|c| match c
+		{
+		b'a' ... b'z' | b'A' ... b'Z' | b'0' ... b'9' => true,
+		b'-' | b'_' | b'.' => true,
+		_ => false,
+		});
+	if is_valid {
+		format!("md-{}", name)
+	}
+	else {
+		let u = &sb.set_uuid;
+		format!("md-{:02x}{:02x}{:02x}{:02x}", u[0], u[1], u[2], u[3])
+	}
+}
+
+/// Locate a version 1.x superblock (1.1 at the start, 1.2 at 4KiB, 1.0 near the end)
+fn find_superblock(pv: &storage::PhysicalVolume) -> Result<Option<Superblock>,storage::IoError>
+{
+	let bs = pv.blocksize() as u64;
+	if bs > SB_MAX_SIZE as u64 || SB_MAX_SIZE as u64 % bs != 0 {
+		return Ok(None);
+	}
+	let cap_sectors = match pv.capacity()
+		{
+		Some(v) => v * bs / SECTOR_SIZE,
+		None => return Ok(None),
+		};
+	let mut candidates = vec![ 0, 8 ];
+	// - v1.0 is 8-12KiB from the end, 4KiB aligned
+	if cap_sectors >= 16 {
+		candidates.push( (cap_sectors - 16) & !7 );
+	}
+
+	for sector in candidates
+	{
+		if let Some(sb) = try!(read_superblock(pv, sector)) {
+			return Ok( Some(sb) );
+		}
+	}
+	Ok(None)
+}
+
+fn read_superblock(pv: &storage::PhysicalVolume, sector: u64) -> Result<Option<Superblock>,storage::IoError>
+{
+	let bs = pv.blocksize();
+	if (sector * SECTOR_SIZE) % bs as u64 != 0 {
+		return Ok(None);
+	}
+	let first_block = sector * SECTOR_SIZE / bs as u64;
+
+	let mut block = vec![0u8; SB_MAX_SIZE];
+	for (i, blk) in block.chunks_mut(bs).enumerate()
+	{
+		try!(pv.read(0, first_block + i as u64, 1, blk).wait());
+	}
+
+	if LittleEndian::read_u32(&block[0..]) != MD_MAGIC || LittleEndian::read_u32(&block[4..]) != 1 {
+		return Ok(None);
+	}
+	if LittleEndian::read_u64(&block[144..]) != sector {
+		log_notice!("md superblock at sector {:#x} has mismatched super_offset", sector);
+		return Ok(None);
+	}
+	let max_dev = LittleEndian::read_u32(&block[220..]) as usize;
+	if SB_FIXED_SIZE + max_dev * 2 > SB_MAX_SIZE {
+		log_notice!("md superblock at sector {:#x} has too many devices ({})", sector, max_dev);
+		return Ok(None);
+	}
+	let sb_len = SB_FIXED_SIZE + max_dev * 2;
+	if calc_checksum(&block[.. sb_len]) != LittleEndian::read_u32(&block[216..]) {
+		log_notice!("md superblock at sector {:#x} checksum mismatch", sector);
+		return Ok(None);
+	}
+
+	let dev_number = LittleEndian::read_u32(&block[160..]) as usize;
+	let mut set_uuid = [0; 16];
+	set_uuid.copy_from_slice(&block[16 .. 32]);
+	let name_bytes = &block[32 .. 64];
+	let name_len = name_bytes.iter().position(|&b| b == 0).unwrap_or(name_bytes.len());
+	Ok(Some(Superblock {
+		sector: sector,
+		max_dev: max_dev,
+		set_uuid: set_uuid,
+		set_name: String::from(::core::str::from_utf8(&name_bytes[..name_len]).unwrap_or("")),
+		level: LittleEndian::read_u32(&block[72..]) as i32,
+		chunk_sectors: LittleEndian::read_u32(&block[88..]),
+		raid_disks: LittleEndian::read_u32(&block[92..]),
+		data_offset: LittleEndian::read_u64(&block[128..]),
+		data_size: LittleEndian::read_u64(&block[136..]),
+		events: LittleEndian::read_u64(&block[200..]),
+		role: if dev_number < max_dev { LittleEndian::read_u16(&block[SB_FIXED_SIZE + dev_number * 2 ..]) } else { 0xFFFF },
+		}))
+}
+
+/// Calculate the superblock checksum (32-bit sum with carries folded in, with the checksum field zeroed)
+fn calc_checksum(sb: &[u8]) -> u32
+{
+	let mut sum: u64 = 0;
+	for (i, w) in sb.chunks(4).enumerate()
+	{
+		sum += match (i, w.len())
+			{
+			(54, _) => 0,	// `sb_csum` field (offset 216)
+			(_, 4) => LittleEndian::read_u32(w) as u64,
+			(_, 2) => LittleEndian::read_u16(w) as u64,
+			_ => 0,
+			};
+	}
+	((sum & 0xFFFFFFFF) + (sum >> 32)) as u32
+}
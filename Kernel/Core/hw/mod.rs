@@ -7,6 +7,8 @@
 pub mod bus_pci;
 
 pub mod mapper_mbr;
+pub mod mapper_gpt;
+pub mod mapper_md;
 
 // vim: ft=rust
 
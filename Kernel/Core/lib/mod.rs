@@ -48,6 +48,7 @@ pub extern crate stack_dst;
 
 pub mod io;
 pub mod byteorder;
+pub mod crc32;
 
 mod pod;
 
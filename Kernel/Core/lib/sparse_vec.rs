@@ -61,6 +61,19 @@ impl<T> SparseVec<T>
 		}
 	}
 	
+	/// Remove and return the item at the specified location
+	pub fn take(&mut self, idx: usize) -> Option<T> {
+		if idx < self.data.len() && self.data[idx].is_some()
+		{
+			self.count -= 1;
+			self.data[idx].take()
+		}
+		else
+		{
+			None
+		}
+	}
+	
 	pub fn get(&self, idx: usize) -> Option<&T> {
 		match self.data.get(idx) {
 		Some(r) => r.as_ref(),
@@ -5,9 +5,9 @@
 //! Page-based cache controller
 //!
 //! This module provides a type that controls a region of memory used only for mapping segments of the
-//! file/block cache into memory. It does _not_ manage eviction of cache entries from physical memory.
-//
-// TODO: Should this module handle a LRU cache of mappings (not actually unmap until needed)
+//! file/block cache into memory. It does _not_ manage eviction of cache entries from physical memory,
+//! instead users can register a reclaim callback that is invoked to release idle mappings when the
+//! region is full.
 use core::ptr::Unique;
 use PAGE_SIZE;
 use memory::phys::FrameHandle;
@@ -25,17 +25,22 @@ impl_from! {
 	}
 }
 
-/// Unique handle to a page in the cache
-pub struct CachedPage(Unique<Page>);
+/// Unique handle to a page (or a contiguous run of pages) in the cache
+pub struct CachedPage(Unique<Page>, usize);
 unsafe impl Send for CachedPage {}
 unsafe impl Sync for CachedPage {}
 
 const MAX_ENTS: usize = 1024;	// 4MB of active cache entries.
+/// Maximum number of pages in a single mapping (runs must fit within one bitmap word)
+pub const MAX_PAGES_PER_MAPPING: usize = 32;
+const MAX_RECLAIMERS: usize = 4;
 
 /// Actual cache structure
 pub struct PageCache
 {
 	avail_ents: ::sync::Semaphore,
+	/// Posted when entries are returned to the free pool (wakes blocked mapping requests)
+	freed: ::sync::EventChannel,
 	bitmap: [AtomicU32; MAX_ENTS / 32],
 	cache_start: AtomicPtr<Page>,
 }
@@ -47,17 +52,51 @@ struct Page([u8; PAGE_SIZE]);
 /// Global page cache instance, use this to access the cache.
 pub static S_PAGE_CACHE: PageCache = PageCache::new();
 
+/// Callbacks used to release idle mappings when the cache is full
+static S_RECLAIMERS: ::sync::Mutex<[Option<fn()->bool>; MAX_RECLAIMERS]> = ::sync::Mutex::new([None; MAX_RECLAIMERS]);
+
 pub fn init()
 {
 	S_PAGE_CACHE.cache_start.store( super::bump_region::delegate(MAX_ENTS).expect("page_cache init") as *mut Page, Ordering::Release ); 
 }
 
+/// Register a callback that releases idle mappings (returning true if any were released)
+///
+/// Reclaimers are called when a blocking mapping request finds the cache full, and must not
+/// themselves call the blocking mapping functions.
+pub fn register_reclaimer(f: fn()->bool)
+{
+	let mut lh = S_RECLAIMERS.lock();
+	for slot in lh.iter_mut()
+	{
+		if slot.is_none() {
+			*slot = Some(f);
+			return ;
+		}
+	}
+	log_error!("page_cache: Too many reclaimers registered, ignoring");
+}
+
+/// Invoke all registered reclaimers, returns true if any released a mapping
+fn run_reclaimers() -> bool
+{
+	// Copy the list out, so the lock isn't held during the callbacks
+	let reclaimers = *S_RECLAIMERS.lock();
+	let mut rv = false;
+	for f in reclaimers.iter().filter_map(|f| *f)
+	{
+		rv |= f();
+	}
+	rv
+}
+
 impl PageCache
 {
 	const fn new() -> PageCache
 	{
 		PageCache {
 			avail_ents: ::sync::Semaphore::new( MAX_ENTS as isize, MAX_ENTS as isize ),
+			freed: ::sync::EventChannel::new(),
 			bitmap: [
 				AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0),  AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0),
 				AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0),  AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0),
@@ -74,30 +113,108 @@ impl PageCache
 		(base as usize + idx * PAGE_SIZE) as *mut Page
 	}
 
-	fn get_free_ent<F,R>(&self, cb: F) -> Result<R, Error>
+	/// Reserve a run of `count` entries and pass the first index to `cb`
+	///
+	/// If `blocking` is set, registered reclaimers are invoked (and the caller sleeps) until entries are available.
+	/// Fails if enough entries are free but none are contiguous, and reclaimers can't release any more.
+	fn get_free_ents<F,R>(&self, count: usize, blocking: bool, cb: F) -> Result<R, Error>
 	where
 		F: FnOnce(usize) -> Result<R, Error>
 	{
-		self.avail_ents.acquire();
+		assert!(count > 0 && count <= MAX_PAGES_PER_MAPPING, "Bad page cache mapping size {}", count);
+		loop
+		{
+			let reserved = self.reserve(count);
+			if reserved
+			{
+				if let Some(idx) = self.alloc_run(count)
+				{
+					return match cb(idx)
+						{
+						Ok(v) => Ok(v),
+						Err(e) => {
+							self.free_run(idx, count);
+							Err(e)
+							},
+						};
+				}
+				// Enough free entries, but no contiguous run
+				for _ in 0 .. count {
+					self.avail_ents.release();
+				}
+			}
+
+			if !blocking {
+				return Err(Error);
+			}
+			// Ask the cache users to release idle mappings
+			if run_reclaimers() {
+				continue ;
+			}
+			// - If the entries could be reserved, enough are free but they're fragmented (and the rest are in use)
+			if reserved {
+				log_notice!("page_cache: No contiguous run of {} entries free", count);
+				return Err(Error);
+			}
+			// - Otherwise, wait for a mapping to be released
+			self.freed.sleep();
+		}
+	}
+
+	/// Take `count` from the available entry count (without blocking)
+	fn reserve(&self, count: usize) -> bool
+	{
+		for i in 0 .. count
+		{
+			if !self.avail_ents.try_acquire() {
+				for _ in 0 .. i {
+					self.avail_ents.release();
+				}
+				return false;
+			}
+		}
+		true
+	}
 
+	/// Mark a run of `count` free entries as used
+	fn alloc_run(&self, count: usize) -> Option<usize>
+	{
+		let mask: u32 = if count == 32 { !0 } else { (1 << count) - 1 };
 		for (blk, e) in self.bitmap.iter().enumerate()
 		{
 			loop
 			{
 				let cur = e.load(Ordering::Relaxed);
-				if cur == !0 {
-					break;
-				}
-
-				let i = (!cur).trailing_zeros() as usize;
+				let i = match (0 .. 32 - count + 1).find(|&i| cur & (mask << i) == 0)
+					{
+					Some(i) => i,
+					None => break,
+					};
 				
-				if cur == e.compare_and_swap(cur, cur | (1 << i), Ordering::Acquire) {
-					return cb( blk * 32 + i );
+				if cur == e.compare_and_swap(cur, cur | (mask << i), Ordering::Acquire) {
+					return Some( blk * 32 + i );
 				}
 			}
 		}
+		None
+	}
 
-		todo!("Is this even possible?");
+	/// Return a run of entries to the free pool
+	fn free_run(&self, idx: usize, count: usize)
+	{
+		let e = &self.bitmap[idx / 32];
+		let mask: u32 = (if count == 32 { !0 } else { (1 << count) - 1 }) << (idx % 32);
+		loop
+		{
+			let cur = e.load(Ordering::Acquire);
+			if cur == e.compare_and_swap(cur, cur & !mask, Ordering::Release) {
+				break ;
+			}
+		}
+		for _ in 0 .. count {
+			self.avail_ents.release();
+		}
+		self.freed.post();
 	}
 
 	/// Map the provided physical frame into virtual memory and return a handle to it
@@ -105,52 +222,73 @@ impl PageCache
 	// TODO: This should be unsafe, as passing the same FrameHandle twice will induce aliasing
 	pub fn map(&self, frame_handle: &FrameHandle) -> Result<CachedPage, Error>
 	{
-		self.get_free_ent(|idx| {
+		self.get_free_ents(1, true, |idx| {
 			let addr = self.addr( idx );
 			assert!( !addr.is_null() );
 			// SAFE: Assuming that we're passed an unaliased handle. Address is non-zero
 			unsafe {
 				::memory::virt::map(addr as *mut (), frame_handle.clone().into_addr(), ProtectionMode::KernelRW);
-				Ok( CachedPage(Unique::new_unchecked(addr as *mut _)) )
+				Ok( CachedPage(Unique::new_unchecked(addr as *mut _), 1) )
 			}
 			})
 	}
 
+	/// Map a set of frames into a contiguous region, failing instead of blocking if the cache is full
+	///
+	// TODO: Same aliasing issue as `map`
+	pub fn try_map_multiple(&self, frame_handles: &[FrameHandle]) -> Result<CachedPage, Error>
+	{
+		self.get_free_ents(frame_handles.len(), false, |idx| {
+			let addr = self.addr( idx );
+			assert!( !addr.is_null() );
+			for (i, fh) in frame_handles.iter().enumerate()
+			{
+				// SAFE: Assuming that we're passed unaliased handles. Address is non-zero and reserved
+				unsafe {
+					::memory::virt::map((addr as usize + i * PAGE_SIZE) as *mut (), fh.clone().into_addr(), ProtectionMode::KernelRW);
+				}
+			}
+			// SAFE: Non-null pointer
+			Ok( CachedPage(unsafe { Unique::new_unchecked(addr as *mut _) }, frame_handles.len()) )
+			})
+	}
+
 	/// Allocate a new frame and place it in the cache
 	pub fn create(&self) -> Result<CachedPage, Error>
 	{
-		self.get_free_ent(|idx| {
+		self.get_free_ents(1, true, |idx| {
 			let addr = self.addr(idx);
 			try!(::memory::virt::allocate(addr as *mut (), 1));
 			// SAFE: Non-null pointer
-			Ok( CachedPage(unsafe { Unique::new_unchecked(addr as *mut _) }) )
+			Ok( CachedPage(unsafe { Unique::new_unchecked(addr as *mut _) }, 1) )
 			})
 	}
 
+	/// Allocate `count` new frames as a contiguous mapping, failing instead of blocking if the cache is full
+	pub fn try_create_multiple(&self, count: usize) -> Result<CachedPage, Error>
+	{
+		self.get_free_ents(count, false, |idx| {
+			let addr = self.addr(idx);
+			try!(::memory::virt::allocate(addr as *mut (), count));
+			// SAFE: Non-null pointer
+			Ok( CachedPage(unsafe { Unique::new_unchecked(addr as *mut _) }, count) )
+			})
+	}
 
-	fn release(&self, addr: *mut Page)
+	fn release(&self, addr: *mut Page, count: usize)
 	{
 		assert!(addr as usize % ::PAGE_SIZE == 0);
 		let base = self.addr(0);
 		assert!(addr as usize >= base as usize);
 		let idx = (addr as usize - base as usize) / ::PAGE_SIZE;
-		assert!(idx < MAX_ENTS);
+		assert!(idx + count <= MAX_ENTS);
 
 		// SAFE: Internally only called on drop of handle
 		unsafe {
-			::memory::virt::unmap(addr as *mut (), 1);
+			::memory::virt::unmap(addr as *mut (), count);
 		}
 
-		let e = &self.bitmap[idx / 32];
-		let mask: u32 = 1 << (idx % 32);
-		loop
-		{
-			let cur = e.load(Ordering::Acquire);
-			if cur == e.compare_and_swap(cur, cur & !mask, Ordering::Release) {
-				break ;
-			}
-		}
-		self.avail_ents.release();
+		self.free_run(idx, count);
 	}
 }
 
@@ -159,25 +297,34 @@ impl PageCache
 impl CachedPage
 {
 	pub fn get_frame_handle(&self) -> FrameHandle {
+		self.get_frame_handle_at(0)
+	}
+	/// Obtain the frame backing the `page`th page of this mapping
+	pub fn get_frame_handle_at(&self, page: usize) -> FrameHandle {
+		assert!(page < self.1);
 		// TODO: Is this actually safe? It would allow aliasing if someone maps this FrameHandle
 		// SAFE: Physical address is valid
 		unsafe {
-			FrameHandle::from_addr( ::memory::virt::get_phys(self.0.as_ptr()) )
+			FrameHandle::from_addr( ::memory::virt::get_phys( (self.0.as_ptr() as usize + page * PAGE_SIZE) as *const Page ) )
 		}
 	}
+	/// Number of pages in this mapping
+	pub fn page_count(&self) -> usize {
+		self.1
+	}
 	pub fn data(&self) -> &[u8] {
-		// SAFE: Owned and valid
-		unsafe { &self.0.as_ref().0 }
+		// SAFE: Owned and valid for the entire run
+		unsafe { ::core::slice::from_raw_parts(self.0.as_ptr() as *const u8, self.1 * PAGE_SIZE) }
 	}
 	pub fn data_mut(&mut self) -> &mut [u8] {
-		// SAFE: Owned and valid
-		unsafe { &mut self.0.as_mut().0 }
+		// SAFE: Owned and valid for the entire run
+		unsafe { ::core::slice::from_raw_parts_mut(self.0.as_ptr() as *mut u8, self.1 * PAGE_SIZE) }
 	}
 }
 impl ::core::ops::Drop for CachedPage
 {
 	fn drop(&mut self) {
-		S_PAGE_CACHE.release( self.0.as_ptr() );
+		S_PAGE_CACHE.release( self.0.as_ptr(), self.1 );
 	}
 }
 
@@ -23,6 +23,9 @@ static S_MAPALLOC : ::sync::Mutex<(usize,PAddr)> = mutex_init!( (0,0) );
 // TODO: Multiple stacks based on page colouring
 static S_FREE_STACK : ::sync::Mutex<PAddr> = mutex_init!( NOPAGE );
 // TODO: Reference counts (maybe require arch to expose that)
+const MAX_RECLAIMERS: usize = 4;
+/// Callbacks used to release cached frames when physical memory is exhausted
+static S_RECLAIMERS: ::sync::Mutex<[Option<fn()->bool>; MAX_RECLAIMERS]> = ::sync::Mutex::new([None; MAX_RECLAIMERS]);
 
 /// A handle to a physical page (maintaining a reference to it, even when not mapped)
 pub struct FrameHandle(PAddr);
@@ -71,6 +74,10 @@ impl FrameHandle
 		::core::mem::forget(self);
 		rv
 	}
+	/// Physical address of the frame (valid while the handle exists)
+	pub fn addr(&self) -> PAddr {
+		self.0
+	}
 }
 impl Clone for FrameHandle
 {
@@ -189,10 +196,60 @@ pub fn allocate(address: *mut ()) -> bool {
 	allocate_int(Some(address)).is_ok()
 }
 
+/// Register a callback that releases cached frames (returning true if any were released)
+///
+/// Reclaimers are called from within the allocator (possibly with the heap or address space locks held),
+/// so must not block, allocate or free heap memory, or map/unmap non-temporary kernel mappings. Temporary
+/// mappings (`virt::with_temp`) are allowed, as they don't take the address space lock, so reclaimers can
+/// release frames (which uses a temporary mapping to push the frame onto the free stack).
+pub fn register_reclaimer(f: fn()->bool)
+{
+	let mut lh = S_RECLAIMERS.lock();
+	for slot in lh.iter_mut()
+	{
+		if slot.is_none() {
+			*slot = Some(f);
+			return ;
+		}
+	}
+	log_error!("phys: Too many reclaimers registered, ignoring");
+}
+
+/// Invoke all registered reclaimers, returns true if any released a frame
+fn run_reclaimers() -> bool
+{
+	// Copy the list out, so the lock isn't held during the callbacks
+	let reclaimers = *S_RECLAIMERS.lock();
+	let mut rv = false;
+	for f in reclaimers.iter().filter_map(|f| *f)
+	{
+		rv |= f();
+	}
+	rv
+}
+
 /// Allocate a page at the given (optional) address
 /// 
 /// If no address is provided, a temporary handle is returned
 fn allocate_int( address: Option<*mut ()> ) -> Result<Option<TempHandle<u8>>, Error>
+{
+	loop
+	{
+		match allocate_once(address)
+		{
+		Some(rv) => return Ok(rv),
+		// 3. If none, release cached frames and try again
+		None => if !run_reclaimers() {
+			break;
+			},
+		}
+	}
+	// 4. Fail
+	log_warning!("Out of physical memory");
+	Err( Error )
+}
+/// Attempt an allocation from the free stack and then the memory map (`None` if both are exhausted)
+fn allocate_once( address: Option<*mut ()> ) -> Option<Option<TempHandle<u8>>>
 {
 	log_trace!("allocate(address={:?})", address);
 	// 1. Pop a page from the free stack
@@ -223,14 +280,14 @@ fn allocate_int( address: Option<*mut ()> ) -> Result<Option<TempHandle<u8>>, Er
 				*(address as *mut [u8; ::PAGE_SIZE]) = ::core::mem::zeroed();
 				log_trace!("- {:p} (stack) paddr = {:#x}", address, paddr);
 				mark_used(paddr);
-				return Ok(None);
+				return Some(None);
 				},
 			None => {
 				let handle = ::arch::memory::virt::TempHandle::new(paddr);
 				*h = *(&handle[0] as *const u8 as *const PAddr);
 				log_trace!("- None (stack) paddr = {:#x}", paddr);
 				mark_used(paddr);
-				return Ok( Some(handle) );
+				return Some( Some(handle) );
 				},
 			}
 		}
@@ -247,19 +304,17 @@ fn allocate_int( address: Option<*mut ()> ) -> Result<Option<TempHandle<u8>>, Er
 			}
 			log_trace!("- {:p} (range) paddr = {:#x}", address, paddr);
 			mark_used(paddr);
-			return Ok( None );
+			return Some( None );
 		}
 		else {
 			log_trace!("- None (range) paddr = {:#x}", paddr);
 			mark_used(paddr);
 			// SAFE: Physical address was just allocated, can't alias
 			let handle = unsafe { ::arch::memory::virt::TempHandle::new(paddr) };
-			return Ok( Some(handle) );
+			return Some( Some(handle) );
 		}
 	}
-	// 3. Fail
-	log_warning!("Out of physical memory");
-	Err( Error )
+	None
 }
 
 pub fn ref_frame(paddr: PAddr)
@@ -219,13 +219,19 @@ impl Reservation
 		// SAFE: Unique, and owned
 		unsafe { ::core::slice::from_raw_parts_mut( (self.0 as usize + idx * ::PAGE_SIZE) as *mut u8, ::PAGE_SIZE) }
 	}
-	//pub fn map_at(&mut self, idx: usize, frame: FrameHandle) -> Result<(),FrameHandle> {
-	//	assert!(idx < self.1);
-	//	let addr = (self.0 as usize + idx * ::PAGE_SIZE) as *mut ();
-	//	::arch::memory::virt::unmap(addr);
-	//	::arch::memory::virt::map(addr, frame.into_addr(), ProtectionMode::KernelRW);
-	//	Ok( () )
-	//}
+	/// Replace the placeholder page at `idx` with the provided frame (the mapping takes the handle's reference)
+	pub fn map_at(&mut self, idx: usize, frame: ::memory::phys::FrameHandle) {
+		assert!(idx < self.1);
+		let addr = (self.0 as usize + idx * ::PAGE_SIZE) as *mut ();
+		// SAFE: 'self' owns this region of memory, and the placeholder frame is released
+		unsafe {
+			if let Some(paddr) = ::arch::memory::virt::unmap(addr) {
+				::memory::phys::deref_frame(paddr);
+			}
+			// - Mapped read-only until `finalise`, as the frame can be shared
+			::arch::memory::virt::map(addr, frame.into_addr(), ProtectionMode::KernelRO);
+		}
+	}
 	pub fn finalise(self, final_mode: ProtectionMode) -> Result<(),()> {
 		log_trace!("Reservation::finalise(final_mode={:?})", final_mode);
 		for addr in Pages(self.0, self.1) {
@@ -13,6 +13,16 @@ module_define!{Storage, [], init}
 
 pub type AsyncIoResult<'a, T> = ::async::BoxAsyncResult<'a, T, IoError>;
 
+/// Priority for normal (demand) IO requests
+pub const IO_PRIO_DEFAULT: u8 = 0;
+/// Priority for background IO (e.g. write-back of cached data), serviced after other requests
+pub const IO_PRIO_BACKGROUND: u8 = 192;
+
+/// Maximum size of a request formed by merging adjacent requests
+const MAX_MERGE_BYTES: usize = 128*1024;
+/// Maximum number of batches (merged requests) in progress on a physical volume at one time
+const MAX_IN_FLIGHT: usize = 4;
+
 /// A unique handle to a storage volume (logical)
 pub struct VolumeHandle
 {
@@ -91,6 +101,8 @@ pub trait PhysicalVolume: Send + 'static
 	fn blocksize(&self) -> usize;
 	/// Returns the number of blocks in this volume (i.e. the capacity)
 	fn capacity(&self) -> Option<u64>;
+	/// Returns true if the volume can't be written (e.g. optical media, or write-protected devices)
+	fn is_readonly(&self) -> bool { false }
 	
 	/// Reads a number of blocks from the volume into the provided buffer
 	///
@@ -123,15 +135,101 @@ pub trait Mapper: Send + Sync
 	
 	/// Enumerate volumes
 	fn enum_volumes(&self, pv: &PhysicalVolume, f: &mut FnMut(String, u64, u64)) -> Result<(),IoError>;
+	
+	/// Enumerate regions of this volume that are members of multi-volume arrays
+	///
+	/// The array's logical volume is created once all of its members have been found (or, for mirrored
+	/// arrays, once at least half have been found).
+	fn enum_array_members(&self, _pv: &PhysicalVolume, _f: &mut FnMut(ArrayMember)) -> Result<(),IoError> {
+		Ok( () )
+	}
+
+	/// Update the array update counter (`ArrayMember::events`) stored on this volume
+	///
+	/// Called before writing to a mirrored array with missing or failed members, so that those members are
+	/// seen as stale when the array is next assembled. Arrays are not writable while degraded if this fails.
+	fn set_array_events(&self, _pv: &PhysicalVolume, _events: u64) -> Result<(),IoError> {
+		Err( IoError::Unknown("Array metadata not writable") )
+	}
+}
+
+/// How the members of a multi-volume array are combined
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum ArrayLayout
+{
+	/// Members are joined end-to-end
+	Concatenated,
+	/// Data is striped across members in chunks of the given number of blocks (RAID0)
+	Striped(usize),
+	/// Each member holds a full copy of the data (RAID1)
+	Mirrored,
+}
+
+/// A region of a physical volume that is part of a multi-volume array
+#[derive(Debug)]
+pub struct ArrayMember
+{
+	/// Unique identifier for the array (shared by all members)
+	pub array_id: [u8; 16],
+	/// Name of the logical volume to create
+	pub name: String,
+	pub layout: ArrayLayout,
+	/// Total number of members in the array
+	pub member_count: usize,
+	/// Position of this member within the array
+	pub slot: usize,
+	pub first_block: u64,
+	pub block_count: u64,
+	/// Update counter, members with a lower value than the newest member are stale
+	pub events: u64,
 }
 
 
 /// A single physical volume
 struct PhysicalVolumeInfo
 {
-	dev: Box<PhysicalVolume>,
+	queue: Arc<IoQueue>,
 	mapper: Option<(usize,&'static Mapper)>,
 }
+/// Request queue for a physical volume
+///
+/// Requests are dispatched by the submitting threads (highest priority first), with up to `MAX_IN_FLIGHT`
+/// batches in progress at once. A submitter dispatches batches until its own request has been issued, then
+/// waits for it to complete. Adjacent requests are merged into a single device request.
+struct IoQueue
+{
+	dev: Box<PhysicalVolume>,
+	state: ::sync::Mutex<IoQueueState>,
+}
+struct IoQueueState
+{
+	/// Number of batches in progress
+	active: usize,
+	pending: Vec<Arc<IoRequest>>,
+	/// Requests in the batches in progress
+	in_flight: Vec<Arc<IoRequest>>,
+}
+#[derive(PartialEq,Copy,Clone)]
+enum IoOp
+{
+	Read,
+	Write,
+}
+/// A queued request (the submitting thread waits until it completes)
+struct IoRequest
+{
+	op: IoOp,
+	prio: u8,
+	first: u64,
+	count: usize,
+	/// Data buffer (only read from for writes)
+	buf: *mut u8,
+	result: ::sync::Mutex<Option<Result<(),IoError>>>,
+	/// Released when the request completes, or to wake the submitter to dispatch (see `handoff`)
+	done: ::sync::Semaphore,
+	/// Set when the submitter has been woken to dispatch pending requests
+	handoff: ::core::sync::atomic::AtomicBool,
+}
 /// A single logical volume, composed of 1 or more physical blocks
 #[derive(Default)]
 struct LogicalVolume
@@ -146,9 +244,30 @@ struct LogicalVolume
 	block_size: usize,
 	/// Stripe size (number of blocks), None = JBOD
 	chunk_size: Option<usize>,
+	/// If true, every region holds a full copy of the volume (RAID1)
+	mirrored: bool,
+	/// Array identifier, used to add late members to a degraded mirrored array
+	array: Option<[u8; 16]>,
+	/// Update counter and failed members of a mirrored array
+	mirror: ::sync::Mutex<MirrorState>,
 	/// Physical regions that compose this logical volume
 	regions: Vec<PhysicalRegion>,
 }
+/// Runtime state of a mirrored (RAID1) array
+#[derive(Default)]
+struct MirrorState
+{
+	/// Update counter of the live members
+	events: u64,
+	/// Total number of members in the array (more than the number of regions if degraded)
+	member_count: usize,
+	/// Regions that have failed a write (no longer read from or written to)
+	failed: Vec<usize>,
+	/// Set when members are missing or have failed, and the live members' update counter hasn't been increased
+	///
+	/// Writes must not be made until it has, otherwise the absent members would look current at the next assembly.
+	needs_bump: bool,
+}
 /// Physical region used by a logical volume
 struct PhysicalRegion
 {
@@ -162,6 +281,8 @@ static S_PHYSICAL_VOLUMES: LazyMutex<VecMap<usize,PhysicalVolumeInfo>> = lazymut
 static S_NEXT_LV_IDX: AtomicUsize = ATOMIC_USIZE_INIT;
 static S_LOGICAL_VOLUMES: LazyMutex<VecMap<usize,Arc<LogicalVolume>>> = lazymutex_init!();
 static S_MAPPERS: LazyMutex<Vec<&'static Mapper>> = lazymutex_init!();
+/// Array members waiting for the rest of their array to appear (pv_id, block_size, member)
+static S_PENDING_MEMBERS: LazyMutex<Vec<(usize,usize,ArrayMember)>> = lazymutex_init!();
 
 // NOTE: Should unbinding of LVs be allowed? (Yes, for volume removal)
 
@@ -170,6 +291,7 @@ fn init()
 	S_PHYSICAL_VOLUMES.init( || VecMap::new() );
 	S_LOGICAL_VOLUMES.init( || VecMap::new() );
 	S_MAPPERS.init( || Vec::new() );
+	S_PENDING_MEMBERS.init( || Vec::new() );
 	
 	// Default mapper just exposes the PV as a single LV
 	//S_MAPPERS.lock().push_back(&default_mapper::Mapper);
@@ -216,7 +338,7 @@ pub fn register_pv(dev: Box<PhysicalVolume>) -> PhysicalVolumeReg
 	
 	// Wait until after checking for a handler before we add the PV to the list
 	S_PHYSICAL_VOLUMES.lock().insert(pv_id, PhysicalVolumeInfo {
-		dev: dev,
+		queue: Arc::new(IoQueue::new(dev)),
 		mapper: None,
 		});
 	
@@ -243,13 +365,13 @@ pub fn register_mapper(mapper: &'static Mapper)
 	// Check unbound PVs
 	for (&id,pv) in S_PHYSICAL_VOLUMES.lock().iter_mut()
 	{
-		if pv.dev.capacity().is_none() {
+		if pv.queue.dev.capacity().is_none() {
 			// No media, skip
 			continue ;
 		}
-		match mapper.handles_pv(&*pv.dev)
+		match mapper.handles_pv(&*pv.queue.dev)
 		{
-		Err(e) => log_error!("Error checking PV{}: {:?}", pv.dev.name(), e),
+		Err(e) => log_error!("Error checking PV{}: {:?}", pv.queue.dev.name(), e),
 		Ok(0) => {},	// Ignore
 		Ok(level) => 
 			if let Some( (lvl, _other) ) = pv.mapper
@@ -294,7 +416,7 @@ fn apply_mapper_to_pv(mapper: &'static Mapper, level: usize, pv_id: usize, pvi:
 				.filter(|&(_,lv)| lv.is_opened)
 				.count();
 			if num_mounted > 0 {
-				log_notice!("{}LVs using PV #{} {} are mounted, not updating mapping", num_mounted, pv_id, pvi.dev.name() );
+				log_notice!("{}LVs using PV #{} {} are mounted, not updating mapping", num_mounted, pv_id, pvi.queue.dev.name() );
 				return ;
 			}
 			// > If none are mounted, then remove the mappings
@@ -307,6 +429,7 @@ fn apply_mapper_to_pv(mapper: &'static Mapper, level: usize, pv_id: usize, pvi:
 		for k in keys {
 			lh.remove(&k);
 		}
+		S_PENDING_MEMBERS.lock().retain(|&(id, _, _)| id != pv_id);
 		pvi.mapper = None;
 	}
 	// 2. Bind this new mapper to the volume
@@ -314,14 +437,180 @@ fn apply_mapper_to_pv(mapper: &'static Mapper, level: usize, pv_id: usize, pvi:
 	pvi.mapper = Some( (level, mapper) );
 	// - Enumerate volumes
 	//  TODO: Support more complex volume types
-	match mapper.enum_volumes(&*pvi.dev, &mut |name, base, len| {
-		new_simple_lv(name, pv_id, pvi.dev.blocksize(), base, len);
+	match mapper.enum_volumes(&*pvi.queue.dev, &mut |name, base, len| {
+		new_simple_lv(name, pv_id, pvi.queue.dev.blocksize(), base, len);
+		})
+	{
+	Err(e) => log_error!("IO Error while enumerating {}: {:?}", pvi.queue.dev.name(), e),
+	Ok(_) => {},
+	}
+	match mapper.enum_array_members(&*pvi.queue.dev, &mut |member| {
+		add_array_member(pv_id, pvi.queue.dev.blocksize(), member);
 		})
 	{
-	Err(e) => log_error!("IO Error while enumerating {}: {:?}", pvi.dev.name(), e),
+	Err(e) => log_error!("IO Error while enumerating array members on {}: {:?}", pvi.queue.dev.name(), e),
 	Ok(_) => {},
 	}
 }
+/// Record an array member, creating the array's LV once all members are present
+fn add_array_member(pv_id: usize, block_size: usize, member: ArrayMember)
+{
+	log_debug!("Array member PV{}: {:?}", pv_id, member);
+	if member.layout == ArrayLayout::Mirrored && add_mirror_member(pv_id, block_size, &member) {
+		return ;
+	}
+	let members = {
+		let mut lh = S_PENDING_MEMBERS.lock();
+		if lh.iter().any(|&(_, _, ref m)| m.array_id == member.array_id && m.slot == member.slot) {
+			log_warning!("Duplicate member for slot {} of array '{}', ignoring PV{}", member.slot, member.name, pv_id);
+			return ;
+		}
+		let array_id = member.array_id;
+		let member_count = member.member_count;
+		let layout = member.layout;
+		lh.push( (pv_id, block_size, member) );
+
+		// - Mirrored arrays only need half of their members to be usable (the rest are added as they appear)
+		let quorum = if layout == ArrayLayout::Mirrored { (member_count + 1) / 2 } else { member_count };
+		if lh.iter().filter(|&&(_, _, ref m)| m.array_id == array_id).count() < quorum {
+			return ;
+		}
+		// - Enough present, remove from the pending list
+		let mut members = Vec::with_capacity(member_count);
+		let mut i = 0;
+		while i < lh.len()
+		{
+			if lh[i].2.array_id == array_id {
+				members.push( lh.remove(i) );
+			}
+			else {
+				i += 1;
+			}
+		}
+		members
+		};
+	new_array_lv(members);
+}
+/// Add a member to an already assembled (degraded) mirrored array, returns false if the array doesn't exist yet
+fn add_mirror_member(pv_id: usize, block_size: usize, member: &ArrayMember) -> bool
+{
+	let mut lh = S_LOGICAL_VOLUMES.lock();
+	let lv = match lh.iter_mut().find(|&(_, ref v)| v.array == Some(member.array_id))
+		{
+		Some((_,v)) => v,
+		None => return false,
+		};
+	let events = lv.mirror.lock().events;
+	if member.events < events {
+		log_warning!("Stale member for slot {} of array '{}' (events {} < {}), ignoring PV{}", member.slot, member.name, member.events, events, pv_id);
+		return true;
+	}
+	if block_size != lv.block_size || member.block_count < lv.block_count() {
+		log_error!("Member for slot {} of array '{}' has a different block size or is too small, ignoring PV{}", member.slot, member.name, pv_id);
+		return true;
+	}
+	// - The regions can only be changed while the volume isn't open
+	match Arc::get_mut(lv)
+	{
+	Some(lv) => {
+		let region_size = lv.regions[0].block_count;
+		let new_region = PhysicalRegion {
+			volume: pv_id,
+			block_count: region_size,
+			first_block: member.first_block,
+			};
+		let mut state = lv.mirror.lock();
+		// - A newer member means that the already assembled members are stale, so replaces them
+		if member.events > events {
+			log_warning!("Array '{}' members assembled so far are stale (events {} < {}), replacing with PV{}", lv.name, events, member.events, pv_id);
+			lv.regions = vec![ new_region ];
+			state.events = member.events;
+			state.failed.clear();
+			state.needs_bump = lv.regions.len() < state.member_count;
+		}
+		else {
+			lv.regions.push( new_region );
+			if lv.regions.len() == state.member_count && state.failed.is_empty() {
+				state.needs_bump = false;
+			}
+		}
+		log_log!("Logical Volume: {} gained mirror PV{} ({} of {} present)", lv.name, pv_id, lv.regions.len(), member.member_count);
+		},
+	None => log_warning!("Array '{}' is in use, not adding member PV{}", member.name, pv_id),
+	}
+	true
+}
+fn new_array_lv(mut members: Vec<(usize,usize,ArrayMember)>)
+{
+	members.sort_by(|a, b| a.2.slot.cmp(&b.2.slot));
+	let name = members[0].2.name.clone();
+	let layout = members[0].2.layout;
+	let block_size = members[0].1;
+	if members.iter().any(|&(_, bs, ref m)| m.layout != layout || bs != block_size) {
+		log_error!("Array '{}' members disagree on layout or block size, not creating LV", name);
+		return ;
+	}
+	// - Mirrored arrays can be missing members, but other layouts need every slot
+	if layout != ArrayLayout::Mirrored && members.iter().enumerate().any(|(i, &(_, _, ref m))| m.slot != i) {
+		log_error!("Array '{}' has inconsistent member slots, not creating LV", name);
+		return ;
+	}
+	// - Members that missed updates (e.g. were absent when the array was last used) hold stale data
+	let events = members.iter().map(|&(_, _, ref m)| m.events).max().unwrap_or(0);
+	for &(pv, _, ref m) in members.iter().filter(|&&(_, _, ref m)| m.events < events) {
+		log_warning!("Stale member for slot {} of array '{}' (events {} < {}), ignoring PV{}", m.slot, name, m.events, events, pv);
+	}
+	if layout != ArrayLayout::Mirrored && members.iter().any(|&(_, _, ref m)| m.events < events) {
+		log_error!("Array '{}' has stale members, not creating LV", name);
+		return ;
+	}
+	members.retain(|&(_, _, ref m)| m.events == events);
+	if layout == ArrayLayout::Striped(0) {
+		log_error!("Array '{}' has a zero chunk size, not creating LV", name);
+		return ;
+	}
+
+	// - Striped and mirrored arrays only use the size of the smallest member
+	let min_size = members.iter().map(|&(_, _, ref m)| m.block_count).min().unwrap_or(0);
+	let region_size = match layout
+		{
+		ArrayLayout::Concatenated => None,
+		ArrayLayout::Striped(chunk) => Some(min_size - min_size % chunk as u64),
+		ArrayLayout::Mirrored => Some(min_size),
+		};
+	let regions: Vec<_> = members.iter()
+		.map(|&(pv, _, ref m)| PhysicalRegion {
+			volume: pv,
+			block_count: region_size.unwrap_or(m.block_count) as usize,
+			first_block: m.first_block,
+			})
+		.collect();
+
+	let lvidx = S_NEXT_LV_IDX.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed);
+	let lv = Arc::new( LogicalVolume {
+		index: lvidx,
+		name: name,
+		is_opened: false,
+		block_size: block_size,
+		chunk_size: match layout { ArrayLayout::Striped(c) => Some(c), _ => None },
+		mirrored: layout == ArrayLayout::Mirrored,
+		array: if layout == ArrayLayout::Mirrored { Some(members[0].2.array_id) } else { None },
+		mirror: ::sync::Mutex::new(MirrorState {
+			events: events,
+			member_count: members[0].2.member_count,
+			failed: Vec::new(),
+			needs_bump: layout == ArrayLayout::Mirrored && regions.len() < members[0].2.member_count,
+			}),
+		regions: regions,
+		} );
+	
+	log_log!("Logical Volume: {} {} ({:?} over {} PVs)", lv.name, SizePrinter(lv.block_count() * block_size as u64), layout, lv.regions.len());
+	if lv.regions.len() < members[0].2.member_count {
+		log_warning!("Array '{}' is degraded ({} of {} members present)", lv.name, lv.regions.len(), members[0].2.member_count);
+	}
+	
+	S_LOGICAL_VOLUMES.lock().insert(lvidx, lv);
+}
 fn new_simple_lv(name: String, pv_id: usize, block_size: usize, base: u64, size: u64)
 {
 	let lvidx = S_NEXT_LV_IDX.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed);
@@ -333,6 +622,9 @@ fn new_simple_lv(name: String, pv_id: usize, block_size: usize, base: u64, size:
 		is_opened: false,
 		block_size: block_size,
 		chunk_size: None,
+		mirrored: false,
+		array: None,
+		mirror: Default::default(),
 		regions: vec![ PhysicalRegion{ volume: pv_id, block_count: size as usize, first_block: base } ],
 		} );
 	
@@ -349,7 +641,7 @@ fn new_simple_lv(name: String, pv_id: usize, block_size: usize, base: u64, size:
 /// Enumerate present physical volumes (returning both the identifier and name)
 pub fn enum_pvs() -> Vec<(usize,String)>
 {
-	S_PHYSICAL_VOLUMES.lock().iter().map(|(k,v)| (*k, String::from_str(v.dev.name())) ).collect()
+	S_PHYSICAL_VOLUMES.lock().iter().map(|(k,v)| (*k, String::from_str(v.queue.dev.name())) ).collect()
 }
 
 
@@ -386,9 +678,16 @@ impl VolumeHandle
 	/// Acquire an unique handle to a logical volume
 	pub fn open_idx(idx: usize) -> Result<VolumeHandle,VolOpenError>
 	{
-		match S_LOGICAL_VOLUMES.lock().get(&idx)
+		match S_LOGICAL_VOLUMES.lock().get_mut(&idx)
 		{
-		Some(v) => todo!("open_lv '{}'", v.name),
+		Some(v) => {
+			if Arc::get_mut(v).is_some() {
+				Ok( VolumeHandle { handle: v.clone() } )
+			}
+			else {
+				Err( VolOpenError::Locked )
+			}
+			},
 		None => Err( VolOpenError::NotFound ),
 		}
 	}
@@ -418,13 +717,36 @@ impl VolumeHandle
 	pub fn name(&self) -> &str {
 		&self.handle.name
 	}
+	/// Number of logical blocks in the volume
+	pub fn block_count(&self) -> u64 {
+		self.handle.block_count()
+	}
+	/// Returns true if any of the underlying physical volumes are read-only
+	pub fn is_readonly(&self) -> bool {
+		let pvs = S_PHYSICAL_VOLUMES.lock();
+		self.handle.regions.iter().any(|r| pvs.get(&r.volume).map(|pv| pv.queue.dev.is_readonly()).unwrap_or(false))
+	}
 	
 	// TODO: Return a more complex type that can be incremented
 	// Returns: VolIdx, Block, Count
 	fn get_phys_block(&self, idx: u64, count: usize) -> Option<(usize,u64,usize)> {
-		if let Some(size) = self.handle.chunk_size
+		if self.handle.mirrored
 		{
-			todo!("Non JBOD logocal volumes ({} block stripe)", size);
+			// Reads go to the first live mirror (falling back to the others on error), see `get_mirror_block`
+			self.get_mirror_block(self.first_mirror(), idx, count)
+		}
+		else if let Some(size) = self.handle.chunk_size
+		{
+			// Chunks are distributed round-robin across the regions
+			let nregions = self.handle.regions.len() as u64;
+			let chunk = idx / size as u64;
+			let chunk_ofs = idx % size as u64;
+			let region = &self.handle.regions[ (chunk % nregions) as usize ];
+			let blk = (chunk / nregions) * size as u64 + chunk_ofs;
+			if blk < region.block_count as u64 {
+				let ret_count = ::core::cmp::min(size as u64 - chunk_ofs, count as u64) as usize;
+				return Some( (region.volume, region.first_block + blk, ret_count) );
+			}
 		}
 		else
 		{
@@ -445,12 +767,27 @@ impl VolumeHandle
 		}
 		None
 	}
+	/// Obtain the location of a block on a specific mirror (for mirrored volumes)
+	fn get_mirror_block(&self, mirror: usize, idx: u64, count: usize) -> Option<(usize,u64,usize)> {
+		let v = &self.handle.regions[mirror];
+		if idx < v.block_count as u64 {
+			let ret_count = ::core::cmp::min(v.block_count as u64 - idx, count as u64) as usize;
+			Some( (v.volume, v.first_block + idx, ret_count) )
+		}
+		else {
+			None
+		}
+	}
 	
 	/// Read a series of blocks from the volume into the provided buffer.
 	/// 
 	/// The buffer must be a multiple of the logical block size
 	pub fn read_blocks(&self, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
-		log_trace!("VolumeHandle::read_blocks(idx={}, dst={{len={}}})", idx, dst.len());
+		self.read_blocks_prio(IO_PRIO_DEFAULT, idx, dst)
+	}
+	/// Read a series of blocks with the specified priority (0 = highest, 255 = lowest)
+	pub fn read_blocks_prio(&self, prio: u8, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
+		log_trace!("VolumeHandle::read_blocks(prio={}, idx={}, dst={{len={}}})", prio, idx, dst.len());
 		if dst.len() % self.block_size() != 0 {
 			log_warning!("Read size {} not a multiple of {} bytes", dst.len(), self.block_size());
 			return Err( IoError::InvalidParameter );
@@ -471,15 +808,61 @@ impl VolumeHandle
 			assert!(count <= rem);
 			let bofs = blk as usize * self.block_size();
 			let dst = &mut dst[bofs .. bofs + count * self.block_size()];
-			try!( S_PHYSICAL_VOLUMES.lock().get(&pv).expect("Volume missing").read(ofs, dst) );
+			let queue = S_PHYSICAL_VOLUMES.lock().get(&pv).expect("Volume missing").queue.clone();
+			match queue.read(prio, ofs, dst)
+			{
+			Ok(_) => {},
+			// - Mirrored volumes can retry on another copy
+			Err(e) if self.handle.mirrored => try!(self.read_other_mirrors(prio, idx + blk as u64, dst, e)),
+			Err(e) => return Err(e),
+			}
 			blk += count;
 			rem -= count;
 		}
 		Ok( () )
 	}
 
+	/// Index of the first mirror that hasn't failed (reads are served from it)
+	fn first_mirror(&self) -> usize {
+		let state = self.handle.mirror.lock();
+		(0 .. self.handle.regions.len()).find(|i| !state.failed.contains(i)).unwrap_or(0)
+	}
+	fn is_failed_mirror(&self, mirror: usize) -> bool {
+		self.handle.mirror.lock().failed.contains(&mirror)
+	}
+
+	/// Retry a read that failed on the first mirror using the remaining mirrors
+	fn read_other_mirrors(&self, prio: u8, idx: u64, dst: &mut [u8], err: IoError) -> Result<(),IoError> {
+		let count = dst.len() / self.block_size();
+		let first = self.first_mirror();
+		for mirror in 0 .. self.handle.regions.len()
+		{
+			if mirror == first || self.is_failed_mirror(mirror) {
+				continue ;
+			}
+			let (pv, ofs, _) = self.get_mirror_block(mirror, idx, count).expect("Mirrors differ in size");
+			let queue = S_PHYSICAL_VOLUMES.lock().get(&pv).expect("Volume missing").queue.clone();
+			match queue.read(prio, ofs, dst)
+			{
+			Ok(_) => {
+				log_notice!("{}: Read of block {} failed on first mirror ({:?}), read from PV{} instead", self.name(), idx, err, pv);
+				return Ok( () );
+				},
+			Err(e) => log_warning!("{}: Read of block {} failed on PV{} - {:?}", self.name(), idx, pv, e),
+			}
+		}
+		Err(err)
+	}
+
 	pub fn write_blocks(&self, idx: u64, dst: &[u8]) -> Result<(),IoError> {
-		log_trace!("VolumeHandle::write_blocks(idx={}, dst={{len={}}})", idx, dst.len());
+		self.write_blocks_prio(IO_PRIO_DEFAULT, idx, dst)
+	}
+	/// Write a series of blocks with the specified priority (0 = highest, 255 = lowest)
+	pub fn write_blocks_prio(&self, prio: u8, idx: u64, dst: &[u8]) -> Result<(),IoError> {
+		log_trace!("VolumeHandle::write_blocks(prio={}, idx={}, dst={{len={}}})", prio, idx, dst.len());
+		if self.is_readonly() {
+			return Err( IoError::ReadOnly );
+		}
 		if dst.len() % self.block_size() != 0 {
 			log_warning!("Write size {} not a multiple of {} bytes", dst.len(), self.block_size());
 			return Err( IoError::InvalidParameter );
@@ -500,81 +883,388 @@ impl VolumeHandle
 			assert!(count <= rem);
 			let bofs = blk as usize * self.block_size();
 			let dst = &dst[bofs .. bofs + count * self.block_size()];
-			try!( S_PHYSICAL_VOLUMES.lock().get(&pv).unwrap().write(ofs, dst) );
+			if self.handle.mirrored {
+				// - Mirrored volumes need the data written to every copy
+				try!( self.write_mirrors(prio, idx + blk as u64, count, dst) );
+			}
+			else {
+				let queue = S_PHYSICAL_VOLUMES.lock().get(&pv).unwrap().queue.clone();
+				try!( queue.write(prio, ofs, dst) );
+			}
 			blk += count;
 			rem -= count;
 		}
 		Ok( () )
 	}
+
+	/// Write to every live mirror, removing mirrors that fail from the array
+	///
+	/// Only fails if no mirror could be written.
+	fn write_mirrors(&self, prio: u8, idx: u64, count: usize, src: &[u8]) -> Result<(),IoError> {
+		// - Absent members must be marked stale before the live members diverge from them
+		try!( self.update_array_events() );
+		let mut written = 0;
+		let mut err = IoError::NoMedium;
+		for mirror in 0 .. self.handle.regions.len()
+		{
+			if self.is_failed_mirror(mirror) {
+				continue ;
+			}
+			let (pv, ofs, _) = self.get_mirror_block(mirror, idx, count).expect("Mirrors differ in size");
+			let queue = S_PHYSICAL_VOLUMES.lock().get(&pv).unwrap().queue.clone();
+			match queue.write(prio, ofs, src)
+			{
+			Ok(_) => written += 1,
+			Err(e) => {
+				log_error!("{}: Write of block {} failed on PV{} ({:?}), removing it from the array", self.name(), idx, pv, e);
+				let mut state = self.handle.mirror.lock();
+				state.failed.push(mirror);
+				state.needs_bump = true;
+				err = e;
+				},
+			}
+		}
+		if written == 0 {
+			return Err(err);
+		}
+		// - Mark any members that just failed as stale
+		self.update_array_events()
+	}
+
+	/// Increase the update counter on the live members of a degraded mirrored array (if not already done)
+	fn update_array_events(&self) -> Result<(),IoError> {
+		let mut state = self.handle.mirror.lock();
+		if !state.needs_bump {
+			return Ok( () );
+		}
+		let events = state.events + 1;
+		let mut updated = 0;
+		let mut err = IoError::NoMedium;
+		for (i, r) in self.handle.regions.iter().enumerate()
+		{
+			if state.failed.contains(&i) {
+				continue ;
+			}
+			let (queue, mapper) = {
+				let pvs = S_PHYSICAL_VOLUMES.lock();
+				let pvi = pvs.get(&r.volume).expect("Volume missing");
+				(pvi.queue.clone(), pvi.mapper)
+				};
+			let res = match mapper
+				{
+				Some( (_, m) ) => m.set_array_events(&*queue.dev, events),
+				None => Err( IoError::Unknown("No mapper") ),
+				};
+			match res
+			{
+			Ok(_) => updated += 1,
+			Err(e) => {
+				log_error!("{}: Updating array metadata on PV{} failed ({:?}), removing it from the array", self.name(), r.volume, e);
+				state.failed.push(i);
+				err = e;
+				},
+			}
+		}
+		if updated == 0 {
+			log_error!("{}: No array members could be updated, array is read-only", self.name());
+			return Err(err);
+		}
+		log_notice!("{}: Array degraded ({} of {} members live), update counter increased to {}",
+			self.name(), updated, state.member_count, events);
+		state.events = events;
+		state.needs_bump = false;
+		Ok( () )
+	}
 }
 
-impl PhysicalVolumeInfo
+impl LogicalVolume
 {
-	fn max_blocks_per_read(&self) -> usize {
-		// 32 blocks per read op, = 0x4000 (16KB) for 512 byte sectors
-		// TODO: Remove this?
-		32
+	/// Number of logical blocks in the volume
+	fn block_count(&self) -> u64 {
+		if self.mirrored {
+			self.regions.get(0).map(|r| r.block_count as u64).unwrap_or(0)
+		}
+		else {
+			self.regions.iter().map(|r| r.block_count as u64).sum()
+		}
 	}
-	
-	/// Read blocks from the device
-	pub fn read(&self, first: u64, dst: &mut [u8]) -> Result<usize,IoError>
+}
+
+// SAFE: `PhysicalVolume` methods take `&self` and return async handles, so must already handle concurrent requests
+unsafe impl Sync for IoQueue {}
+// SAFE: The buffer is only accessed by the dispatcher while the submitting thread waits for completion
+unsafe impl Send for IoRequest {}
+unsafe impl Sync for IoRequest {}
+
+impl IoQueue
+{
+	fn new(dev: Box<PhysicalVolume>) -> IoQueue
+	{
+		IoQueue {
+			dev: dev,
+			state: ::sync::Mutex::new(IoQueueState {
+				active: 0,
+				pending: Vec::new(),
+				in_flight: Vec::new(),
+				}),
+		}
+	}
+
+	/// Read blocks from the device (via the queue)
+	pub fn read(&self, prio: u8, first: u64, dst: &mut [u8]) -> Result<(),IoError>
+	{
+		log_trace!("IoQueue::read(prio={},first={},{} bytes)", prio, first, dst.len());
+		self.submit(IoOp::Read, prio, first, dst.as_mut_ptr(), dst.len())
+	}
+	/// Write blocks to the device (via the queue)
+	pub fn write(&self, prio: u8, first: u64, src: &[u8]) -> Result<(),IoError>
+	{
+		log_trace!("IoQueue::write(prio={},first={},{} bytes)", prio, first, src.len());
+		self.submit(IoOp::Write, prio, first, src.as_ptr() as *mut u8, src.len())
+	}
+
+	fn submit(&self, op: IoOp, prio: u8, first: u64, buf: *mut u8, len: usize) -> Result<(),IoError>
+	{
+		assert!(len % self.dev.blocksize() == 0);
+		let req = Arc::new(IoRequest {
+			op: op,
+			prio: prio,
+			first: first,
+			count: len / self.dev.blocksize(),
+			buf: buf,
+			result: ::sync::Mutex::new(None),
+			// - Max of 2: A hand-off wake can race with completion
+			done: ::sync::Semaphore::new(0, 2),
+			handoff: ::core::sync::atomic::AtomicBool::new(false),
+			});
+		self.state.lock().pending.push( req.clone() );
+		loop
+		{
+			// - Dispatch batches (not necessarily containing this request) until this request has been issued
+			let batch = {
+				let mut lh = self.state.lock();
+				if lh.active < MAX_IN_FLIGHT && lh.is_pending(&req) {
+					lh.take_batch(MAX_MERGE_BYTES / self.dev.blocksize())
+				}
+				else {
+					None
+				}
+				};
+			match batch
+			{
+			Some(batch) => self.run_and_complete(batch),
+			None => {
+				// - Wait for completion, or to be woken to dispatch
+				req.done.acquire();
+				req.handoff.store(false, ::core::sync::atomic::Ordering::SeqCst);
+				let rv = req.result.lock().take();
+				if let Some(rv) = rv {
+					return rv;
+				}
+				},
+			}
+		}
+	}
+
+	/// Run a batch taken from the queue, and complete its requests
+	fn run_and_complete(&self, batch: Vec<Arc<IoRequest>>)
+	{
+		let res = self.run_batch(&batch);
+		for r in batch.iter()
+		{
+			*r.result.lock() = Some(res);
+			r.done.release();
+		}
+
+		let mut lh = self.state.lock();
+		lh.active -= 1;
+		lh.in_flight.retain(|r| !batch.iter().any(|b| &**b as *const IoRequest == &**r as *const IoRequest));
+		// - Wake a sleeping submitter to dispatch the next batch (its own thread may not be dispatching)
+		if let Some(r) = lh.pending.iter().find(|r| !r.handoff.swap(true, ::core::sync::atomic::Ordering::SeqCst)) {
+			r.done.release();
+		}
+	}
+
+	/// Issue a batch of adjacent requests (sorted by block) as a single device request
+	fn run_batch(&self, batch: &[Arc<IoRequest>]) -> Result<(),IoError>
 	{
-		log_trace!("PhysicalVolumeInfo::read(first={},{} bytes)", first, dst.len());
 		let block_size = self.dev.blocksize();
-		let total_blocks = dst.len() / block_size;
-		// Read up to 'block_step' blocks in each read call
-		// - TODO: Request a read of as much as possible, and be told by the device how many were serviced
+		let head = &batch[0];
+		if batch.len() == 1 {
+			// SAFE: Buffer is valid until the request is completed
+			let buf = unsafe { ::core::slice::from_raw_parts_mut(head.buf, head.count * block_size) };
+			return match head.op
+				{
+				IoOp::Read => self.dev_read(head.prio, head.first, buf),
+				IoOp::Write => self.dev_write(head.prio, head.first, buf),
+				};
+		}
+
+		let prio = batch.iter().map(|r| r.prio).min().unwrap();
+		let count: usize = batch.iter().map(|r| r.count).sum();
+		log_trace!("Merged {} requests into {}+{}", batch.len(), head.first, count);
+		let mut bounce = vec![0u8; count * block_size];
+		match head.op
 		{
-			let mut buf = dst;
-			let mut blk_id = first;
-			while buf.len() > 0
+		IoOp::Read => {
+			try!(self.dev_read(prio, head.first, &mut bounce));
+			for r in batch
+			{
+				let ofs = (r.first - head.first) as usize * block_size;
+				// SAFE: Buffer is valid until the request is completed
+				let dst = unsafe { ::core::slice::from_raw_parts_mut(r.buf, r.count * block_size) };
+				dst.clone_from_slice( &bounce[ofs ..][.. dst.len()] );
+			}
+			Ok( () )
+			},
+		IoOp::Write => {
+			for r in batch
 			{
-				assert!(buf.len() % block_size == 0);
-				let prio = 0;
-				let blocks = buf.len() / block_size;
-				
-				// TODO: Async! (maybe return a composite read handle?)
-				let real_count = match self.dev.read(prio, blk_id, blocks, buf).wait()
-					{
-					Ok(v) => v,
-					Err(e) => todo!("Error when PV fails to read: {:?}", e),
-					};
-				assert!(real_count <= blocks);
-				blk_id += real_count as u64;
-
-				// SAFE: Evil stuff to advance the buffer
-				buf = unsafe { &mut *(&mut buf[real_count * block_size..] as *mut _) };
-//				split_at_mut_inplace(&mut buf, real_count * block_size);
+				let ofs = (r.first - head.first) as usize * block_size;
+				// SAFE: Buffer is valid until the request is completed
+				let src = unsafe { ::core::slice::from_raw_parts(r.buf as *const u8, r.count * block_size) };
+				bounce[ofs ..][.. src.len()].clone_from_slice( src );
 			}
+			self.dev_write(prio, head.first, &bounce)
+			},
 		}
+	}
+	
+	/// Read blocks from the device
+	fn dev_read(&self, prio: u8, first: u64, dst: &mut [u8]) -> Result<(),IoError>
+	{
+		let block_size = self.dev.blocksize();
+		// Request as much as possible, and advance by how many blocks the device serviced
+		let mut buf = dst;
+		let mut blk_id = first;
+		while buf.len() > 0
+		{
+			assert!(buf.len() % block_size == 0);
+			let blocks = buf.len() / block_size;
+			
+			// TODO: Async! (maybe return a composite read handle?)
+			let real_count = try!(self.dev.read(prio, blk_id, blocks, buf).wait());
+			assert!(real_count <= blocks);
+			if real_count == 0 {
+				return Err( IoError::Unknown("Device serviced zero blocks") );
+			}
+			blk_id += real_count as u64;
 
-		Ok(total_blocks)
+			// SAFE: Evil stuff to advance the buffer
+			buf = unsafe { &mut *(&mut buf[real_count * block_size..] as *mut _) };
+		}
+		Ok( () )
 	}
 	
-	/// Write blocks from the device
-	pub fn write(&self, first: u64, dst: &[u8]) -> Result<usize,IoError>
+	/// Write blocks to the device
+	fn dev_write(&self, prio: u8, first: u64, src: &[u8]) -> Result<(),IoError>
 	{
-		log_trace!("PhysicalVolumeInfo::write(first={},{} bytes)", first, dst.len());
-		let block_step = self.max_blocks_per_read();
 		let block_size = self.dev.blocksize();
-		// Read up to 'block_step' blocks in each read call
+		let mut buf = src;
+		let mut blk_id = first;
+		while buf.len() > 0
 		{
-			let iter_ids  = (first .. ).step_by(block_step);
-			let iter_bufs = dst.chunks( block_step * block_size );
-			for (blk_id,buf) in iter_ids.zip( iter_bufs )
+			assert!(buf.len() % block_size == 0);
+			let blocks = buf.len() / block_size;
+			
+			// TODO: Async! (maybe return a composite read handle?)
+			let real_count = try!(self.dev.write(prio, blk_id, blocks, buf).wait());
+			assert!(real_count <= blocks);
+			if real_count == 0 {
+				return Err( IoError::Unknown("Device serviced zero blocks") );
+			}
+			blk_id += real_count as u64;
+			buf = &buf[real_count * block_size ..];
+		}
+		Ok( () )
+	}
+}
+
+impl IoRequest
+{
+	/// Returns true if the two requests touch the same blocks, and at least one is a write (so their order matters)
+	fn conflicts(&self, other: &IoRequest) -> bool
+	{
+		let overlap = self.first < other.first + other.count as u64 && other.first < self.first + self.count as u64;
+		overlap && (self.op == IoOp::Write || other.op == IoOp::Write)
+	}
+}
+
+impl IoQueueState
+{
+	fn is_pending(&self, req: &Arc<IoRequest>) -> bool
+	{
+		self.pending.iter().any(|r| &**r as *const IoRequest == &**req as *const IoRequest)
+	}
+
+	/// Remove the highest priority request, along with any requests it can be merged with, and mark them as in flight
+	///
+	/// Requests never overtake an older request that they conflict with (see `IoRequest::conflicts`), instead the
+	/// older request is promoted. Requests that conflict with one in flight wait for it to complete, returning
+	/// None if all pending requests conflict.
+	fn take_batch(&mut self, max_blocks: usize) -> Option<Vec<Arc<IoRequest>>>
+	{
+		// - Oldest of the highest priority requests (that can be issued now)
+		let mut order: Vec<usize> = (0 .. self.pending.len()).collect();
+		order.sort_by_key(|&i| (self.pending[i].prio, i));
+		let idx = match order.into_iter()
+			.map(|mut idx| {
+				// - If an older request conflicts with it, that has to go first
+				while let Some(i) = self.oldest_conflict(idx) {
+					idx = i;
+				}
+				idx
+				})
+			.find(|&idx| !self.conflicts_in_flight(idx))
 			{
-				let prio = 0;
-				let blocks = buf.len() / block_size;
-				
-				// TODO: Async! (maybe return a composite read handle?)
-				match self.dev.write(prio, blk_id, blocks, buf).wait()
-				{
-				Ok(real_count) => { assert!(real_count == blocks, "TODO: Handle incomplete writes"); },
-				Err(e) => todo!("Error when PV fails to write: {:?}", e),
+			Some(v) => v,
+			None => return None,
+			};
+		let head = self.pending.remove(idx);
+		let op = head.op;
+		let mut start = head.first;
+		let mut end = start + head.count as u64;
+		let mut batch = vec![head];
+
+		// - Merge in requests that extend the batch on either side
+		loop
+		{
+			let pos = (0 .. self.pending.len()).position(|i| {
+				let r = &self.pending[i];
+				r.op == op && (r.first == end || r.first + r.count as u64 == start) && (end - start) as usize + r.count <= max_blocks
+					&& self.oldest_conflict(i).is_none() && !self.conflicts_in_flight(i)
+				});
+			match pos
+			{
+			Some(i) => {
+				let p = self.pending.remove(i);
+				if p.first == end {
+					end += p.count as u64;
 				}
+				else {
+					start = p.first;
+				}
+				batch.push(p);
+				},
+			None => break,
 			}
 		}
-		Ok(dst.len()/block_size)
+		batch.sort_by_key(|p| p.first);
+		self.in_flight.extend( batch.iter().cloned() );
+		self.active += 1;
+		Some(batch)
+	}
+	/// Returns true if the pending request at `idx` conflicts with a request in flight
+	fn conflicts_in_flight(&self, idx: usize) -> bool
+	{
+		let r = &self.pending[idx];
+		self.in_flight.iter().any(|p| p.conflicts(r))
+	}
+	/// Locate the oldest pending request that was submitted before `idx` and conflicts with it
+	fn oldest_conflict(&self, idx: usize) -> Option<usize>
+	{
+		let r = &self.pending[idx];
+		self.pending[..idx].iter().position(|p| p.conflicts(r))
 	}
 }
 
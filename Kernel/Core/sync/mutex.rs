@@ -72,6 +72,18 @@ impl<T: Send> Mutex<T>
 		::core::sync::atomic::fence(::core::sync::atomic::Ordering::Acquire);
 		return HeldMutex { lock: self };
 	}
+	/// Lock the mutex if it's not already held (never blocks)
+	pub fn try_lock(&self) -> Option<HeldMutex<T>> {
+		{
+			let mut lh = self.inner.lock();
+			if lh.held != false {
+				return None;
+			}
+			lh.held = true;
+		}
+		::core::sync::atomic::fence(::core::sync::atomic::Ordering::Acquire);
+		Some( HeldMutex { lock: self } )
+	}
 	/// Release the mutex
 	fn unlock(&self) {
 		::core::sync::atomic::fence(::core::sync::atomic::Ordering::Release);
@@ -128,6 +140,15 @@ impl<T: Send> LazyMutex<T>
 		assert!(lh.is_some(), "Locking an uninitialised LazyMutex<{}>", type_name!(T));
 		HeldLazyMutex( lh )
 	}
+	/// Lock the lazy mutex if it's initialised and not already held (never blocks)
+	pub fn try_lock(&self) -> Option<HeldLazyMutex<T>>
+	{
+		match self.0.try_lock()
+		{
+		Some(lh) => if lh.is_some() { Some(HeldLazyMutex(lh)) } else { None },
+		None => None,
+		}
+	}
 }
 
 impl<'lock,T:Send> ops::Drop for HeldMutex<'lock,T>
@@ -46,6 +46,17 @@ impl Semaphore
 			lh.value -= 1;
 		}
 	}
+	/// Attempt to acquire without blocking, returns false if the value is zero
+	pub fn try_acquire(&self) -> bool {
+		let mut lh = self.internals.lock();
+		if lh.value < 1 {
+			false
+		}
+		else {
+			lh.value -= 1;
+			true
+		}
+	}
 	pub fn release(&self) {
 		let mut lh = self.internals.lock();
 		if lh.wait_queue.has_waiter() {
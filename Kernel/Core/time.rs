@@ -13,6 +13,24 @@ pub fn ticks() -> u64
 	::arch::cur_timestamp()
 }
 
+/// Wall-clock time (seconds since 1970-01-01 00:00:00 UTC)
+pub type Timestamp = i64;
+
+/// Convert a calendar date/time (UTC, `month` and `day` are 1-based) into a `Timestamp`
+pub fn timestamp_from_date(year: i64, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Timestamp
+{
+	// Count years from March, so the leap day is the last day of the year
+	let y = if month <= 2 { year - 1 } else { year };
+	let era = (if y >= 0 { y } else { y - 399 }) / 400;
+	let year_of_era = y - era * 400;
+	let m = month as i64;
+	let day_of_year = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	// 719468 = Days from 0000-03-01 to 1970-01-01
+	let days = era * 146097 + day_of_era - 719468;
+	days * 86400 + hour as i64 * 3600 + minute as i64 * 60 + second as i64
+}
+
 
 /// Records the current time on construction, and prints the elapsed time with {:?} / {}
 pub struct ElapsedLogger(TickCount);
@@ -35,6 +53,108 @@ impl_fmt! {
 }
 
 
+/// Maximum number of kernel timers active at one time
+const MAX_TIMERS: usize = 32;
+
+/// Kernel timer, signalling a sleep object once a timestamp is reached
+///
+/// The timer is cancelled when this handle is dropped. Timers are checked from the architecture's timer interrupt,
+/// so may fire up to one timer period late.
+pub struct Timer
+{
+	slot: usize,
+	id: u64,
+	_obj: ::threads::SleepObjectRef,
+}
+#[derive(Copy,Clone)]
+struct TimerSlot
+{
+	id: u64,
+	expiry: TickCount,
+	obj: *const ::threads::SleepObject<'static>,
+}
+struct TimerList
+{
+	next_id: u64,
+	slots: [Option<TimerSlot>; MAX_TIMERS],
+}
+// SAFE: The sleep object pointers are kept valid by the `SleepObjectRef` in `Timer`
+unsafe impl Send for TimerList {}
+static S_TIMERS: ::sync::Spinlock<TimerList> = ::sync::Spinlock::new(TimerList { next_id: 0, slots: [None; MAX_TIMERS] });
+
+impl Timer
+{
+	/// Request that `obj` be signalled once `ticks()` reaches `expiry`
+	///
+	/// Returns None if all timer slots are in use.
+	pub fn new(expiry: TickCount, obj: ::threads::SleepObjectRef) -> Option<Timer>
+	{
+		let _irq = ::sync::hold_interrupts();
+		let mut lh = S_TIMERS.lock();
+		let slot = match lh.slots.iter().position(|s| s.is_none())
+			{
+			Some(i) => i,
+			None => return None,
+			};
+		let id = lh.next_id;
+		lh.next_id += 1;
+		lh.slots[slot] = Some(TimerSlot {
+			id: id,
+			expiry: expiry,
+			obj: &*obj as *const _,
+			});
+		// - Already expired, fire now instead of waiting for the next tick
+		if expiry <= ticks() {
+			lh.slots[slot] = None;
+			obj.signal();
+		}
+		Some(Timer {
+			slot: slot,
+			id: id,
+			_obj: obj,
+			})
+	}
+}
+impl ::core::ops::Drop for Timer
+{
+	fn drop(&mut self)
+	{
+		let _irq = ::sync::hold_interrupts();
+		let mut lh = S_TIMERS.lock();
+		// - Only clear the slot if it hasn't fired (and been reused)
+		if lh.slots[self.slot].map(|s| s.id == self.id).unwrap_or(false) {
+			lh.slots[self.slot] = None;
+		}
+	}
+}
+
+/// Fire expired timers (called from the architecture's timer interrupt)
+#[doc(hidden)]
+#[is_safe(irq)]
+pub fn handle_tick()
+{
+	// - If the lock is held (on this CPU or another), try again on the next tick
+	if let Some(mut lh) = S_TIMERS.try_lock_cpu()
+	{
+		let now = ticks();
+		for slot in lh.slots.iter_mut()
+		{
+			let fired = match *slot
+				{
+				Some(ref s) if s.expiry <= now => {
+					// SAFE: Pointer is valid while the slot is populated (see `Timer::drop`)
+					unsafe { (*s.obj).signal(); }
+					true
+					},
+				_ => false,
+				};
+			if fired {
+				*slot = None;
+			}
+		}
+	}
+}
+
 pub struct CacheTimer(::sync::atomic::AtomicValue<TickCount>);
 impl Default for CacheTimer {
 	fn default() -> Self {
@@ -50,6 +170,11 @@ impl CacheTimer
 	pub fn bump(&self) {
 		self.0.store(ticks(), ::core::sync::atomic::Ordering::SeqCst)
 	}
+
+	/// Time of the last bump (or construction)
+	pub fn get(&self) -> TickCount {
+		self.0.load(::core::sync::atomic::Ordering::SeqCst)
+	}
 }
 
 // vim: ft=rust
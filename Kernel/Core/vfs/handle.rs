@@ -5,20 +5,25 @@
 //! Opened file interface
 #[allow(unused_imports)]
 use prelude::*;
-use super::node::{CacheHandle,NodeType};
+use super::node::{CacheHandle,NodeType,NodeInfo,Access};
 use lib::byte_str::{ByteStr,ByteString};
 use super::Path;
+use super::watch::{self,Origin};
+use memory::phys::FrameHandle;
 
 #[derive(Debug,Clone)]
 /// Open without caring what the file type is (e.g. enumeration)
 pub struct Any {
 	node: CacheHandle,
+	/// Where the node was opened from (for change notifications)
+	origin: Option<Origin>,
 }
-#[derive(Debug,Clone)]
+#[derive(Debug)]
 /// Normal file
 pub struct File {
 	node: CacheHandle,
 	mode: FileOpenMode,
+	origin: Option<Origin>,
 }
 #[derive(Debug,Clone)]
 /// Directory (for enumeration)
@@ -65,7 +70,7 @@ pub enum FileOpenMode
 	Unsynch,
 }
 
-#[derive(Debug)]
+#[derive(Debug,Copy,Clone,PartialEq)]
 pub enum MemoryMapMode
 {
 	/// Read-only mapping of a file
@@ -83,13 +88,17 @@ impl Any
 	/// Open the specified path (not caring what the actual type is)
 	pub fn open(path: &Path) -> super::Result<Any> {
 		log_trace!("Any::open({:?})", path);
-		let node = try!(CacheHandle::from_path(path));
-		Ok(Any { node: node })
+		let (node, parents) = try!(CacheHandle::from_path_with_parents(path));
+		Ok(Any { origin: Origin::from_walk(&parents, path), node: node })
 	}
 
 	pub fn get_class(&self) -> super::node::NodeClass {
 		self.node.get_class()
 	}
+	/// Obtain the node's metadata
+	pub fn get_info(&self) -> super::Result<NodeInfo> {
+		self.node.get_info()
+	}
 	
 	/// Upgrade the handle to a directory handle
 	pub fn to_dir(self) -> super::Result<Dir> {
@@ -103,7 +112,7 @@ impl Any
 
 	pub fn to_file(self, mode: FileOpenMode) -> super::Result<File> {
 		if self.node.is_file() {
-			File::from_node(self.node, mode)
+			File::from_node(self.node, self.origin, mode)
 		}
 		else {
 			Err(super::Error::TypeMismatch)
@@ -120,41 +129,90 @@ impl Any
 	}
 }
 
-pub struct MemoryMapHandle<'a>
+/// Handle to a mapped region of a file, unmaps the region when dropped
+///
+/// `WriteBack` mappings write modified pages back to the file when dropped.
+pub struct MemoryMapHandle
 {
-	handle: &'a File,
+	handle: File,
+	mode: MemoryMapMode,
+	/// Process that owns the address space containing the mapping
+	pid: ::threads::ProcessID,
 	base: *mut (),
-	len: usize,
+	/// First page of the file in the mapping
+	file_page: u64,
+	/// Number of pages in the mapping
+	page_count: usize,
+	/// Private copies of the pages (`WriteBack` only)
+	/// - Held so they can be written back even once the owning address space has been released
+	copies: Vec<FrameHandle>,
 }
+unsafe impl Send for MemoryMapHandle {}
+unsafe impl Sync for MemoryMapHandle {}
 
 impl File
 {
 	/// Open the specified path as a file
 	pub fn open(path: &Path, mode: FileOpenMode) -> super::Result<File> {
-		let node = try!(CacheHandle::from_path(path));
-		Self::from_node(node, mode)
+		let (node, parents) = try!(CacheHandle::from_path_with_parents(path));
+		Self::from_node(node, Origin::from_walk(&parents, path), mode)
 	}
 
-	fn from_node(node: CacheHandle, mode: FileOpenMode) -> super::Result<File> {
+	fn from_node(node: CacheHandle, origin: Option<Origin>, mode: FileOpenMode) -> super::Result<File> {
 		if !node.is_file() {
 			return Err(super::Error::TypeMismatch);
 		}
 		match mode
 		{
-		// TODO: Mark file as shared
-		// TODO: Check permissions (must be readable in current context)
-		FileOpenMode::SharedRO => {},
-		// TODO: Mark file as shared
-		// TODO: Check permissions (must be executable in current context)
-		FileOpenMode::Execute => {},
-		_ => todo!("Acquire lock depending on mode({:?})", mode),
+		FileOpenMode::SharedRO | FileOpenMode::Execute => {},
+		_ => if node.is_readonly() {
+			return Err(super::Error::ReadOnlyFilesystem);
+			},
 		}
-		Ok(File { node: node, mode: mode })
+		// - Check that the file's permissions allow this mode
+		match mode
+		{
+		FileOpenMode::SharedRO => try!(node.check_access(Access::Read)),
+		FileOpenMode::Execute => try!(node.check_access(Access::Execute)),
+		FileOpenMode::Append => try!(node.check_access(Access::Write)),
+		FileOpenMode::ExclRW | FileOpenMode::UniqueRW | FileOpenMode::Unsynch => {
+			try!(node.check_access(Access::Read));
+			try!(node.check_access(Access::Write));
+			},
+		}
+		{
+			let mut lh = node.file_locks().expect("File node without lock state").lock();
+			let available = match mode
+				{
+				// - Readers see a stable file, so can't coexist with anything that modifies the existing contents
+				FileOpenMode::SharedRO | FileOpenMode::Execute => lh.exclusive == 0 && lh.unsynch == 0,
+				// - Exclusive access (Append is still allowed, as it can't modify the existing contents)
+				// TODO: UniqueRW should create a copy-on-write view of the file, until then it's treated as ExclRW
+				FileOpenMode::ExclRW | FileOpenMode::UniqueRW => lh.readers == 0 && lh.exclusive == 0 && lh.unsynch == 0,
+				FileOpenMode::Append => lh.unsynch == 0,
+				// - Multiple unsynchronised handles are allowed, but nothing else
+				FileOpenMode::Unsynch => lh.readers == 0 && lh.exclusive == 0 && lh.append == 0,
+				};
+			if !available {
+				log_debug!("File::from_node - {:?} conflicts with {:?}", mode, *lh);
+				return Err(super::Error::Locked);
+			}
+			*lock_count(&mut lh, &mode) += 1;
+		}
+		Ok(File { node: node, mode: mode, origin: origin })
 	}
 	
 	pub fn size(&self) -> u64 {
 		self.node.get_valid_size()
 	}
+	/// Obtain the file's metadata
+	pub fn get_info(&self) -> super::Result<NodeInfo> {
+		self.node.get_info()
+	}
+	/// Write cached modifications to the file's volume back to disk
+	pub fn sync(&self) -> super::Result<()> {
+		super::mount::Handle::from_id(self.node.get_ids().0).sync()
+	}
 
 	/// Read data from the file at the specified offset
 	///
@@ -162,15 +220,39 @@ impl File
 	/// slice).
 	pub fn read(&self, ofs: u64, dst: &mut [u8]) -> super::Result<usize> {
 		assert!(self.node.is_file());
-		self.node.read(ofs, dst)
+		match self.mode
+		{
+		FileOpenMode::Append => Err(super::Error::PermissionDenied),
+		_ => self.node.read(ofs, dst),
+		}
 	}
+	/// Write data to the file at the specified offset
+	///
+	/// For `Append` handles, the offset is ignored and the data is written to the end of the file
 	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
 		assert!(self.node.is_file());
-		todo!("Handle::write({:#x}, {:p}+{}", ofs, src.as_ptr(), src.len());
+		let rv = match self.mode
+			{
+			FileOpenMode::SharedRO | FileOpenMode::Execute => return Err(super::Error::PermissionDenied),
+			FileOpenMode::Append => {
+				// Hold the lock state while appending, so each append is atomic
+				let _lh = self.node.file_locks().unwrap().lock();
+				let size = self.node.get_valid_size();
+				try!(self.node.write(size, src))
+				},
+			_ => try!(self.node.write(ofs, src)),
+			};
+		if let Some(ref o) = self.origin {
+			o.notify(watch::EventKind::Modified);
+		}
+		Ok(rv)
 	}
 
 	
 	/// Map a file into the address space
+	///
+	/// The address and file offset must have the same alignment within a page, the mapping is expanded to cover
+	/// entire pages (with anything past the end of the file zeroed).
 	pub fn memory_map(&self, address: usize, ofs: u64, size: usize, mode: MemoryMapMode) -> super::Result<MemoryMapHandle> {
 		log_debug!("memory_map(self={{mode:{:?}}}, address={:#x}, ofs={:#x}, size={:#x}, mode={:?})",
 			self.mode, address, ofs, size, mode);
@@ -192,37 +274,54 @@ impl File
 			FileOpenMode::Execute => {},
 			_ => return Err(super::Error::PermissionDenied),
 			},
-		// COW - Execute mode only
-		// - TODO: Could allow COW of readonly files? (as soon as it's written, the page is detached from the file)
+		// COW - Any mode where the file contents can't change under the mapping
+		// - As soon as a page is written, it's detached from the file
 		MemoryMapMode::COW => match self.mode
 			{
 			FileOpenMode::Execute => {},
-			//FileOpenMode::SharedRO => {},
+			FileOpenMode::SharedRO => {},
 			_ => return Err(super::Error::PermissionDenied),
 			},
 		// Writeback - Requires exclusive access to the file (or a copy)
 		MemoryMapMode::WriteBack => match self.mode
 			{
-			//FileOpenMode::ExclRW => /* NOTE: Needs extra checks to ensure that aliasing does not occur */
-			//FileOpenMode::UniqueRW => /* NOTE: Needs extra checks to ensure that aliasing does not occur */
+			FileOpenMode::ExclRW => {},
+			FileOpenMode::UniqueRW => {},
 			_ => return Err(super::Error::PermissionDenied),
 			},
 		}
 		
-		// TODO: Handle unaligned mappings somehow
-		// - Depends on several qirks:
-		//  > Unaligned address could write to an existing page (converting it to a private) - But how would that interact with existing mappings?
-		//  > Unaligned sizes would usually cause a new anon mapping, but if its unaligned becuase of EOF, it should just be COW as usual
-		assert!(address % ::PAGE_SIZE == 0, "TODO: Unaligned memory_map (address={})", address);
-		assert!(size % ::PAGE_SIZE == 0, "TODO: Unaligned memory_map (size={})", size);
+		// - Unaligned mappings are expanded to whole pages, so the address and offset must be equally misaligned
 		if address % ::PAGE_SIZE != (ofs % ::PAGE_SIZE as u64) as usize {
-			return Err( super::Error::Unknown("memory_map alignment mismatch") );
+			return Err( super::Error::InvalidParameter );
 		}
 		// - Limit checking (ofs + size must be within size of the file)
-		// TODO: Limit checking
-		// - Reserve the region to be mapped (reserve sticks a zero page in)
-		let page_count = size / ::PAGE_SIZE;
-		let mut resv = match ::memory::virt::reserve(address as *mut (), page_count)
+		if size == 0 || ofs.checked_add(size as u64).map(|end| end > self.size()).unwrap_or(true) {
+			return Err( super::Error::InvalidParameter );
+		}
+		let lead = address % ::PAGE_SIZE;
+		let base = address - lead;
+		let file_page = ofs / ::PAGE_SIZE as u64;
+		let page_count = (lead + size + ::PAGE_SIZE - 1) / ::PAGE_SIZE;
+
+		// - Obtain handles to each cached page (before reserving, so a read error doesn't leave a partial mapping)
+		let mut pages = Vec::with_capacity(page_count);
+		for i in 0 .. page_count {
+			pages.push( try!(self.node.get_page(file_page + i as u64)) );
+		}
+		// - `WriteBack` mappings are of private copies (compared against the file when unmapped)
+		let copies = if mode == MemoryMapMode::WriteBack {
+				let mut copies = Vec::with_capacity(page_count);
+				for page in &pages {
+					copies.push( try!(copy_frame(page)) );
+				}
+				copies
+			}
+			else {
+				Vec::new()
+			};
+		// - Reserve the region to be mapped (reserve sticks a zero page in), and map over it
+		let mut resv = match ::memory::virt::reserve(base as *mut (), page_count)
 			{
 			Ok(v) => v,
 			Err(e) => {
@@ -230,24 +329,15 @@ impl File
 				return Err( super::Error::Locked );
 				},
 			};
-		// - Obtain handles to each cached page, and map into the reservation
-		for i in 0 .. page_count {
-			let page = ofs / ::PAGE_SIZE as u64 + i as u64;
-			// 1. Search the node for this particular page
-			//let lh = self.page_cache.read();
-			//  - If found, map over region
-			// 2. Drop lock, read data from file, and try again
-			//drop(lh)
-			try!( self.node.read(page * ::PAGE_SIZE as u64, resv.get_mut_page(i)) );
-			// 3. Acquire write on lock, and attempt to insert a handle to this page
-			//let lh = self.page_cache.write();
-			//match lh.try_insert(pag, self.get_page_handle(i))
-			//{
-			//Ok(_) => {},	// Inserted correctly
-			//Err(h) => {	// Another handle made a page for this first
-			//	resv.map_at(i, h);	// - Map over our original attempt
-			//	},
-			//}
+		if copies.is_empty() {
+			for (i, page) in pages.into_iter().enumerate() {
+				resv.map_at(i, page);
+			}
+		}
+		else {
+			for (i, copy) in copies.iter().enumerate() {
+				resv.map_at(i, copy.clone());
+			}
 		}
 		resv.finalise( match mode
 			{
@@ -257,32 +347,122 @@ impl File
 			MemoryMapMode::WriteBack => ::memory::virt::ProtectionMode::UserRW,
 			})
 			.unwrap();
-		log_debug!("- Mapped at {:p} + {:#x}", address as *mut (), page_count * ::PAGE_SIZE);
+		log_debug!("- Mapped at {:p} + {:#x}", base as *mut (), page_count * ::PAGE_SIZE);
 		Ok(MemoryMapHandle {
-			handle: self,
-			base: address as *mut (),
-			len: page_count * ::PAGE_SIZE,
+			handle: self.clone(),
+			mode: mode,
+			pid: ::threads::get_process_id(),
+			base: base as *mut (),
+			file_page: file_page,
+			page_count: page_count,
+			copies: copies,
 			})
 	}
 }
+/// Allocate a private copy of a frame
+fn copy_frame(frame: &FrameHandle) -> super::Result<FrameHandle>
+{
+	let mut new_page = match ::memory::virt::alloc_free()
+		{
+		Ok(v) => v,
+		Err(_) => return Err( super::Error::OutOfMemory ),
+		};
+	// SAFE: The frame is kept valid by the handle, and only read
+	unsafe {
+		::memory::virt::with_temp(frame.addr(), |src| new_page.as_slice_mut::<u8>().clone_from_slice(&src[..]));
+	}
+	Ok( new_page.into_frame() )
+}
+/// Obtain the count of handles for the specified mode
+fn lock_count<'a>(locks: &'a mut super::node::FileLocks, mode: &FileOpenMode) -> &'a mut usize
+{
+	match *mode
+	{
+	FileOpenMode::SharedRO | FileOpenMode::Execute => &mut locks.readers,
+	FileOpenMode::ExclRW | FileOpenMode::UniqueRW => &mut locks.exclusive,
+	FileOpenMode::Append => &mut locks.append,
+	FileOpenMode::Unsynch => &mut locks.unsynch,
+	}
+}
+impl Clone for File
+{
+	fn clone(&self) -> File {
+		// A clone shares the original's access, so always succeeds
+		let mut lh = self.node.file_locks().unwrap().lock();
+		*lock_count(&mut lh, &self.mode) += 1;
+		File { node: self.node.clone(), mode: self.mode.clone(), origin: self.origin.clone() }
+	}
+}
 impl ::core::ops::Drop for File
 {
 	fn drop(&mut self) {
-		match self.mode
-		{
-		FileOpenMode::SharedRO => {},
-		FileOpenMode::Execute => {},
-		_ => todo!("File::drop() - mode={:?}", self.mode),
-		}
-		// TODO: For files, we need to release the lock
+		let mut lh = self.node.file_locks().unwrap().lock();
+		let count = lock_count(&mut lh, &self.mode);
+		assert!(*count > 0, "File::drop() - mode={:?} not held", self.mode);
+		*count -= 1;
 	}
 }
 
-impl<'a> Drop for MemoryMapHandle<'a>
+impl MemoryMapHandle
+{
+	/// Write a page of a `WriteBack` mapping back to the file, if it differs from the file's contents
+	///
+	/// The private copy is accessed using a temporary mapping, so this works from any address space.
+	fn flush_page(&self, idx: usize, copy: &FrameHandle) -> super::Result<()> {
+		let file_page = self.file_page + idx as u64;
+		let file_ofs = file_page * ::PAGE_SIZE as u64;
+		let size = self.handle.size();
+		if file_ofs >= size {
+			return Ok( () );
+		}
+		let len = ::core::cmp::min(::PAGE_SIZE as u64, size - file_ofs) as usize;
+		// - Copied out, so a temporary mapping isn't held during IO
+		let mut data = Vec::from_elem(len, 0u8);
+		// SAFE: The frame is kept valid by the handle, and only read
+		unsafe {
+			::memory::virt::with_temp(copy.addr(), |src| data.clone_from_slice(&src[..len]));
+		}
+		let cached = try!(self.handle.node.get_page(file_page));
+		// SAFE: As above
+		let changed = unsafe { ::memory::virt::with_temp(cached.addr(), |src| &src[..len] != &data[..]) };
+		if changed {
+			try!(self.handle.write(file_ofs, &data));
+		}
+		Ok( () )
+	}
+}
+impl Drop for MemoryMapHandle
 {
 	fn drop(&mut self)
 	{
-		todo!("MemoryMapHandle::drop {{ handle={:?}, base={:p}+{}}}", self.handle, self.base, self.len);
+		log_debug!("MemoryMapHandle::drop - {:p}+{:#x} {:?}", self.base, self.page_count * ::PAGE_SIZE, self.mode);
+		// The mapping can only be removed from within its own address space
+		// - If the process is being torn down, its address space (and the mapping) is released separately
+		if ::threads::get_process_id() != self.pid {
+			log_notice!("MemoryMapHandle::drop - {:p}+{:#x} dropped outside owning process, not unmapping",
+				self.base, self.page_count * ::PAGE_SIZE);
+		}
+		else {
+			for i in 0 .. self.page_count
+			{
+				let addr = (self.base as usize + i * ::PAGE_SIZE) as *mut ();
+				if ::memory::virt::get_info(addr).is_none() {
+					log_notice!("MemoryMapHandle::drop - {:p} already unmapped", addr);
+					continue ;
+				}
+				// SAFE: This handle owns the mapping, and it's not accessed after this
+				unsafe {
+					let _ = ::memory::virt::reprotect_user(addr, ::memory::virt::ProtectionMode::Unmapped);
+				}
+			}
+		}
+		// Write back modified pages (using the handle's references to the copies, after the mapping is gone)
+		for (i, copy) in self.copies.iter().enumerate()
+		{
+			if let Err(e) = self.flush_page(i, copy) {
+				log_error!("MemoryMapHandle::drop - Failed to write back page {} of {:p}: {:?}", i, self.base, e);
+			}
+		}
 	}
 }
 
@@ -312,21 +492,65 @@ impl Dir
 		assert!(node.is_dir());
 		Ok( Dir { node: node } )
 	}
+	/// Create a new file, and open it with the specified mode
+	pub fn create_file(&self, name: &str, mode: FileOpenMode) -> super::Result<File> {
+		let node = try!(self.node.create(name.as_ref(), NodeType::File));
+		File::from_node(node, Origin::new(&self.node, name.as_ref()), mode)
+	}
 	/// Create a new symbolic link
 	pub fn symlink(&self, name: &str, target: &Path) -> super::Result<()> {
 		try!(self.node.create(name.as_ref(), NodeType::Symlink(target)));
 		Ok( () )
 	}
 
+	/// Rename (or move) a node, both paths are relative to this directory
+	///
+	/// Both locations must be on the same filesystem, and the destination must not already exist.
+	pub fn rename(&self, src: &Path, dst: &Path) -> super::Result<()> {
+		let (src_parent, src_name) = try!(src.split_off_last().ok_or(super::Error::MalformedPath));
+		let (dst_parent, dst_name) = try!(dst.split_off_last().ok_or(super::Error::MalformedPath));
+		let src_dir = try!(CacheHandle::from_path_at_node(self.node.clone(), src_parent));
+		let (dst_dir, dst_ancestors) = try!(CacheHandle::from_path_at_node_with_parents(self.node.clone(), dst_parent));
+		if !src_dir.is_dir() || !dst_dir.is_dir() {
+			return Err(super::Error::NonDirComponent);
+		}
+
+		// A directory can't be moved to within itself
+		let src_node = try!(src_dir.open_child(src_name));
+		if src_node.is_dir() {
+			let src_ids = src_node.get_ids();
+			if dst_dir.get_ids() == src_ids || dst_ancestors.iter().any(|n| n.get_ids() == src_ids) {
+				return Err(super::Error::InvalidParameter);
+			}
+		}
+		drop(src_node);
+
+		src_dir.rename(src_name, &dst_dir, dst_name)
+	}
+
 	/// Open a child of this node
 	pub fn open_child(&self, name: &ByteStr) -> super::Result<Any> {
 		let node = try!(self.node.open_child(name));
-		Ok(Any { node: node })
+		Ok(Any { node: node, origin: Origin::new(&self.node, name) })
 	}
 
 	pub fn open_child_path(&self, path: &Path) -> super::Result<Any> {
-		let node = try!(CacheHandle::from_path_at_node(self.node.clone(), path));
-		Ok(Any{ node: node })
+		let (node, parents) = try!(CacheHandle::from_path_at_node_with_parents(self.node.clone(), path));
+		Ok(Any{ origin: Origin::from_walk(&parents, path), node: node })
+	}
+
+	/// Add a new name for a node (which must be on the same filesystem as this directory)
+	pub fn link(&self, name: &str, node: &Any) -> super::Result<()> {
+		self.node.link(name.as_ref(), &node.node)
+	}
+	/// Remove a child of this directory
+	pub fn unlink(&self, name: &ByteStr) -> super::Result<()> {
+		self.node.unlink(name)
+	}
+
+	/// Start watching this directory for changes
+	pub fn watch(&self) -> watch::Watch {
+		watch::Watch::new(&self.node)
 	}
 
 
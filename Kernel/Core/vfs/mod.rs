@@ -33,6 +33,10 @@ pub enum Error
 	NonDirComponent,
 	/// Symbolic link recursion limit reached
 	RecursionDepthExceeded,
+	/// Operation would span two filesystems (e.g. renaming across mounts)
+	CrossFilesystem,
+	/// Node is in use (e.g. has a filesystem mounted on it)
+	Busy,
 
 
 	/// Block-level IO Error
@@ -55,7 +59,11 @@ pub enum Error
 }
 impl From<::metadevs::storage::IoError> for Error {
 	fn from(v: ::metadevs::storage::IoError) -> Error {
-		Error::BlockIoError(v)
+		match v
+		{
+		::metadevs::storage::IoError::ReadOnly => Error::ReadOnlyFilesystem,
+		_ => Error::BlockIoError(v),
+		}
 	}
 }
 //impl_fmt! {
@@ -73,6 +81,7 @@ pub use self::path::{Path,PathBuf};
 pub mod node;
 pub mod mount;
 pub mod handle;
+pub mod watch;
 mod path;
 mod ramfs;
 
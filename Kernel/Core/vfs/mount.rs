@@ -8,6 +8,7 @@ use super::path::Path;
 use super::node::{InodeId,Node,CacheHandle};
 use sync::RwLock;
 use lib::{LazyStatic,SparseVec,VecMap};
+use core::sync::atomic::{AtomicBool,Ordering};
 
 use metadevs::storage::VolumeHandle;
 
@@ -18,7 +19,7 @@ pub struct Handle(usize);
 
 /// Handle to a mounted filesystem held by the filesystem itself
 ///
-/// Allows access to the node cache
+/// Allows access to the node cache, contains the mount's ID (the same value as `Handle::id`)
 pub struct SelfHandle(usize);
 
 /// Internal representation of a mounted volume
@@ -26,6 +27,9 @@ struct MountedVolume
 {
 	mountpoint_node: CacheHandle,
 	fs: Box<Filesystem>,
+	read_only: AtomicBool,
+	/// The underlying volume is read-only (so the mount can't be made writable)
+	volume_readonly: bool,
 }
 
 
@@ -35,6 +39,16 @@ pub trait Filesystem:
 {
 	fn root_inode(&self) -> InodeId;
 	fn get_node_by_inode(&self, InodeId) -> Option<Node>;
+	/// Write any cached modifications back to the underlying volume
+	fn sync(&self) -> super::Result<()> {
+		Ok( () )
+	}
+	/// Returns true if inode numbers depend on the containing directory (i.e. a node's number changes when it's moved)
+	///
+	/// The VFS won't move such a node to another directory while it's in use.
+	fn parent_dependent_inodes(&self) -> bool {
+		false
+	}
 }
 
 struct NullFs;
@@ -54,8 +68,23 @@ pub trait Driver:
 
 	/// Mount the provided volume as this filesystem
 	///
+	/// `options` contains the driver-specific mount options (i.e. those not handled by the VFS)
+	///
 	/// NOTE: `handle` isn't actually usable until after this function returns
-	fn mount(&self, vol: VolumeHandle, handle: SelfHandle) -> super::Result<Box<Filesystem>>;
+	fn mount(&self, vol: VolumeHandle, handle: SelfHandle, options: &[&str]) -> super::Result<Box<Filesystem>>;
+}
+
+/// Helper for drivers that don't take any mount options
+pub fn reject_options(options: &[&str]) -> super::Result<()>
+{
+	match options.first()
+	{
+	Some(o) => {
+		log_notice!("Unknown mount option '{}'", o);
+		Err(super::Error::InvalidParameter)
+		},
+	None => Ok( () ),
+	}
 }
 
 pub struct DriverRegistration(&'static str);
@@ -66,6 +95,10 @@ static S_DRIVERS: LazyStatic<RwLock< VecMap<&'static str, &'static Driver> >> =
 static S_VOLUMES: LazyStatic<RwLock< SparseVec<MountedVolume> >> = lazystatic_init!();
 /// Root mount
 static S_ROOT_VOLUME: RwLock<Option<Box<Filesystem>>> = RwLock::new(None);
+/// Root mount was mounted with the `ro` option
+static S_ROOT_READONLY: AtomicBool = ::core::sync::atomic::ATOMIC_BOOL_INIT;
+/// Root mount's volume is read-only
+static S_ROOT_VOLUME_READONLY: AtomicBool = ::core::sync::atomic::ATOMIC_BOOL_INIT;
 
 pub fn init()
 {
@@ -76,10 +109,39 @@ pub fn init()
 	}
 }
 
+/// Split the options handled by the VFS from those passed to the driver
+///
+/// Returns (read_only, driver_options)
+fn parse_options<'a>(options: &[&'a str]) -> (bool, Vec<&'a str>)
+{
+	let mut read_only = false;
+	let mut driver_options = Vec::new();
+	for &o in options
+	{
+		match o
+		{
+		"" => {},
+		"ro" => read_only = true,
+		"rw" => read_only = false,
+		_ => driver_options.push(o),
+		}
+	}
+	(read_only, driver_options)
+}
+
 /// Mount a volume at the provided location
-// TODO: Parse options
-pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) -> Result<(),MountError>
+///
+/// Mounting at `/` replaces the current root filesystem, provided that none of its nodes are in use.
+pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, options: &[&str]) -> Result<(),MountError>
 {
+	let (read_only, driver_options) = parse_options(options);
+	// - Read-only volumes are always mounted read-only
+	let volume_readonly = vol.is_readonly();
+	if volume_readonly && !read_only {
+		log_notice!("Volume '{}' is read-only, mounting read-only", vol.name());
+	}
+	let read_only = read_only || volume_readonly;
+
 	let drivers = S_DRIVERS.read();
 	// 1. (maybe) detect filesystem
 	let driver = if fs == "" {
@@ -105,17 +167,38 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 	
 	if location == Path::new("/")
 	{
-		let fs: Box<_> = match driver.mount(vol, SelfHandle(0))
+		let fs: Box<_> = match driver.mount(vol, SelfHandle(0), &driver_options)
 			{
 			Ok(v) => v,
+			Err(super::Error::InvalidParameter) => return Err(MountError::BadOption),
 			Err(_) => return Err(MountError::CallFailed),
 			};
-		let mut lh = S_ROOT_VOLUME.write();
-		if lh.is_some() {
-			log_warning!("TODO: Support remounting /");
-			return Err(MountError::MountpointUsed);
+		let is_initial = {
+			let mut lh = S_ROOT_VOLUME.write();
+			if lh.is_none() {
+				*lh = Some(fs);
+				None
+			}
+			else {
+				Some(fs)
+			}
+			};
+		if let Some(fs) = is_initial
+		{
+			// Replace the existing root (only possible if nothing is using it, including other mounts)
+			// - The old filesystem is dropped after its nodes have been evicted
+			let old_fs = match super::node::purge_mount(0, || ::core::mem::replace(&mut *S_ROOT_VOLUME.write(), Some(fs)))
+				{
+				Some(v) => v,
+				None => return Err(MountError::Busy),
+				};
+			if let Some(Err(e)) = old_fs.as_ref().map(|fs| fs.sync()) {
+				log_error!("Error syncing old root filesystem: {:?}", e);
+			}
+			drop(old_fs);
 		}
-		*lh = Some(fs);
+		S_ROOT_READONLY.store(read_only, Ordering::Relaxed);
+		S_ROOT_VOLUME_READONLY.store(volume_readonly, Ordering::Relaxed);
 	}
 	else
 	{
@@ -134,28 +217,142 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 		
 		// 3. Reserve the mountpoint ID (using a placeholder instance)
 		// NOTE: Nothing should know of this index until after mount is completed
-		let vidx = S_VOLUMES.write().insert(MountedVolume { mountpoint_node: nh, fs: Box::new(NullFs) });
+		let vidx = S_VOLUMES.write().insert(MountedVolume { mountpoint_node: nh, fs: Box::new(NullFs), read_only: AtomicBool::new(read_only), volume_readonly: volume_readonly });
 
 		// 4. Mount and register volume
-		let fs = match driver.mount(vol, SelfHandle(vidx))
+		let fs = match driver.mount(vol, SelfHandle(vidx + 1), &driver_options)
 			{
 			Ok(v) => v,
-			Err(_) => return Err(MountError::CallFailed),
+			Err(e) => {
+				// Release the reservation (dropped after the lock is released, as it contains a node handle)
+				let placeholder = S_VOLUMES.write().take(vidx);
+				drop(placeholder);
+				return Err(match e
+					{
+					super::Error::InvalidParameter => MountError::BadOption,
+					_ => MountError::CallFailed,
+					});
+				},
 			};
 
 		// 5. Store and bind to mountpoint
-		{
+		let failed = {
 			let mut lh = S_VOLUMES.write();
 			lh[vidx].fs = fs;
 			if lh[vidx].mountpoint_node.mount(vidx + 1) == false {
-				lh.remove(vidx);
-				return Err(MountError::MountpointUsed);
+				lh.take(vidx)
+			}
+			else {
+				None
 			}
+			};
+		if failed.is_some() {
+			return Err(MountError::MountpointUsed);
 		}
 	}
 
 	Ok( () )
 }
+
+/// Locate the mount whose root is at the specified path
+fn find_mount(location: &Path) -> Result<usize,MountError>
+{
+	let nh = match CacheHandle::from_path(location)
+		{
+		Ok(nh) => nh,
+		Err(_) => return Err(MountError::InvalidMountpoint),
+		};
+	let (mount_id, inode) = nh.get_ids();
+	if inode != Handle::from_id(mount_id).root_inode() {
+		return Err(MountError::NotMounted);
+	}
+	Ok(mount_id)
+}
+
+/// Unmount the volume mounted at the provided location
+///
+/// Fails with `Busy` if any nodes on the volume are still open (including mountpoints for other volumes)
+pub fn unmount(location: &Path) -> Result<(),MountError>
+{
+	let mount_id = try!(find_mount(location));
+	if mount_id == 0 {
+		// The root can't be unmounted, only replaced
+		return Err(MountError::Busy);
+	}
+
+	// Unbind and remove the volume (with the node cache locked, so the volume can't be opened)
+	let vol = match super::node::purge_mount(mount_id, || {
+			let v = S_VOLUMES.write().take(mount_id - 1);
+			if let Some(ref v) = v {
+				v.mountpoint_node.unmount(mount_id);
+			}
+			v
+			})
+		{
+		Some(Some(v)) => v,
+		Some(None) => return Err(MountError::NotMounted),
+		None => return Err(MountError::Busy),
+		};
+	log_log!("Unmounted {:?} (mount {})", location, mount_id);
+	// - Write back cached data before the filesystem is dropped
+	if let Err(e) = vol.fs.sync() {
+		log_error!("Error syncing {:?} during unmount: {:?}", location, e);
+	}
+	drop(vol);
+	Ok( () )
+}
+
+/// Write cached modifications back to disk for all mounted volumes
+///
+/// Returns the first error encountered (after attempting all volumes)
+pub fn sync_all() -> super::Result<()>
+{
+	let mut rv = Ok( () );
+	if let Some(ref fs) = *S_ROOT_VOLUME.read() {
+		rv = fs.sync();
+	}
+	for v in S_VOLUMES.read().iter()
+	{
+		let r = v.fs.sync();
+		if rv.is_ok() {
+			rv = r;
+		}
+	}
+	rv
+}
+
+/// Change the options of an existing mount
+///
+/// Only options handled by the VFS (`ro`/`rw`) can be changed
+pub fn remount(location: &Path, options: &[&str]) -> Result<(),MountError>
+{
+	let (read_only, driver_options) = parse_options(options);
+	if let Some(o) = driver_options.first() {
+		log_notice!("Mount option '{}' can't be changed on remount", o);
+		return Err(MountError::BadOption);
+	}
+
+	let mount_id = try!(find_mount(location));
+	if mount_id == 0 {
+		if !read_only && S_ROOT_VOLUME_READONLY.load(Ordering::Relaxed) {
+			return Err(MountError::ReadOnlyVolume);
+		}
+		S_ROOT_READONLY.store(read_only, Ordering::Relaxed);
+	}
+	else {
+		match S_VOLUMES.read().get(mount_id - 1)
+		{
+		Some(v) => {
+			if !read_only && v.volume_readonly {
+				return Err(MountError::ReadOnlyVolume);
+			}
+			v.read_only.store(read_only, Ordering::Relaxed)
+			},
+		None => return Err(MountError::NotMounted),
+		}
+	}
+	Ok( () )
+}
 #[derive(Debug)]
 pub enum MountError
 {
@@ -164,6 +361,14 @@ pub enum MountError
 	InvalidMountpoint,
 	MountpointUsed,
 	CallFailed,
+	/// An option wasn't recognised
+	BadOption,
+	/// Nothing is mounted at the location
+	NotMounted,
+	/// Nodes on the volume are still in use
+	Busy,
+	/// The volume is read-only, so can't be mounted writable
+	ReadOnlyVolume,
 }
 impl_fmt! {
 	Display(self,f) for MountError {
@@ -174,6 +379,10 @@ impl_fmt! {
 			&MountError::InvalidMountpoint => "The specified mountpoint was invalid",
 			&MountError::MountpointUsed => "The specified mountpoint was already used",
 			&MountError::CallFailed => "Driver's mount call failed",
+			&MountError::BadOption => "Unknown mount option",
+			&MountError::NotMounted => "Nothing is mounted at the specified location",
+			&MountError::Busy => "The mounted volume is in use",
+			&MountError::ReadOnlyVolume => "The volume is read-only",
 			})
 	}
 }
@@ -196,14 +405,19 @@ impl DriverRegistration
 impl Handle
 {
 	pub fn from_id(id: usize) -> Handle {
-		if id == 0 {
-			Handle(0)
+		match Handle::try_from_id(id)
+		{
+		Some(v) => v,
+		None => panic!("Handle::from_id - ID {} not valid", id),
+		}
+	}
+	/// Obtain a handle, returning `None` if the mount doesn't exist (e.g. has been unmounted)
+	pub fn try_from_id(id: usize) -> Option<Handle> {
+		if id == 0 || S_VOLUMES.read().get(id-1).is_some() {
+			Some( Handle(id) )
 		}
 		else {
-			if ! S_VOLUMES.read().get(id-1).is_some() {
-				panic!("Handle::from_id - ID {} not valid", id);
-			}
-			Handle(id)
+			None
 		}
 	}
 	
@@ -217,6 +431,23 @@ impl Handle
 	pub fn get_node(&self, id: InodeId) -> Option<Node> {
 		self.with_fs(|fs| fs.get_node_by_inode(id))
 	}
+	/// Write cached modifications back to disk
+	pub fn sync(&self) -> super::Result<()> {
+		self.with_fs(|fs| fs.sync())
+	}
+	/// Returns true if moving a node to another directory changes its inode number
+	pub fn parent_dependent_inodes(&self) -> bool {
+		self.with_fs(|fs| fs.parent_dependent_inodes())
+	}
+	/// Returns true if the volume was mounted read-only
+	pub fn is_readonly(&self) -> bool {
+		if self.0 == 0 {
+			S_ROOT_READONLY.load(Ordering::Relaxed)
+		}
+		else {
+			S_VOLUMES.read().get(self.0 - 1).unwrap().read_only.load(Ordering::Relaxed)
+		}
+	}
 
 	fn with_fs<R, F: FnOnce(&Filesystem)->R>(&self, f: F) -> R {
 		if self.0 == 0 {
@@ -8,6 +8,8 @@ use super::Path;
 use sync::mutex::LazyMutex;
 use lib::byte_str::{ByteStr,ByteString};
 use core::sync::atomic::{self,AtomicUsize};
+use time::Timestamp;
+use memory::phys::FrameHandle;
 
 pub type InodeId = u64;
 pub type Result<T> = ::core::result::Result<T,super::Error>;
@@ -27,10 +29,44 @@ pub enum NodeClass {
 	Special,
 }
 
+/// Node metadata, returned by `NodeBase::get_info`
+#[derive(Debug,Default,Clone)]
+pub struct NodeInfo
+{
+	/// Size of the node's data in bytes
+	pub size: u64,
+	/// Last modification time
+	pub mtime: Timestamp,
+	/// Last metadata change time
+	pub ctime: Timestamp,
+	/// Last access time
+	pub atime: Timestamp,
+	/// Unix-style permission bits (see `MODE_*`)
+	pub mode: u32,
+	/// Owning user
+	pub uid: u32,
+	/// Owning group
+	pub gid: u32,
+}
+pub const MODE_OWNER_READ: u32 = 0o400;
+pub const MODE_OWNER_WRITE: u32 = 0o200;
+pub const MODE_OWNER_EXEC: u32 = 0o100;
+
+/// Access types checked by `CacheHandle::check_access`
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Access
+{
+	Read,
+	Write,
+	Execute,
+}
+
 /// Base trait for a VFS node, defines common operation on nodes
 pub trait NodeBase: Send {
 	/// Return the volume's inode number
 	fn get_id(&self) -> InodeId;
+	/// Return the node's metadata (sizes, timestamps, permissions and owner)
+	fn get_info(&self) -> Result<NodeInfo>;
 	/// Return an &Any associated with this node (not nessesarily same as `self`, up to the driver)
 	fn get_any(&self) -> &Any;
 }
@@ -73,6 +109,11 @@ pub trait Dir: NodeBase {
 	fn link(&self, name: &ByteStr, inode: &NodeBase) -> Result<()>;
 	/// Remove the specified name
 	fn unlink(&self, name: &ByteStr) -> Result<()>;
+	/// Move the entry `src_name` to `dst_name` in `dst_dir` (which might be `self`)
+	///
+	/// `dst_dir` is on the same filesystem, and the operation must not leave both (or neither) names present.
+	/// Fails with `AlreadyExists` if `dst_name` is in use.
+	fn rename(&self, src_name: &ByteStr, dst_dir: &Dir, dst_name: &ByteStr) -> Result<()>;
 }
 /// Trait for symbolic link nodes.
 pub trait Symlink: NodeBase {
@@ -101,10 +142,12 @@ pub enum Node
 enum CacheNodeInt
 {
 	File {
-		fsnode: Box<File>
+		fsnode: Box<File>,
+		/// Open handles (see `handle::FileOpenMode`)
+		locks: ::sync::Mutex<FileLocks>,
 		
-		// File memory map data
-		//mapped_pages: HashMap<u64,FrameHandle>,
+		/// Page cache for memory mappings (page index to frame)
+		pages: ::sync::Mutex<::lib::VecMap<u64,FrameHandle>>,
 		},
 	Dir {
 		mountpoint: AtomicUsize,	// 0 is invalid (that's root), so means "no mount"
@@ -122,7 +165,7 @@ impl_from!{
 	From<Node>(v) for CacheNodeInt {
 		match v
 		{
-		Node::File(f) => CacheNodeInt::File { fsnode: f },
+		Node::File(f) => CacheNodeInt::File { fsnode: f, locks: Default::default(), pages: Default::default() },
 		Node::Dir(f) => CacheNodeInt::Dir { fsnode: f, mountpoint: AtomicUsize::new(0) },
 		Node::Symlink(f) => CacheNodeInt::Symlink { target: f.read(), fsnode: f },
 		Node::Special(f) => CacheNodeInt::Special { fsnode: f },
@@ -130,8 +173,24 @@ impl_from!{
 	}
 }
 
+/// Counts of active handles to a file, by open mode
+#[derive(Default,Debug)]
+pub struct FileLocks
+{
+	/// `SharedRO` and `Execute` handles
+	pub readers: usize,
+	/// `ExclRW` and `UniqueRW` handles
+	pub exclusive: usize,
+	/// `Append` handles
+	pub append: usize,
+	/// `Unsynch` handles
+	pub unsynch: usize,
+}
+
 struct CachedNode
 {
+	mountpt: usize,
+	inode: InodeId,
 	refcount: AtomicUsize,
 	node: CacheNodeInt,
 }
@@ -145,11 +204,248 @@ pub struct CacheHandle
 unsafe impl Sync for CacheHandle {}
 unsafe impl Send for CacheHandle {}
 
-static S_NODE_CACHE: LazyMutex<::lib::VecMap<(usize,InodeId),Box<CachedNode>>> = lazymutex_init!();
+/// Maximum number of nested symbolic links followed while resolving a path
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// Number of hash buckets in the node cache
+const NODE_CACHE_BUCKETS: usize = 64;
+/// Number of unreferenced nodes kept in the cache before the least recently used is evicted
+const NODE_CACHE_MAX_UNUSED: usize = 64;
+/// Number of hash buckets in the name cache
+const NAME_CACHE_BUCKETS: usize = 64;
+/// Maximum number of entries in each name cache bucket (least recently used is evicted)
+const NAME_CACHE_BUCKET_SIZE: usize = 8;
+
+/// Cache of loaded nodes, keyed by (mountpoint, inode)
+struct NodeCache
+{
+	buckets: Vec< Vec<Box<CachedNode>> >,
+	/// Nodes with no active handles, least recently used first
+	unused: Vec<(usize,InodeId)>,
+	/// Nodes that were unlinked while open (released when the last handle is dropped)
+	/// - Removed from the buckets, as the inode number may be reused
+	detached: Vec<Box<CachedNode>>,
+}
+/// Cache of directory lookups (including failed lookups)
+struct NameCache
+{
+	buckets: Vec< Vec<NameCacheEnt> >,
+}
+struct NameCacheEnt
+{
+	mountpt: usize,
+	dir: InodeId,
+	name: ByteString,
+	/// `None` indicates that the name doesn't exist
+	inode: Option<InodeId>,
+}
+
+static S_NODE_CACHE: LazyMutex<NodeCache> = lazymutex_init!();
+static S_NAME_CACHE: LazyMutex<NameCache> = lazymutex_init!();
 
 pub fn init()
 {
-	S_NODE_CACHE.init(|| Default::default());
+	S_NODE_CACHE.init(|| NodeCache {
+		buckets: Vec::from_fn(NODE_CACHE_BUCKETS, |_| Vec::new()),
+		unused: Vec::new(),
+		detached: Vec::new(),
+		});
+	S_NAME_CACHE.init(|| NameCache {
+		buckets: Vec::from_fn(NAME_CACHE_BUCKETS, |_| Vec::new()),
+		});
+}
+
+/// FNV-1a, used to select hash buckets
+fn hash_bytes(mut hash: u32, data: &[u8]) -> u32 {
+	for &b in data {
+		hash ^= b as u32;
+		hash = hash.wrapping_mul(16777619);
+	}
+	hash
+}
+fn hash_ids(mountpt: usize, inode: InodeId) -> u32 {
+	let h = hash_bytes(2166136261, ::lib::as_byte_slice(&(mountpt as u64)));
+	hash_bytes(h, ::lib::as_byte_slice(&inode))
+}
+
+impl NodeCache
+{
+	fn bucket(&self, key: (usize,InodeId)) -> usize {
+		hash_ids(key.0, key.1) as usize % self.buckets.len()
+	}
+	fn get(&self, key: (usize,InodeId)) -> Option<&CachedNode> {
+		let b = self.bucket(key);
+		self.buckets[b].iter().find(|n| (n.mountpt, n.inode) == key).map(|v| &**v)
+	}
+	fn insert(&mut self, node: Box<CachedNode>) -> &CachedNode {
+		let b = self.bucket( (node.mountpt, node.inode) );
+		self.buckets[b].push(node);
+		&**self.buckets[b].last().unwrap()
+	}
+	fn remove(&mut self, key: (usize,InodeId)) -> Option<Box<CachedNode>> {
+		let b = self.bucket(key);
+		match self.buckets[b].iter().position(|n| (n.mountpt, n.inode) == key)
+		{
+		Some(i) => Some( self.buckets[b].remove(i) ),
+		None => None,
+		}
+	}
+
+	/// Remove an unused node from the cache (returning it so it can be dropped with the cache unlocked)
+	fn evict_unused(&mut self, key: (usize,InodeId)) -> Option<Box<CachedNode>> {
+		match self.unused.iter().position(|&k| k == key)
+		{
+		Some(i) => {
+			self.unused.remove(i);
+			self.remove(key)
+			},
+		None => None,
+		}
+	}
+
+	/// Remove an unlinked node from the cache
+	///
+	/// Returns the node if it's unused, otherwise it's moved to the detached list until its last handle is dropped.
+	fn detach(&mut self, key: (usize,InodeId)) -> Option<Box<CachedNode>> {
+		match self.get(key).map(|n| n.refcount.load(atomic::Ordering::Relaxed))
+		{
+		None => None,
+		Some(0) => self.evict_unused(key),
+		Some(_) => {
+			let n = self.remove(key).expect("Cached node vanished");
+			self.detached.push(n);
+			None
+			},
+		}
+	}
+
+	/// Node has been referenced again, remove it from the eviction list
+	fn mark_used(&mut self, key: (usize,InodeId)) {
+		if let Some(i) = self.unused.iter().position(|&k| k == key) {
+			self.unused.remove(i);
+		}
+	}
+	/// Last handle to a node has been dropped, returns a node to evict (to be dropped after the lock is released)
+	fn mark_unused(&mut self, key: (usize,InodeId)) -> Option<Box<CachedNode>> {
+		self.unused.push(key);
+		if self.unused.len() > NODE_CACHE_MAX_UNUSED {
+			let old = self.unused.remove(0);
+			self.remove(old)
+		}
+		else {
+			None
+		}
+	}
+}
+
+impl NameCache
+{
+	fn bucket(&self, mountpt: usize, dir: InodeId, name: &ByteStr) -> usize {
+		hash_bytes(hash_ids(mountpt, dir), name.as_bytes()) as usize % self.buckets.len()
+	}
+	/// Returns `Some(None)` for a cached failed lookup, and `None` if the name isn't cached
+	fn get(&mut self, mountpt: usize, dir: InodeId, name: &ByteStr) -> Option<Option<InodeId>> {
+		let b = self.bucket(mountpt, dir, name);
+		let bucket = &mut self.buckets[b];
+		match bucket.iter().position(|e| e.mountpt == mountpt && e.dir == dir && &*e.name == name)
+		{
+		Some(i) => {
+			// Move to the end of the bucket (most recently used)
+			let ent = bucket.remove(i);
+			let rv = ent.inode;
+			bucket.push(ent);
+			Some(rv)
+			},
+		None => None,
+		}
+	}
+	fn set(&mut self, mountpt: usize, dir: InodeId, name: &ByteStr, inode: Option<InodeId>) {
+		self.invalidate(mountpt, dir, name);
+		let b = self.bucket(mountpt, dir, name);
+		let bucket = &mut self.buckets[b];
+		if bucket.len() >= NAME_CACHE_BUCKET_SIZE {
+			bucket.remove(0);
+		}
+		bucket.push(NameCacheEnt {
+			mountpt: mountpt,
+			dir: dir,
+			name: From::from(name),
+			inode: inode,
+			});
+	}
+	fn purge_mount(&mut self, mountpt: usize) {
+		self.purge(|e| e.mountpt == mountpt);
+	}
+	/// Remove all entries for a directory (used when its node is evicted, as the inode number could be reused)
+	fn purge_dir(&mut self, mountpt: usize, dir: InodeId) {
+		self.purge(|e| e.mountpt == mountpt && e.dir == dir);
+	}
+	fn purge<F: Fn(&NameCacheEnt)->bool>(&mut self, f: F) {
+		for b in self.buckets.iter_mut()
+		{
+			let mut i = 0;
+			while i < b.len()
+			{
+				if f(&b[i]) {
+					b.remove(i);
+				}
+				else {
+					i += 1;
+				}
+			}
+		}
+	}
+	fn invalidate(&mut self, mountpt: usize, dir: InodeId, name: &ByteStr) {
+		let b = self.bucket(mountpt, dir, name);
+		let bucket = &mut self.buckets[b];
+		if let Some(i) = bucket.iter().position(|e| e.mountpt == mountpt && e.dir == dir && &*e.name == name) {
+			bucket.remove(i);
+		}
+	}
+}
+
+/// Evict all nodes belonging to a mount, provided that none of them are in use
+///
+/// `f` is called with the node cache locked (so no nodes on the mount can be opened), and the evicted nodes are dropped
+/// before its return value is returned. Returns `None` (without calling `f`) if any nodes are in use.
+pub fn purge_mount<F,R>(mountpt: usize, f: F) -> Option<R>
+where
+	F: FnOnce()->R
+{
+	let (rv, nodes) = {
+		let mut lh = S_NODE_CACHE.lock();
+		if lh.buckets.iter().any(|b| b.iter().any(|n| n.mountpt == mountpt && n.refcount.load(atomic::Ordering::Relaxed) > 0)) {
+			return None;
+		}
+		let mut nodes = Vec::new();
+		for b in lh.buckets.iter_mut()
+		{
+			let mut i = 0;
+			while i < b.len()
+			{
+				if b[i].mountpt == mountpt {
+					nodes.push( b.remove(i) );
+				}
+				else {
+					i += 1;
+				}
+			}
+		}
+		let mut i = 0;
+		while i < lh.unused.len()
+		{
+			if lh.unused[i].0 == mountpt {
+				lh.unused.remove(i);
+			}
+			else {
+				i += 1;
+			}
+		}
+		(f(), nodes)
+		};
+	drop(nodes);
+	S_NAME_CACHE.lock().purge_mount(mountpt);
+	Some(rv)
 }
 
 impl_fmt! {
@@ -178,21 +474,30 @@ impl CacheHandle
 	/// Obtain a node handle using a mountpoint ID and inode number
 	pub fn from_ids(mountpoint: usize, inode: InodeId) -> super::Result<CacheHandle>
 	{
-		use lib::vec_map::Entry;
-		// TODO: Use a hashmap of some form and use a fixed-range allocation (same as VMM code)
-		let ptr: *const _ = &**match S_NODE_CACHE.lock().entry( (mountpoint, inode) )
-			{
-			Entry::Occupied(mut e) =>
+		let ptr: *const CachedNode = {
+			let mut lh = S_NODE_CACHE.lock();
+			let key = (mountpoint, inode);
+			let found = match lh.get(key)
 				{
-				e.get_mut().refcount.fetch_add(1, atomic::Ordering::Relaxed);
-				e.into_mut()
+				Some(e) => Some( (e as *const _, e.refcount.fetch_add(1, atomic::Ordering::Relaxed) == 0) ),
+				None => None,
+				};
+			match found
+			{
+			Some( (p, was_unused) ) => {
+				if was_unused {
+					lh.mark_used(key);
+				}
+				p
 				},
-			Entry::Vacant(e) =>
-				match super::mount::Handle::from_id(mountpoint).get_node(inode)
+			None =>
+				// NOTE: The mount may have been removed since the ID was obtained
+				match super::mount::Handle::try_from_id(mountpoint).and_then(|h| h.get_node(inode))
 				{
-				Some(node) => e.insert(Box::new(CachedNode { node: node.into(), refcount: AtomicUsize::new(1) })),
+				Some(node) => lh.insert(Box::new(CachedNode { mountpt: mountpoint, inode: inode, node: node.into(), refcount: AtomicUsize::new(1) })) as *const _,
 				None => return Err( super::Error::NotFound ),
 				},
+			}
 			};
 
 		// SAFE: Reference count has been incremented by this function, and will be valid until return
@@ -223,55 +528,58 @@ impl CacheHandle
 	}
 	
 	
-	pub fn from_path_at_node(mut node_h: CacheHandle, path: &Path) -> super::Result<CacheHandle>
+	pub fn from_path_at_node(node_h: CacheHandle, path: &Path) -> super::Result<CacheHandle>
 	{
 		log_function!("CacheHandle::from_path_at_node(node_h={:?}, {:?})", node_h, path);
-		let path = if path.is_absolute() {
-				try!(path.split_off_first().ok_or(super::Error::MalformedPath)).1
-			}
-			else {
-				path
-			};
-		// Iterate path components
+		let mut parents = Vec::new();
+		let rv = try!(CacheHandle::walk_path(node_h, &mut parents, path, 0));
+		log_trace!("CacheHandle::from_path_at_node() {:?}", rv);
+		Ok( rv )
+	}
+	/// Walk a path starting at `node_h`, also returning the directories traversed to reach the node
+	pub fn from_path_at_node_with_parents(node_h: CacheHandle, path: &Path) -> super::Result<(CacheHandle, Vec<CacheHandle>)>
+	{
+		let mut parents = Vec::new();
+		let rv = try!(CacheHandle::walk_path(node_h, &mut parents, path, 0));
+		Ok( (rv, parents) )
+	}
+
+	/// Walk a path starting at `node_h`
+	///
+	/// `parents` holds the directories traversed to reach `node_h` (used for `..` and relative symbolic links)
+	fn walk_path(mut node_h: CacheHandle, parents: &mut Vec<CacheHandle>, path: &Path, depth: usize) -> super::Result<CacheHandle>
+	{
+		// NOTE: Leading `/` is ignored, absolute paths are walked relative to the passed node
 		for seg in path
 		{
 			log_trace!("seg = {:?}", seg);
-			// Loop resolving symbolic links
-			loop
-			{
-				// TODO: Should symlinks be handled in this function? Or should the passed path be without symlinks?
-				node_h = if let CacheNodeInt::Symlink { ref target, .. } = *node_h.as_ref() {
-					//log_debug!("- seg={:?} : SYMLINK {:?}", seg, name);
-					let linkpath = Path::new(&target);
-					if linkpath.is_absolute() {
-						try!(CacheHandle::from_path(linkpath))
-					}
-					else {
-						//TODO: To make this work (or any path-relative symlink), the current position in
-						//      `path` needs to be known.
-						// It can be hacked up though...
-						let parent_segs_len = seg.as_ref().as_ptr() as usize - AsRef::<[u8]>::as_ref(path).as_ptr() as usize;
-						let parent = ByteStr::new( &AsRef::<[u8]>::as_ref(path)[..parent_segs_len] );
-
-						//let segs = [ &path[..pos], &link ];
-						//let p = PathChain::new(&segs);
-						//try!( CacheHandle::from_path(p) )
-						// Recurse with a special chained path type
-						// (that iterates but can't be sliced).
-						todo!("Relative symbolic links {:?} relative to {:?}", target, parent)
-					}
-				}
-				else {
-					break;
-				};
+			// If the current node is a symbolic link, resolve it before continuing
+			// - A link's target can itself be a link, each one followed counts towards the depth limit
+			let mut link_depth = depth;
+			while node_h.is_symlink() {
+				node_h = try!(CacheHandle::resolve_symlink(node_h, parents, link_depth));
+				link_depth += 1;
+			}
+
+			if seg == "" || seg == "." {
+				continue ;
+			}
+			if seg == ".." {
+				node_h = match parents.pop()
+					{
+					Some(v) => v,
+					None if node_h.is_root() => node_h,
+					// Unknown parent (started part-way through the tree), ask the filesystem
+					None => try!(node_h.open_child(seg)),
+					};
+				continue ;
 			}
 
 			// Look up this component in the current node
-			node_h = match *node_h.as_ref()
+			let next = match *node_h.as_ref()
 				{
 				CacheNodeInt::Dir { fsnode: ref dir, .. } => {
-					//log_debug!("- seg={:?} : DIR", seg);
-					let next_id = match dir.lookup(seg)
+					let next_id = match node_h.lookup_child(&**dir, seg)
 						{
 						Ok(v) => v,
 						Err(_) => return Err(super::Error::NotFound),
@@ -280,16 +588,52 @@ impl CacheHandle
 					},
 				_ => return Err(super::Error::NonDirComponent),
 				};
+			parents.push(node_h);
+			node_h = next;
 		}
-		log_trace!("CacheHandle::from_path_at_node() {:?}", node_h);
 		Ok( node_h )
 	}
 
+	/// Resolve a symbolic link (reached by walking `parents`) to its target
+	fn resolve_symlink(link: CacheHandle, parents: &mut Vec<CacheHandle>, depth: usize) -> super::Result<CacheHandle>
+	{
+		if depth >= MAX_SYMLINK_DEPTH {
+			return Err(super::Error::RecursionDepthExceeded);
+		}
+		let target = try!(link.get_target());
+		let linkpath = Path::new(&target);
+		log_trace!("CacheHandle::resolve_symlink({:?}) {:?}", link, linkpath);
+		let start = if linkpath.is_absolute() {
+				parents.truncate(0);
+				let mph = super::mount::Handle::from_id(0);
+				try!(CacheHandle::from_ids( mph.id(), mph.root_inode() ))
+			}
+			else {
+				// Relative links are relative to the directory containing the link
+				match parents.pop()
+				{
+				Some(v) => v,
+				None => return Err(super::Error::Unknown("Relative symbolic link with unknown parent")),
+				}
+			};
+		CacheHandle::walk_path(start, parents, linkpath, depth + 1)
+	}
+
+	/// Returns true if this node is the root of the VFS
+	fn is_root(&self) -> bool {
+		self.mountpt == 0 && self.inode == super::mount::Handle::from_id(0).root_inode()
+	}
+
 	/// Obtain a node handle using a path
 	pub fn from_path(path: &Path) -> super::Result<CacheHandle>
 	{
-		log_function!("CacheHandle::from_path({:?})", path);
-		// TODO: Support path caching?
+		Ok( try!(CacheHandle::from_path_with_parents(path)).0 )
+	}
+	/// Obtain a node handle using a path, also returning the directories traversed to reach the node
+	pub fn from_path_with_parents(path: &Path) -> super::Result<(CacheHandle, Vec<CacheHandle>)>
+	{
+		log_function!("CacheHandle::from_path_with_parents({:?})", path);
+		// NOTE: Individual lookups are cached by `lookup_child`
 		
 		// - Remove the leading / from the absolute path
 		//  > Also checks that it's actually abolsute
@@ -302,7 +646,10 @@ impl CacheHandle
 		let mph = super::mount::Handle::from_id(0);
 		let node_h = try!(CacheHandle::from_ids( mph.id(), mph.root_inode() ));
 
-		CacheHandle::from_path_at_node(node_h, path)
+		// - The root has no parents, so `..` stays at the root
+		let mut parents = Vec::new();
+		let rv = try!(CacheHandle::walk_path(node_h, &mut parents, path, 0));
+		Ok( (rv, parents) )
 	}
 	
 	pub fn get_class(&self) -> NodeClass {
@@ -324,6 +671,15 @@ impl CacheHandle
 		self.get_class() == NodeClass::Symlink
 	}
 
+	/// Returns the (mount, inode) pair identifying this node
+	pub fn get_ids(&self) -> (usize, InodeId) {
+		(self.mountpt, self.inode)
+	}
+	/// Returns true if the node is on a read-only mount
+	pub fn is_readonly(&self) -> bool {
+		super::mount::Handle::from_id(self.mountpt).is_readonly()
+	}
+
 	pub fn get_any(&self) -> &Any {
 		match self.as_ref()
 		{
@@ -333,6 +689,33 @@ impl CacheHandle
 		&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.get_any(),
 		}
 	}
+	pub fn get_info(&self) -> super::Result<NodeInfo> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Dir { ref fsnode, .. } => fsnode.get_info(),
+		&CacheNodeInt::File { ref fsnode, .. } => fsnode.get_info(),
+		&CacheNodeInt::Special { ref fsnode, .. } => fsnode.get_info(),
+		&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.get_info(),
+		}
+	}
+	/// Check that the node's permission bits allow the requested access
+	// TODO: Processes don't have an identity yet, so only the owner bits are checked
+	pub fn check_access(&self, access: Access) -> super::Result<()> {
+		let mode = try!(self.get_info()).mode;
+		let bit = match access
+			{
+			Access::Read => MODE_OWNER_READ,
+			Access::Write => MODE_OWNER_WRITE,
+			Access::Execute => MODE_OWNER_EXEC,
+			};
+		if mode & bit != 0 {
+			Ok( () )
+		}
+		else {
+			log_debug!("check_access({:?}) - Denied by mode {:#o}", access, mode);
+			Err( super::Error::PermissionDenied )
+		}
+	}
 }
 /// Directory methods
 impl CacheHandle
@@ -341,7 +724,14 @@ impl CacheHandle
 		match self.as_ref()
 		{
 		&CacheNodeInt::Dir { ref fsnode, .. } => {
+			if self.is_readonly() {
+				return Err( super::Error::ReadOnlyFilesystem );
+			}
+			try!(self.check_access(Access::Write));
 			let inode = try!(fsnode.create(name, ty));
+			// - Drop any cached failed lookup of this name
+			self.name_cache_invalidate(name);
+			super::watch::notify(self.get_ids(), super::watch::EventKind::Created, name);
 			Ok( try!(CacheHandle::from_ids(self.mountpt, inode)) )
 			},
 		_ => Err( super::Error::Unknown("Calling create on non-directory") ),
@@ -358,16 +748,141 @@ impl CacheHandle
 		match self.as_ref()
 		{
 		&CacheNodeInt::Dir { ref fsnode, .. } => {
-			let inode = try!(fsnode.lookup(name));
+			let inode = try!(self.lookup_child(&**fsnode, name));
 			Ok( try!(CacheHandle::from_ids(self.mountpt, inode)) )
 			},
 		_ => Err( super::Error::Unknown("Calling open_child on non-directory") ),
 		}
 	}
+	/// Move `src_name` in this directory to `dst_name` in `dst_dir`
+	///
+	/// NOTE: The caller is responsible for ensuring that a directory isn't moved into itself
+	pub fn rename(&self, src_name: &ByteStr, dst_dir: &CacheHandle, dst_name: &ByteStr) -> super::Result<()> {
+		match (self.as_ref(), dst_dir.as_ref())
+		{
+		(&CacheNodeInt::Dir { ref fsnode, .. }, &CacheNodeInt::Dir { fsnode: ref dst_fsnode, .. }) => {
+			if self.mountpt != dst_dir.mountpt {
+				return Err( super::Error::CrossFilesystem );
+			}
+			if self.is_readonly() {
+				return Err( super::Error::ReadOnlyFilesystem );
+			}
+			if src_name == "" || src_name == "." || src_name == ".." || dst_name == "" || dst_name == "." || dst_name == ".." {
+				return Err( super::Error::InvalidParameter );
+			}
+			try!(self.check_access(Access::Write));
+			try!(dst_dir.check_access(Access::Write));
+
+			// - Mountpoints can't be moved or replaced (the binding is on the cached node)
+			//   Checked before `from_ids`, as that follows mounts (returning the mounted root).
+			let src_inode = try!(self.lookup_child(&**fsnode, src_name));
+			if is_mounted_on(self.mountpt, src_inode) {
+				return Err( super::Error::Busy );
+			}
+			if let Ok(dst_inode) = dst_dir.lookup_child(&**dst_fsnode, dst_name) {
+				if is_mounted_on(self.mountpt, dst_inode) {
+					return Err( super::Error::Busy );
+				}
+			}
+			let src = try!(CacheHandle::from_ids(self.mountpt, src_inode));
+			// - If the node's inode number changes when it's moved, it can't be moved to another directory while in use
+			//   (the cached node would be left with the old number)
+			let changes_inode = self.inode != dst_dir.inode && super::mount::Handle::from_id(self.mountpt).parent_dependent_inodes();
+			if changes_inode && src.refcount() > 1 {
+				return Err( super::Error::Locked );
+			}
+
+			try!(fsnode.rename(src_name, &**dst_fsnode, dst_name));
+
+			// - Drop the (now stale) cached node for the old inode number
+			drop(src);
+			if changes_inode {
+				let stale = S_NODE_CACHE.lock().evict_unused( (self.mountpt, src_inode) );
+				if let Some(n) = stale {
+					drop_evicted(n);
+				}
+			}
+
+			self.name_cache_invalidate(src_name);
+			dst_dir.name_cache_invalidate(dst_name);
+			// - A moved directory has a new parent
+			S_NAME_CACHE.lock().invalidate(self.mountpt, src_inode, ByteStr::new(".."));
+			super::watch::notify(self.get_ids(), super::watch::EventKind::RenamedFrom, src_name);
+			super::watch::notify(dst_dir.get_ids(), super::watch::EventKind::RenamedTo, dst_name);
+			Ok( () )
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	/// Add `name` to this directory as a new link to `node` (which must be on the same filesystem)
+	pub fn link(&self, name: &ByteStr, node: &CacheHandle) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Dir { ref fsnode, .. } => {
+			if self.mountpt != node.mountpt {
+				return Err( super::Error::CrossFilesystem );
+			}
+			if self.is_readonly() {
+				return Err( super::Error::ReadOnlyFilesystem );
+			}
+			if name == "" || name == "." || name == ".." {
+				return Err( super::Error::InvalidParameter );
+			}
+			try!(self.check_access(Access::Write));
+
+			try!(fsnode.link(name, &NodeBaseRef(node.as_ref())));
+			// - Drop any cached failed lookup of this name
+			self.name_cache_invalidate(name);
+			super::watch::notify(self.get_ids(), super::watch::EventKind::Created, name);
+			Ok( () )
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	/// Remove `name` from this directory
+	///
+	/// If the node is open, it stays usable through the existing handles.
+	pub fn unlink(&self, name: &ByteStr) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Dir { ref fsnode, .. } => {
+			if self.is_readonly() {
+				return Err( super::Error::ReadOnlyFilesystem );
+			}
+			if name == "" || name == "." || name == ".." {
+				return Err( super::Error::InvalidParameter );
+			}
+			try!(self.check_access(Access::Write));
+
+			// - Mountpoints can't be removed
+			let inode = try!(self.lookup_child(&**fsnode, name));
+			if is_mounted_on(self.mountpt, inode) {
+				return Err( super::Error::Busy );
+			}
+
+			try!(fsnode.unlink(name));
+
+			self.name_cache_invalidate(name);
+			// - Drop the cached node, as the inode number could be reused
+			let evicted = S_NODE_CACHE.lock().detach( (self.mountpt, inode) );
+			if let Some(n) = evicted {
+				drop_evicted(n);
+			}
+			super::watch::notify(self.get_ids(), super::watch::EventKind::Removed, name);
+			Ok( () )
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
 }
 /// Directory methods (mountpoint)
 impl CacheHandle
 {
+	/// Number of handles to this node (including this one)
+	fn refcount(&self) -> usize {
+		// SAFE: Pointer is valid while this handle exists
+		unsafe { (*self.ptr).refcount.load(atomic::Ordering::Relaxed) }
+	}
 	pub fn is_mountpoint(&self) -> bool {
 		match self.as_ref()
 		{
@@ -387,6 +902,16 @@ impl CacheHandle
 		_ => false,
 		}
 	}
+	/// Returns `true` if the binding to `filesystem_id` was removed
+	pub fn unmount(&self, filesystem_id: usize) -> bool {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Dir { ref mountpoint, .. } => {
+			mountpoint.compare_and_swap(filesystem_id, 0, atomic::Ordering::Relaxed) == filesystem_id
+			},
+		_ => false,
+		}
+	}
 }
 /// Normal file methods
 impl CacheHandle
@@ -406,6 +931,65 @@ impl CacheHandle
 		_ => Err( super::Error::Unknown("Calling read on non-file") ),
 		}
 	}
+	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { .. } if self.is_readonly() => Err( super::Error::ReadOnlyFilesystem ),
+		&CacheNodeInt::File { ref fsnode, ref pages, .. } => {
+			// Drop any cached pages covering the written range, so later mappings see the new data
+			// - Existing mappings keep the old frames
+			if src.len() > 0 {
+				let mut lh = pages.lock();
+				for page in ofs / ::PAGE_SIZE as u64 .. (ofs + src.len() as u64 - 1) / ::PAGE_SIZE as u64 + 1 {
+					lh.remove(&page);
+				}
+			}
+			Ok( try!(fsnode.write(ofs, src)) )
+			},
+		_ => Err( super::Error::Unknown("Calling write on non-file") ),
+		}
+	}
+	/// Obtain the cached frame for a page of the file, reading it in if it's not already cached
+	///
+	/// The frame is shared by all mappings of this page, anything past the end of the file is zero.
+	pub fn get_page(&self, page: u64) -> super::Result<FrameHandle> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, ref pages, .. } => {
+			let mut lh = pages.lock();
+			if let Some(h) = lh.get(&page) {
+				return Ok( h.clone() );
+			}
+
+			let mut cp = match ::memory::page_cache::S_PAGE_CACHE.create()
+				{
+				Ok(v) => v,
+				Err(_) => return Err( super::Error::OutOfMemory ),
+				};
+			{
+				let data = cp.data_mut();
+				let ofs = page * ::PAGE_SIZE as u64;
+				let len = if ofs < fsnode.size() { try!(fsnode.read(ofs, data)) } else { 0 };
+				for b in &mut data[len..] {
+					*b = 0;
+				}
+			}
+			// - The frame handle keeps the frame alive once the temporary mapping is released
+			let rv = cp.get_frame_handle();
+			lh.insert(page, rv.clone());
+			Ok( rv )
+			},
+		_ => Err( super::Error::Unknown("Calling get_page on non-file") ),
+		}
+	}
+	/// Lock state for the file (used by `handle::File`)
+	pub fn file_locks(&self) -> Option<&::sync::Mutex<FileLocks>> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref locks, .. } => Some(locks),
+		_ => None,
+		}
+	}
 }
 
 
@@ -424,16 +1008,123 @@ impl CacheHandle
 impl CacheHandle
 {
 	fn as_ref(&self) -> &CacheNodeInt {
-		let lh = S_NODE_CACHE.lock();
-		// SAFE: While this handle is active, the box will be present
+		// SAFE: While this handle is active, the node won't be evicted (and boxed, so won't move)
 		unsafe {
-			let box_r = lh.get( &(self.mountpt, self.inode) ).expect("Cached node with open handle absent");
-			let cn_ref: &CachedNode = &**box_r;
-			// Dereference a raw pointer to disconnect the lifetimes
-			&*(&cn_ref.node as *const _)
+			&(*self.ptr).node
+		}
+	}
+
+	/// Look up a name in this directory (using the name cache)
+	fn lookup_child(&self, dir: &Dir, name: &ByteStr) -> super::Result<InodeId> {
+		if let Some(v) = S_NAME_CACHE.lock().get(self.mountpt, self.inode, name) {
+			return v.ok_or(super::Error::NotFound);
+		}
+		match dir.lookup(name)
+		{
+		Ok(v) => {
+			S_NAME_CACHE.lock().set(self.mountpt, self.inode, name, Some(v));
+			Ok(v)
+			},
+		Err(super::Error::NotFound) => {
+			S_NAME_CACHE.lock().set(self.mountpt, self.inode, name, None);
+			Err(super::Error::NotFound)
+			},
+		Err(e) => Err(e),
+		}
+	}
+	/// Remove a cached lookup result (to be called when a name is added or removed)
+	fn name_cache_invalidate(&self, name: &ByteStr) {
+		S_NAME_CACHE.lock().invalidate(self.mountpt, self.inode, name);
+	}
+}
+impl ::core::ops::Drop for CacheHandle
+{
+	fn drop(&mut self) {
+		let evicted = {
+			let mut lh = S_NODE_CACHE.lock();
+			// SAFE: Pointer is valid while this handle exists (the count is only incremented with the lock held, or by another handle)
+			let cn = unsafe { &*self.ptr };
+			if cn.refcount.fetch_sub(1, atomic::Ordering::Relaxed) == 1 {
+				// Unlinked nodes are released as soon as they're unused
+				let detached_pos = lh.detached.iter().position(|n| &**n as *const CachedNode == self.ptr);
+				match cn.node
+				{
+				_ if detached_pos.is_some() => Some( lh.detached.remove(detached_pos.unwrap()) ),
+				// Mountpoints must stay in the cache (they hold the mount binding)
+				CacheNodeInt::Dir { ref mountpoint, .. } if mountpoint.load(atomic::Ordering::Relaxed) != 0 => None,
+				_ => lh.mark_unused( (self.mountpt, self.inode) ),
+				}
+			}
+			else {
+				None
+			}
+			};
+		// Drop the evicted node with the cache unlocked, as the filesystem might need to do IO
+		if let Some(n) = evicted {
+			drop_evicted(n);
+		}
+	}
+}
+/// Presents a cached node as a `NodeBase` (trait objects can't be upcast), used by `CacheHandle::link`
+struct NodeBaseRef<'a>(&'a CacheNodeInt);
+// SAFE: Only lives for the duration of a call, while a `CacheHandle` (which is Send) keeps the node alive
+unsafe impl<'a> Send for NodeBaseRef<'a> {}
+impl<'a> NodeBase for NodeBaseRef<'a>
+{
+	fn get_id(&self) -> InodeId {
+		match self.0
+		{
+		&CacheNodeInt::Dir { ref fsnode, .. } => fsnode.get_id(),
+		&CacheNodeInt::File { ref fsnode, .. } => fsnode.get_id(),
+		&CacheNodeInt::Special { ref fsnode, .. } => fsnode.get_id(),
+		&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.get_id(),
+		}
+	}
+	fn get_info(&self) -> Result<NodeInfo> {
+		match self.0
+		{
+		&CacheNodeInt::Dir { ref fsnode, .. } => fsnode.get_info(),
+		&CacheNodeInt::File { ref fsnode, .. } => fsnode.get_info(),
+		&CacheNodeInt::Special { ref fsnode, .. } => fsnode.get_info(),
+		&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.get_info(),
+		}
+	}
+	fn get_any(&self) -> &Any {
+		match self.0
+		{
+		&CacheNodeInt::Dir { ref fsnode, .. } => fsnode.get_any(),
+		&CacheNodeInt::File { ref fsnode, .. } => fsnode.get_any(),
+		&CacheNodeInt::Special { ref fsnode, .. } => fsnode.get_any(),
+		&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.get_any(),
 		}
 	}
 }
+/// Returns true if the node `(mountpt, inode)` has a filesystem mounted on it
+///
+/// Checks the cached node directly, as `CacheHandle::from_ids` follows mounts. Mountpoints are never evicted, so
+/// an uncached node isn't a mountpoint.
+fn is_mounted_on(mountpt: usize, inode: InodeId) -> bool
+{
+	match S_NODE_CACHE.lock().get( (mountpt, inode) )
+	{
+	Some(n) => match n.node
+		{
+		CacheNodeInt::Dir { ref mountpoint, .. } => mountpoint.load(atomic::Ordering::Relaxed) != 0,
+		_ => false,
+		},
+	None => false,
+	}
+}
+/// Release a node that has been removed from the cache (called with the cache unlocked)
+fn drop_evicted(n: Box<CachedNode>)
+{
+	log_trace!("Evicting node {}:{:#x}", n.mountpt, n.inode);
+	// - Cached lookups in an evicted directory are dropped with it
+	if let CacheNodeInt::Dir { .. } = n.node {
+		S_NAME_CACHE.lock().purge_dir(n.mountpt, n.inode);
+	}
+	drop(n);
+}
 //impl ::core::convert::AsMut<Node> for CacheHandle
 //{
 //	fn as_ref(&mut self) -> &mut Node {
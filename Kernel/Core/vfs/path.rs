@@ -68,6 +68,22 @@ impl Path
 		}
 	}
 	
+	/// Return the path without its last element, and the last element
+	pub fn split_off_last(&self) -> Option<(&Path, &ByteStr)> {
+		if self.0.len() == 0 {
+			None
+		}
+		else {
+			let mut i = self.0.as_bytes().rsplitn(2, |&c| c == b'/');
+			let last = i.next().unwrap();
+			match i.next()
+			{
+			Some(rest) => Some( (Path::new(rest), ByteStr::new(last)) ),
+			None => Some( (Path::new(""), ByteStr::new(last)) ),
+			}
+		}
+	}
+	
 	/// Returns Some(remainder) if this path starts with another path
 	pub fn starts_with<P: AsRef<Path>>(&self, other: P) -> Option<&Path> {
 		let other: &Path = other.as_ref();
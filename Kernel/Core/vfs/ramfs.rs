@@ -14,9 +14,15 @@ use lib::mem::aref::{Aref,ArefInner,ArefBorrow};
 pub struct Driver;
 pub static S_DRIVER: Driver = Driver;
 
-enum RamFile
+struct RamFile
 {
-	//File(RamFileFile),
+	/// Number of directory entries referring to this node
+	link_count: ::core::sync::atomic::AtomicUsize,
+	data: RamFileData,
+}
+enum RamFileData
+{
+	File(RamFileFile),
 	Dir(RamFileDir),
 	Symlink(RamFileSymlink),
 }
@@ -30,13 +36,19 @@ struct RamFileSymlink
 {
 	target: super::PathBuf,
 }
-//#[derive(Default)]
-//struct RamFileFile
-//{
-//	ofs: usize,
-//	size: usize,
-//}
-struct FileRef(ArefBorrow<RamFSInner>,ArefBorrow<RamFile>);
+#[derive(Default)]
+struct RamFileFile
+{
+	contents: ::sync::RwLock<RamFileContents>,
+}
+#[derive(Default)]
+struct RamFileContents
+{
+	size: u64,
+	/// File data, one entry per page (`None` for pages that have never been written)
+	pages: Vec<Option<Box<[u8]>>>,
+}
+struct FileRef(ArefBorrow<RamFSInner>,ArefBorrow<RamFile>,node::InodeId);
 
 struct RamFS
 {
@@ -62,7 +74,8 @@ impl mount::Driver for Driver
 		// RAMFS should never bind to an arbitary volume
 		Ok(0)
 	}
-	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle) -> super::Result<Box<mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle, options: &[&str]) -> super::Result<Box<mount::Filesystem>> {
+		try!(mount::reject_options(options));
 		let rv = Box::new(RamFS {
 			// SAFE: ArefInner must not change addresses, but because you can't move out of a boxed trait, we're good
 			inner: unsafe { ArefInner::new( RamFSInner {
@@ -70,7 +83,7 @@ impl mount::Driver for Driver
 				nodes: Default::default(),
 				}) },
 			});
-		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamFile::Dir(Default::default())) );
+		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamFile::new(RamFileData::Dir(Default::default()))) );
 		assert_eq!(root_inode, 0);
 		Ok(rv)
 	}
@@ -84,44 +97,89 @@ impl mount::Filesystem for RamFS
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		log_trace!("RamFS::get_node_by_inode({})", id);
 		let nodes = self.inner.nodes.lock();
-		if id >= nodes.len() as node::InodeId {
+		match nodes.get(id as usize)
+		{
+		None => {
 			log_log!("RamFile::get_node_by_inode - Inode {} out of range", id);
 			None
-		}
-		else {
+			},
+		Some(n) => {
 			let fr = Box::new(FileRef(
 				self.inner.borrow(),
-				nodes[id as usize].borrow()
+				n.borrow(),
+				id
 				));
-			match *nodes[id as usize]
+			match n.data
 			{
-			RamFile::Dir(_) => Some(node::Node::Dir(fr)),
-			RamFile::Symlink(_) => Some(node::Node::Symlink(fr)),
-			//RamFile::File(_) => todo!("normal files"),
+			RamFileData::Dir(_) => Some(node::Node::Dir(fr)),
+			RamFileData::Symlink(_) => Some(node::Node::Symlink(fr)),
+			RamFileData::File(_) => Some(node::Node::File(fr)),
 			}
+			},
 		}
 	}
 }
 
+impl RamFile {
+	fn new(data: RamFileData) -> RamFile {
+		RamFile {
+			link_count: ::core::sync::atomic::AtomicUsize::new(1),
+			data: data,
+			}
+	}
+}
+
 impl FileRef {
 	fn dir(&self) -> &RamFileDir {
-		match &*self.1
+		match self.1.data
 		{
-		&RamFile::Dir(ref e) => e,
+		RamFileData::Dir(ref e) => e,
 		_ => panic!("Called FileRef::dir() on non-dir"),
 		}
 	}
 	fn symlink(&self) -> &RamFileSymlink {
-		match &*self.1
+		match self.1.data
 		{
-		&RamFile::Symlink(ref e) => e,
+		RamFileData::Symlink(ref e) => e,
 		_ => panic!("Called FileRef::symlink() on non-symlink"),
 		}
 	}
+	fn file(&self) -> &RamFileFile {
+		match self.1.data
+		{
+		RamFileData::File(ref e) => e,
+		_ => panic!("Called FileRef::file() on non-file"),
+		}
+	}
+}
+impl ::core::ops::Drop for FileRef {
+	fn drop(&mut self) {
+		// Once the last name is gone and the node is no longer in use, release the file data
+		// TODO: Also release the inode slot (requires that no other `FileRef`s exist)
+		if self.1.link_count.load(::core::sync::atomic::Ordering::SeqCst) == 0 {
+			if let RamFileData::File(ref f) = self.1.data {
+				*f.contents.write() = Default::default();
+			}
+		}
+	}
 }
 impl node::NodeBase for FileRef {
 	fn get_id(&self) -> node::InodeId {
-		unimplemented!()
+		self.2
+	}
+	fn get_info(&self) -> vfs::Result<node::NodeInfo> {
+		// NOTE: No timestamps are recorded, as there's no wall-clock time source yet
+		let (size, mode) = match self.1.data
+			{
+			RamFileData::File(ref f) => (f.contents.read().size, 0o644),
+			RamFileData::Dir(_) => (0, 0o755),
+			RamFileData::Symlink(ref l) => (AsRef::<[u8]>::as_ref(&*l.target).len() as u64, 0o777),
+			};
+		Ok(node::NodeInfo {
+			size: size,
+			mode: mode,
+			.. Default::default()
+			})
 	}
 	fn get_any(&self) -> &::core::any::Any {
 		self
@@ -160,23 +218,111 @@ impl node::Dir for FileRef {
 		Entry::Vacant(e) => {
 			let nn = match nodetype
 				{
-				node::NodeType::Dir  => RamFile::Dir (Default::default()),
-				//node::NodeType::File => RamFile::File(Default::default()),
-				node::NodeType::File => return Err(vfs::Error::Unknown("TODO: Files")),
+				node::NodeType::Dir  => RamFileData::Dir (Default::default()),
+				node::NodeType::File => RamFileData::File(Default::default()),
 				node::NodeType::Symlink(v) =>
-					RamFile::Symlink(RamFileSymlink{target: From::from(v)}),
+					RamFileData::Symlink(RamFileSymlink{target: From::from(v)}),
 				};
-			let inode = self.0.nodes.lock().insert( Aref::new(nn) );
+			let inode = self.0.nodes.lock().insert( Aref::new(RamFile::new(nn)) );
 			e.insert(inode);
 			Ok(inode as node::InodeId)
 			},
 		}
 	}
 	fn link(&self, name: &ByteStr, node: &node::NodeBase) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::link({:?}, inode={})", name, node.get_id())
+		use lib::vec_map::Entry;
+		// The node must be from this filesystem
+		let other: &FileRef = match node.get_any().downcast_ref()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::Unknown("Can't link across filesystems")),
+			};
+		if &*other.0 as *const RamFSInner != &*self.0 as *const RamFSInner {
+			return Err(vfs::Error::Unknown("Can't link across filesystems"));
+		}
+		if let RamFileData::Dir(_) = other.1.data {
+			return Err(vfs::Error::Unknown("Can't hard link directories"));
+		}
+
+		let mut lh = self.dir().ents.write();
+		match lh.entry(From::from(name))
+		{
+		Entry::Occupied(_) => Err(vfs::Error::AlreadyExists),
+		Entry::Vacant(e) => {
+			other.1.link_count.fetch_add(1, ::core::sync::atomic::Ordering::SeqCst);
+			e.insert(other.2 as usize);
+			Ok( () )
+			},
+		}
 	}
 	fn unlink(&self, name: &ByteStr) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::unlink({:?})", name)
+		let mut lh = self.dir().ents.write();
+		let inode = match lh.get(name)
+			{
+			Some(&v) => v,
+			None => return Err(vfs::Error::NotFound),
+			};
+		{
+			let nodes = self.0.nodes.lock();
+			let node = match nodes.get(inode)
+				{
+				Some(v) => v,
+				None => return Err(vfs::Error::InconsistentFilesystem),
+				};
+			if let RamFileData::Dir(ref d) = node.data {
+				if d.ents.read().iter().next().is_some() {
+					return Err(vfs::Error::Unknown("Directory not empty"));
+				}
+			}
+			node.link_count.fetch_sub(1, ::core::sync::atomic::Ordering::SeqCst);
+		}
+		lh.remove(&ByteString::from(name));
+		Ok( () )
+	}
+	fn rename(&self, src_name: &ByteStr, dst_dir: &node::Dir, dst_name: &ByteStr) -> vfs::Result<()> {
+		let dst: &FileRef = match dst_dir.get_any().downcast_ref()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::CrossFilesystem),
+			};
+		if &*dst.0 as *const RamFSInner != &*self.0 as *const RamFSInner {
+			return Err(vfs::Error::CrossFilesystem);
+		}
+
+		if dst.2 == self.2 {
+			let mut lh = self.dir().ents.write();
+			if lh.get(src_name).is_none() {
+				return Err(vfs::Error::NotFound);
+			}
+			if src_name == dst_name {
+				return Ok( () );
+			}
+			if lh.get(dst_name).is_some() {
+				return Err(vfs::Error::AlreadyExists);
+			}
+			let inode = lh.remove(&ByteString::from(src_name)).unwrap();
+			lh.insert(ByteString::from(dst_name), inode);
+		}
+		else {
+			// Lock the directories in inode order, so a concurrent rename in the other direction can't deadlock
+			let (mut src_lh, mut dst_lh) = if self.2 < dst.2 {
+					let s = self.dir().ents.write();
+					(s, dst.dir().ents.write())
+				}
+				else {
+					let d = dst.dir().ents.write();
+					(self.dir().ents.write(), d)
+				};
+			if src_lh.get(src_name).is_none() {
+				return Err(vfs::Error::NotFound);
+			}
+			if dst_lh.get(dst_name).is_some() {
+				return Err(vfs::Error::AlreadyExists);
+			}
+			let inode = src_lh.remove(&ByteString::from(src_name)).unwrap();
+			dst_lh.insert(ByteString::from(dst_name), inode);
+		}
+		Ok( () )
 	}
 }
 impl node::Symlink for FileRef {
@@ -185,3 +331,112 @@ impl node::Symlink for FileRef {
 	}
 }
 
+impl node::File for FileRef {
+	fn size(&self) -> u64 {
+		self.file().contents.read().size
+	}
+	fn truncate(&self, newsize: u64) -> node::Result<u64> {
+		let mut lh = self.file().contents.write();
+		if newsize < lh.size {
+			// Zero the tail of the new last page (so a later extension reads as zeroes), and release the rest
+			let (page, ofs) = ::lib::num::div_rem(newsize, ::PAGE_SIZE as u64);
+			if ofs > 0 {
+				if let Some(ref mut p) = lh.pages[page as usize] {
+					for b in &mut p[ofs as usize ..] {
+						*b = 0;
+					}
+				}
+			}
+			let n_pages = ::lib::num::div_up(newsize, ::PAGE_SIZE as u64) as usize;
+			lh.pages.truncate(n_pages);
+		}
+		else {
+			// New pages are left as holes
+			let n_pages = ::lib::num::div_up(newsize, ::PAGE_SIZE as u64) as usize;
+			while lh.pages.len() < n_pages {
+				lh.pages.push(None);
+			}
+		}
+		lh.size = newsize;
+		Ok( newsize )
+	}
+	fn clear(&self, ofs: u64, size: u64) -> node::Result<()> {
+		let mut lh = self.file().contents.write();
+		if ofs > lh.size || size > lh.size - ofs {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		let mut pos = ofs;
+		let end = ofs + size;
+		while pos < end
+		{
+			let (page, page_ofs) = ::lib::num::div_rem(pos, ::PAGE_SIZE as u64);
+			let len = ::core::cmp::min(::PAGE_SIZE as u64 - page_ofs, end - pos) as usize;
+			let page_ofs = page_ofs as usize;
+			if page_ofs == 0 && len == ::PAGE_SIZE {
+				// Entire page cleared, turn it into a hole
+				lh.pages[page as usize] = None;
+			}
+			else if let Some(ref mut p) = lh.pages[page as usize] {
+				for b in &mut p[page_ofs .. page_ofs + len] {
+					*b = 0;
+				}
+			}
+			pos += len as u64;
+		}
+		Ok( () )
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		let lh = self.file().contents.read();
+		if ofs > lh.size {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		let maxread = lh.size - ofs;
+		let buf = if buf.len() as u64 > maxread { &mut buf[.. maxread as usize] } else { buf };
+
+		let mut pos = 0;
+		while pos < buf.len()
+		{
+			let (page, page_ofs) = ::lib::num::div_rem(ofs + pos as u64, ::PAGE_SIZE as u64);
+			let page_ofs = page_ofs as usize;
+			let len = ::core::cmp::min(::PAGE_SIZE - page_ofs, buf.len() - pos);
+			let dst = &mut buf[pos .. pos + len];
+			match lh.pages[page as usize]
+			{
+			Some(ref p) => dst.clone_from_slice( &p[page_ofs .. page_ofs + len] ),
+			None => for b in dst { *b = 0; },
+			}
+			pos += len;
+		}
+		Ok( buf.len() )
+	}
+	fn write(&self, ofs: u64, buf: &[u8]) -> node::Result<usize> {
+		let mut lh = self.file().contents.write();
+		if ofs > lh.size {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		// Writes at (or crossing) the end of the file extend it
+		let end = ofs + buf.len() as u64;
+		let n_pages = ::lib::num::div_up(end, ::PAGE_SIZE as u64) as usize;
+		while lh.pages.len() < n_pages {
+			lh.pages.push(None);
+		}
+
+		let mut pos = 0;
+		while pos < buf.len()
+		{
+			let (page, page_ofs) = ::lib::num::div_rem(ofs + pos as u64, ::PAGE_SIZE as u64);
+			let page_ofs = page_ofs as usize;
+			let len = ::core::cmp::min(::PAGE_SIZE - page_ofs, buf.len() - pos);
+			let p = &mut lh.pages[page as usize];
+			if p.is_none() {
+				*p = Some( vec![0u8; ::PAGE_SIZE].into_boxed_slice() );
+			}
+			p.as_mut().unwrap()[page_ofs .. page_ofs + len].clone_from_slice( &buf[pos .. pos + len] );
+			pos += len;
+		}
+		if end > lh.size {
+			lh.size = end;
+		}
+		Ok( buf.len() )
+	}
+}
@@ -0,0 +1,178 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/watch.rs
+//! Directory change notifications
+use prelude::*;
+use lib::mem::Arc;
+use lib::byte_str::{ByteStr,ByteString};
+use sync::Mutex;
+use core::sync::atomic::{AtomicBool,AtomicUsize,ATOMIC_USIZE_INIT,Ordering};
+use super::node::{CacheHandle,InodeId};
+
+/// Maximum number of queued events per watch, further events are replaced by a single `Overflow`
+const MAX_QUEUED_EVENTS: usize = 64;
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum EventKind
+{
+	/// A new entry was created
+	Created,
+	/// An entry was removed
+	Removed,
+	/// An entry was renamed/moved away from this name
+	RenamedFrom,
+	/// An entry was renamed/moved to this name
+	RenamedTo,
+	/// A file's contents were changed
+	Modified,
+	/// Events were lost (the queue filled), the directory should be re-read
+	Overflow,
+}
+
+#[derive(Debug)]
+pub struct Event
+{
+	pub kind: EventKind,
+	/// Name of the affected entry (empty for `Overflow`)
+	pub name: ByteString,
+}
+
+struct WatchShared
+{
+	node: (usize, InodeId),
+	queue: Mutex<Vec<Event>>,
+	overflowed: AtomicBool,
+	waiters: ::async::queue::Source,
+}
+
+static S_WATCHES: Mutex<Vec<Arc<WatchShared>>> = Mutex::new(Vec::new_const());
+/// Number of entries in `S_WATCHES` (checked without the lock, so `notify` is cheap when nothing is being watched)
+static S_WATCH_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Handle to a watch on a directory, events are queued until read
+pub struct Watch(Arc<WatchShared>);
+
+impl Watch
+{
+	/// Start watching the specified directory
+	pub fn new(dir: &CacheHandle) -> Watch
+	{
+		let shared = Arc::new(WatchShared {
+			node: dir.get_ids(),
+			queue: Default::default(),
+			overflowed: AtomicBool::new(false),
+			waiters: Default::default(),
+			});
+		let mut lh = S_WATCHES.lock();
+		lh.push(shared.clone());
+		S_WATCH_COUNT.store(lh.len(), Ordering::Relaxed);
+		Watch(shared)
+	}
+
+	/// Remove the oldest queued event
+	pub fn pop_event(&self) -> Option<Event>
+	{
+		let mut lh = self.0.queue.lock();
+		if lh.len() > 0 {
+			Some( lh.remove(0) )
+		}
+		else if self.0.overflowed.swap(false, Ordering::Relaxed) {
+			Some( Event { kind: EventKind::Overflow, name: ByteString::new() } )
+		}
+		else {
+			None
+		}
+	}
+
+	/// Returns true if there are events waiting
+	pub fn has_events(&self) -> bool {
+		self.0.queue.lock().len() > 0 || self.0.overflowed.load(Ordering::Relaxed)
+	}
+
+	pub fn bind_wait(&self, obj: &mut ::threads::SleepObject) {
+		self.0.waiters.wait_upon(obj);
+		if self.has_events() {
+			obj.signal();
+		}
+	}
+	pub fn clear_wait(&self, obj: &mut ::threads::SleepObject) -> bool {
+		self.0.waiters.clear_wait(obj);
+		self.has_events()
+	}
+}
+impl ::core::ops::Drop for Watch
+{
+	fn drop(&mut self)
+	{
+		let mut lh = S_WATCHES.lock();
+		if let Some(i) = lh.iter().position(|w| &**w as *const _ == &*self.0 as *const _) {
+			lh.remove(i);
+		}
+		S_WATCH_COUNT.store(lh.len(), Ordering::Relaxed);
+	}
+}
+
+/// Queue an event on all watches of the directory `dir`
+pub fn notify(dir: (usize, InodeId), kind: EventKind, name: &ByteStr)
+{
+	if S_WATCH_COUNT.load(Ordering::Relaxed) == 0 {
+		return ;
+	}
+	let lh = S_WATCHES.lock();
+	for w in lh.iter().filter(|w| w.node == dir)
+	{
+		{
+			let mut q = w.queue.lock();
+			// - Repeated modifications of the same file only need to be reported once
+			let is_dup = kind == EventKind::Modified && q.last().map(|e| e.kind == kind && &*e.name == name).unwrap_or(false);
+			if is_dup {
+				continue ;
+			}
+			if q.len() >= MAX_QUEUED_EVENTS {
+				// Drop the queue, the watcher has to re-read the directory anyway
+				q.truncate(0);
+				w.overflowed.store(true, Ordering::Relaxed);
+			}
+			else {
+				q.push(Event { kind: kind, name: ByteString::from(name) });
+			}
+		}
+		w.waiters.wake_one();
+	}
+}
+
+/// Location of a node in its parent directory (used to report modifications of opened files)
+#[derive(Debug,Clone)]
+pub struct Origin
+{
+	dir: (usize, InodeId),
+	name: ByteString,
+}
+impl Origin
+{
+	/// Record that a node was reached through `name` in `dir`
+	pub fn new(dir: &CacheHandle, name: &ByteStr) -> Option<Origin>
+	{
+		if name == "" || name == "." || name == ".." {
+			None
+		}
+		else {
+			Some(Origin { dir: dir.get_ids(), name: ByteString::from(name) })
+		}
+	}
+	/// Obtain the origin of the final component of a walked path (with the directories traversed)
+	pub fn from_walk(parents: &[CacheHandle], path: &super::Path) -> Option<Origin>
+	{
+		match (parents.last(), path.split_off_last())
+		{
+		(Some(dir), Some( (_, name) )) => Origin::new(dir, name),
+		_ => None,
+		}
+	}
+
+	pub fn notify(&self, kind: EventKind)
+	{
+		notify(self.dir, kind, &self.name);
+	}
+}
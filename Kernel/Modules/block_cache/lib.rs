@@ -10,9 +10,11 @@
 use kernel::prelude::*;
 use kernel::PAGE_SIZE;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use kernel::metadevs::storage::{VolumeHandle,IoError};
-use kernel::sync::{RwLock,rwlock};
+use kernel::metadevs::storage::{self,VolumeHandle,IoError};
+use kernel::sync::{RwLock,rwlock,Mutex,Spinlock};
 use kernel::sync::mutex::LazyMutex;
+use kernel::lib::mem::Arc;
+use kernel::memory::page_cache::{S_PAGE_CACHE,CachedPage};
 
 // NOTES:
 // - Handles wrap logical volume handles
@@ -20,15 +22,40 @@ use kernel::sync::mutex::LazyMutex;
 //  > read/write (unbuffered)
 //  > read_inner/get/edit (buffered)
 //
-// - The global cache is registered with the PMM as a source of reclaimable memory
+// - Buffered writes are write-back: blocks are marked dirty, and written by the flush worker (or `flush`)
+//  > The worker waits `FLUSH_DELAY` after a block is dirtied (to batch up writes), or less if `FLUSH_THRESHOLD` entries are dirty
+// - Sequential misses (a miss on the entry following the previous miss) trigger read-ahead of the following entries
+// - Missing entries are inserted empty (with their mapping write-locked), and read with the cache unlocked
+//  > Other lookups of a loading entry wait on its mapping lock
+// - Cache entries are at least a page, and contain one or more whole volume blocks
+// - The global cache is registered with the page cache as a source of reclaimable mappings
+//  > Unreferenced entries keep their mapping until it's stolen (least recently used first)
+//  > If memory can't be allocated for a new entry, clean unreferenced entries are dropped
+//  > It's also registered with the physical allocator, which evicts idle entries when out of memory
+//   - Eviction only releases an entry's frames, the (empty) entry is removed when next looked up or when a new entry is created
 
 #[macro_use]
 extern crate kernel;
 
+/// Default number of entries read ahead when sequential access is detected
+const DEFAULT_READAHEAD: usize = 8;
+/// Time (in ms) dirty entries are left before being written back
+const FLUSH_DELAY: u64 = 5*1000;
+/// Number of dirty entries that triggers an immediate write-back
+const FLUSH_THRESHOLD: usize = 256;
+
 /// A handle into the cache corresponding to a logical volume
+///
+/// Dirty blocks are written back when the handle is dropped
 pub struct CacheHandle
 {
-	vh: VolumeHandle,
+	vh: Arc<VolumeHandle>,
+	/// Number of volume blocks in each cache entry
+	entry_blocks: u64,
+	/// Maximum number of entries to read ahead (0 = disabled)
+	readahead: AtomicUsize,
+	/// First block of the entry that would be missed next if access is sequential
+	seq_next: ::kernel::sync::atomic::AtomicValue<u64>,
 }
 
 /// A handle to a block in the cache
@@ -47,17 +74,41 @@ struct CachedBlock
 {
 	// Constant:
 	index: u64,
-	block_paddr: ::kernel::memory::phys::FrameHandle,
+	/// Frames backing the entry (retained while the mapping is stolen)
+	frames: Vec<::kernel::memory::phys::FrameHandle>,
 
 	reference_count: AtomicUsize,
 	last_access: ::kernel::time::CacheTimer,
 	is_dirty: AtomicBool,
+	/// Set while the data is being read from disk (the loader holds `mapping` write-locked)
+	loading: AtomicBool,
+	/// Set if the disk was written directly while loading (the data must be re-read)
+	load_stale: AtomicBool,
+	/// Set if loading failed (the entry is removed once unreferenced)
+	load_failed: AtomicBool,
+
+	mapping: RwLock<Option<CachedPage>>,
+}
 
-	mapping: RwLock<Option<::kernel::memory::page_cache::CachedPage>>,
+/// Entries inserted by `CacheHandle::insert_entries`, to be read by `CacheHandle::load_entries`
+struct PendingLoad
+{
+	first_block: u64,
+	/// Inserted entries, referenced and write-locked until loaded
+	entries: Vec<(MetaBlockHandle<'static>, rwlock::Write<'static, Option<CachedPage>>)>,
 }
 
 
 static S_BLOCK_CACHE: LazyMutex<Cache> = LazyMutex::new();
+/// Volumes with open cache handles (used by the flush worker)
+static S_VOLUMES: LazyMutex<::kernel::lib::VecMap<usize, Arc<VolumeHandle>>> = LazyMutex::new();
+/// Serialises flushing against removal of a volume's blocks
+static S_FLUSH_LOCK: Mutex<()> = Mutex::new(());
+static S_FLUSH_THREAD: LazyMutex<::kernel::threads::WorkerThread> = LazyMutex::new();
+/// Sleep object of the flush worker, signalled when a clean block is modified
+static S_FLUSH_WAKE: Spinlock<Option<::kernel::threads::SleepObjectRef>> = Spinlock::new(None);
+/// Number of dirty entries
+static S_DIRTY_COUNT: AtomicUsize = AtomicUsize::new(0);
 //static S_BLOCK_CACHE: Mutex<Cache> = Mutex::new(Cache {
 //	map: ::kernel::lib::VecMap::new(),
 //	});
@@ -66,17 +117,55 @@ impl CacheHandle
 {
 	pub fn new(vol: VolumeHandle) -> CacheHandle
 	{
-		if vol.block_size() > ::kernel::PAGE_SIZE {
-			todo!("Support caching volumes with block sizes > page size");
-		}
+		CacheHandle::with_entry_size(vol, PAGE_SIZE)
+	}
+
+	/// Create a handle with cache entries of at least `min_size` bytes (e.g. a filesystem's block size)
+	///
+	/// The entry size is rounded up to a whole number of pages and volume blocks.
+	pub fn with_entry_size(vol: VolumeHandle, min_size: usize) -> CacheHandle
+	{
+		let unit = ::core::cmp::max(PAGE_SIZE, vol.block_size());
+		let entry_size = (::core::cmp::max(unit, min_size) + unit - 1) / unit * unit;
+		assert!(entry_size / PAGE_SIZE <= ::kernel::memory::page_cache::MAX_PAGES_PER_MAPPING,
+			"Block cache entry size {:#x} too large for volume {}", entry_size, vol.name());
+
+		let vh = Arc::new(vol);
+		S_VOLUMES.lock_init(|| Default::default()).insert(vh.idx(), vh.clone());
+		// - Start the flush worker (and hook into page cache reclaim) with the first handle
+		S_FLUSH_THREAD.lock_init(|| {
+			::kernel::memory::page_cache::register_reclaimer(reclaim_mappings);
+			::kernel::memory::phys::register_reclaimer(reclaim_frames);
+			::kernel::threads::WorkerThread::new("Block Cache Flush", flush_thread)
+			});
 
 		CacheHandle {
-			vh: vol,
+			entry_blocks: (entry_size / vh.block_size()) as u64,
+			readahead: AtomicUsize::new(DEFAULT_READAHEAD),
+			seq_next: ::kernel::sync::atomic::AtomicValue::new(0),
+			vh: vh,
 			}
 	}
 
-	pub fn blocks_per_page(&self) -> u64 {
-		(PAGE_SIZE / self.vh.block_size()) as u64
+	/// Set the number of entries read ahead on sequential access (0 disables read-ahead)
+	pub fn set_readahead(&self, entries: usize)
+	{
+		self.readahead.store(entries, Ordering::Relaxed);
+	}
+
+	/// Write all dirty blocks for this volume back to disk
+	pub fn flush(&self) -> Result<(), IoError>
+	{
+		let _lh = S_FLUSH_LOCK.lock();
+		flush_volume(&self.vh, storage::IO_PRIO_DEFAULT)
+	}
+
+	/// Number of volume blocks in each cache entry
+	pub fn blocks_per_entry(&self) -> u64 {
+		self.entry_blocks
+	}
+	fn pages_per_entry(&self) -> usize {
+		self.entry_blocks as usize * self.vh.block_size() / PAGE_SIZE
 	}
 }
 
@@ -98,7 +187,53 @@ impl CacheHandle
 	}
 	pub fn write_blocks(&self, block: u64, data: &[u8]) -> Result<(), IoError>
 	{
-		self.vh.write_blocks(block, data)
+		try!(self.vh.write_blocks(block, data));
+		// Keep any cached copies of these blocks coherent with the disk
+		self.update_cached(block, data);
+		Ok( () )
+	}
+
+	/// Update cached copies of blocks that have been written directly to disk
+	fn update_cached(&self, block: u64, data: &[u8])
+	{
+		let bs = self.block_size();
+		let bpe = self.blocks_per_entry();
+		let n_blocks = (data.len() / bs) as u64;
+		let mut lh = S_BLOCK_CACHE.lock_init(|| Default::default());
+		let mut i = 0;
+		while i < n_blocks
+		{
+			let blk = block + i;
+			let page_base = blk - blk % bpe;
+			let count = ::core::cmp::min(bpe - (blk - page_base), n_blocks - i);
+			let key = (self.vh.idx(), page_base);
+			let drop_entry = match lh.map.get(&key)
+				{
+				Some(cached) if cached.is_evicted() => true,
+				// - Still being read, have the loader re-read it
+				Some(cached) if cached.loading.load(Ordering::Acquire) => {
+					cached.load_stale.store(true, Ordering::Release);
+					false
+					},
+				Some(cached) => {
+					let ofs = (blk - page_base) as usize * bs;
+					match cached.update(&lh, ofs, &data[i as usize * bs ..][.. count as usize * bs])
+					{
+					Ok(_) => false,
+					Err(e) => {
+						log_warning!("Unable to update cached block {} on {} - {:?}", page_base, self.vh.name(), e);
+						!cached.is_dirty.load(Ordering::Acquire)
+						},
+					}
+					},
+				None => false,
+				};
+			// Couldn't map the (unreferenced) entry to update it, drop it so it's re-read from disk
+			if drop_entry {
+				lh.map.remove(&key);
+			}
+			i += count;
+		}
 	}
 }
 
@@ -107,23 +242,152 @@ impl CacheHandle
 {
 	fn get_block_meta(&self, block: u64) -> Result<MetaBlockHandle, IoError>
 	{
-		let cache_block = block - block % self.blocks_per_page();
-		let handle = {
-			use kernel::lib::vec_map::Entry;
+		let key = (self.vh.idx(), block - block % self.blocks_per_entry());
+		let (handle, load) = {
 			let mut lh = S_BLOCK_CACHE.lock_init(|| Default::default());
-			let handle = match lh.map.entry( (self.vh.idx(), cache_block) )
+			let remove = match lh.map.get(&key)
 				{
-				Entry::Occupied(v) => v.into_mut().borrow(),
-				Entry::Vacant(v) => v.insert( Box::new( try!(CachedBlock::new(&self.vh, cache_block)) ) ).borrow(),
+				Some(b) => b.is_evicted() || (b.load_failed.load(Ordering::Acquire) && b.reference_count.load(Ordering::Acquire) == 0),
+				None => false,
+				};
+			if remove {
+				lh.map.remove(&key);
+			}
+			let load = if lh.map.get(&key).is_none() {
+					Some( try!(self.insert_entries(&mut lh, key.1)) )
+				}
+				else {
+					None
 				};
-			// SAFE: 1. The internal data is boxed, 2. The box won't be dropped while a borrow exists.
-			unsafe { ::core::mem::transmute::<MetaBlockHandle, MetaBlockHandle>(handle) }
+			let handle = try!(lh.map.get(&key).expect("Block cache entry just inserted").borrow(&lh));
+			// SAFE: 1. The internal data is boxed, 2. The box won't be dropped while a borrow exists (eviction checks the refcount).
+			(unsafe { ::core::mem::transmute::<MetaBlockHandle, MetaBlockHandle>(handle) }, load)
 			};
+		// - Disk IO is done with the cache unlocked
+		if let Some(load) = load {
+			try!(self.load_entries(load));
+		}
+		try!(handle.wait_loaded());
 		Ok(handle)
 	}
 
+	/// Insert empty entries for a missing entry, along with the following entries if access looks sequential
+	fn insert_entries(&self, cache: &mut Cache, first_block: u64) -> Result<PendingLoad, IoError>
+	{
+		let bpe = self.blocks_per_entry();
+		let n_ahead = self.readahead_count(cache, first_block);
+		self.seq_next.store(first_block + (1 + n_ahead as u64) * bpe, Ordering::Relaxed);
+
+		let mut entries = Vec::with_capacity(1 + n_ahead);
+		for i in 0 .. 1 + n_ahead
+		{
+			let blk = first_block + i as u64 * bpe;
+			// - Read-ahead entries only use free cache space, so they can't evict the requested entry
+			let mapping = if i == 0 {
+					try!(cache.create_mapping(self.pages_per_entry()))
+				}
+				else {
+					match S_PAGE_CACHE.try_create_multiple(self.pages_per_entry())
+					{
+					Ok(v) => v,
+					Err(_) => break,
+					}
+				};
+			let key = (self.vh.idx(), blk);
+			cache.map.insert( key, Box::new(CachedBlock::new_loading(blk, mapping)) );
+			// SAFE: The box isn't freed while referenced (see `get_block_meta`)
+			let b: &'static CachedBlock = unsafe { &*(&**cache.map.get(&key).expect("Block cache entry just inserted") as *const CachedBlock) };
+			b.reference_count.fetch_add(1, Ordering::Acquire);
+			entries.push( (MetaBlockHandle(b), b.mapping.write()) );
+		}
+		Ok( PendingLoad { first_block: first_block, entries: entries } )
+	}
+	/// Read the data for entries inserted by `insert_entries` (called with the cache unlocked)
+	///
+	/// Returns the result of reading the first (requested) entry.
+	fn load_entries(&self, load: PendingLoad) -> Result<(), IoError>
+	{
+		let PendingLoad { first_block, mut entries } = load;
+		let entry_size = self.blocks_per_entry() as usize * self.block_size();
+		let mut loaded = vec![false; entries.len()];
+
+		if entries.len() > 1
+		{
+			// Read all entries with a single request, then split into entries
+			let mut buf = vec![0u8; entries.len() * entry_size];
+			match self.vh.read_blocks(first_block, &mut buf)
+			{
+			Ok(_) => {
+				for (i, (&mut (_, ref mut w), data)) in entries.iter_mut().zip(buf.chunks(entry_size)).enumerate()
+				{
+					w.as_mut().expect("Loading entry without mapping").data_mut().clone_from_slice(data);
+					loaded[i] = true;
+				}
+				},
+			Err(e) => log_notice!("Read-ahead of {} entries on {} failed ({:?}), reading single entry", entries.len() - 1, self.vh.name(), e),
+			}
+		}
+		let mut rv = Ok( () );
+		if !loaded[0] {
+			rv = self.vh.read_blocks(first_block, entries[0].1.as_mut().expect("Loading entry without mapping").data_mut());
+			loaded[0] = rv.is_ok();
+		}
+
+		loop
+		{
+			// - Re-read entries that were written directly while being read
+			let stale: Vec<usize> = {
+				let mut lh = S_BLOCK_CACHE.lock_init(|| Default::default());
+				let stale: Vec<usize> = (0 .. entries.len()).filter(|&i| loaded[i] && entries[i].0 .0.load_stale.swap(false, Ordering::Acquire)).collect();
+				if stale.len() == 0
+				{
+					// - Complete the load (with the cache locked, so `borrow` sees a consistent state)
+					for ((h, w), ok) in entries.into_iter().zip(loaded.into_iter())
+					{
+						let key = (self.vh.idx(), h.index());
+						if !ok {
+							h.0.load_failed.store(true, Ordering::Release);
+						}
+						drop(w);
+						h.0.loading.store(false, Ordering::Release);
+						drop(h);
+						// - Failed entries are removed if nothing else is waiting on them
+						if !ok && lh.map.get(&key).map(|b| b.reference_count.load(Ordering::Acquire) == 0).unwrap_or(false) {
+							lh.map.remove(&key);
+						}
+					}
+					return rv;
+				}
+				stale
+				};
+			for i in stale
+			{
+				let r = self.vh.read_blocks(entries[i].0.index(), entries[i].1.as_mut().expect("Loading entry without mapping").data_mut());
+				loaded[i] = r.is_ok();
+				if i == 0 {
+					rv = r;
+				}
+			}
+		}
+	}
+	/// Number of entries to read ahead of a miss on the entry at `first_block`
+	fn readahead_count(&self, cache: &Cache, first_block: u64) -> usize
+	{
+		let max = self.readahead.load(Ordering::Relaxed);
+		// - Only read ahead if this miss follows on from the previous one
+		if max == 0 || first_block != self.seq_next.load(Ordering::Relaxed) {
+			return 0;
+		}
+		// - Stop at the end of the volume, or at the first entry that's already cached
+		let bpe = self.blocks_per_entry();
+		let end = self.vh.block_count();
+		(1 .. max as u64 + 1)
+			.take_while(|&i| first_block + (i + 1) * bpe <= end && cache.map.get( &(self.vh.idx(), first_block + i * bpe) ).map(|b| b.is_evicted()).unwrap_or(true))
+			.count()
+	}
+
 	/// Obtain a handle to a cached block.
-	/// NOTE: The returned handle will point to the start of the cache block, which may be larger than the disk block. Remember to check the returned block index.
+	/// NOTE: The returned handle will point to the start of the cache entry, which may be larger than the disk block. Remember to check the returned block index.
 	pub fn get_block(&self, block: u64) -> Result<CachedBlockHandle, IoError>
 	{
 		Ok( try!(self.get_block_meta(block)).into_ro() )
@@ -135,13 +399,9 @@ impl CacheHandle
 		let cached_block = try!(self.get_block(block));
 		let blk_ofs = (block - cached_block.index()) as usize * self.block_size();
 
-		if offset >= self.block_size() {
+		if offset >= self.block_size() || data.len() > self.block_size() - offset {
 			return Err(IoError::InvalidParameter);
 		}
-		if offset >= self.block_size() - blk_ofs {
-			return Err(IoError::InvalidParameter);
-		}
-		assert!(data.len() <= self.block_size() - offset);
 		let bytes = data.len();
 		data.clone_from_slice( &cached_block.data()[blk_ofs + offset .. ][ .. bytes] );
 		Ok( () )
@@ -152,17 +412,15 @@ impl CacheHandle
 		let cached_block = try!(self.get_block_meta(block));
 		let blk_ofs = (block - cached_block.index()) as usize * self.block_size();
 
-		if offset >= self.block_size() {
-			return Err(IoError::InvalidParameter);
-		}
-		if blk_ofs + offset >= self.block_size() {
+		if offset >= self.block_size() || data.len() > self.block_size() - offset {
 			return Err(IoError::InvalidParameter);
 		}
 
 		cached_block.edit(|block_data| {
-			block_data[blk_ofs + offset ..].clone_from_slice( data );
-			Ok( () )
-			})
+			block_data[blk_ofs + offset ..][.. data.len()].clone_from_slice( data );
+			});
+
+		Ok( () )
 	}
 	/// Edit block
 	pub fn edit<F: FnOnce(&mut [u8])->R,R>(&self, block: u64, count: usize, f: F) -> Result<R, IoError>
@@ -170,7 +428,7 @@ impl CacheHandle
 		let cached_block = try!(self.get_block_meta(block));
 		let blk_ofs = (block - cached_block.index()) as usize * self.block_size();
 
-		if (block - cached_block.index()) as usize + count > self.blocks_per_page() as usize {
+		if (block - cached_block.index()) as usize + count > self.blocks_per_entry() as usize {
 			return Err(IoError::InvalidParameter);
 		}
 
@@ -178,65 +436,256 @@ impl CacheHandle
 			f( &mut block_data[blk_ofs ..][ .. count * self.block_size()] )
 			});
 
-		try!(cached_block.0.flush(&self.vh));
-
 		Ok( rv )
 	}
 }
 
-fn map_cached_frame(frame: &::kernel::memory::phys::FrameHandle) -> ::kernel::memory::page_cache::CachedPage
+impl ::core::ops::Drop for CacheHandle
+{
+	fn drop(&mut self)
+	{
+		let _lh = S_FLUSH_LOCK.lock();
+		if let Err(e) = flush_volume(&self.vh, storage::IO_PRIO_DEFAULT) {
+			log_error!("Error flushing cache for {} - {:?}", self.vh.name(), e);
+		}
+		S_VOLUMES.lock_init(|| Default::default()).remove(&self.vh.idx());
+
+		// Release this volume's blocks (no handles can exist, as they borrow this handle)
+		let mut lh = S_BLOCK_CACHE.lock_init(|| Default::default());
+		let keys: Vec<_> = lh.map.iter().map(|(k,_)| *k).filter(|k| k.0 == self.vh.idx()).collect();
+		for k in keys {
+			lh.map.remove(&k);
+		}
+	}
+}
+
+/// Background worker, writes back dirty blocks
+fn flush_thread()
+{
+	let so = ::kernel::threads::SleepObject::new("Block Cache Flush");
+	*S_FLUSH_WAKE.lock() = Some(so.get_ref());
+	loop
+	{
+		// Sleep until a block is dirtied
+		// - Blocks modified while a flush is in progress signal again, so aren't missed
+		so.wait();
+
+		// Delay to batch up writes, unless enough blocks are dirty
+		let deadline = ::kernel::time::ticks() + FLUSH_DELAY;
+		match ::kernel::time::Timer::new(deadline, so.get_ref())
+		{
+		Some(_timer) => {
+			while ::kernel::time::ticks() < deadline && S_DIRTY_COUNT.load(Ordering::Relaxed) < FLUSH_THRESHOLD {
+				so.wait();
+			}
+			},
+		None => log_notice!("No kernel timer available, flushing immediately"),
+		}
+
+		let vols: Vec<Arc<VolumeHandle>> = S_VOLUMES.lock_init(|| Default::default()).iter().map(|(_,v)| v.clone()).collect();
+		for vh in vols
+		{
+			let _lh = S_FLUSH_LOCK.lock();
+			if let Err(e) = flush_volume(&vh, storage::IO_PRIO_BACKGROUND) {
+				log_error!("Error flushing cache for {} - {:?}", vh.name(), e);
+			}
+		}
+	}
+}
+
+/// Write back all dirty blocks for a volume (caller must hold `S_FLUSH_LOCK`)
+fn flush_volume(vh: &VolumeHandle, prio: u8) -> Result<(), IoError>
 {
-	// TODO: If this returns that there's no free mappings, go and steal one from within the cache
-	// - Or just do a GC pass, then try again.
-	::kernel::memory::page_cache::S_PAGE_CACHE.map(frame).expect("TODO: OOM in CachedBlock::borrow")
+	let dirty: Vec<u64> = S_BLOCK_CACHE.lock_init(|| Default::default()).map.iter()
+		.filter(|&(k,b)| k.0 == vh.idx() && b.is_dirty.load(Ordering::Relaxed))
+		.map(|(k,_)| k.1)
+		.collect();
+	if dirty.len() > 0 {
+		log_debug!("Flushing {} blocks on {}", dirty.len(), vh.name());
+	}
+	for blk in dirty
+	{
+		let handle = {
+			let lh = S_BLOCK_CACHE.lock_init(|| Default::default());
+			match lh.map.get( &(vh.idx(), blk) )
+			{
+			// SAFE: Same as `get_block_meta`, the box isn't freed while a borrow exists
+			Some(b) => unsafe { ::core::mem::transmute::<MetaBlockHandle, MetaBlockHandle>(try!(b.borrow(&lh))) },
+			None => continue,
+			}
+			};
+		try!(handle.0.flush(vh, prio));
+	}
+	Ok( () )
+}
+
+/// Page cache reclaim callback, releases the least recently used idle mapping
+fn reclaim_mappings() -> bool
+{
+	S_BLOCK_CACHE.lock_init(|| Default::default()).steal_mapping()
+}
+/// Physical allocator reclaim callback, releases the frames of the least recently used idle entry
+///
+/// The allocator can be called with the cache lock held, so this gives up instead of waiting for it.
+fn reclaim_frames() -> bool
+{
+	match S_BLOCK_CACHE.try_lock()
+	{
+	Some(mut lh) => lh.evict_block(),
+	None => false,
+	}
 }
 
 // --------------------------------------------------------------------
-impl CachedBlock
+// NOTE: All of these are called with the cache lock held, which serialises them against `CachedBlock::borrow`
+impl Cache
 {
-	fn new(vol: &VolumeHandle, first_block: u64) -> Result<CachedBlock, IoError>
+	/// Allocate a new entry of `page_count` pages, releasing mappings and entries as required
+	fn create_mapping(&mut self, page_count: usize) -> Result<CachedPage, IoError>
+	{
+		self.remove_evicted();
+		loop
+		{
+			if let Ok(v) = S_PAGE_CACHE.try_create_multiple(page_count) {
+				return Ok(v);
+			}
+			// Either out of mappings or out of memory, stealing mappings handles the former, dropping entries the latter
+			if !self.steal_mapping() && !self.evict_block() {
+				return Err( IoError::Unknown("Block cache exhausted") );
+			}
+		}
+	}
+	/// Map an existing entry's frames, releasing idle mappings as required
+	fn map_frames(&self, frames: &[::kernel::memory::phys::FrameHandle]) -> Result<CachedPage, IoError>
+	{
+		loop
+		{
+			if let Ok(v) = S_PAGE_CACHE.try_map_multiple(frames) {
+				return Ok(v);
+			}
+			if !self.steal_mapping() {
+				return Err( IoError::Unknown("Block cache mappings exhausted") );
+			}
+		}
+	}
+
+	/// Unmap the least recently used unreferenced entry (the data stays cached)
+	fn steal_mapping(&self) -> bool
+	{
+		let lru = self.map.iter()
+			.map(|(_,b)| b)
+			.filter(|b| b.reference_count.load(Ordering::Acquire) == 0 && b.mapping.read().is_some())
+			.min_by_key(|b| b.last_access.get());
+		match lru
+		{
+		Some(b) => {
+			*b.mapping.write() = None;
+			true
+			},
+		None => false,
+		}
+	}
+	/// Release the frames of the least recently used unreferenced clean entry (freeing its memory)
+	///
+	/// Dirty entries are left for the flush worker to clean, and mapped entries must have their mapping stolen first.
+	/// The emptied entry is left in the map, as this is called by the physical allocator (which can't free heap memory
+	/// or change non-temporary kernel mappings, releasing the frames only uses temporary mappings).
+	fn evict_block(&mut self) -> bool
+	{
+		let lru = self.map.iter()
+			.filter(|&(_,b)| b.reference_count.load(Ordering::Acquire) == 0 && !b.is_dirty.load(Ordering::Acquire))
+			.filter(|&(_,b)| !b.is_evicted() && b.mapping.read().is_none())
+			.min_by_key(|&(_,b)| b.last_access.get())
+			.map(|(k,_)| *k);
+		match lru
+		{
+		Some(k) => {
+			log_trace!("Evicting block {} of volume #{}", k.1, k.0);
+			// - `truncate` releases the frames without freeing the vector's buffer
+			self.map.get_mut(&k).expect("Block cache LRU entry missing").frames.truncate(0);
+			true
+			},
+		None => false,
+		}
+	}
+	/// Remove entries emptied by `evict_block`
+	fn remove_evicted(&mut self)
 	{
-		let mut mapping = try!(::kernel::memory::page_cache::S_PAGE_CACHE.create().map_err(|_| IoError::Unknown("OOM")));
+		let keys: Vec<_> = self.map.iter().filter(|&(_,b)| b.is_evicted()).map(|(k,_)| *k).collect();
+		for k in keys {
+			self.map.remove(&k);
+		}
+	}
+}
 
-		// TODO: Defer disk read until after the cache entry is created
-		try!( vol.read_blocks(first_block, mapping.data_mut()) );
-		
-		Ok(CachedBlock {
+impl CachedBlock
+{
+	/// Create an entry whose data is yet to be read (see `CacheHandle::load_entries`)
+	fn new_loading(first_block: u64, mapping: CachedPage) -> CachedBlock
+	{
+		CachedBlock {
 			index: first_block,
-			block_paddr: mapping.get_frame_handle(),
+			frames: (0 .. mapping.page_count()).map(|i| mapping.get_frame_handle_at(i)).collect(),
 			reference_count: AtomicUsize::new(0),
 
 			last_access: Default::default(),
 			is_dirty: AtomicBool::new(false),
+			loading: AtomicBool::new(true),
+			load_stale: AtomicBool::new(false),
+			load_failed: AtomicBool::new(false),
 			mapping: RwLock::new(Some(mapping)),
-			})
+			}
+	}
+	
+	/// Check if the entry's frames have been released by `Cache::evict_block`
+	fn is_evicted(&self) -> bool
+	{
+		self.frames.is_empty()
 	}
 	
 	/// Write a modified block back to disk
-	fn flush(&self, vol: &VolumeHandle) -> Result<(), IoError>
+	fn flush(&self, vol: &VolumeHandle, prio: u8) -> Result<(), IoError>
 	{
 		let lh = self.mapping.read();
 		if self.is_dirty.swap(false, Ordering::Acquire)
 		{
-			try!( vol.write_blocks(self.index, lh.as_ref().expect("CachedBlock::flush - None mapping").data()) );
+			S_DIRTY_COUNT.fetch_sub(1, Ordering::Relaxed);
+			// - If the write fails, the block is still dirty (and so mustn't be evicted)
+			if let Err(e) = vol.write_blocks_prio(prio, self.index, lh.as_ref().expect("CachedBlock::flush - None mapping").data()) {
+				if !self.is_dirty.swap(true, Ordering::Release) {
+					S_DIRTY_COUNT.fetch_add(1, Ordering::Relaxed);
+				}
+				return Err(e);
+			}
 		}
 		Ok( () )
 	}
 	
-	fn borrow(&self) -> MetaBlockHandle {
+	/// Overwrite part of the cached data without marking it as dirty (used when the disk has been written directly)
+	fn update(&self, cache: &Cache, ofs: usize, data: &[u8]) -> Result<(), IoError>
+	{
+		let _h = try!(self.borrow(cache));
+		let mut lh = self.mapping.write();
+		let block_data = lh.as_mut().expect("CachedBlock::update - None mapping").data_mut();
+		block_data[ofs ..][.. data.len()].clone_from_slice(data);
+		Ok( () )
+	}
 
-		if self.mapping.read().is_none()
+	/// Obtain a reference to the entry, mapping it if the mapping was stolen (caller holds the cache lock)
+	fn borrow(&self, cache: &Cache) -> Result<MetaBlockHandle, IoError> {
+		// - Loading entries are always mapped (and write-locked, so can't be checked here)
+		if !self.loading.load(Ordering::Acquire) && self.mapping.read().is_none()
 		{
 			let mut lh = self.mapping.write();
 			if lh.is_none() {
-				*lh = Some( map_cached_frame(&self.block_paddr) );
+				*lh = Some( try!(cache.map_frames(&self.frames)) );
 			}
 		}
 
 		self.reference_count.fetch_add(1, Ordering::Acquire);
 		self.last_access.bump();
 
-		MetaBlockHandle(self)
+		Ok( MetaBlockHandle(self) )
 	}
 }
 
@@ -246,10 +695,27 @@ impl<'a> MetaBlockHandle<'a>
 		self.0.index
 	}
 
+	/// Wait for the entry's data to be read (if it's still loading)
+	fn wait_loaded(&self) -> Result<(), IoError> {
+		if self.0.loading.load(Ordering::Acquire) {
+			// - The loader holds the mapping write-locked until it's done
+			drop(self.0.mapping.read());
+		}
+		if self.0.load_failed.load(Ordering::Acquire) {
+			return Err( IoError::Unknown("Reading block cache entry failed") );
+		}
+		Ok( () )
+	}
+
 	pub fn edit<F: FnOnce(&mut [u8])->R, R>(&self, f: F) -> R {
 		let mut lh = self.0.mapping.write();
 		let dataptr = lh.as_mut().expect("CachedBlock mapping is None").data_mut();
-		self.0.is_dirty.store(true, Ordering::Relaxed);
+		if ! self.0.is_dirty.swap(true, Ordering::Relaxed) {
+			S_DIRTY_COUNT.fetch_add(1, Ordering::Relaxed);
+			if let Some(ref r) = *S_FLUSH_WAKE.lock() {
+				r.signal();
+			}
+		}
 		f(dataptr)
 	}
 
@@ -263,15 +729,8 @@ impl<'a> ::core::ops::Drop for MetaBlockHandle<'a>
 {
 	fn drop(&mut self)
 	{
-		// TODO: Clearing this when the refcount reaches zero will lead to a lot of mapping/unmapping
-		// - Maybe keep a local queue or run a purge every now and then. Using a LRU list could work.
-		/*
-		if self.0.reference_count.fetch_sub(1, Ordering::Release) == 1
-		{
-			let mut wh = self.0.mapping.write();
-			*wh = None;
-		}
-		*/
+		// NOTE: The mapping is left in place once unreferenced, and is only released when stolen by `Cache::steal_mapping`
+		self.0.reference_count.fetch_sub(1, Ordering::Release);
 	}
 }
 
@@ -3,6 +3,7 @@
 //
 // Modules/fs_extN/dir.rs
 //! Directory handling
+use kernel::prelude::*;
 use kernel::vfs;
 use kernel::lib::byte_str::ByteStr;
 
@@ -21,89 +22,205 @@ impl Dir
 			inode: inode,
 			}
 	}
+}
 
+/// Returns (block_index, offset)
+fn find_name(dir: &::inodes::Inode, name: &ByteStr) -> vfs::node::Result<(usize, usize, vfs::node::InodeId)>
+{
+	if dir.is_indexed()
+	{
+		match ::htree::find_name(dir, name.as_ref())
+		{
+		Err(vfs::Error::InconsistentFilesystem) => {
+			log_warning!("Directory {}: Unusable hash index, falling back to linear search", dir.get_id());
+			},
+		rv @ _ => return rv,
+		}
+	}
 
-	/// Returns (block_index, offset)
-	fn find_name(&self, name: &ByteStr) -> vfs::node::Result<(usize, usize, vfs::node::InodeId)>
+	// Linear search
+	for (blk_index, vol_blk) in dir.blocks().enumerate()
 	{
-		// Linear search
-		// TODO: Later revisions have B+ trees
-		for (blk_index, vol_blk) in self.inode.blocks().enumerate()
+		let blk_data = try!(dir.fs.get_block(vol_blk));
+		if let Some( (offset, inode) ) = try!(find_in_block(&blk_data, name.as_ref()))
 		{
-			let blk_data = try!(self.inode.fs.get_block(vol_blk));
-			
-			let mut offset = 0;
-			for ent in DirEnts(&blk_data)
-			{
-				if ent.d_rec_len == 0 {
-					return Err( vfs::Error::InconsistentFilesystem );
-				}
-				else if ent.d_inode == 0 {
-					return Err(vfs::Error::NotFound);
-				}
-				else if &ent.d_name == name.as_ref()
-				{
-					return Ok( (blk_index, offset, ent.d_inode as vfs::node::InodeId) );
-				}
-				else {
-					offset += ent.u32_len() * 4;
-				}
-			}
+			return Ok( (blk_index, offset, inode) );
 		}
-		Err(vfs::Error::NotFound)
 	}
+	Err(vfs::Error::NotFound)
+}
 
 
-	fn find_free(&self, name: &ByteStr) -> vfs::node::Result<(u32, usize)>
+/// Add an entry to the directory, expanding the directory if there's no space in the existing blocks
+fn add_dir_ent(dir: &::inodes::Inode, name: &ByteStr, inode: u32, d_type: u8) -> vfs::node::Result<()>
+{
+	assert!(name.len() <= 255);
+	let name = name.as_ref();
+
+	if dir.is_indexed()
 	{
-		assert!(name.len() <= 255);
-		// Linear search
-		// TODO: Later revisions have B+ trees
-		for (blk_index, vol_blk) in self.inode.blocks().enumerate()
+		match ::htree::add_entry(dir, name, inode, d_type)
 		{
-			let blk_data = try!(self.inode.fs.get_block(vol_blk));
-			
-			let mut offset = 0;
-			for ent in DirEnts(&blk_data)
+		Ok(true) => return Ok( () ),
+		Ok(false) => {},
+		Err(vfs::Error::InconsistentFilesystem) => {},
+		Err(e) => return Err(e),
+		}
+		// The index can't be updated (e.g. it's full), so drop it. The directory is still valid as a linear directory.
+		log_notice!("Directory {}: Hash index can't be updated, converting to a linear directory", dir.get_id());
+		try!(dir.clear_flags(::ondisk::EXT4_INDEX_FL));
+	}
+
+	// 1. Find either an unused entry, or an entry with enough slack space to split
+	// Linear search
+	for vol_blk in dir.blocks()
+	{
+		if try!(dir.fs.edit_block(vol_blk, |blk_data| insert_in_block(blk_data, name, inode, d_type))) {
+			return Ok( () );
+		}
+	}
+
+	// 2. No space, expand the directory by a block (containing a single entry spanning the entire block)
+	let (vol_blk, _) = try!(append_block(dir));
+	dir.fs.edit_block(vol_blk, |blk_data| {
+		::ondisk::DirEnt::write(blk_data, inode, (blk_data.len() * 4) as u16, d_type, name);
+		Ok( () )
+		})
+}
+
+/// Remove the entry at the specified location (as returned by `find_name`)
+fn remove_dir_ent(dir: &::inodes::Inode, blk_idx: usize, ofs: usize) -> vfs::node::Result<()>
+{
+	let vol_blk = try!( dir.blocks_from(blk_idx as u32).next_or_err() );
+	dir.fs.edit_block(vol_blk, |blk_data| {
+		// Locate the previous entry in the block
+		let mut prev = None;
+		let mut cur = 0;
+		while cur < ofs
+		{
+			match ::ondisk::DirEnt::new(&blk_data[cur/4 ..])
 			{
-				if ent.d_rec_len == 0 {
-					return Err( vfs::Error::InconsistentFilesystem );
-				}
-				else if ent.d_inode == 0 && (ent.d_rec_len - 8) <= name.len() as u16
-				{
-					// Free entry with sufficient space!
-					return Ok( (blk_index as u32, offset) );
-				}
-				else {
-					offset += ent.u32_len() * 4;
-				}
+			Some(ent) if (ent.d_rec_len as usize) >= ::ondisk::DIRENT_MIN_SIZE => {
+				prev = Some(cur);
+				cur += ent.d_rec_len as usize;
+				},
+			_ => return Err( vfs::Error::InconsistentFilesystem ),
 			}
 		}
+		if cur != ofs {
+			return Err( vfs::Error::InconsistentFilesystem );
+		}
+		let rec_len = match ::ondisk::DirEnt::new(&blk_data[ofs/4 ..])
+			{
+			Some(ent) => ent.d_rec_len,
+			None => return Err( vfs::Error::InconsistentFilesystem ),
+			};
 
-		todo!("Dir::find_free - expand directory");
+		match prev
+		{
+		// Merge the space into the previous entry
+		Some(prev) => ::ondisk::DirEnt::new_mut(&mut blk_data[prev/4 ..]).unwrap().d_rec_len += rec_len,
+		// First entry in the block, just mark as unused
+		None => ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..]).unwrap().d_inode = 0,
+		}
+		Ok( () )
+		})
+}
+
+/// Locate a name within a directory block, returning the offset of the entry and the inode number
+pub fn find_in_block(blk_data: &[u32], name: &[u8]) -> vfs::node::Result<Option<(usize, vfs::node::InodeId)>>
+{
+	let mut offset = 0;
+	for ent in DirEnts(blk_data)
+	{
+		if ent.d_rec_len == 0 {
+			return Err( vfs::Error::InconsistentFilesystem );
+		}
+		else if ent.d_inode != 0 && &ent.d_name == name
+		{
+			return Ok( Some( (offset, ent.d_inode as vfs::node::InodeId) ) );
+		}
+		offset += ent.u32_len() * 4;
 	}
+	Ok( None )
+}
 
-	fn add_dir_ent(&self, name: &ByteStr, inode: u32) -> Result<(), vfs::Error>
+/// Insert an entry into a directory block, using either an unused entry or the slack space after an entry
+///
+/// Returns false if there was no space
+pub fn insert_in_block(blk_data: &mut [u32], name: &[u8], inode: u32, d_type: u8) -> vfs::node::Result<bool>
+{
+	let required = ::ondisk::DirEnt::size_for_name(name.len());
+	let mut offset = 0;
+	while offset < blk_data.len() * 4
 	{
-		// 1. Find a suitable slot
-		let (blk, ofs) = try!(self.find_free(name));
-		// 2. Fill said slot
-		let vol_blk = try!( self.inode.blocks_from(blk as u32).next_or_err() );
-		self.inode.fs.edit_block(vol_blk, |blk_data| {
-				match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
-				{
-				None => return Err(vfs::Error::InconsistentFilesystem),
-				Some(ent) => {
-					ent.d_name_len = name.len() as u8;
-					ent.d_inode = inode as u32;
-					},
-				}
-				// - Now that name length is longer, update the name
-				::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..]).unwrap()
-					.d_name.clone_from_slice( name.as_ref() );
-				Ok( () )
-				})
+		let (ent_inode, rec_len, used) = match ::ondisk::DirEnt::new(&blk_data[offset/4 ..])
+			{
+			None => return Err( vfs::Error::InconsistentFilesystem ),
+			Some(ent) if (ent.d_rec_len as usize) < ::ondisk::DIRENT_MIN_SIZE => return Err( vfs::Error::InconsistentFilesystem ),
+			Some(ent) => (ent.d_inode, ent.d_rec_len as usize, ::ondisk::DirEnt::size_for_name(ent.d_name.len())),
+			};
+		if ent_inode == 0 && rec_len >= required
+		{
+			// Free entry with sufficient space!
+			::ondisk::DirEnt::write(&mut blk_data[offset/4 ..], inode, rec_len as u16, d_type, name);
+			return Ok(true);
+		}
+		else if ent_inode != 0 && rec_len >= used + required
+		{
+			// Split the unused tail off this entry
+			::ondisk::DirEnt::new_mut(&mut blk_data[offset/4 ..]).unwrap().d_rec_len = used as u16;
+			::ondisk::DirEnt::write(&mut blk_data[(offset + used)/4 ..], inode, (rec_len - used) as u16, d_type, name);
+			return Ok(true);
+		}
+		offset += rec_len;
+	}
+	Ok(false)
+}
+
+/// Add a new (zeroed) block to the end of a directory, returning (volume_block, block_index)
+pub fn append_block(inode: &::inodes::Inode) -> vfs::node::Result<(u64, u32)>
+{
+	let bs = inode.fs.fs_block_size;
+	let blk_idx = inode.max_blocks();
+	let (vol_blk, _) = try!(inode.get_or_allocate_block(blk_idx));
+	try!(inode.fs.write_blocks(vol_blk, &vec![0u8; bs]));
+	try!(inode.set_size( (blk_idx as u64 + 1) * bs as u64 ));
+	Ok( (vol_blk, blk_idx) )
+}
+
+/// Populate a newly allocated directory with `.` and `..`
+fn init_dir(inode: &::inodes::Inode, parent: u32) -> vfs::node::Result<()>
+{
+	let bs = inode.fs.fs_block_size;
+	let (vol_blk, _) = try!(inode.get_or_allocate_block(0));
+	let mut blk_data = vec![0u32; bs / 4];
+	let dot_len = ::ondisk::DirEnt::size_for_name(1);
+	::ondisk::DirEnt::write(&mut blk_data, inode.get_id() as u32, dot_len as u16, ::ondisk::FT_DIR, b".");
+	::ondisk::DirEnt::write(&mut blk_data[dot_len/4 ..], parent, (bs - dot_len) as u16, ::ondisk::FT_DIR, b"..");
+	try!(inode.fs.write_blocks(vol_blk, ::kernel::lib::as_byte_slice(&blk_data[..])));
+	try!(inode.set_size(bs as u64));
+	// `.` refers to the directory itself
+	inode.inc_link_count()
+}
+
+/// Check if a directory contains only `.` and `..`
+fn dir_is_empty(inode: &::inodes::Inode) -> vfs::node::Result<bool>
+{
+	for vol_blk in inode.blocks()
+	{
+		let blk_data = try!(inode.fs.get_block(vol_blk));
+		for ent in DirEnts(&blk_data)
+		{
+			if ent.d_rec_len == 0 {
+				return Err( vfs::Error::InconsistentFilesystem );
+			}
+			else if ent.d_inode != 0 && &ent.d_name != b"." && &ent.d_name != b".." {
+				return Ok(false);
+			}
+		}
 	}
+	Ok(true)
 }
 
 impl vfs::node::NodeBase for Dir
@@ -111,8 +228,11 @@ impl vfs::node::NodeBase for Dir
 	fn get_id(&self) -> vfs::node::InodeId {
 		self.inode.get_id()
 	}
+	fn get_info(&self) -> vfs::node::Result<vfs::node::NodeInfo> {
+		Ok( self.inode.get_info() )
+	}
 	fn get_any(&self) -> &::core::any::Any {
-		self
+		&self.inode
 	}
 }
 impl vfs::node::Dir for Dir
@@ -123,13 +243,15 @@ impl vfs::node::Dir for Dir
 			Err(vfs::Error::NotFound)
 		}
 		else {
-			let (_, _, rv) = try!(self.find_name(name));
+			let _lh = self.inode.read_lock();
+			let (_, _, rv) = try!(find_name(&self.inode, name));
 			Ok( rv )
 		}
 	}
 	fn read(&self, start_ofs: usize, callback: &mut vfs::node::ReadDirCallback) -> vfs::Result<usize>
 	{
 		log_trace!("read(start_ofs={}, ...)", start_ofs);
+		let _lh = self.inode.read_lock();
 		let (blk_idx, ofs) = ::kernel::lib::num::div_rem(start_ofs, self.inode.fs.fs_block_size);
 		let mut blk_ofs = start_ofs - ofs;
 
@@ -201,26 +323,53 @@ impl vfs::node::Dir for Dir
 		{
 			Err( vfs::Error::ReadOnlyFilesystem )
 		}
+		else if name == ""
+		{
+			Err( vfs::Error::InvalidParameter )
+		}
+		else if name.len() > 255
+		{
+			Err( vfs::Error::Unknown("Filename too long") )
+		}
 		else
 		{
 			let _lh = self.inode.write_lock();
 
-			let ino_id = try!( self.inode.fs.allocate_inode(self.inode.get_id() as u32, nodetype) );
-			match self.add_dir_ent(name, ino_id)
+			match find_name(&self.inode, name)
 			{
-			Ok(()) => Ok(ino_id as vfs::node::InodeId),
-			Err(e) => {
-				// Call with_inode to force the inode to be deallocated
-				let _ = self.inode.fs.with_inode(ino_id, |_| Ok(()));
-				Err(e)
-				},
+			Ok(_) => return Err( vfs::Error::AlreadyExists ),
+			Err(vfs::Error::NotFound) => {},
+			Err(e) => return Err(e),
 			}
+
+			let is_dir = match nodetype { vfs::node::NodeType::Dir => true, _ => false };
+			let d_type = if is_dir { ::ondisk::FT_DIR } else { ::ondisk::FT_REG_FILE };
+			let parent_id = self.inode.get_id() as u32;
+
+			let ino_id = try!( self.inode.fs.allocate_inode(parent_id, nodetype) );
+			if let Err(e) = add_dir_ent(&self.inode, name, ino_id, d_type)
+			{
+				// The inode was never linked (or loaded), so can be released immediately
+				let _ = self.inode.fs.free_inode(ino_id, is_dir);
+				return Err(e);
+			}
+			try!(self.inode.fs.with_inode(ino_id, |ino| {
+				if is_dir {
+					try!(init_dir(ino, parent_id));
+				}
+				ino.inc_link_count()
+				}));
+			if is_dir {
+				// The new directory's `..` refers to this directory
+				try!(self.inode.inc_link_count());
+			}
+			Ok(ino_id as vfs::node::InodeId)
 		}
 	}
 	fn link(&self, name: &ByteStr, node: &vfs::node::NodeBase) -> vfs::node::Result<()> {
 		if self.inode.fs.is_readonly()
 		{
-			Err( vfs::Error::ReadOnlyFilesystem )
+			Err(vfs::Error::ReadOnlyFilesystem)
 		}
 		else if name == ""
 		{
@@ -232,18 +381,33 @@ impl vfs::node::Dir for Dir
 		}
 		else
 		{
+			// The node must be an inode on this filesystem
+			let inode: &::inodes::Inode = match node.get_any().downcast_ref()
+				{
+				Some(v) => v,
+				None => return Err(vfs::Error::Unknown("Can't link across filesystems")),
+				};
+			if &*inode.fs as *const _ != &*self.inode.fs as *const _ {
+				return Err(vfs::Error::Unknown("Can't link across filesystems"));
+			}
+			let d_type = match inode.i_mode_fmt()
+				{
+				::ondisk::S_IFREG => ::ondisk::FT_REG_FILE,
+				::ondisk::S_IFLNK => ::ondisk::FT_SYMLINK,
+				::ondisk::S_IFDIR => return Err(vfs::Error::Unknown("Can't hard link directories")),
+				_ => ::ondisk::FT_UNKNOWN,
+				};
+
 			let _lh = self.inode.write_lock();
+			match find_name(&self.inode, name)
+			{
+			Ok(_) => return Err( vfs::Error::AlreadyExists ),
+			Err(vfs::Error::NotFound) => {},
+			Err(e) => return Err(e),
+			}
 
-			// TODO: How can I be sure that the passed inode number is valid? (or that it stays valid)
-			let inode = node.get_id();
-			try!(self.add_dir_ent(name, inode as u32));
-			// 3. Update inode's link count
-			try!(self.inode.fs.with_inode(inode as u32, |ino| {
-				ino.inc_link_count();
-				Ok( () )
-				}));
-			
-			todo!("link(name={:?}, inode={:?})", name, inode);
+			try!(add_dir_ent(&self.inode, name, inode.get_id() as u32, d_type));
+			inode.inc_link_count()
 		}
 	}
 	fn unlink(&self, name: &ByteStr) -> vfs::node::Result<()> {
@@ -251,7 +415,7 @@ impl vfs::node::Dir for Dir
 		{
 			Err( vfs::Error::ReadOnlyFilesystem )
 		}
-		else if name == ""
+		else if name == "" || name == "." || name == ".."
 		{
 			Err( vfs::Error::InvalidParameter )
 		}
@@ -259,32 +423,127 @@ impl vfs::node::Dir for Dir
 		{
 			let _lh = self.inode.write_lock();
 
-			let (blk, ofs, _) = try!(self.find_name(name));
+			let (blk, ofs, ino_id) = try!(find_name(&self.inode, name));
+			let ino_id = ino_id as u32;
+
+			let is_dir = try!(self.inode.fs.with_inode(ino_id, |ino| {
+				if ino.i_mode_fmt() != ::ondisk::S_IFDIR {
+					Ok(false)
+				}
+				else if ! try!(dir_is_empty(ino)) {
+					Err( vfs::Error::Unknown("Directory not empty") )
+				}
+				else {
+					Ok(true)
+				}
+				}));
+
+			try!(remove_dir_ent(&self.inode, blk, ofs));
 
-			let vol_blk = try!( self.inode.blocks_from(blk as u32).next_or_err() );
+			// Decrement inode's reference count (the inode is released once the count hits zero and it's no longer in use)
+			try!(self.inode.fs.with_inode(ino_id, |ino| {
+				try!(ino.dec_link_count());
+				if is_dir {
+					// - Directories also reference themselves with `.`
+					try!(ino.dec_link_count());
+				}
+				Ok( () )
+				}));
+			if is_dir {
+				// The removed directory's `..` no longer references this directory
+				try!(self.inode.dec_link_count());
+			}
+			Ok( () )
+		}
+	}
+	fn rename(&self, src_name: &ByteStr, dst_dir: &vfs::node::Dir, dst_name: &ByteStr) -> vfs::node::Result<()> {
+		if self.inode.fs.is_readonly()
+		{
+			return Err( vfs::Error::ReadOnlyFilesystem );
+		}
+		if src_name == "" || src_name == "." || src_name == ".." || dst_name == "" || dst_name == "." || dst_name == ".."
+		{
+			return Err( vfs::Error::InvalidParameter );
+		}
+		if dst_name.len() > 255
+		{
+			return Err( vfs::Error::Unknown("Filename too long") );
+		}
+		let dst: &::inodes::Inode = match dst_dir.get_any().downcast_ref()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::CrossFilesystem),
+			};
+		if &*dst.fs as *const _ != &*self.inode.fs as *const _ {
+			return Err(vfs::Error::CrossFilesystem);
+		}
+		let same_dir = dst.get_id() == self.inode.get_id();
 
-			self.inode.fs.edit_block(vol_blk, |blk_data| {
-				match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
-				{
-				None => return Err(vfs::Error::InconsistentFilesystem),
-				Some(ent) => {
-					// Clear name length
-					ent.d_name_len = 0;
-					// Decrement inode's reference count
-					try!(self.inode.fs.with_inode(ent.d_inode, |ino| {
-						ino.dec_link_count();
+		// Lock the directories in inode order, so a concurrent rename in the other direction can't deadlock
+		let _lh = if same_dir {
+				(self.inode.write_lock(), None)
+			}
+			else if self.inode.get_id() < dst.get_id() {
+				let lh = self.inode.write_lock();
+				(lh, Some(dst.write_lock()))
+			}
+			else {
+				let dst_lh = dst.write_lock();
+				(self.inode.write_lock(), Some(dst_lh))
+			};
+
+		let (_, _, ino_id) = try!(find_name(&self.inode, src_name));
+		let ino_id = ino_id as u32;
+		if same_dir && src_name == dst_name {
+			return Ok( () );
+		}
+		match find_name(dst, dst_name)
+		{
+		Ok(_) => return Err( vfs::Error::AlreadyExists ),
+		Err(vfs::Error::NotFound) => {},
+		Err(e) => return Err(e),
+		}
+
+		let (d_type, is_dir) = try!(self.inode.fs.with_inode(ino_id, |ino| Ok(match ino.i_mode_fmt()
+			{
+			::ondisk::S_IFREG => (::ondisk::FT_REG_FILE, false),
+			::ondisk::S_IFLNK => (::ondisk::FT_SYMLINK, false),
+			::ondisk::S_IFDIR => (::ondisk::FT_DIR, true),
+			_ => (::ondisk::FT_UNKNOWN, false),
+			})));
+
+		// Add the new name before removing the old one, so the node is never unreachable
+		try!(add_dir_ent(dst, dst_name, ino_id, d_type));
+		// - Adding can move entries (when an index block is split), so look the old name up again
+		let (blk, ofs, _) = try!(find_name(&self.inode, src_name));
+		try!(remove_dir_ent(&self.inode, blk, ofs));
+
+		if is_dir && !same_dir
+		{
+			// Point the moved directory's `..` at its new parent, and move that link
+			let dst_id = dst.get_id() as u32;
+			try!(self.inode.fs.with_inode(ino_id, |ino| {
+				let vol_blk = try!(ino.blocks().next_or_err());
+				ino.fs.edit_block(vol_blk, |blk_data| {
+					match try!(find_in_block(blk_data, b".."))
+					{
+					Some( (ofs, _) ) => {
+						::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..]).unwrap().d_inode = dst_id;
 						Ok( () )
-						}));
-					Ok( () )
-					},
-				}
-				})
+						},
+					None => Err( vfs::Error::InconsistentFilesystem ),
+					}
+					})
+				}));
+			try!(self.inode.dec_link_count());
+			try!(dst.inc_link_count());
 		}
+		Ok( () )
 	}
 }
 
 
-struct DirEnts<'a>(&'a [u32]);
+pub struct DirEnts<'a>(pub &'a [u32]);
 
 impl<'a> Iterator for DirEnts<'a>
 {
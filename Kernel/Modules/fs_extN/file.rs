@@ -3,6 +3,7 @@
 //
 // Modules/fs_extN/file.rs
 //! Regular file
+use kernel::prelude::*;
 use kernel::vfs;
 
 pub struct File
@@ -23,6 +24,98 @@ impl File
 	fn fs_block_size(&self) -> usize {
 		self.inode.fs.fs_block_size
 	}
+
+	/// Read a single block (uncached), handling sparse blocks
+	fn read_block(&self, blk: u64) -> vfs::node::Result<Box<[u32]>>
+	{
+		if blk == 0 {
+			Ok( vec![0u32; self.fs_block_size() / 4].into_boxed_slice() )
+		}
+		else {
+			self.inode.fs.get_block_uncached(blk)
+		}
+	}
+
+	/// Write data (or zeroes if `data` is None) to the file, allocating blocks as required
+	///
+	/// NOTE: Caller must hold the inode write lock, and update the size if the write extends the file
+	fn write_data(&self, ofs: u64, len: u64, data: Option<&[u8]>) -> vfs::node::Result<()>
+	{
+		let bs = self.fs_block_size();
+		let (blk_idx, blk_ofs) = ::kernel::lib::num::div_rem(ofs, bs as u64);
+		if blk_idx + ::kernel::lib::num::div_up(blk_ofs + len, bs as u64) > ::core::u32::MAX as u64 {
+			return Err( vfs::Error::Unknown("File too large") );
+		}
+		let mut blk_idx = blk_idx as u32;
+		let mut written: u64 = 0;
+
+		// 1. Leading partial
+		let blk_ofs = blk_ofs as usize;
+		if blk_ofs > 0
+		{
+			let count = ::core::cmp::min( (bs - blk_ofs) as u64, len ) as usize;
+			try!(self.write_partial(blk_idx, blk_ofs, count, data.map(|d| &d[..count])));
+			written += count as u64;
+			blk_idx += 1;
+		}
+
+		// 2. Inner (merging physically contiguous blocks into a single write)
+		let zero_block = if data.is_none() { vec![0u8; bs] } else { Vec::new() };
+		while len - written >= bs as u64
+		{
+			let (first_blk, _) = try!(self.inode.get_or_allocate_block(blk_idx));
+			let mut count = 1;
+			blk_idx += 1;
+			if let Some(d) = data
+			{
+				let max_count = (len - written) / bs as u64;
+				while count < max_count
+				{
+					let (blk, _) = try!(self.inode.get_or_allocate_block(blk_idx));
+					if blk != first_blk + count {
+						break;
+					}
+					count += 1;
+					blk_idx += 1;
+				}
+				let byte_count = count as usize * bs;
+				try!(self.inode.fs.write_blocks(first_blk, &d[written as usize ..][.. byte_count]));
+			}
+			else
+			{
+				try!(self.inode.fs.write_blocks(first_blk, &zero_block));
+			}
+			written += count * bs as u64;
+		}
+
+		// 3. Trailing partial
+		let trailing_bytes = (len - written) as usize;
+		if trailing_bytes > 0
+		{
+			try!(self.write_partial(blk_idx, 0, trailing_bytes, data.map(|d| &d[written as usize ..])));
+		}
+		Ok( () )
+	}
+	/// Read-modify-write a single block
+	fn write_partial(&self, blk_idx: u32, ofs: usize, len: usize, data: Option<&[u8]>) -> vfs::node::Result<()>
+	{
+		let (blk, is_new) = try!(self.inode.get_or_allocate_block(blk_idx));
+		let mut blk_data = if is_new {
+				vec![0u32; self.fs_block_size() / 4].into_boxed_slice()
+			}
+			else {
+				try!(self.inode.fs.get_block_uncached(blk))
+			};
+		{
+			let dst = &mut ::kernel::lib::as_byte_slice_mut(&mut blk_data[..])[ofs ..][.. len];
+			match data
+			{
+			Some(d) => dst.clone_from_slice(d),
+			None => for b in dst { *b = 0; },
+			}
+		}
+		self.inode.fs.write_blocks(blk, ::kernel::lib::as_byte_slice(&blk_data[..]))
+	}
 }
 
 impl vfs::node::NodeBase for File
@@ -30,8 +123,11 @@ impl vfs::node::NodeBase for File
 	fn get_id(&self) -> vfs::node::InodeId {
 		self.inode.get_id()
 	}
+	fn get_info(&self) -> vfs::node::Result<vfs::node::NodeInfo> {
+		Ok( self.inode.get_info() )
+	}
 	fn get_any(&self) -> &::core::any::Any {
-		self
+		&self.inode
 	}
 }
 impl vfs::node::File for File
@@ -41,6 +137,7 @@ impl vfs::node::File for File
 	}
 	fn read(&self, ofs: u64, buf: &mut [u8]) -> vfs::node::Result<usize>
 	{
+		let _lh = self.inode.read_lock();
 		if ofs > self.inode.i_size() {
 			return Err(vfs::Error::InvalidParameter);
 		}
@@ -70,16 +167,17 @@ impl vfs::node::File for File
 		{
 			let partial_bytes = self.fs_block_size() - blk_ofs;
 			
-			let blk_data = try!(self.inode.fs.get_block_uncached( try!(blocks.next_or_err()) ));
+			let blk_data = try!(self.read_block( try!(blocks.next_or_err()) ));
 			let blk_data = ::kernel::lib::as_byte_slice(&blk_data[..]);
 			if buf.len() <= partial_bytes
 			{
-				buf.clone_from_slice( blk_data );
-				read_bytes += buf.len();
+				let len = buf.len();
+				buf.clone_from_slice( &blk_data[blk_ofs ..][.. len] );
+				read_bytes += len;
 			}
 			else
 			{
-				buf[..partial_bytes].clone_from_slice(blk_data);
+				buf[..partial_bytes].clone_from_slice( &blk_data[blk_ofs ..] );
 				read_bytes += partial_bytes;
 			}
 		}
@@ -91,7 +189,15 @@ impl vfs::node::File for File
 			let remain_blocks = (buf.len() - read_bytes)/self.fs_block_size();
 			let (blkid, count) = try!(blocks.next_extent_or_err( remain_blocks as u32 ));
 			let byte_count = count as usize * self.fs_block_size();
-			try!(self.inode.fs.read_blocks(blkid, &mut buf[read_bytes ..][.. byte_count]));
+			if blkid == 0 {
+				// Sparse region
+				for b in &mut buf[read_bytes ..][.. byte_count] {
+					*b = 0;
+				}
+			}
+			else {
+				try!(self.inode.fs.read_blocks(blkid, &mut buf[read_bytes ..][.. byte_count]));
+			}
 			read_bytes += byte_count;
 		}
 
@@ -99,9 +205,10 @@ impl vfs::node::File for File
 		//log_trace!("remain {} (tail)", buf.len() - read_bytes);
 		if buf.len() - read_bytes > 0
 		{
-			let blk_data = try!(self.inode.fs.get_block_uncached( try!(blocks.next_or_err()) ));
+			let blk_data = try!(self.read_block( try!(blocks.next_or_err()) ));
 			let blk_data = ::kernel::lib::as_byte_slice(&blk_data[..]);
-			buf[read_bytes..].clone_from_slice(&blk_data);
+			let len = buf.len() - read_bytes;
+			buf[read_bytes..].clone_from_slice(&blk_data[.. len]);
 			read_bytes = buf.len();
 		}
 
@@ -110,21 +217,30 @@ impl vfs::node::File for File
 	}
 
 	fn truncate(&self, newsize: u64) -> vfs::node::Result<u64> {
-		if newsize == 0
+		if self.inode.fs.is_readonly()
 		{
-			todo!("truncate - 0");
+			return Err( vfs::Error::ReadOnlyFilesystem );
 		}
-		else if newsize == self.inode.i_size()
+		let _lh = self.inode.write_lock();
+		let size = self.inode.i_size();
+		if newsize == size
 		{
 			Ok( newsize )
 		}
-		else if newsize < self.inode.i_size()
+		else if newsize < size
 		{
-			todo!("truncate - shrink");
+			// Release all blocks past the new end (a size of zero releases everything)
+			let first_unused = ::kernel::lib::num::div_up(newsize, self.fs_block_size() as u64);
+			try!(self.inode.set_size(newsize));
+			try!(self.inode.free_blocks_from(first_unused as u32));
+			Ok( newsize )
 		}
 		else
 		{
-			todo!("truncate - grow");
+			// Zero the tail of the current last block, and allocate zeroed blocks for the rest
+			try!(self.write_data(size, newsize - size, None));
+			try!(self.inode.set_size(newsize));
+			Ok( newsize )
 		}
 	}
 	fn clear(&self, ofs: u64, size: u64) -> vfs::node::Result<()> {
@@ -136,55 +252,32 @@ impl vfs::node::File for File
 			Err( vfs::Error::InvalidParameter )
 		}
 		else {
-			// 1. Leading partial
-			// 2. Inner
-			// 3. Trailing partial
-			todo!("clear");
+			let _lh = self.inode.write_lock();
+			self.write_data(ofs, size, None)
 		}
 	}
 	fn write(&self, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
 		if self.inode.fs.is_readonly()
 		{
-			Err( vfs::Error::ReadOnlyFilesystem )
+			return Err( vfs::Error::ReadOnlyFilesystem );
 		}
-		else if ofs == self.inode.i_size()
+		// NOTE: Writes are serialised by the inode lock, but the VFS itself handles the file "borrow checking".
+		//       A file race is the userland's problem (if a SharedRW handle is used)
+		let _lh = self.inode.write_lock();
+		let size = self.inode.i_size();
+		if ofs > size
 		{
-			todo!("write - extend");
-		}
-		else if ofs > self.inode.i_size() || buf.len() as u64 > self.inode.i_size() || ofs + buf.len() as u64 > self.inode.i_size() {
 			Err( vfs::Error::InvalidParameter )
 		}
-		else {
-			// NOTE: In this section, we're free to read-modify-write blocks without fear, as the VFS itself handles
-			//       the file "borrow checking". A file race is the userland's problem (if a SharedRW handle is used)
-			let (blk_idx, blk_ofs) = ::kernel::lib::num::div_rem(ofs, self.fs_block_size() as u64);
-			let mut blocks = self.inode.blocks_from(blk_idx as u32);
-			let mut written = 0;
-			// 1. Leading partial
-			let blk_ofs = blk_ofs as usize;
-			if blk_ofs > 0
-			{
-				todo!("write - mutate - leading partial {}", blk_ofs);
-				//written += blk_ofs;
-			}
-			// 2. Inner
-			while buf.len() - written >= self.fs_block_size()
-			{
-				let remain_blocks = (buf.len() - written)/self.fs_block_size();
-				let (blkid, count) = try!(blocks.next_extent_or_err( remain_blocks as u32 ));
-				let byte_count = count as usize * self.fs_block_size();
-				try!(self.inode.fs.write_blocks(blkid, &buf[written ..][.. byte_count]));
-				written += byte_count;
-			}
-			// 3. Trailing partial
-			let trailing_bytes = buf.len() - written;
-			if trailing_bytes > 0
-			{
-				todo!("write - mutate - trailing partial {}", trailing_bytes);
+		else
+		{
+			// Writes at (or crossing) the end of the file extend it
+			let end = ofs + buf.len() as u64;
+			try!(self.write_data(ofs, buf.len() as u64, Some(buf)));
+			if end > size {
+				try!(self.inode.set_size(end));
 			}
-
-			Ok( written )
+			Ok( buf.len() )
 		}
 	}
 }
-
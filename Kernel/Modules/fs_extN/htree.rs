@@ -0,0 +1,598 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/htree.rs
+//! Hashed directory indexes (HTree)
+//!
+//! Index blocks look like unused directory entries spanning the entire block, so an indexed directory is still a
+//! valid linear directory (the index just has to be kept in sync when entries are added).
+use kernel::prelude::*;
+use kernel::vfs;
+use inodes::Inode;
+
+/// Upper bits of an index entry's block number are reserved
+const DX_BLOCK_MASK: u32 = 0x0FFFFFFF;
+/// Offset (in words) of the entries in an interior node (following an empty directory entry)
+const DX_NODE_ENTS: usize = 8 / 4;
+/// Offset (in words) of the `DxRootInfo` structure in the first block (following `.` and `..`)
+const DX_ROOT_INFO: usize = 24 / 4;
+/// Maximum supported number of interior levels
+const DX_MAX_LEVELS: usize = 2;
+
+/// A node visited while walking the index
+struct Frame
+{
+	/// Index of the block within the directory
+	blk_idx: u32,
+	data: Vec<u32>,
+	/// Offset (in words) of the entry array, the first entry's hash is replaced by the limit and count
+	ents: usize,
+	/// Selected entry
+	pos: usize,
+}
+impl Frame
+{
+	fn limit(&self) -> usize {
+		(self.data[self.ents] & 0xFFFF) as usize
+	}
+	fn count(&self) -> usize {
+		(self.data[self.ents] >> 16) as usize
+	}
+	fn set_count(&mut self, count: usize) {
+		self.data[self.ents] = (self.data[self.ents] & 0xFFFF) | (count as u32) << 16;
+	}
+	/// Lowest hash covered by an entry (the first entry covers everything below the second)
+	fn hash(&self, idx: usize) -> u32 {
+		if idx == 0 { 0 } else { self.data[self.ents + idx * 2] }
+	}
+	fn block(&self, idx: usize) -> u32 {
+		self.data[self.ents + idx * 2 + 1] & DX_BLOCK_MASK
+	}
+
+	/// Insert a new entry (shifting the following entries up)
+	fn insert(&mut self, idx: usize, hash: u32, block: u32) {
+		let count = self.count();
+		assert!(idx > 0 && idx <= count && count < self.limit());
+		let mut i = count;
+		while i > idx
+		{
+			self.data[self.ents + i * 2] = self.data[self.ents + (i - 1) * 2];
+			self.data[self.ents + i * 2 + 1] = self.data[self.ents + (i - 1) * 2 + 1];
+			i -= 1;
+		}
+		self.data[self.ents + idx * 2] = hash;
+		self.data[self.ents + idx * 2 + 1] = block;
+		self.set_count(count + 1);
+	}
+}
+
+/// Path from the root of the index to a leaf
+struct Index
+{
+	hasher: Hasher,
+	frames: Vec<Frame>,
+}
+impl Index
+{
+	fn leaf(&self) -> u32 {
+		let f = self.frames.last().unwrap();
+		f.block(f.pos)
+	}
+}
+
+/// Look up a name using the directory's index
+///
+/// Returns (block_index, offset, inode), or `InconsistentFilesystem` if the index can't be used
+pub fn find_name(dir: &Inode, name: &[u8]) -> vfs::node::Result<(usize, usize, vfs::node::InodeId)>
+{
+	let (mut idx, hash) = try!(probe(dir, name));
+	loop
+	{
+		let leaf = idx.leaf();
+		let data = try!(read_block(dir, leaf));
+		if let Some( (ofs, inode) ) = try!(::dir::find_in_block(&data, name))
+		{
+			return Ok( (leaf as usize, ofs, inode) );
+		}
+		// Entries with colliding hashes can spill into the following leaves
+		if ! try!(next_leaf(dir, &mut idx, hash))
+		{
+			return Err(vfs::Error::NotFound);
+		}
+	}
+}
+
+/// Add an entry to an indexed directory
+///
+/// Returns `false` if the index can't accommodate the new entry (and should be discarded by the caller)
+pub fn add_entry(dir: &Inode, name: &[u8], inode: u32, d_type: u8) -> vfs::node::Result<bool>
+{
+	let (mut idx, hash) = try!(probe(dir, name));
+
+	// 1. Attempt to insert into the existing leaf
+	let leaf = idx.leaf();
+	let leaf_addr = try!(block_addr(dir, leaf));
+	if try!(dir.fs.edit_block(leaf_addr, |data| ::dir::insert_in_block(data, name, inode, d_type)))
+	{
+		return Ok(true);
+	}
+
+	// 2. Leaf is full, so it needs to be split (which requires a free slot in the index)
+	let is_full = {
+		let f = idx.frames.last().unwrap();
+		f.count() >= f.limit()
+		};
+	if is_full
+	{
+		if ! try!(make_index_space(dir, &mut idx)) {
+			return Ok(false);
+		}
+		// The index has been restructured, start again
+		return add_entry(dir, name, inode, d_type);
+	}
+
+	let (split_hash, new_leaf) = try!(split_leaf(dir, &idx.hasher, leaf));
+	{
+		let f = idx.frames.last_mut().unwrap();
+		let pos = f.pos;
+		f.insert(pos + 1, split_hash, new_leaf);
+		try!(write_block(dir, f.blk_idx, &f.data));
+	}
+
+	// 3. Insert into the correct half
+	let target = if hash >= split_hash { new_leaf } else { leaf };
+	let target_addr = try!(block_addr(dir, target));
+	if try!(dir.fs.edit_block(target_addr, |data| ::dir::insert_in_block(data, name, inode, d_type)))
+	{
+		Ok(true)
+	}
+	else
+	{
+		Err(vfs::Error::Unknown("Directory leaf full after split"))
+	}
+}
+
+/// Read the index root and walk down to the leaf covering `name`
+fn probe(dir: &Inode, name: &[u8]) -> vfs::node::Result<(Index, u32)>
+{
+	let root = try!(read_block(dir, 0));
+	if root.len() < DX_ROOT_INFO + 4 {
+		return Err(vfs::Error::InconsistentFilesystem);
+	}
+	let (hash_version, info_length, levels) = {
+		let info = ::ondisk::DxRootInfo::from_slice(&root[DX_ROOT_INFO ..][.. 2]);
+		(info.hash_version, info.info_length as usize, info.indirect_levels as usize)
+		};
+	if info_length != 8 || levels > DX_MAX_LEVELS {
+		log_warning!("Directory {}: Unsupported index (info_length={}, levels={})", dir.get_id(), info_length, levels);
+		return Err(vfs::Error::InconsistentFilesystem);
+	}
+	let hasher = try!(Hasher::new(dir, hash_version));
+	let hash = hasher.hash(name);
+
+	let mut rv = Index {
+		hasher: hasher,
+		frames: Vec::with_capacity(levels + 1),
+		};
+	let mut frame = Frame {
+		blk_idx: 0,
+		data: root,
+		ents: DX_ROOT_INFO + info_length / 4,
+		pos: 0,
+		};
+	loop
+	{
+		let count = frame.count();
+		if count == 0 || count > frame.limit() || frame.ents + frame.limit() * 2 > frame.data.len() {
+			log_warning!("Directory {}: Bad index node in block {} (count={}, limit={})", dir.get_id(), frame.blk_idx, count, frame.limit());
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		// Binary search for the last entry with a hash at or below the target
+		let (mut lo, mut hi) = (1, count);
+		while lo < hi
+		{
+			let mid = (lo + hi) / 2;
+			if frame.hash(mid) > hash {
+				hi = mid;
+			}
+			else {
+				lo = mid + 1;
+			}
+		}
+		frame.pos = lo - 1;
+
+		let next = frame.block(frame.pos);
+		rv.frames.push(frame);
+		if rv.frames.len() > levels {
+			break;
+		}
+		frame = Frame {
+			blk_idx: next,
+			data: try!(read_block(dir, next)),
+			ents: DX_NODE_ENTS,
+			pos: 0,
+			};
+	}
+	Ok( (rv, hash) )
+}
+
+/// Advance to the next leaf if it continues the current hash value
+fn next_leaf(dir: &Inode, idx: &mut Index, hash: u32) -> vfs::node::Result<bool>
+{
+	// Locate the deepest level with a following entry
+	let mut level = idx.frames.len();
+	loop
+	{
+		if level == 0 {
+			return Ok(false);
+		}
+		level -= 1;
+		if idx.frames[level].pos + 1 < idx.frames[level].count() {
+			break;
+		}
+	}
+	idx.frames[level].pos += 1;
+	// The low bit of the hash flags a continuation of the previous range
+	let next_hash = idx.frames[level].hash(idx.frames[level].pos);
+	if next_hash & !1 != hash {
+		return Ok(false);
+	}
+	// Descend to the first leaf under this entry
+	for l in level + 1 .. idx.frames.len()
+	{
+		let blk = idx.frames[l - 1].block(idx.frames[l - 1].pos);
+		idx.frames[l] = Frame {
+			blk_idx: blk,
+			data: try!(read_block(dir, blk)),
+			ents: DX_NODE_ENTS,
+			pos: 0,
+			};
+	}
+	Ok(true)
+}
+
+/// Live entry from a leaf being split
+struct LeafEnt
+{
+	hash: u32,
+	inode: u32,
+	d_type: u8,
+	name: Vec<u8>,
+}
+
+/// Split a full leaf, moving the upper half (by hash) to a new block
+///
+/// Returns the hash to index the new block with, and the new block's index
+fn split_leaf(dir: &Inode, hasher: &Hasher, leaf: u32) -> vfs::node::Result<(u32, u32)>
+{
+	let bs = dir.fs.fs_block_size;
+	let leaf_addr = try!(block_addr(dir, leaf));
+
+	let mut ents = Vec::new();
+	{
+		let data = try!(dir.fs.get_block(leaf_addr));
+		for ent in ::dir::DirEnts(&data)
+		{
+			if ent.d_rec_len == 0 {
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			if ent.d_inode != 0 {
+				ents.push(LeafEnt {
+					hash: hasher.hash(&ent.d_name),
+					inode: ent.d_inode,
+					d_type: ent.d_type,
+					name: From::from(&ent.d_name[..]),
+					});
+			}
+		}
+	}
+	if ents.len() < 2 {
+		return Err(vfs::Error::Unknown("Directory leaf full with less than two entries"));
+	}
+	// Insertion sort by hash (leaves are small)
+	for i in 1 .. ents.len()
+	{
+		let mut j = i;
+		while j > 0 && ents[j - 1].hash > ents[j].hash
+		{
+			ents.swap(j - 1, j);
+			j -= 1;
+		}
+	}
+
+	let mid = ents.len() / 2;
+	let split_hash = ents[mid].hash;
+	// If the hash spans both blocks, flag the new block as a continuation
+	let split_hash = if ents[mid - 1].hash == split_hash { split_hash | 1 } else { split_hash };
+
+	let (new_addr, new_leaf) = try!(::dir::append_block(dir));
+	try!(dir.fs.write_blocks(new_addr, ::kernel::lib::as_byte_slice(&pack_entries(bs, &ents[mid ..])[..])));
+	try!(dir.fs.write_blocks(leaf_addr, ::kernel::lib::as_byte_slice(&pack_entries(bs, &ents[.. mid])[..])));
+
+	Ok( (split_hash, new_leaf) )
+}
+
+/// Build a directory block from a list of entries (the last entry covers the rest of the block)
+fn pack_entries(bs: usize, ents: &[LeafEnt]) -> Vec<u32>
+{
+	let mut data = vec![0u32; bs / 4];
+	let mut ofs = 0;
+	for (i, e) in ents.iter().enumerate()
+	{
+		let len = if i == ents.len() - 1 { bs - ofs } else { ::ondisk::DirEnt::size_for_name(e.name.len()) };
+		::ondisk::DirEnt::write(&mut data[ofs / 4 ..], e.inode, len as u16, e.d_type, &e.name);
+		ofs += len;
+	}
+	data
+}
+
+/// Create space in the lowest level of the index
+///
+/// Returns false if the index is at its maximum size
+fn make_index_space(dir: &Inode, idx: &mut Index) -> vfs::node::Result<bool>
+{
+	let bs = dir.fs.fs_block_size;
+	let node_limit = ((bs - DX_NODE_ENTS * 4) / 8) as u32;
+
+	if idx.frames.len() == 1
+	{
+		// Root is full: Move its entries into a new node, and add a level
+		let (new_addr, new_idx) = try!(::dir::append_block(dir));
+		let root = &mut idx.frames[0];
+		let count = root.count();
+
+		let mut node = vec![0u32; bs / 4];
+		::ondisk::DirEnt::write(&mut node, 0, bs as u16, 0, b"");
+		node[DX_NODE_ENTS] = node_limit | (count as u32) << 16;
+		node[DX_NODE_ENTS + 1 .. DX_NODE_ENTS + count * 2].clone_from_slice( &root.data[root.ents + 1 .. root.ents + count * 2] );
+		try!(dir.fs.write_blocks(new_addr, ::kernel::lib::as_byte_slice(&node[..])));
+
+		root.set_count(1);
+		root.data[root.ents + 1] = new_idx;
+		// Set `indirect_levels` to 1
+		root.data[DX_ROOT_INFO + 1] = (root.data[DX_ROOT_INFO + 1] & !0x00FF0000) | 1 << 16;
+		try!(write_block(dir, 0, &root.data));
+		Ok(true)
+	}
+	else if idx.frames.len() == 2 && idx.frames[0].count() < idx.frames[0].limit()
+	{
+		// Interior node is full: Split it in half, and add the upper half to the root
+		let (new_addr, new_idx) = try!(::dir::append_block(dir));
+		let split_hash = {
+			let node = &mut idx.frames[1];
+			let count = node.count();
+			let mid = count / 2;
+
+			let mut new_node = vec![0u32; bs / 4];
+			::ondisk::DirEnt::write(&mut new_node, 0, bs as u16, 0, b"");
+			new_node[DX_NODE_ENTS] = node_limit | ((count - mid) as u32) << 16;
+			new_node[DX_NODE_ENTS + 1] = node.block(mid);
+			new_node[DX_NODE_ENTS + 2 .. DX_NODE_ENTS + (count - mid) * 2].clone_from_slice( &node.data[node.ents + (mid + 1) * 2 .. node.ents + count * 2] );
+			try!(dir.fs.write_blocks(new_addr, ::kernel::lib::as_byte_slice(&new_node[..])));
+
+			node.set_count(mid);
+			try!(write_block(dir, node.blk_idx, &node.data));
+			node.hash(mid)
+			};
+
+		let root = &mut idx.frames[0];
+		let pos = root.pos;
+		root.insert(pos + 1, split_hash, new_idx);
+		try!(write_block(dir, 0, &root.data));
+		Ok(true)
+	}
+	else
+	{
+		Ok(false)
+	}
+}
+
+fn block_addr(dir: &Inode, blk_idx: u32) -> vfs::node::Result<u64>
+{
+	if blk_idx >= dir.max_blocks() {
+		return Err(vfs::Error::InconsistentFilesystem);
+	}
+	match try!(dir.get_block_addr(blk_idx))
+	{
+	0 => Err(vfs::Error::InconsistentFilesystem),
+	v => Ok(v),
+	}
+}
+fn read_block(dir: &Inode, blk_idx: u32) -> vfs::node::Result<Vec<u32>>
+{
+	let addr = try!(block_addr(dir, blk_idx));
+	Ok( try!(dir.fs.get_block(addr))[..].to_owned() )
+}
+fn write_block(dir: &Inode, blk_idx: u32, data: &[u32]) -> vfs::node::Result<()>
+{
+	let addr = try!(block_addr(dir, blk_idx));
+	dir.fs.write_blocks(addr, ::kernel::lib::as_byte_slice(data))
+}
+
+/// Directory name hashing
+struct Hasher
+{
+	version: u8,
+	seed: [u32; 4],
+}
+impl Hasher
+{
+	fn new(dir: &Inode, root_version: u8) -> vfs::node::Result<Hasher>
+	{
+		use ondisk::{DX_HASH_TEA, DX_HASH_TEA_UNSIGNED};
+		if root_version > DX_HASH_TEA_UNSIGNED {
+			log_warning!("Directory {}: Unknown hash version {}", dir.get_id(), root_version);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		// The signedness of the hash depends on the platform that created the filesystem (recorded in the superblock)
+		let version = if root_version <= DX_HASH_TEA && dir.fs.htree_unsigned_hash() {
+				root_version + 3
+			}
+			else {
+				root_version
+			};
+		Ok(Hasher {
+			version: version,
+			seed: dir.fs.htree_hash_seed(),
+			})
+	}
+
+	/// Calculate the major hash of a name (the low bit is reserved for continuation flags)
+	fn hash(&self, name: &[u8]) -> u32
+	{
+		use ondisk::*;
+		let mut buf = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+		if self.seed.iter().any(|&v| v != 0) {
+			buf = self.seed;
+		}
+		let is_unsigned = self.version >= DX_HASH_LEGACY_UNSIGNED;
+
+		let hash = match self.version
+			{
+			DX_HASH_LEGACY | DX_HASH_LEGACY_UNSIGNED => dx_hack_hash(name, is_unsigned),
+			DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+				let mut input = [0u32; 8];
+				let mut ofs = 0;
+				while ofs < name.len()
+				{
+					str2hashbuf(&name[ofs..], &mut input, is_unsigned);
+					half_md4_transform(&mut buf, &input);
+					ofs += 32;
+				}
+				buf[1]
+				},
+			DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+				let mut input = [0u32; 4];
+				let mut ofs = 0;
+				while ofs < name.len()
+				{
+					str2hashbuf(&name[ofs..], &mut input, is_unsigned);
+					tea_transform(&mut buf, &input);
+					ofs += 16;
+				}
+				buf[0]
+				},
+			_ => unreachable!(),
+			};
+		let hash = hash & !1;
+		// The maximum value is reserved as an end-of-directory marker
+		if hash == 0x7FFFFFFF << 1 {
+			(0x7FFFFFFF - 1) << 1
+		}
+		else {
+			hash
+		}
+	}
+}
+
+fn char_val(c: u8, is_unsigned: bool) -> u32 {
+	if is_unsigned { c as u32 } else { c as i8 as i32 as u32 }
+}
+
+/// Original hash used by ext3
+fn dx_hack_hash(name: &[u8], is_unsigned: bool) -> u32
+{
+	let mut hash0: u32 = 0x12a3fe2d;
+	let mut hash1: u32 = 0x37abe8f9;
+	for &c in name
+	{
+		let mut hash = hash1.wrapping_add( hash0 ^ char_val(c, is_unsigned).wrapping_mul(7152373) );
+		if hash & 0x80000000 != 0 {
+			hash = hash.wrapping_sub(0x7fffffff);
+		}
+		hash1 = hash0;
+		hash0 = hash;
+	}
+	hash0 << 1
+}
+
+/// Pack the start of a name into words (padded using the remaining name length)
+fn str2hashbuf(msg: &[u8], out: &mut [u32], is_unsigned: bool)
+{
+	let mut pad = msg.len() as u32 | (msg.len() as u32) << 8;
+	pad |= pad << 16;
+
+	let mut val = pad;
+	let mut o = 0;
+	for (i, &c) in msg.iter().take(out.len() * 4).enumerate()
+	{
+		val = char_val(c, is_unsigned).wrapping_add(val << 8);
+		if i % 4 == 3 {
+			out[o] = val;
+			o += 1;
+			val = pad;
+		}
+	}
+	if o < out.len() {
+		out[o] = val;
+		o += 1;
+	}
+	for v in &mut out[o..] {
+		*v = pad;
+	}
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4])
+{
+	let (mut b0, mut b1) = (buf[0], buf[1]);
+	let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+	let mut sum: u32 = 0;
+	for _ in 0 .. 16
+	{
+		sum = sum.wrapping_add(0x9E3779B9);
+		b0 = b0.wrapping_add( (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b) );
+		b1 = b1.wrapping_add( (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d) );
+	}
+	buf[0] = buf[0].wrapping_add(b0);
+	buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// Cut-down MD4 (three rounds of eight steps)
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8])
+{
+	fn f(x: u32, y: u32, z: u32) -> u32 { z ^ (x & (y ^ z)) }
+	fn g(x: u32, y: u32, z: u32) -> u32 { (x & y).wrapping_add((x ^ y) & z) }
+	fn h(x: u32, y: u32, z: u32) -> u32 { x ^ y ^ z }
+	macro_rules! round {
+		($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+			$a = $a.wrapping_add( $f($b, $c, $d) ).wrapping_add($x).rotate_left($s);
+			};
+	}
+	const K1: u32 = 0;
+	const K2: u32 = 0x5A827999;
+	const K3: u32 = 0x6ED9EBA1;
+
+	let (mut a, mut b, mut c, mut d) = (buf[0], buf[1], buf[2], buf[3]);
+
+	round!(f, a, b, c, d, input[0].wrapping_add(K1),  3);
+	round!(f, d, a, b, c, input[1].wrapping_add(K1),  7);
+	round!(f, c, d, a, b, input[2].wrapping_add(K1), 11);
+	round!(f, b, c, d, a, input[3].wrapping_add(K1), 19);
+	round!(f, a, b, c, d, input[4].wrapping_add(K1),  3);
+	round!(f, d, a, b, c, input[5].wrapping_add(K1),  7);
+	round!(f, c, d, a, b, input[6].wrapping_add(K1), 11);
+	round!(f, b, c, d, a, input[7].wrapping_add(K1), 19);
+
+	round!(g, a, b, c, d, input[1].wrapping_add(K2),  3);
+	round!(g, d, a, b, c, input[3].wrapping_add(K2),  5);
+	round!(g, c, d, a, b, input[5].wrapping_add(K2),  9);
+	round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+	round!(g, a, b, c, d, input[0].wrapping_add(K2),  3);
+	round!(g, d, a, b, c, input[2].wrapping_add(K2),  5);
+	round!(g, c, d, a, b, input[4].wrapping_add(K2),  9);
+	round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+	round!(h, a, b, c, d, input[3].wrapping_add(K3),  3);
+	round!(h, d, a, b, c, input[7].wrapping_add(K3),  9);
+	round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+	round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+	round!(h, a, b, c, d, input[1].wrapping_add(K3),  3);
+	round!(h, d, a, b, c, input[5].wrapping_add(K3),  9);
+	round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+	round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+	buf[0] = buf[0].wrapping_add(a);
+	buf[1] = buf[1].wrapping_add(b);
+	buf[2] = buf[2].wrapping_add(c);
+	buf[3] = buf[3].wrapping_add(d);
+}
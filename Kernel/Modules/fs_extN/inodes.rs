@@ -4,17 +4,25 @@
 //! 
 use instance::InstancePtr;
 use kernel::vfs;
+use kernel::prelude::*;
+use kernel::sync::RwLock;
 use core::sync::atomic::{AtomicBool,Ordering};
 
 pub struct Inode
 {
 	pub fs: InstancePtr,
 	inode_idx: u32,
-	ondisk: ::ondisk::Inode,
+	ondisk: RwLock<::ondisk::Inode>,
 
 	is_dirty: AtomicBool,
+	/// Lock held by directory/file operations that modify the inode's contents
+	lock: RwLock<()>,
 }
 
+const SI_BLOCK: usize = 12;
+const DI_BLOCK: usize = 13;
+const TI_BLOCK: usize = 14;
+
 impl Inode
 {
 	pub fn from_id(fs: InstancePtr, id: u32) -> vfs::Result<Inode>
@@ -23,16 +31,34 @@ impl Inode
 		Ok(Inode {
 			fs: fs,
 			inode_idx: id,
-			ondisk: od,
+			ondisk: RwLock::new(od),
 			is_dirty: AtomicBool::new(false),
+			lock: RwLock::new( () ),
 			})
 	}
 
-	pub fn dec_link_count(&self) {
-		todo!("Inode::dec_link_count");
+	pub fn dec_link_count(&self) -> vfs::Result<()> {
+		{
+			let mut od = self.ondisk.write();
+			if od.i_links_count == 0 {
+				log_warning!("Inode {}: Link count decremented below zero", self.inode_idx);
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			od.i_links_count -= 1;
+		}
+		self.is_dirty.store(true, Ordering::Relaxed);
+		self.flush()
 	}
-	pub fn inc_link_count(&self) {
-		todo!("Inode::inc_link_count");
+	pub fn inc_link_count(&self) -> vfs::Result<()> {
+		{
+			let mut od = self.ondisk.write();
+			if od.i_links_count == ::core::u16::MAX {
+				return Err(vfs::Error::Unknown("Too many links to inode"));
+			}
+			od.i_links_count += 1;
+		}
+		self.is_dirty.store(true, Ordering::Relaxed);
+		self.flush()
 	}
 
 
@@ -40,7 +66,8 @@ impl Inode
 	{
 		if self.is_dirty.swap(false, Ordering::Relaxed)
 		{
-			try!(self.fs.write_inode(self.inode_idx, &self.ondisk));
+			let od = *self.ondisk.read();
+			try!(self.fs.write_inode(self.inode_idx, &od));
 		}
 		Ok( () )
 	}
@@ -50,7 +77,24 @@ impl Drop for Inode
 {
 	fn drop(&mut self)
 	{
-		if self.is_dirty.load(Ordering::Relaxed)
+		let (links, fmt) = {
+			let od = self.ondisk.read();
+			(od.i_links_count, od.i_mode & ::ondisk::S_IFMT)
+			};
+		if links == 0 && fmt != 0 && !self.fs.is_readonly()
+		{
+			log_debug!("Inode::drop - Releasing unlinked inode {}", self.inode_idx);
+			if let Err(e) = self.free_blocks_from(0) {
+				log_error!("Inode::drop - Error releasing blocks of inode {}: {:?}", self.inode_idx, e);
+			}
+			self.ondisk.write().i_mode = 0;
+			self.is_dirty.store(true, Ordering::Relaxed);
+			let _ = self.flush();
+			if let Err(e) = self.fs.free_inode(self.inode_idx, fmt == ::ondisk::S_IFDIR) {
+				log_error!("Inode::drop - Error releasing inode {}: {:?}", self.inode_idx, e);
+			}
+		}
+		else if self.is_dirty.load(Ordering::Relaxed)
 		{
 			log_warning!("Inode::drop - Dirty node being dropped, writing back and ignoring errors");
 			let _ = self.flush();
@@ -61,10 +105,69 @@ impl Drop for Inode
 impl Inode
 {
 	pub fn i_mode_fmt(&self) -> u16 {
-		self.ondisk.i_mode & ::ondisk::S_IFMT
+		self.ondisk.read().i_mode & ::ondisk::S_IFMT
 	}
 	pub fn i_size(&self) -> u64 {
-		self.ondisk.i_size as u64
+		let od = self.ondisk.read();
+		// `i_dir_acl` holds the upper 32 bits of the size for regular files
+		if od.i_mode & ::ondisk::S_IFMT == ::ondisk::S_IFREG {
+			(od.i_dir_acl as u64) << 32 | od.i_size as u64
+		}
+		else {
+			od.i_size as u64
+		}
+	}
+	/// Returns true if the inode's data is mapped using an extent tree
+	pub fn uses_extents(&self) -> bool {
+		self.ondisk.read().i_flags & ::ondisk::EXT4_EXTENTS_FL != 0
+	}
+	/// Returns true if the directory has a hashed index (HTree)
+	pub fn is_indexed(&self) -> bool {
+		self.ondisk.read().i_flags & ::ondisk::EXT4_INDEX_FL != 0
+	}
+	pub fn clear_flags(&self, mask: u32) -> vfs::Result<()> {
+		self.ondisk.write().i_flags &= !mask;
+		self.is_dirty.store(true, Ordering::Relaxed);
+		self.flush()
+	}
+	pub fn i_links_count(&self) -> u16 {
+		self.ondisk.read().i_links_count
+	}
+	/// Obtain the VFS metadata for this inode
+	pub fn get_info(&self) -> vfs::node::NodeInfo {
+		let size = self.i_size();
+		let od = self.ondisk.read();
+		// The upper 16 bits of the owner IDs are stored in the OS-dependent area (`l_i_uid_high`/`l_i_gid_high`)
+		let ids_hi = od._osd2[1];
+		vfs::node::NodeInfo {
+			size: size,
+			mtime: od.i_mtime as i32 as ::kernel::time::Timestamp,
+			ctime: od.i_ctime as i32 as ::kernel::time::Timestamp,
+			atime: od.i_atime as i32 as ::kernel::time::Timestamp,
+			mode: (od.i_mode & !::ondisk::S_IFMT) as u32,
+			uid: od.i_uid as u32 | (ids_hi & 0xFFFF) << 16,
+			gid: od.i_gid as u32 | (ids_hi >> 16) << 16,
+			}
+	}
+
+	/// Update the file size (caller is responsible for allocating/releasing blocks)
+	pub fn set_size(&self, size: u64) -> vfs::Result<()> {
+		{
+			let mut od = self.ondisk.write();
+			if od.i_mode & ::ondisk::S_IFMT == ::ondisk::S_IFREG {
+				// Sizes of 2GB and over require FEAT_RO_COMPAT_LARGE_FILE
+				if size >= 1 << 31 && !self.fs.has_large_files() {
+					return Err(vfs::Error::Unknown("File too large"));
+				}
+				od.i_dir_acl = (size >> 32) as u32;
+			}
+			else if size > ::core::u32::MAX as u64 {
+				return Err(vfs::Error::Unknown("File too large"));
+			}
+			od.i_size = size as u32;
+		}
+		self.is_dirty.store(true, Ordering::Relaxed);
+		self.flush()
 	}
 }
 
@@ -86,29 +189,121 @@ impl Inode
 
 impl Inode
 {
+	pub fn read_lock(&self) -> ::kernel::sync::rwlock::Read<()> {
+		self.lock.read()
+	}
 	pub fn write_lock(&self) -> ::kernel::sync::rwlock::Write<()> {
-		todo!("Inode::write_lock");
+		self.lock.write()
+	}
+
+	fn u32_per_fs_block(&self) -> u32 {
+		(self.fs.fs_block_size / ::core::mem::size_of::<u32>()) as u32
+	}
+
+	/// Obtain the filesystem block for `block_idx` and the number of contiguous blocks following it (up to `max_blocks`)
+	///
+	/// A zero block number indicates a sparse region
+	pub fn get_extent_from_block(&self, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u64, u32)>
+	{
+		if self.uses_extents()
+		{
+			let (fs_start, count) = try!(self.extent_lookup(block_idx));
+			Ok( (fs_start, ::core::cmp::min(count, max_blocks)) )
+		}
+		else
+		{
+			let (fs_start, count) = try!(self.get_extent_from_block_map(block_idx, max_blocks));
+			Ok( (fs_start as u64, count) )
+		}
 	}
 
-	pub fn get_extent_from_block(&self, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)>
+	/// Look up a block in the extent tree, returning the filesystem block and the number of blocks remaining in the extent
+	///
+	/// Sparse (and uninitialised) regions return a zero block number
+	fn extent_lookup(&self, block_idx: u32) -> vfs::node::Result<(u64, u32)>
 	{
-		let u32_per_fs_block = (self.fs.fs_block_size / ::core::mem::size_of::<u32>()) as u32;
+		// Copy of the current tree node (starting with the root stored in the inode)
+		let mut node: Vec<u32> = self.ondisk.read().i_block[..].to_owned();
+		// Limit the depth, to avoid looping forever on a corrupted tree
+		for _ in 0 .. 8
+		{
+			let child = {
+				let (depth, n_ents) = {
+					let hdr = ::ondisk::ExtentHeader::from_slice(&node[..3]);
+					if hdr.eh_magic != ::ondisk::EXT4_EXTENT_MAGIC {
+						log_warning!("Inode {}: Bad extent header magic {:#x}", self.inode_idx, hdr.eh_magic);
+						return Err(vfs::Error::InconsistentFilesystem);
+					}
+					(hdr.eh_depth, hdr.eh_entries as usize)
+					};
+				if 3 + n_ents * 3 > node.len() {
+					return Err(vfs::Error::InconsistentFilesystem);
+				}
+				let ents = node[3 ..][.. n_ents * 3].chunks(3);
+
+				if depth == 0
+				{
+					// Leaf node, entries are sorted by starting block
+					for ent in ents
+					{
+						let ent = ::ondisk::Extent::from_slice(ent);
+						if block_idx < ent.ee_block {
+							// Sparse region before this extent
+							return Ok( (0, ent.ee_block - block_idx) );
+						}
+						let (len, is_init) = ent.len();
+						if block_idx - ent.ee_block < len {
+							let ofs = block_idx - ent.ee_block;
+							return Ok( (if is_init { ent.start() + ofs as u64 } else { 0 }, len - ofs) );
+						}
+					}
+					// Sparse until the end of the file
+					return Ok( (0, !0 - block_idx) );
+				}
+				else
+				{
+					// Interior node, use the last index that starts at or before the block
+					let mut child = None;
+					for ent in ents
+					{
+						let ent = ::ondisk::ExtentIdx::from_slice(ent);
+						if ent.ei_block > block_idx {
+							if child.is_none() {
+								return Ok( (0, ent.ei_block - block_idx) );
+							}
+							break ;
+						}
+						child = Some(ent.leaf());
+					}
+					match child
+					{
+					Some(v) => v,
+					None => return Ok( (0, !0 - block_idx) ),
+					}
+				}
+				};
+			node = try!(self.fs.get_block(child))[..].to_owned();
+		}
+		log_warning!("Inode {}: Extent tree too deep", self.inode_idx);
+		Err(vfs::Error::InconsistentFilesystem)
+	}
+
+	fn get_extent_from_block_map(&self, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)>
+	{
+		let u32_per_fs_block = self.u32_per_fs_block();
+		let i_block = self.ondisk.read().i_block;
 
-		const SI_BLOCK: usize = 12;
-		const DI_BLOCK: usize = 13;
-		const TI_BLOCK: usize = 14;
-		
 		let si_base = SI_BLOCK as u32;
 		let di_base = si_base + u32_per_fs_block;
 		let ti_base = di_base + u32_per_fs_block*u32_per_fs_block;
 
 		if block_idx < si_base
 		{
-			let fs_start = self.ondisk.i_block[block_idx as usize];
+			let fs_start = i_block[block_idx as usize];
 			let max_blocks = ::core::cmp::min( si_base - block_idx, max_blocks );
 			for num in 1 .. max_blocks
 			{
-				if fs_start + num != self.ondisk.i_block[(block_idx + num) as usize] {
+				if fs_start + num != i_block[(block_idx + num) as usize] {
 					return Ok( (fs_start, num) );
 				}
 			}
@@ -118,7 +313,7 @@ impl Inode
 		{
 			let idx = block_idx - si_base;
 			// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
-			let si_block = try!( self.fs.get_block( self.ondisk.i_block[SI_BLOCK] ) );
+			let si_block = try!( self.fs.get_block( i_block[SI_BLOCK] as u64 ) );
 			
 			let fs_start = si_block[idx as usize];
 			let max_blocks = ::core::cmp::min( di_base - block_idx, max_blocks );
@@ -134,8 +329,8 @@ impl Inode
 		{
 			let idx = block_idx - di_base;
 			let (blk, idx) = (idx / u32_per_fs_block, idx % u32_per_fs_block);
-			let di_block = try!( self.fs.get_block( self.ondisk.i_block[DI_BLOCK] ) );
-			let di_block = try!( self.fs.get_block( di_block[blk as usize] ) );
+			let di_block = try!( self.fs.get_block( i_block[DI_BLOCK] as u64 ) );
+			let di_block = try!( self.fs.get_block( di_block[blk as usize] as u64 ) );
 
 
 			let fs_start = di_block[idx as usize];
@@ -154,9 +349,9 @@ impl Inode
 			let idx = block_idx - ti_base;
 			let (blk, idx) = (idx / u32_per_fs_block, idx % u32_per_fs_block);
 			let (blk_o, blk_i) = (blk / u32_per_fs_block, blk % u32_per_fs_block);
-			let ti_block = try!( self.fs.get_block( self.ondisk.i_block[TI_BLOCK] ) );
-			let ti_block = try!( self.fs.get_block( ti_block[blk_o as usize] ) );
-			let ti_block = try!( self.fs.get_block( ti_block[blk_i as usize] ) );
+			let ti_block = try!( self.fs.get_block( i_block[TI_BLOCK] as u64 ) );
+			let ti_block = try!( self.fs.get_block( ti_block[blk_o as usize] as u64 ) );
+			let ti_block = try!( self.fs.get_block( ti_block[blk_i as usize] as u64 ) );
 
 
 			let fs_start = ti_block[idx as usize];
@@ -171,51 +366,377 @@ impl Inode
 		}
 	}
 
-	pub fn get_block_addr(&self, block_idx: u32) -> vfs::node::Result<u32>
+	/// Split a block index into the root pointer index and the indexes within each level of indirect block
+	///
+	/// Returns (root_index, path, levels)
+	fn get_block_path(&self, block_idx: u32) -> (usize, [u32; 3], usize)
 	{
-		let u32_per_fs_block = (self.fs.fs_block_size / ::core::mem::size_of::<u32>()) as u32;
+		let u32_per_fs_block = self.u32_per_fs_block();
 
-		let si_base = 12;
-		let di_base = 12 + u32_per_fs_block ;
-		let ti_base = 12 + u32_per_fs_block + u32_per_fs_block*u32_per_fs_block;
+		let si_base = SI_BLOCK as u32;
+		let di_base = si_base + u32_per_fs_block;
+		let ti_base = di_base + u32_per_fs_block*u32_per_fs_block;
 
 		if block_idx < si_base
 		{
-			// Direct block
-			Ok( self.ondisk.i_block[block_idx as usize] )
+			(block_idx as usize, [0; 3], 0)
 		}
 		else if block_idx < di_base
 		{
-			// Single-indirect block
-			let idx = block_idx - si_base;
-			// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
-			let si_block = try!( self.fs.get_block( self.ondisk.i_block[12] ) );
-			Ok( si_block[ idx as usize ] )
+			(SI_BLOCK, [block_idx - si_base, 0, 0], 1)
 		}
 		else if block_idx < ti_base
 		{
-			// Double-indirect block
 			let idx = block_idx - di_base;
-			let (blk, idx) = (idx / u32_per_fs_block, idx % u32_per_fs_block);
-			let di_block = try!( self.fs.get_block( self.ondisk.i_block[13] ) );
-			let di_block = try!( self.fs.get_block( di_block[blk as usize] ) );
-			Ok( di_block[idx as usize] )
+			(DI_BLOCK, [idx / u32_per_fs_block, idx % u32_per_fs_block, 0], 2)
 		}
 		else
 		{
-			// Triple-indirect block
 			let idx = block_idx - ti_base;
 			let (blk, idx) = (idx / u32_per_fs_block, idx % u32_per_fs_block);
-			let (blk_o, blk_i) = (blk / u32_per_fs_block, blk % u32_per_fs_block);
-			let ti_block = try!( self.fs.get_block( self.ondisk.i_block[14] ) );
-			let ti_block = try!( self.fs.get_block( ti_block[blk_o as usize] ) );
-			let ti_block = try!( self.fs.get_block( ti_block[blk_i as usize] ) );
-			Ok( ti_block[idx as usize] )
+			(TI_BLOCK, [blk / u32_per_fs_block, blk % u32_per_fs_block, idx], 3)
+		}
+	}
+
+	/// Obtain the filesystem block address of a block in this inode (returns zero for sparse/unallocated blocks)
+	pub fn get_block_addr(&self, block_idx: u32) -> vfs::node::Result<u64>
+	{
+		if self.uses_extents() {
+			return Ok( try!(self.extent_lookup(block_idx)).0 );
+		}
+		Ok( try!(self.get_block_map_addr(block_idx)) as u64 )
+	}
+	fn get_block_map_addr(&self, block_idx: u32) -> vfs::node::Result<u32>
+	{
+		let (root, path, levels) = self.get_block_path(block_idx);
+		let mut blk = self.ondisk.read().i_block[root];
+		for &idx in &path[..levels]
+		{
+			if blk == 0 {
+				break;
+			}
+			// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
+			blk = try!( self.fs.get_block(blk as u64) )[idx as usize];
+		}
+		Ok( blk )
+	}
+
+	/// Obtain the address of the specified block, allocating it (and any required indirect blocks) if not yet present
+	///
+	/// Returns the address, and `true` if the block was newly allocated (and hence contains junk)
+	pub fn get_or_allocate_block(&self, block_idx: u32) -> vfs::node::Result<(u64, bool)>
+	{
+		if self.uses_extents() {
+			// TODO: Extent tree modification
+			return match try!(self.extent_lookup(block_idx))
+				{
+				(0, _) => Err(vfs::Error::Unknown("TODO: Allocating blocks in extent-mapped files")),
+				(v, _) => Ok( (v, false) ),
+				};
+		}
+		let mut n_allocated = 0;
+		let rv = self.get_or_allocate_block_inner(block_idx, &mut n_allocated);
+		if n_allocated > 0
+		{
+			let sectors_per_block = (self.fs.fs_block_size / 512) as u32;
+			self.ondisk.write().i_blocks += n_allocated * sectors_per_block;
+			self.is_dirty.store(true, Ordering::Relaxed);
+			try!(self.flush());
+		}
+		rv.map(|(blk, is_new)| (blk as u64, is_new))
+	}
+	fn get_or_allocate_block_inner(&self, block_idx: u32, n_allocated: &mut u32) -> vfs::node::Result<(u32, bool)>
+	{
+		let cur = try!(self.get_block_map_addr(block_idx));
+		if cur != 0 {
+			return Ok( (cur, false) );
+		}
+
+		let (root, path, levels) = self.get_block_path(block_idx);
+
+		// Attempt to keep the file contiguous
+		let mut goal = if block_idx > 0 {
+				try!(self.get_block_map_addr(block_idx - 1)) as u64
+			}
+			else {
+				0
+			};
+		if goal == 0 {
+			goal = self.fs.inode_block_goal(self.inode_idx);
+		}
+
+		// Root pointer (direct block, or top-level indirect block)
+		let (mut blk, mut is_new) = {
+			let mut od = self.ondisk.write();
+			if od.i_block[root] == 0 {
+				let new_blk = try!(self.allocate_mapped_block(goal));
+				*n_allocated += 1;
+				od.i_block[root] = new_blk;
+				self.is_dirty.store(true, Ordering::Relaxed);
+				(new_blk, true)
+			}
+			else {
+				(od.i_block[root], false)
+			}
+			};
+		// Walk (and populate) the indirect blocks
+		for (lvl, &idx) in path[..levels].iter().enumerate()
+		{
+			if is_new {
+				// Newly allocated indirect blocks must be cleared before use
+				try!(self.fs.write_blocks(blk as u64, &vec![0u8; self.fs.fs_block_size]));
+			}
+			let ent = try!(self.fs.get_block(blk as u64))[idx as usize];
+			if ent == 0 {
+				let new_blk = try!(self.allocate_mapped_block(if lvl == levels - 1 { goal } else { blk as u64 + 1 }));
+				*n_allocated += 1;
+				try!(self.fs.edit_block(blk as u64, |data| { data[idx as usize] = new_blk; Ok( () ) }));
+				blk = new_blk;
+				is_new = true;
+			}
+			else {
+				blk = ent;
+				is_new = false;
+			}
+		}
+		Ok( (blk, is_new) )
+	}
+	/// Allocate a block that can be referenced by a (32-bit) block map
+	fn allocate_mapped_block(&self, goal: u64) -> vfs::node::Result<u32>
+	{
+		let blk = try!(self.fs.allocate_block(goal));
+		if blk > ::core::u32::MAX as u64 {
+			let _ = self.fs.free_block(blk);
+			return Err(vfs::Error::Unknown("Block map can't address allocated block"));
+		}
+		Ok(blk as u32)
+	}
+
+	/// Release all blocks from `first_idx` onwards (used by truncate and inode release)
+	pub fn free_blocks_from(&self, first_idx: u32) -> vfs::node::Result<()>
+	{
+		if self.uses_extents() {
+			return self.free_extents_from(first_idx);
+		}
+		let u32_per_fs_block = self.u32_per_fs_block() as u64;
+		let mut n_freed = 0;
+
+		let i_block = self.ondisk.read().i_block;
+		let mut new_i_block = i_block;
+		let rv: vfs::node::Result<()> = (|| {
+			// Direct blocks
+			for i in (first_idx as usize) .. SI_BLOCK
+			{
+				if i_block[i] != 0 {
+					try!(self.fs.free_block(i_block[i] as u64));
+					n_freed += 1;
+					new_i_block[i] = 0;
+				}
+			}
+			// Indirect blocks
+			let mut base = SI_BLOCK as u64;
+			let mut span = u32_per_fs_block;
+			for (level, root) in (SI_BLOCK ..).take(3).enumerate()
+			{
+				if i_block[root] != 0 && (first_idx as u64) < base + span
+				{
+					try!(self.free_tree(i_block[root], level as u32 + 1, base, first_idx as u64, &mut n_freed));
+					if (first_idx as u64) <= base {
+						try!(self.fs.free_block(i_block[root] as u64));
+						n_freed += 1;
+						new_i_block[root] = 0;
+					}
+				}
+				base += span;
+				span *= u32_per_fs_block;
+			}
+			Ok( () )
+			})();
+
+		{
+			let sectors_per_block = (self.fs.fs_block_size / 512) as u32;
+			let mut od = self.ondisk.write();
+			od.i_block = new_i_block;
+			od.i_blocks -= ::core::cmp::min(od.i_blocks, n_freed * sectors_per_block);
+		}
+		self.is_dirty.store(true, Ordering::Relaxed);
+		try!(self.flush());
+		rv
+	}
+	/// Release all blocks from `first_idx` onwards in an extent-mapped inode
+	fn free_extents_from(&self, first_idx: u32) -> vfs::node::Result<()>
+	{
+		let mut n_freed = 0;
+		let mut root: Vec<u32> = self.ondisk.read().i_block[..].to_owned();
+		let rv = self.free_extent_node(&mut root, first_idx, 0, &mut n_freed);
+
+		{
+			let sectors_per_block = (self.fs.fs_block_size / 512) as u32;
+			let mut od = self.ondisk.write();
+			od.i_block.clone_from_slice(&root);
+			od.i_blocks -= ::core::cmp::min(od.i_blocks, n_freed * sectors_per_block);
+		}
+		self.is_dirty.store(true, Ordering::Relaxed);
+		try!(self.flush());
+		rv.map(|_| ())
+	}
+	/// Release blocks from `first_idx` onwards covered by an extent tree node (the node is updated in place)
+	///
+	/// Returns true if the node has no entries left. If an error occurs, the entries not yet processed are retained
+	/// (leaking blocks instead of leaving references to freed blocks).
+	fn free_extent_node(&self, node: &mut [u32], first_idx: u32, level: usize, n_freed: &mut u32) -> vfs::node::Result<bool>
+	{
+		// Limit the depth, to avoid looping forever on a corrupted tree
+		if level >= 8 {
+			log_warning!("Inode {}: Extent tree too deep", self.inode_idx);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let (depth, n_ents) = {
+			let hdr = ::ondisk::ExtentHeader::from_slice(&node[..3]);
+			if hdr.eh_magic != ::ondisk::EXT4_EXTENT_MAGIC {
+				log_warning!("Inode {}: Bad extent header magic {:#x}", self.inode_idx, hdr.eh_magic);
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			(hdr.eh_depth, hdr.eh_entries as usize)
+			};
+		if 3 + n_ents * 3 > node.len() {
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+
+		let mut rv = Ok( () );
+		let mut n_kept = 0;
+		for i in 0 .. n_ents
+		{
+			let keep = if rv.is_err() {
+					true
+				}
+				else if depth == 0 {
+					// Leaf node: Release whole extents that start at or after `first_idx`, and shorten the one spanning it
+					let ent = ::ondisk::Extent::from_slice_mut(&mut node[3 + i * 3 ..][.. 3]);
+					let (len, is_init) = ent.len();
+					if ent.ee_block >= first_idx {
+						match self.free_block_run(ent.start(), len, n_freed)
+						{
+						Ok(_) => false,
+						Err(e) => { rv = Err(e); true },
+						}
+					}
+					else if first_idx - ent.ee_block < len {
+						let keep_len = first_idx - ent.ee_block;
+						match self.free_block_run(ent.start() + keep_len as u64, len - keep_len, n_freed)
+						{
+						Ok(_) => {},
+						Err(e) => rv = Err(e),
+						}
+						ent.ee_len = if is_init { keep_len as u16 } else { keep_len as u16 + 32768 };
+						true
+					}
+					else {
+						true
+					}
+				}
+				else {
+					// Interior node: Only visit children that can cover blocks past `first_idx`
+					let child = ::ondisk::ExtentIdx::from_slice(&node[3 + i * 3 ..][.. 3]).leaf();
+					let next_start = if i + 1 < n_ents { ::ondisk::ExtentIdx::from_slice(&node[3 + (i+1) * 3 ..][.. 3]).ei_block } else { !0 };
+					if next_start <= first_idx {
+						true
+					}
+					else {
+						match self.free_extent_child(child, first_idx, level, n_freed)
+						{
+						// - Empty children (which includes all those starting after `first_idx`) are released
+						Ok(true) => match self.free_block_run(child, 1, n_freed)
+							{
+							Ok(_) => false,
+							Err(e) => { rv = Err(e); true },
+							},
+						Ok(false) => true,
+						Err(e) => { rv = Err(e); true },
+						}
+					}
+				};
+			if keep {
+				if n_kept != i {
+					for w in 0 .. 3 {
+						node[3 + n_kept * 3 + w] = node[3 + i * 3 + w];
+					}
+				}
+				n_kept += 1;
+			}
+		}
+
+		{
+			let hdr = ::ondisk::ExtentHeader::from_slice_mut(&mut node[..3]);
+			hdr.eh_entries = n_kept as u16;
+			// - An empty tree is a single (empty) leaf
+			if n_kept == 0 {
+				hdr.eh_depth = 0;
+			}
+		}
+		rv.map(|_| n_kept == 0)
+	}
+	/// Release blocks from `first_idx` onwards below the extent tree node stored in block `blk`, returning true if it's now empty
+	fn free_extent_child(&self, blk: u64, first_idx: u32, level: usize, n_freed: &mut u32) -> vfs::node::Result<bool>
+	{
+		let mut data: Vec<u32> = try!(self.fs.get_block(blk))[..].to_owned();
+		let prev_freed = *n_freed;
+		let rv = self.free_extent_node(&mut data, first_idx, level + 1, n_freed);
+		// - Write back the updated node (if it's staying and was changed)
+		if rv.as_ref().map(|&empty| !empty).unwrap_or(true) && *n_freed != prev_freed {
+			try!(self.fs.edit_block(blk, |d| { d.clone_from_slice(&data); Ok( () ) }));
+		}
+		rv
+	}
+	/// Release `count` consecutive blocks starting at `first`
+	fn free_block_run(&self, first: u64, count: u32, n_freed: &mut u32) -> vfs::node::Result<()>
+	{
+		for blk in first .. first + count as u64
+		{
+			try!(self.fs.free_block(blk));
+			*n_freed += 1;
 		}
+		Ok( () )
+	}
+	/// Free the contents of an indirect block covering indexes from `base`, leaves the block itself allocated
+	fn free_tree(&self, blk: u32, level: u32, base: u64, first_idx: u64, n_freed: &mut u32) -> vfs::node::Result<()>
+	{
+		let u32_per_fs_block = self.u32_per_fs_block() as u64;
+		let span = u32_per_fs_block.pow(level - 1);
+
+		let start = if first_idx > base { (first_idx - base) / span } else { 0 };
+		let ents: Vec<u32> = try!(self.fs.get_block(blk as u64))[start as usize ..].to_owned();
+		for (i, &ent) in (start ..).zip( ents.iter() )
+		{
+			if ent == 0 {
+				continue ;
+			}
+			let sub_base = base + i * span;
+			if level > 1 {
+				try!(self.free_tree(ent, level - 1, sub_base, first_idx, n_freed));
+			}
+			// Only release the block if everything it covers is being released
+			if first_idx <= sub_base {
+				try!(self.fs.free_block(ent as u64));
+				*n_freed += 1;
+			}
+		}
+		// If this block is staying, clear the released pointers
+		if first_idx > base
+		{
+			let first_cleared = if (first_idx - base) % span == 0 { start } else { start + 1 };
+			try!(self.fs.edit_block(blk as u64, |data| {
+				for v in &mut data[first_cleared as usize ..] {
+					*v = 0;
+				}
+				Ok( () )
+				}));
+		}
+		Ok( () )
 	}
 
 
-	pub fn blocks(&self) -> Blocks//impl Iterator<Item=u32>
+	pub fn blocks(&self) -> Blocks//impl Iterator<Item=u64>
 	{
 		Blocks {
 			inode: self,
@@ -223,7 +744,7 @@ impl Inode
 			}
 		//(0 .. self.i_size() / self.fs.fs_block_size).map(|i| self.get_block_addr(i))
 	}
-	pub fn blocks_from(&self, start: u32) -> Blocks//impl Iterator<Item=u32>
+	pub fn blocks_from(&self, start: u32) -> Blocks//impl Iterator<Item=u64>
 	{
 		Blocks {
 			inode: self,
@@ -240,12 +761,12 @@ pub struct Blocks<'a>
 }
 impl<'a> Blocks<'a>
 {
-	pub fn next_or_err(&mut self) -> ::kernel::vfs::Result<u32>
+	pub fn next_or_err(&mut self) -> ::kernel::vfs::Result<u64>
 	{
 		self.next().ok_or( ::kernel::vfs::Error::Unknown("Unexpected end of block list") )
 	}
 
-	pub fn next_extent_or_err(&mut self, max: u32) -> ::kernel::vfs::Result<(u32, u32)>
+	pub fn next_extent_or_err(&mut self, max: u32) -> ::kernel::vfs::Result<(u64, u32)>
 	{
 		if self.inner_idx >= self.inode.max_blocks() {
 			Err( ::kernel::vfs::Error::Unknown("Unexpected end of block list") )
@@ -261,8 +782,8 @@ impl<'a> Blocks<'a>
 }
 impl<'a> Iterator for Blocks<'a>
 {
-	type Item = u32;
-	fn next(&mut self) -> Option<u32>
+	type Item = u64;
+	fn next(&mut self) -> Option<u64>
 	{
 		if self.inner_idx >= self.inode.max_blocks() {
 			None
@@ -7,6 +7,7 @@ use kernel::prelude::*;
 use kernel::vfs::{self, node};
 use kernel::metadevs::storage::VolumeHandle;
 use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
+use kernel::sync::Mutex;
 
 pub struct Instance(ArefInner<InstanceInner>);
 pub type InstancePtr = ArefBorrow<InstanceInner>;
@@ -19,7 +20,19 @@ pub struct InstanceInner
 	pub fs_block_size: usize,
 
 	mount_handle: vfs::mount::SelfHandle,
-	group_descriptors: Vec<::ondisk::GroupDesc>,
+	/// Byte offset of the group descriptor table
+	gdt_offset: u64,
+	/// Size of each on-disk group descriptor (32 bytes, unless FEAT_INCOMPAT_64BIT is set)
+	desc_size: usize,
+	/// Allocation state (free counts and group descriptors), the lock also serialises bitmap updates
+	alloc: Mutex<AllocState>,
+}
+
+struct AllocState
+{
+	free_blocks_count: u64,
+	free_inodes_count: u32,
+	group_descriptors: Vec<::ondisk::GroupDesc64>,
 }
 
 pub enum FeatureState
@@ -72,14 +85,11 @@ impl Instance
 		let superblock_idx = (1024 / vol_bs) as u64;
 		let superblock_ofs = (1024 % vol_bs) as usize;
 
-		let (superblock, first_block) = {
+		let superblock = {
 			let mut first_block: Vec<u32> = vec![0; ::core::cmp::max(1024, vol_bs)/4];
 			try!(vol.read_blocks(superblock_idx, ::kernel::lib::as_byte_slice_mut(&mut first_block[..])));
 			assert!(superblock_ofs % 4 == 0);
-			(
-				*::ondisk::Superblock::from_slice(&first_block[superblock_ofs/4 ..][..1024/4]),
-				first_block,
-				)
+			*::ondisk::Superblock::from_slice(&first_block[superblock_ofs/4 ..][..1024/4])
 			};
 
 
@@ -90,7 +100,7 @@ impl Instance
 			{
 			FeatureState::Incompatible(_) => return Err(vfs::Error::TypeMismatch),
 			FeatureState::ReadOnly(_) => true,
-			_ => false,
+			_ => vol.is_readonly(),
 			};
 
 		// - Limit block size to 1MB each
@@ -103,61 +113,46 @@ impl Instance
 			log_warning!("ExtN TODO: Handle filesystem block size smaller than disk block size?");
 			return Err(vfs::Error::InconsistentFilesystem);
 		}
-		let num_groups = ::kernel::lib::num::div_up(superblock.data.s_blocks_count, superblock.data.s_blocks_per_group);
+		let is_64bit = superblock.has_feature_incompat(::ondisk::FEAT_INCOMPAT_64BIT);
+		let blocks_count = superblock.blocks_count();
+		let num_groups = ::kernel::lib::num::div_up(blocks_count - superblock.data.s_first_data_block as u64, superblock.data.s_blocks_per_group as u64);
+		let desc_size = if is_64bit {
+				let v = superblock.ext.s_desc_size as usize;
+				if v < 64 || v & (v - 1) != 0 {
+					log_warning!("{}: Invalid group descriptor size {} on 64-bit volume", vol.name(), v);
+					return Err(vfs::Error::InconsistentFilesystem);
+				}
+				v
+			}
+			else {
+				::core::mem::size_of::<::ondisk::GroupDesc>()
+			};
 
 		// Read group descriptor table
-		// - This always resides immediately after the superblock
+		// - This always resides in the block following the superblock
+		let gdt_offset = (superblock.data.s_first_data_block as u64 + 1) * fs_block_size as u64;
 		let group_descs = {
-			use kernel::lib::{as_byte_slice_mut,as_byte_slice};
-			#[allow(non_snake_case)]
-			let GROUP_DESC_SIZE = ::core::mem::size_of::<::ondisk::GroupDesc>();
+			use kernel::lib::as_byte_slice_mut;
+			// NOTE: Descriptors may be larger than the in-memory version, but only the 64-byte base is used
+			let copy_size = ::core::cmp::min(desc_size, ::core::mem::size_of::<::ondisk::GroupDesc64>());
 
-			let groups_per_vol_block = vol_bs / GROUP_DESC_SIZE;
+			let mut gds: Vec<::ondisk::GroupDesc64> = vec![Default::default(); num_groups as usize];
 
-			let mut gds: Vec<::ondisk::GroupDesc> = vec![Default::default(); num_groups as usize];
+			let (first_block, ofs) = ::kernel::lib::num::div_rem(gdt_offset, vol_bs as u64);
+			let n_bytes = gds.len() * desc_size;
+			let n_blocks = ::kernel::lib::num::div_up(ofs as usize + n_bytes, vol_bs);
+			log_trace!("GDT: first_block={}, ofs={}, n_blocks={}, desc_size={}", first_block, ofs, n_blocks, desc_size);
 
-			let (n_skip, mut block) = if vol_bs % (2*1024) == 0 {
-					// Volume block size is larger than the superblock
-					// - This means that at least 2048 bytes of the group descriptors are in the same block as the superblock
-					let n_shared = (vol_bs - 2*1024) / GROUP_DESC_SIZE;
-
-					let src = as_byte_slice(&first_block[2*1024/4..]);
-					let count = ::core::cmp::min(n_shared, gds.len());
-					assert_eq!(src.len(), count * GROUP_DESC_SIZE);
-					as_byte_slice_mut(&mut gds[..count]).clone_from_slice( src );
-					(count, 1)
-				}
-				else {
-					// Volume BS <= superblock
-					(0, (2*1024 / vol_bs) as u64)
-				};
-
-			let rem_count = gds.len() - n_skip;
-			let tail_count = rem_count % groups_per_vol_block;
-			let body_count = rem_count - tail_count;
-			log_trace!("n_skip={}, block={}, rem_count={},  tail_count={}, body_count={}",
-				n_skip, block, rem_count,  tail_count, body_count);
-
-			if body_count > 0 
+			let mut buf: Vec<u8> = vec![0; n_blocks * vol_bs];
+			try!(vol.read_blocks(first_block, &mut buf));
+			for (gd, src) in gds.iter_mut().zip( buf[ofs as usize ..][.. n_bytes].chunks(desc_size) )
 			{
-				try!(vol.read_blocks(block, as_byte_slice_mut(&mut gds[n_skip .. ][ .. body_count])));
-				block += (body_count / groups_per_vol_block) as u64;
-			}
-
-			if tail_count > 0
-			{
-				let ofs = n_skip + body_count;
-				// Read a single volume block into a buffer, then populate from that
-				let mut buf: Vec<u8> = vec![0; vol_bs];
-				try!(vol.read_blocks(block, &mut buf));
-				let n_bytes = (gds.len() - ofs) * GROUP_DESC_SIZE;
-				as_byte_slice_mut(&mut gds[ofs ..]).clone_from_slice( &buf[..n_bytes] );
+				as_byte_slice_mut(gd)[.. copy_size].clone_from_slice( &src[.. copy_size] );
 			}
 
 			gds
 			};
 
-
 		for (i, gd) in group_descs.iter().enumerate()
 		{
 			log_debug!("{}: Group #{}: {:?}", vol.name(), i, gd);
@@ -167,9 +162,21 @@ impl Instance
 			is_readonly: is_readonly,
 			fs_block_size: fs_block_size,
 			superblock: superblock,
-			group_descriptors: group_descs,
 			mount_handle: mount_handle,
-			vol: ::block_cache::CacheHandle::new(vol),
+			gdt_offset: gdt_offset,
+			desc_size: desc_size,
+			alloc: Mutex::new(AllocState {
+				free_blocks_count: if is_64bit {
+						(superblock.ext.s_free_blocks_count_hi as u64) << 32 | superblock.data.s_free_blocks_count as u64
+					}
+					else {
+						superblock.data.s_free_blocks_count as u64
+					},
+				free_inodes_count: superblock.data.s_free_inodes_count,
+				group_descriptors: group_descs,
+				}),
+			// - Cache entries cover at least one filesystem block, so blocks are never split across entries
+			vol: ::block_cache::CacheHandle::with_entry_size(vol, fs_block_size),
 			};
 
 		// SAFE: Boxed instantly
@@ -185,6 +192,10 @@ impl vfs::mount::Filesystem for Instance
 		// ext* uses inode 2 as the root
 		2
 	}
+	fn sync(&self) -> vfs::Result<()> {
+		try!(self.0.vol.flush());
+		Ok( () )
+	}
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		log_trace!("get_node_by_inode(id={})", id);
 		let inode = match ::inodes::Inode::from_id(self.0.borrow(), id as u32)
@@ -218,21 +229,37 @@ impl InstanceInner
 	{
 		self.is_readonly
 	}
+	/// Seed for directory index hashes
+	pub fn htree_hash_seed(&self) -> [u32; 4]
+	{
+		self.superblock.ext.s_hash_seed
+	}
+	/// Returns true if directory index hashes treat name bytes as unsigned
+	pub fn htree_unsigned_hash(&self) -> bool
+	{
+		self.superblock.ext.s_flags & ::ondisk::EXT2_FLAGS_UNSIGNED_HASH != 0
+	}
+	/// Returns true if files can be 2GB or larger (FEAT_RO_COMPAT_LARGE_FILE)
+	pub fn has_large_files(&self) -> bool
+	{
+		self.superblock.data.s_rev_level > 0 && self.superblock.ext.s_feature_ro_compat & ::ondisk::FEAT_RO_COMPAT_LARGE_FILE != 0
+	}
 }
 
-/// Structure representing a view into a BlockCache entry
-pub struct Block<'a>(::block_cache::CachedBlockHandle<'a>, u16,u16);
+/// Structure representing a view into a BlockCache entry (handle, offset, size)
+pub struct Block<'a>(::block_cache::CachedBlockHandle<'a>, u32,u32);
 impl<'a> ::core::ops::Deref for Block<'a>
 {
 	type Target = [u32];
 	fn deref(&self) -> &[u32] {
-		let &Block(ref handle, ofs, size) = self;
+		let (ofs, size) = (self.1 as usize, self.2 as usize);
+		let data = self.0.data();
 		// SAFE: Alignment should be good (but is checked anyway)
 		unsafe {
-			assert!(ofs as usize + size as usize <= handle.data().len());
+			assert!(ofs + size <= data.len());
 			assert!(ofs % 4 == 0);
-			assert!(&handle.data()[0] as *const _ as usize % 4 == 0);
-			::core::slice::from_raw_parts(&handle.data()[ofs as usize] as *const u8 as *const u32, (size / 4) as usize)
+			assert!(&data[0] as *const _ as usize % 4 == 0);
+			::core::slice::from_raw_parts(&data[ofs] as *const u8 as *const u32, size / 4)
 		}
 	}
 }
@@ -240,33 +267,23 @@ impl<'a> ::core::ops::Deref for Block<'a>
 impl InstanceInner
 {
 	/// Obtain a block (using the block cache)
-	pub fn get_block(&self, block: u32) -> vfs::node::Result<Block>
+	pub fn get_block(&self, block: u64) -> vfs::node::Result<Block>
 	{
-		if self.fs_block_size > ::kernel::PAGE_SIZE {
-			// TODO: To handle extN blocks larger than the system's page size, we'd need to start packing multiple cache handles into
-			//       the `Block` structure
-			todo!("Handle extN block sizes > PAGE_SIZE - {} > {}", self.fs_block_size, ::kernel::PAGE_SIZE);
-		}
 		log_trace!("get_block({})", block);
-		let sector = block as u64 * self.vol_blocks_per_fs_block();
+		let sector = block * self.vol_blocks_per_fs_block();
 
 		let ch = try!(self.vol.get_block(sector));
 		let ofs = (sector - ch.index()) as usize * self.vol.block_size();
-		Ok( Block(ch, ofs as u16, self.fs_block_size as u16) )
+		Ok( Block(ch, ofs as u32, self.fs_block_size as u32) )
 	}
 
 	/// Edit a block in the cache using the provided closure
-	pub fn edit_block<F,R>(&self, block: u32, f: F) -> vfs::node::Result<R>
+	pub fn edit_block<F,R>(&self, block: u64, f: F) -> vfs::node::Result<R>
 	where
 		F: FnOnce(&mut [u32]) -> vfs::node::Result<R>
 	{
-		if self.fs_block_size > ::kernel::PAGE_SIZE {
-			// TODO: To handle extN blocks larger than the system's page size, we'd need to start packing multiple cache handles into
-			//       the `Block` structure
-			todo!("Handle extN block sizes > PAGE_SIZE - {} > {}", self.fs_block_size, ::kernel::PAGE_SIZE);
-		}
-		log_trace!("get_block({})", block);
-		let sector = block as u64 * self.vol_blocks_per_fs_block();
+		log_trace!("edit_block({})", block);
+		let sector = block * self.vol_blocks_per_fs_block();
 
 		try!(self.vol.edit(sector, self.vol_blocks_per_fs_block() as usize, |data| {
 			// SAFE: Alignment checked, range valid
@@ -282,26 +299,25 @@ impl InstanceInner
 	///
 	/// This is the more expensive version of `get_block`, which doesn't directly touch the block cache.
 	/// It's used to handle partial file reads (which should be cached by higher layers)
-	pub fn get_block_uncached(&self, block: u32) -> vfs::node::Result<Box<[u32]>>                           
-	{                                                                                              
-		log_trace!("get_block_uncached({})", block);                                                    
-		let mut rv = vec![0u32; self.fs_block_size / 4].into_boxed_slice();                    
-		try!( self.read_blocks( block, ::kernel::lib::as_byte_slice_mut(&mut rv[..]) ) );      
-		Ok(rv)                                                                                 
-	}                
+	pub fn get_block_uncached(&self, block: u64) -> vfs::node::Result<Box<[u32]>>
+	{
+		log_trace!("get_block_uncached({})", block);
+		let mut rv = vec![0u32; self.fs_block_size / 4].into_boxed_slice();
+		try!( self.read_blocks( block, ::kernel::lib::as_byte_slice_mut(&mut rv[..]) ) );
+		Ok(rv)
+	}
 
 	/// Read a sequence of blocks into a user-provided buffer
-	pub fn read_blocks(&self, first_block: u32, data: &mut [u8]) -> vfs::node::Result<()>
+	pub fn read_blocks(&self, first_block: u64, data: &mut [u8]) -> vfs::node::Result<()>
 	{
-		try!( self.vol.read_blocks( first_block as u64 * self.vol_blocks_per_fs_block(), data) );
+		try!( self.vol.read_blocks( first_block * self.vol_blocks_per_fs_block(), data) );
 		Ok( () )
 	}
 
 	/// Write a sequence of blocks from a user-provided buffer
-	pub fn write_blocks(&self, first_block: u32, data: &[u8]) -> vfs::node::Result<()>
+	pub fn write_blocks(&self, first_block: u64, data: &[u8]) -> vfs::node::Result<()>
 	{
-		// TODO: Requires maybe interfacing with the cache used by get_block?
-		try!( self.vol.write_blocks( first_block as u64 * self.vol_blocks_per_fs_block(), data) );
+		try!( self.vol.write_blocks( first_block * self.vol_blocks_per_fs_block(), data) );
 		Ok( () )
 	}
 }
@@ -320,7 +336,8 @@ impl InstanceInner
 	fn get_inode_pos(&self, inode_num: u32) -> (u64, usize) {
 		let (group, ofs) = self.get_inode_grp_id(inode_num);
 
-		let base_blk_id = self.group_descriptors[group as usize].bg_inode_table as u64 * self.vol_blocks_per_fs_block();
+		let inode_table = self.alloc.lock().group_descriptors[group as usize].inode_table();
+		let base_blk_id = inode_table * self.vol_blocks_per_fs_block();
 		let ofs_bytes = (ofs as usize) * self.s_inode_size();
 		let (sub_blk_id, sub_blk_ofs) = (ofs_bytes / self.vol.block_size(), ofs_bytes % self.vol.block_size());
 
@@ -332,10 +349,7 @@ impl InstanceInner
 	where
 		F: FnOnce(&::inodes::Inode) -> vfs::node::Result<R>
 	{
-		// TODO: Hook into the VFS's node cache somehow (we'd need to know our mount ID) and
-		//       obtain a reference to a cached inode.
-		// - This prevents us from having to maintain our own node cache
-
+		// Uses the VFS's node cache (via the mount's handle), so there's only ever one `Inode` for each inode number
 		let node = try!(self.mount_handle.get_node(inode_num as vfs::node::InodeId));
 		match node.get_any().downcast_ref()
 		{
@@ -345,22 +359,95 @@ impl InstanceInner
 	}
 
 	/// Allocate a new inode number, possibly in the same block group as `parent_inode_num`.
+	///
+	/// The new inode is written to disk with a link count of zero (it's released when the node is dropped unless a link is added)
 	pub fn allocate_inode(&self, parent_inode_num: u32, nodetype: vfs::node::NodeType) -> vfs::node::Result< u32 >
 	{
-		let (grp, _idx) = self.get_inode_grp_id(parent_inode_num);
-		let gd = &self.group_descriptors[grp as usize];
-		if gd.bg_free_inodes_count > 0
-		{
-			todo!("InstanceInner::allocate_inode - Current BG");
-		}
-		else if self.superblock.data.s_free_inodes_count > 0
-		{
-			todo!("InstanceInner::allocate_inode - Search for any BG");
+		let (mode, is_dir) = match nodetype
+			{
+			vfs::node::NodeType::File => (::ondisk::S_IFREG | 0o644, false),
+			vfs::node::NodeType::Dir => (::ondisk::S_IFDIR | 0o755, true),
+			// TODO: Symbolic links (get_node_by_inode doesn't handle them yet either)
+			vfs::node::NodeType::Symlink(_) => return Err(vfs::Error::Unknown("TODO: extN symbolic link creation")),
+			};
+
+		let inode_num = {
+			let mut st = self.alloc.lock();
+			if st.free_inodes_count == 0 {
+				return Err(vfs::Error::OutOfSpace);
+			}
+
+			let (start_grp, _idx) = self.get_inode_grp_id(parent_inode_num);
+			let n_groups = st.group_descriptors.len();
+			let mut found = None;
+			for i in 0 .. n_groups
+			{
+				let grp = (start_grp as usize + i) % n_groups;
+				if st.group_descriptors[grp].free_inodes_count() == 0 {
+					continue ;
+				}
+				match try!(self.alloc_bit(st.group_descriptors[grp].inode_bitmap(), self.s_inodes_per_group()))
+				{
+				Some(bit) => {
+					found = Some( (grp, bit) );
+					break;
+					},
+				None => log_warning!("{}: Group #{} has a free inode count of {} but a full bitmap",
+					self.vol.name(), grp, st.group_descriptors[grp].free_inodes_count()),
+				}
+			}
+			let (grp, bit) = match found
+				{
+				Some(v) => v,
+				None => return Err(vfs::Error::OutOfSpace),
+				};
+
+			{
+				let gd = &mut st.group_descriptors[grp];
+				let v = gd.free_inodes_count();
+				gd.set_free_inodes_count(v - 1);
+				if is_dir {
+					let v = gd.used_dirs_count();
+					gd.set_used_dirs_count(v + 1);
+				}
+			}
+			st.free_inodes_count -= 1;
+			try!(self.write_group_desc(grp, &st.group_descriptors[grp]));
+			try!(self.write_superblock_counts(&st));
+
+			grp as u32 * self.s_inodes_per_group() + bit + 1
+			};
+		log_debug!("allocate_inode(parent={}) = {} (mode={:#o})", parent_inode_num, inode_num, mode);
+
+		// Initialise the on-disk inode
+		let mut inode = ::ondisk::Inode::default();
+		inode.i_mode = mode;
+		try!(self.write_inode(inode_num, &inode));
+		Ok( inode_num )
+	}
+	/// Release an inode number (called once the link count is zero and the node is no longer in use)
+	pub fn free_inode(&self, inode_num: u32, is_dir: bool) -> vfs::node::Result<()>
+	{
+		let (grp, bit) = self.get_inode_grp_id(inode_num);
+		let grp = grp as usize;
+		let mut st = self.alloc.lock();
+		if ! try!(self.free_bit(st.group_descriptors[grp].inode_bitmap(), bit)) {
+			log_warning!("{}: Freeing inode {} which wasn't allocated", self.vol.name(), inode_num);
+			return Ok( () );
 		}
-		else
 		{
-			Err(vfs::Error::OutOfSpace)
+			let gd = &mut st.group_descriptors[grp];
+			let v = gd.free_inodes_count();
+			gd.set_free_inodes_count(v + 1);
+			if is_dir {
+				let v = gd.used_dirs_count();
+				gd.set_used_dirs_count(v - 1);
+			}
 		}
+		st.free_inodes_count += 1;
+		try!(self.write_group_desc(grp, &st.group_descriptors[grp]));
+		try!(self.write_superblock_counts(&st));
+		Ok( () )
 	}
 
 	/// Read an inode descriptor from the disk
@@ -372,7 +459,7 @@ impl InstanceInner
 		let mut rv = ::ondisk::Inode::default();
 		{
 			// NOTE: Unused fields in the inode are zero
-			let slice = &mut ::kernel::lib::as_byte_slice_mut(&mut rv)[.. self.s_inode_size()];
+			let slice = &mut ::kernel::lib::as_byte_slice_mut(&mut rv)[.. self.s_inode_size_base()];
 			try!( self.vol.read_inner(vol_block, blk_ofs, slice) );
 		}
 		log_trace!("- rv={:?}", rv);
@@ -383,13 +470,159 @@ impl InstanceInner
 	{
 		let (vol_block, blk_ofs) = self.get_inode_pos(inode_num);
 		
-		let slice = &::kernel::lib::as_byte_slice(inode_data)[.. self.s_inode_size()];
+		let slice = &::kernel::lib::as_byte_slice(inode_data)[.. self.s_inode_size_base()];
 		try!( self.vol.write_inner(vol_block, blk_ofs, slice) );
 
 		Ok( () )
 	}
 }
 
+/// Block allocation
+impl InstanceInner
+{
+	/// Allocate a filesystem block, preferably near `goal`
+	pub fn allocate_block(&self, goal: u64) -> vfs::node::Result<u64>
+	{
+		let first_data_block = self.superblock.data.s_first_data_block as u64;
+		let blocks_per_group = self.superblock.data.s_blocks_per_group as u64;
+		let blocks_count = self.superblock.blocks_count();
+
+		let mut st = self.alloc.lock();
+		if st.free_blocks_count == 0 {
+			return Err(vfs::Error::OutOfSpace);
+		}
+
+		let n_groups = st.group_descriptors.len();
+		let start_grp = if goal >= first_data_block {
+				::core::cmp::min( ((goal - first_data_block) / blocks_per_group) as usize, n_groups - 1 )
+			}
+			else {
+				0
+			};
+		for i in 0 .. n_groups
+		{
+			let grp = (start_grp + i) % n_groups;
+			if st.group_descriptors[grp].free_blocks_count() == 0 {
+				continue ;
+			}
+			// The last group can be shorter than the others
+			let grp_base = first_data_block + grp as u64 * blocks_per_group;
+			let grp_blocks = ::core::cmp::min(blocks_per_group, blocks_count - grp_base) as u32;
+			match try!(self.alloc_bit(st.group_descriptors[grp].block_bitmap(), grp_blocks))
+			{
+			Some(bit) => {
+				{
+					let gd = &mut st.group_descriptors[grp];
+					let v = gd.free_blocks_count();
+					gd.set_free_blocks_count(v - 1);
+				}
+				st.free_blocks_count -= 1;
+				try!(self.write_group_desc(grp, &st.group_descriptors[grp]));
+				try!(self.write_superblock_counts(&st));
+				log_trace!("allocate_block(goal={}) = {}", goal, grp_base + bit as u64);
+				return Ok(grp_base + bit as u64);
+				},
+			None => log_warning!("{}: Group #{} has a free block count of {} but a full bitmap",
+				self.vol.name(), grp, st.group_descriptors[grp].free_blocks_count()),
+			}
+		}
+		Err(vfs::Error::OutOfSpace)
+	}
+	/// Obtain a suitable allocation goal for a data block owned by the specified inode (the start of the inode's group)
+	pub fn inode_block_goal(&self, inode_num: u32) -> u64
+	{
+		let (grp, _) = self.get_inode_grp_id(inode_num);
+		self.superblock.data.s_first_data_block as u64 + grp as u64 * self.superblock.data.s_blocks_per_group as u64
+	}
+	/// Release a filesystem block
+	pub fn free_block(&self, block: u64) -> vfs::node::Result<()>
+	{
+		let first_data_block = self.superblock.data.s_first_data_block as u64;
+		let blocks_per_group = self.superblock.data.s_blocks_per_group as u64;
+		if block < first_data_block || block >= self.superblock.blocks_count() {
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let (grp, bit) = ::kernel::lib::num::div_rem(block - first_data_block, blocks_per_group);
+		let grp = grp as usize;
+
+		let mut st = self.alloc.lock();
+		if ! try!(self.free_bit(st.group_descriptors[grp].block_bitmap(), bit as u32)) {
+			log_warning!("{}: Freeing block {} which wasn't allocated", self.vol.name(), block);
+			return Ok( () );
+		}
+		{
+			let gd = &mut st.group_descriptors[grp];
+			let v = gd.free_blocks_count();
+			gd.set_free_blocks_count(v + 1);
+		}
+		st.free_blocks_count += 1;
+		try!(self.write_group_desc(grp, &st.group_descriptors[grp]));
+		try!(self.write_superblock_counts(&st));
+		Ok( () )
+	}
+
+	/// Locate and set a clear bit in a bitmap block (only considering the first `count` bits)
+	///
+	/// NOTE: Caller must hold the allocation lock
+	fn alloc_bit(&self, bitmap_block: u64, count: u32) -> vfs::node::Result<Option<u32>>
+	{
+		self.edit_block(bitmap_block, |data| {
+			// NOTE: Bitmaps are little-endian bytes, which matches a little-endian u32
+			for (i, word) in data.iter_mut().enumerate()
+			{
+				if *word == !0 {
+					continue ;
+				}
+				let bit = (!*word).trailing_zeros();
+				let idx = i as u32 * 32 + bit;
+				if idx >= count {
+					break ;
+				}
+				*word |= 1 << bit;
+				return Ok( Some(idx) );
+			}
+			Ok( None )
+			})
+	}
+	/// Clear a bit in a bitmap block, returning false if it was already clear
+	///
+	/// NOTE: Caller must hold the allocation lock
+	fn free_bit(&self, bitmap_block: u64, idx: u32) -> vfs::node::Result<bool>
+	{
+		self.edit_block(bitmap_block, |data| {
+			let (word, bit) = ((idx / 32) as usize, idx % 32);
+			let was_set = data[word] & (1 << bit) != 0;
+			data[word] &= !(1 << bit);
+			Ok( was_set )
+			})
+	}
+
+	/// Write a group descriptor back to the disk
+	fn write_group_desc(&self, idx: usize, gd: &::ondisk::GroupDesc64) -> vfs::node::Result<()>
+	{
+		let size = ::core::cmp::min(self.desc_size, ::core::mem::size_of::<::ondisk::GroupDesc64>());
+		let (vol_block, ofs) = ::kernel::lib::num::div_rem(self.gdt_offset + (idx * self.desc_size) as u64, self.vol.block_size() as u64);
+		try!(self.vol.write_inner(vol_block, ofs as usize, &::kernel::lib::as_byte_slice(gd)[.. size]));
+		Ok( () )
+	}
+	/// Update the free block/inode counts in the superblock
+	fn write_superblock_counts(&self, st: &AllocState) -> vfs::node::Result<()>
+	{
+		// `s_free_blocks_count` and `s_free_inodes_count` are consecutive at offset 12
+		let counts = [st.free_blocks_count as u32, st.free_inodes_count];
+		let (vol_block, ofs) = ::kernel::lib::num::div_rem(1024 + 12, self.vol.block_size() as u64);
+		try!(self.vol.write_inner(vol_block, ofs as usize, ::kernel::lib::as_byte_slice(&counts)));
+		if self.superblock.has_feature_incompat(::ondisk::FEAT_INCOMPAT_64BIT)
+		{
+			// `s_free_blocks_count_hi` is at offset 0x158
+			let hi = (st.free_blocks_count >> 32) as u32;
+			let (vol_block, ofs) = ::kernel::lib::num::div_rem(1024 + 0x158, self.vol.block_size() as u64);
+			try!(self.vol.write_inner(vol_block, ofs as usize, ::kernel::lib::as_byte_slice(&hi)));
+		}
+		Ok( () )
+	}
+}
+
 /// Superblock parameters
 impl InstanceInner
 {
@@ -409,6 +642,10 @@ impl InstanceInner
 			128
 		}
 	}
+	/// Number of bytes of each inode that are covered by `ondisk::Inode`
+	fn s_inode_size_base(&self) -> usize {
+		::core::cmp::min( self.s_inode_size(), ::core::mem::size_of::<::ondisk::Inode>() )
+	}
 }
 
 
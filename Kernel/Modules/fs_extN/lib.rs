@@ -22,6 +22,7 @@ mod ondisk;
 mod inodes;
 
 mod dir;
+mod htree;
 mod file;
 mod instance;
 
@@ -34,14 +35,19 @@ fn init()
 const SUPPORTED_OPT_FEATURES: u32 = 0
 	| ::ondisk::FEAT_COMPAT_EXT_ATTR	// Extended attributes
 	| ::ondisk::FEAT_COMPAT_RESIZE_INODE	// Extra space was allocated for resizing the filesystem
+	| ::ondisk::FEAT_COMPAT_DIR_INDEX	// Directories can have hashed indexes
 	;
 /// Read-only features: Missing features stop write support
 const SUPPORTED_RDO_FEATURES: u32 = 0
 	| ::ondisk::FEAT_RO_COMPAT_SPARSE_SUPER	// Enables storing SB backups at group 0, 3^n, 5^n, and 7^n
+	| ::ondisk::FEAT_RO_COMPAT_LARGE_FILE	// Upper 32 bits of file sizes stored in i_dir_acl
 	;
 /// Required Features: Missing features prevent mounting
 const SUPPORTED_REQ_FEATURES: u32 = 0
 	| ::ondisk::FEAT_INCOMPAT_FILETYPE	// DirEnt.d_name_len restricted to 1 byte and extra byte used for file type
+	| ::ondisk::FEAT_INCOMPAT_EXTENTS	// Inodes can use extent trees (NOTE: Extent-mapped files can't be extended yet)
+	| ::ondisk::FEAT_INCOMPAT_64BIT	// 64-bit block numbers and larger group descriptors
+	| ::ondisk::FEAT_INCOMPAT_FLEX_BG	// Group metadata can be located in other groups
 	;
 
 static S_DRIVER: Driver = Driver;
@@ -79,7 +85,8 @@ impl vfs::mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, mounthandle: vfs::mount::SelfHandle) -> vfs::Result<Box<vfs::mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, mounthandle: vfs::mount::SelfHandle, options: &[&str]) -> vfs::Result<Box<vfs::mount::Filesystem>> {
+		try!(vfs::mount::reject_options(options));
 		Ok( try!(instance::Instance::new_boxed(vol, mounthandle)) )
 	}
 }
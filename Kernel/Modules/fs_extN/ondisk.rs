@@ -35,6 +35,15 @@ macro_rules! def_from_slice {
 					&*p
 				}
 			}
+			#[allow(dead_code)]
+			pub fn from_slice_mut(r: &mut [u32]) -> &mut Self {
+				assert_eq!(r.len() * 4, ::core::mem::size_of::<Self>() );
+				// SAFE: Alignment is correct, (max is u32), size checked
+				unsafe {
+					let p = r.as_mut_ptr() as *mut Self;
+					&mut *p
+				}
+			}
 		}
 	};
 }
@@ -58,6 +67,21 @@ fn _sb_size() { unsafe {
 } }
 pod_impls!{ Superblock }
 def_from_slice!{ Superblock }
+impl Superblock
+{
+	pub fn has_feature_incompat(&self, feature: u32) -> bool {
+		self.data.s_rev_level > 0 && self.ext.s_feature_incompat & feature != 0
+	}
+	/// Total number of blocks (including the upper 32 bits if FEAT_INCOMPAT_64BIT is set)
+	pub fn blocks_count(&self) -> u64 {
+		if self.has_feature_incompat(FEAT_INCOMPAT_64BIT) {
+			(self.ext.s_blocks_count_hi as u64) << 32 | self.data.s_blocks_count as u64
+		}
+		else {
+			self.data.s_blocks_count as u64
+		}
+	}
+}
 
 #[repr(C)]
 pub struct SuperblockData
@@ -317,7 +341,94 @@ pub const S_IROTH: u16 =  0o004;	// Global Read
 pub const S_IWOTH: u16 =  0o002;	// Global Write
 pub const S_IXOTH: u16 =  0o001;	// Global Execute
 
-pub const EXT4_INDEX_FL: u16 = 0x1000;	// i_flags: Directory uses a hashed btree
+pub const EXT4_INDEX_FL: u32 = 0x1000;	// i_flags: Directory uses a hashed btree
+pub const EXT4_EXTENTS_FL: u32 = 0x80000;	// i_flags: Inode uses an extent tree (instead of block maps)
+
+/// Superblock s_flags: Directory hashes use signed characters
+pub const EXT2_FLAGS_SIGNED_HASH: u32 = 0x1;
+/// Superblock s_flags: Directory hashes use unsigned characters
+pub const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x2;
+
+// Directory index hash versions
+pub const DX_HASH_LEGACY: u8 = 0;
+pub const DX_HASH_HALF_MD4: u8 = 1;
+pub const DX_HASH_TEA: u8 = 2;
+pub const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+pub const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+pub const DX_HASH_TEA_UNSIGNED: u8 = 5;
+
+/// Directory index information, follows the `.` and `..` entries in the first block of an indexed directory
+#[repr(C)]
+pub struct DxRootInfo
+{
+	pub reserved_zero: u32,
+	pub hash_version: u8,
+	pub info_length: u8,	// Length of this structure (8)
+	pub indirect_levels: u8,	// Number of levels of interior index nodes
+	pub unused_flags: u8,
+}
+pod_impls!{ DxRootInfo }
+def_from_slice!{ DxRootInfo }
+
+pub const EXT4_EXTENT_MAGIC: u16 = 0xF30A;
+
+/// Header at the start of each extent tree node (including the root in `i_block`)
+#[repr(C)]
+pub struct ExtentHeader
+{
+	pub eh_magic: u16,	// Magic number (0xF30A)
+	pub eh_entries: u16,	// Number of valid entries following the header
+	pub eh_max: u16,	// Capacity of this node
+	pub eh_depth: u16,	// Depth of the tree below this node (0 = leaf)
+	pub eh_generation: u32,
+}
+pod_impls!{ ExtentHeader }
+def_from_slice!{ ExtentHeader }
+
+/// Extent tree interior node entry
+#[repr(C)]
+pub struct ExtentIdx
+{
+	pub ei_block: u32,	// First file block covered by this index
+	pub ei_leaf_lo: u32,	// Block number of the next level down
+	pub ei_leaf_hi: u16,
+	pub ei_unused: u16,
+}
+pod_impls!{ ExtentIdx }
+def_from_slice!{ ExtentIdx }
+impl ExtentIdx
+{
+	pub fn leaf(&self) -> u64 {
+		(self.ei_leaf_hi as u64) << 32 | self.ei_leaf_lo as u64
+	}
+}
+
+/// Extent tree leaf entry
+#[repr(C)]
+pub struct Extent
+{
+	pub ee_block: u32,	// First file block covered by this extent
+	pub ee_len: u16,	// Number of blocks (values above 32768 indicate an uninitialised extent)
+	pub ee_start_hi: u16,
+	pub ee_start_lo: u32,
+}
+pod_impls!{ Extent }
+def_from_slice!{ Extent }
+impl Extent
+{
+	pub fn start(&self) -> u64 {
+		(self.ee_start_hi as u64) << 32 | self.ee_start_lo as u64
+	}
+	/// Returns (length, is_initialised)
+	pub fn len(&self) -> (u32, bool) {
+		if self.ee_len > 32768 {
+			( (self.ee_len - 32768) as u32, false )
+		}
+		else {
+			( self.ee_len as u32, true )
+		}
+	}
+}
 
 #[repr(C)]
 pub struct GroupDesc
@@ -333,7 +444,74 @@ pub struct GroupDesc
 }
 pod_impls!{ GroupDesc }
 //def_from_slice!{ GroupDesc }
+
+/// [FEAT_INCOMPAT_64BIT] Upper half of a 64-byte group descriptor
+#[repr(C)]
+pub struct GroupDescHi
+{
+	pub bg_block_bitmap_hi: u32,
+	pub bg_inode_bitmap_hi: u32,
+	pub bg_inode_table_hi: u32,
+	pub bg_free_blocks_count_hi: u16,
+	pub bg_free_inodes_count_hi: u16,
+	pub bg_used_dirs_count_hi: u16,
+	pub bg_itable_unused_hi: u16,
+	pub bg_exclude_bitmap_hi: u32,
+	pub bg_block_bitmap_csum_hi: u16,
+	pub bg_inode_bitmap_csum_hi: u16,
+	pub bg_reserved: u32,
+}
+pod_impls!{ GroupDescHi }
+
+/// Full group descriptor (the upper half is only present on disk when FEAT_INCOMPAT_64BIT is set, and is zero otherwise)
+#[repr(C)]
+pub struct GroupDesc64
+{
+	pub lo: GroupDesc,
+	pub hi: GroupDescHi,
+}
+pod_impls!{ GroupDesc64 }
+
+impl GroupDesc64
+{
+	pub fn block_bitmap(&self) -> u64 {
+		(self.hi.bg_block_bitmap_hi as u64) << 32 | self.lo.bg_block_bitmap as u64
+	}
+	pub fn inode_bitmap(&self) -> u64 {
+		(self.hi.bg_inode_bitmap_hi as u64) << 32 | self.lo.bg_inode_bitmap as u64
+	}
+	pub fn inode_table(&self) -> u64 {
+		(self.hi.bg_inode_table_hi as u64) << 32 | self.lo.bg_inode_table as u64
+	}
+	pub fn free_blocks_count(&self) -> u32 {
+		(self.hi.bg_free_blocks_count_hi as u32) << 16 | self.lo.bg_free_blocks_count as u32
+	}
+	pub fn set_free_blocks_count(&mut self, v: u32) {
+		self.lo.bg_free_blocks_count = v as u16;
+		self.hi.bg_free_blocks_count_hi = (v >> 16) as u16;
+	}
+	pub fn free_inodes_count(&self) -> u32 {
+		(self.hi.bg_free_inodes_count_hi as u32) << 16 | self.lo.bg_free_inodes_count as u32
+	}
+	pub fn set_free_inodes_count(&mut self, v: u32) {
+		self.lo.bg_free_inodes_count = v as u16;
+		self.hi.bg_free_inodes_count_hi = (v >> 16) as u16;
+	}
+	pub fn used_dirs_count(&self) -> u32 {
+		(self.hi.bg_used_dirs_count_hi as u32) << 16 | self.lo.bg_used_dirs_count as u32
+	}
+	pub fn set_used_dirs_count(&mut self, v: u32) {
+		self.lo.bg_used_dirs_count = v as u16;
+		self.hi.bg_used_dirs_count_hi = (v >> 16) as u16;
+	}
+}
 impl_fmt! {
+	Debug(self, f) for GroupDesc64 {
+		write!(f, "GroupDesc {{ addrs: (block_bm: {}, inode_bm: {}, inodes: {}), counts: (free_blk: {}, free_inodes: {}, used_dirs: {}) }}",
+			self.block_bitmap(), self.inode_bitmap(), self.inode_table(),
+			self.free_blocks_count(), self.free_inodes_count(), self.used_dirs_count()
+			)
+	}
 	Debug(self, f) for GroupDesc {
 		write!(f, "GroupDesc {{ addrs: (block_bm: {}, inode_bm: {}, inodes: {}), counts: (free_blk: {}, free_inodes: {}, used_dirs: {}) }}",
 			self.bg_block_bitmap, self.bg_inode_bitmap, self.bg_inode_table,
@@ -361,6 +539,12 @@ pub struct DirEnt
 }
 pub const DIRENT_MIN_SIZE: usize = 8;
 
+// DirEnt.d_type values (FEAT_INCOMPAT_FILETYPE)
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_SYMLINK: u8 = 7;
+
 //pod_impls!{ DirEnt }
 
 impl DirEnt
@@ -411,6 +595,25 @@ impl DirEnt
 	pub fn u32_len(&self) -> usize {
 		(self.d_rec_len as usize + 3) / 4
 	}
+
+	/// Returns the minimum record length required for an entry with the specified name length
+	pub fn size_for_name(name_len: usize) -> usize {
+		(DIRENT_MIN_SIZE + name_len + 3) & !3
+	}
+	/// Initialise a directory entry at the start of `buf`
+	pub fn write(buf: &mut [u32], inode: u32, rec_len: u16, d_type: u8, name: &[u8])
+	{
+		assert!(name.len() <= 255);
+		assert!(Self::size_for_name(name.len()) <= rec_len as usize);
+		assert!(rec_len as usize <= buf.len() * 4);
+		// SAFE: Length checked above, and all values are valid
+		let ent: &mut DirEnt = unsafe { &mut *(Self::new_raw(buf, name.len()) as *mut DirEnt) };
+		ent.d_inode = inode;
+		ent.d_rec_len = rec_len;
+		ent.d_name_len = name.len() as u8;
+		ent.d_type = d_type;
+		ent.d_name.clone_from_slice(name);
+	}
 }
 
 impl_fmt! {
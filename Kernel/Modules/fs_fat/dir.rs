@@ -13,11 +13,18 @@ use super::ClusterList;
 use super::FilesystemInner;
 use utf16::Str16;
 
+/// Maximum length of a long filename (in UTF-16 code units)
+const MAX_LFN_LENGTH: usize = 255;
+/// Number of UTF-16 code units in a single long filename entry
+const LFN_CHARS_PER_ENT: usize = 13;
+
 pub struct DirNode
 {
 	fs: ArefBorrow<::FilesystemInner>,
 	start_cluster: u32,
 	// - Uses the cluster chain
+	/// Location of this directory's entry (parent's first cluster, entry index, and short name), `None` for the root
+	dir_ent: Option<(u32, usize, [u8; 11])>,
 }
 impl_fmt! {
 	Debug(self, f) for DirNode {
@@ -30,16 +37,41 @@ impl DirNode {
 		DirNode {
 			fs: fs,
 			start_cluster: start_cluster,
+			dir_ent: None,
 		}
 	}
 	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, start_cluster: u32) -> Box<DirNode> {
 		Box::new(Self::new(fs, start_cluster))
 	}
+	/// Create a directory node that knows its own entry (for `get_info`)
+	fn new_boxed_child(fs: ArefBorrow<FilesystemInner>, start_cluster: u32, parent: u32, dir_ent: usize, short_name: [u8; 11]) -> Box<DirNode> {
+		Box::new(DirNode {
+			fs: fs,
+			start_cluster: start_cluster,
+			dir_ent: Some( (parent, dir_ent, short_name) ),
+			})
+	}
 }
 
 impl node::NodeBase for DirNode {
 	fn get_id(&self) -> node::InodeId {
-		todo!("DirNode::get_id")
+		match self.dir_ent
+		{
+		Some( (parent, _, _) ) => super::InodeRef::new(self.start_cluster, parent).to_id(),
+		// Only the root has no entry (matches `Filesystem::root_inode`)
+		None => super::InodeRef::new(self.start_cluster, 0).to_id(),
+		}
+	}
+	fn get_info(&self) -> node::Result<node::NodeInfo> {
+		match self.dir_ent
+		{
+		Some( (parent, idx, ref short_name) ) => {
+			let (_, ent) = try!(DirNode::new(self.fs.reborrow(), parent).find_ent(idx, self.start_cluster, short_name));
+			Ok( ent_info(&ent, 0) )
+			},
+		// The root directory has no entry
+		None => Ok(node::NodeInfo { mode: 0o777, .. Default::default() }),
+		}
 	}
 	fn get_any(&self) -> &::core::any::Any {
 		self
@@ -50,6 +82,15 @@ impl DirNode {
 	fn is_fixed_root(&self) -> bool {
 		!is!(self.fs.ty, super::Size::Fat32) && self.start_cluster == self.fs.root_first_cluster
 	}
+	/// Maximum number of entries in this directory (only limited for the FAT12/16 root)
+	fn max_entries(&self) -> Option<usize> {
+		if self.is_fixed_root() {
+			Some(self.fs.root_sector_count as usize * self.fs.vh.block_size() / 32)
+		}
+		else {
+			None
+		}
+	}
 	fn clusters(&self) -> ClusterList {
 		if self.is_fixed_root() {
 			let root_cluster_count = (self.fs.root_sector_count as usize + self.fs.spc-1) / self.fs.spc;
@@ -66,23 +107,25 @@ impl DirNode {
 		match self.find_ent_by_cluster(ent_cluster)
 		{
 		None => None,
-		Some(e) =>
+		Some( (idx, e) ) =>
 			if e.attributes & on_disk::ATTR_DIRECTORY != 0 {
-				Some(node::Node::Dir(DirNode::new_boxed(self.fs.reborrow(), ent_cluster)))
+				Some(node::Node::Dir(DirNode::new_boxed_child(self.fs.reborrow(), ent_cluster, self.start_cluster, idx, e.short_name)))
 			}
 			else if e.attributes & on_disk::ATTR_VOLUMEID != 0 {
 				None
 			}
 			else {
 				Some(node::Node::File(FileNode::new_boxed(
-					self.fs.reborrow(), self.start_cluster, ent_cluster, e.size
+					self.fs.reborrow(), self.start_cluster, idx, e.short_name, ent_cluster, e.size
 					)))
 			},
 		}
 	}
 	
-	fn find_ent_by_cluster(&self, ent_cluster: u32) -> Option<DirEntShort> {
+	/// Locate an entry by its first cluster, returning the entry index and the entry
+	fn find_ent_by_cluster(&self, ent_cluster: u32) -> Option<(usize, DirEntShort)> {
 		log_trace!("find_ent_by_cluster(self={:?}, ent_cluster={})", self, ent_cluster);
+		let mut idx = 0;
 		for c in self.clusters()
 		{
 			let cluster = match self.fs.load_cluster(c) {
@@ -92,13 +135,495 @@ impl DirNode {
 			for ent in DirEnts::new(&cluster) {
 				if let DirEnt::Short(e) = ent {
 					if e.cluster == ent_cluster {
-						return Some(e);
+						return Some( (idx, e) );
 					}
 				}
+				idx += 1;
 			}
 		}
 		None
 	}
+
+	/// Locate an entry by name, returns the index of the first entry (including LFN entries), the index of the short entry, and the entry
+	fn find_ent_by_name(&self, name: &ByteStr) -> node::Result<Option<(usize, usize, DirEntShort)>> {
+		let mut lfn = LFN::new();
+		let mut lfn_start = 0;
+		let mut idx = 0;
+		for c in self.clusters()
+		{
+			let cluster = try!(self.fs.load_cluster(c));
+			for ent in DirEnts::new(&cluster)
+			{
+				match ent
+				{
+				DirEnt::End => return Ok(None),
+				DirEnt::Short(e) => {
+					let lfn_valid = lfn.is_valid();
+					if (lfn_valid && lfn.name() == name) || eq_ignore_case(e.name().as_bytes(), name.as_bytes()) {
+						let first = if lfn_valid { lfn_start } else { idx };
+						return Ok(Some( (first, idx, e) ));
+					}
+					lfn.clear();
+					},
+				DirEnt::Long(e) => {
+					if e.id & on_disk::LFN_LAST_ENTRY != 0 {
+						lfn_start = idx;
+					}
+					lfn.add(&e)
+					},
+				DirEnt::Empty => {
+					lfn.clear();
+					},
+				}
+				idx += 1;
+			}
+		}
+		Ok(None)
+	}
+
+	/// Check if a short name is already in use in this directory
+	fn short_name_exists(&self, short_name: &[u8; 11]) -> node::Result<bool> {
+		let decoded = decode_short_name(short_name);
+		let decoded = decoded.split(|&e| e == 0).next().unwrap();
+		for c in self.clusters()
+		{
+			let cluster = try!(self.fs.load_cluster(c));
+			for ent in DirEnts::new(&cluster)
+			{
+				match ent
+				{
+				DirEnt::End => return Ok(false),
+				DirEnt::Short(e) => if eq_ignore_case(e.name().as_bytes(), decoded) {
+					return Ok(true);
+					},
+				_ => {},
+				}
+			}
+		}
+		Ok(false)
+	}
+
+	/// Check that this directory only contains the `.` and `..` entries
+	fn is_empty(&self) -> node::Result<bool> {
+		for c in self.clusters()
+		{
+			let cluster = try!(self.fs.load_cluster(c));
+			for ent in DirEnts::new(&cluster)
+			{
+				match ent
+				{
+				DirEnt::End => return Ok(true),
+				DirEnt::Short(e) => if e.name() != ByteStr::new(".") && e.name() != ByteStr::new("..") {
+					return Ok(false);
+					},
+				_ => {},
+				}
+			}
+		}
+		Ok(true)
+	}
+
+	/// Locate (or make space for) `count` consecutive free entries.
+	///
+	/// Returns the index of the first entry, and true if the run is at the end of the directory.
+	fn alloc_entries(&self, count: usize) -> node::Result<(usize, bool)> {
+		let ents_per_cluster = self.fs.cluster_size / 32;
+		let clusters: Vec<u32> = self.clusters().collect();
+		let total_ents = match self.max_entries()
+			{
+			Some(m) => ::core::cmp::min(m, clusters.len() * ents_per_cluster),
+			None => clusters.len() * ents_per_cluster,
+			};
+
+		let mut run_start = 0;
+		let mut run_len = 0;
+		'search: for (ci,&c) in clusters.iter().enumerate()
+		{
+			let cluster = try!(self.fs.load_cluster(c));
+			for (i, ent) in cluster.chunks(32).enumerate()
+			{
+				let idx = ci * ents_per_cluster + i;
+				if idx >= total_ents {
+					break 'search;
+				}
+				if ent[0] == 0 {
+					// End of directory, everything from here on is free
+					if run_len == 0 {
+						run_start = idx;
+					}
+					run_len = total_ents - run_start;
+					if run_len >= count {
+						return Ok( (run_start, true) );
+					}
+					break 'search;
+				}
+				else if ent[0] == on_disk::DIRENT_DELETED {
+					if run_len == 0 {
+						run_start = idx;
+					}
+					run_len += 1;
+					if run_len == count {
+						return Ok( (run_start, false) );
+					}
+				}
+				else {
+					run_len = 0;
+				}
+			}
+		}
+
+		// No space, the directory needs to be extended
+		if self.is_fixed_root() {
+			return Err(vfs::Error::OutOfSpace);
+		}
+		// - If the free run doesn't reach the end of the directory, it's useless
+		if run_len > 0 && run_start + run_len != total_ents {
+			run_len = 0;
+		}
+		if run_len == 0 {
+			run_start = total_ents;
+		}
+		let new_clusters = ::kernel::lib::num::div_up(count - run_len, ents_per_cluster);
+		let mut prev = *clusters.last().expect("Directory with no clusters");
+		for _ in 0 .. new_clusters
+		{
+			let c = try!(self.fs.alloc_cluster(prev));
+			try!(self.fs.zero_cluster(c));
+			prev = c;
+		}
+		Ok( (run_start, true) )
+	}
+
+	/// Pick a unique short name for `name` (with a numeric tail if the long name doesn't fit)
+	///
+	/// Returns the short name, and true if it exactly represents the long name (and no LFN is needed)
+	fn pick_short_name(&self, name: &ByteStr) -> node::Result<([u8; 11], bool)> {
+		let (basis, exact) = make_short_name(name.as_bytes());
+		let mut short_name = basis;
+		if !exact {
+			let mut n = 1;
+			loop
+			{
+				apply_numeric_tail(&mut short_name, &basis, n);
+				if ! try!(self.short_name_exists(&short_name)) {
+					break;
+				}
+				n += 1;
+				if n == 1000000 {
+					return Err(vfs::Error::Unknown("Unable to generate a unique short name"));
+				}
+			}
+		}
+		Ok( (short_name, exact) )
+	}
+
+	/// Allocate space for and write a set of LFN entries followed by their short entry, returning the index of the first entry
+	///
+	/// NOTE: Caller must hold the directory lock
+	fn write_entries(&self, lfn_ents: &[on_disk::DirEntLong], short_ent: &on_disk::DirEnt) -> node::Result<usize> {
+		let count = lfn_ents.len() + 1;
+		let (first, at_end) = try!(self.alloc_entries(count));
+		// - If the entries were placed in the unused tail of the directory, make sure the following entry is an end marker
+		let extra = if at_end && self.max_entries().map(|m| first + count < m).unwrap_or(true) { 1 } else { 0 };
+		try!(self.edit_entries(first, count + extra, |idx, data| {
+			let i = idx - first;
+			if i < lfn_ents.len() {
+				lfn_ents[i].write(data);
+			}
+			else if i == lfn_ents.len() {
+				short_ent.write(data);
+			}
+			else {
+				data[0] = 0;
+			}
+			}));
+		Ok( first )
+	}
+
+	/// Call the provided closure on each 32-byte entry in the range `first .. first+count`
+	///
+	/// NOTE: Caller must hold the directory lock
+	fn edit_entries<F: FnMut(usize, &mut [u8])>(&self, first: usize, count: usize, mut f: F) -> node::Result<()> {
+		let ents_per_cluster = self.fs.cluster_size / 32;
+		let mut idx = first;
+		let mut clusters = self.clusters().skip(first / ents_per_cluster);
+		while idx < first + count
+		{
+			let c = match clusters.next()
+				{
+				Some(v) => v,
+				None => return Err(vfs::Error::InconsistentFilesystem),
+				};
+			let c_ofs = idx % ents_per_cluster;
+			let n = ::core::cmp::min(ents_per_cluster - c_ofs, first + count - idx);
+			try!(self.fs.edit_cluster(c, |data| {
+				for i in 0 .. n {
+					f(idx + i, &mut data[(c_ofs + i) * 32 ..][.. 32]);
+				}
+				}));
+			idx += n;
+		}
+		Ok( () )
+	}
+
+	/// Read the short entry at index `idx`
+	pub fn read_ent(&self, idx: usize) -> node::Result<on_disk::DirEnt> {
+		let ents_per_cluster = self.fs.cluster_size / 32;
+		let c = match self.clusters().skip(idx / ents_per_cluster).next()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::InconsistentFilesystem),
+			};
+		let data = try!(self.fs.load_cluster(c));
+		Ok( on_disk::DirEnt::read(&mut &data[(idx % ents_per_cluster) * 32 ..][.. 32]) )
+	}
+
+	/// Locate the short entry for the node starting at `cluster` (last seen at index `hint` with the name `short_name`)
+	///
+	/// NOTE: Caller must hold the directory lock
+	fn locate_ent(&self, hint: usize, cluster: u32, short_name: &[u8; 11]) -> node::Result<(usize, on_disk::DirEnt)> {
+		// The hint is only used if it's still the same entry (same first cluster and name)
+		let ent = try!(self.read_ent(hint));
+		let ent_cluster = (ent.cluster as u32) | (ent.cluster_hi as u32) << 16;
+		if ent.name[0] != 0 && ent.name[0] != on_disk::DIRENT_DELETED && ent.attribs != on_disk::ATTR_LFN
+			&& ent_cluster == cluster && ent.name == *short_name
+		{
+			return Ok( (hint, ent) );
+		}
+		// - Otherwise the entry has moved (e.g. the node was renamed), search for it by cluster
+		// - A node without clusters has a zero cluster, which doesn't identify it.
+		if cluster == 0 {
+			return Err(vfs::Error::NotFound);
+		}
+		match self.find_ent_by_cluster(cluster)
+		{
+		Some( (idx, _) ) => Ok( (idx, try!(self.read_ent(idx))) ),
+		None => Err(vfs::Error::NotFound),
+		}
+	}
+
+	/// Read the short entry for the node starting at `cluster` (see `locate_ent`)
+	pub fn find_ent(&self, hint: usize, cluster: u32, short_name: &[u8; 11]) -> node::Result<(usize, on_disk::DirEnt)> {
+		let _lh = self.fs.dir_lock.lock();
+		self.locate_ent(hint, cluster, short_name)
+	}
+
+	/// Update the short entry for the node starting at `cluster`, returning the entry's current index and new value
+	pub fn edit_ent<F: FnOnce(&mut on_disk::DirEnt)>(&self, hint: usize, cluster: u32, short_name: &[u8; 11], f: F) -> node::Result<(usize, on_disk::DirEnt)> {
+		let _lh = self.fs.dir_lock.lock();
+		let (idx, mut ent) = try!(self.locate_ent(hint, cluster, short_name));
+		f(&mut ent);
+		try!(self.edit_entries(idx, 1, |_, data| ent.write(data)));
+		Ok( (idx, ent) )
+	}
+}
+
+/// Build VFS metadata from a short directory entry
+pub fn ent_info(ent: &on_disk::DirEnt, size: u32) -> node::NodeInfo {
+	let modified = fat_timestamp(ent.modified_date, ent.modified_time);
+	node::NodeInfo {
+		size: size as u64,
+		mtime: modified,
+		// - FAT doesn't record metadata changes, use the modification time
+		ctime: modified,
+		atime: fat_timestamp(ent.accessed_date, 0),
+		// - The only permission FAT has is the read-only flag
+		mode: if ent.attribs & on_disk::ATTR_READONLY != 0 { 0o555 } else { 0o777 },
+		uid: 0,
+		gid: 0,
+		}
+}
+/// Convert a FAT date and time into a timestamp
+// NOTE: FAT stores local time, but there's no timezone information to adjust with
+fn fat_timestamp(date: u16, time: u16) -> ::kernel::time::Timestamp {
+	if date == 0 {
+		return 0;
+	}
+	::kernel::time::timestamp_from_date(
+		1980 + (date >> 9) as i64, ((date >> 5) & 0xF) as u8, (date & 0x1F) as u8,
+		(time >> 11) as u8, ((time >> 5) & 0x3F) as u8, ((time & 0x1F) * 2) as u8
+		)
+}
+
+fn eq_ignore_case(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b.iter()).all(|(a,b)| a.to_ascii_uppercase() == b.to_ascii_uppercase())
+}
+
+/// Convert a (UTF-8) name into UTF-16 for a long filename, checking for invalid characters
+fn encode_long_name(name: &ByteStr) -> node::Result<Vec<u16>> {
+	let s = match ::core::str::from_utf8(name.as_bytes())
+		{
+		Ok(v) => v,
+		Err(_) => return Err(vfs::Error::InvalidParameter),
+		};
+	if s == "" || s == "." || s == ".." || s.chars().all(|c| c == '.' || c == ' ') {
+		return Err(vfs::Error::InvalidParameter);
+	}
+	let mut rv = Vec::with_capacity(s.len());
+	for c in s.chars()
+	{
+		match c
+		{
+		'\0' ... '\x1F' | '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|' => return Err(vfs::Error::InvalidParameter),
+		_ => {},
+		}
+		let v = c as u32;
+		if v >= 0x10000 {
+			let v = v - 0x10000;
+			rv.push( 0xD800 | (v >> 10) as u16 );
+			rv.push( 0xDC00 | (v & 0x3FF) as u16 );
+		}
+		else {
+			rv.push(v as u16);
+		}
+	}
+	if rv.len() > MAX_LFN_LENGTH {
+		return Err(vfs::Error::InvalidParameter);
+	}
+	Ok(rv)
+}
+
+fn is_valid_short_char(b: u8) -> bool {
+	match b
+	{
+	b'A' ... b'Z' | b'0' ... b'9' => true,
+	b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'(' | b')' | b'-' | b'@' | b'^' | b'_' | b'`' | b'{' | b'}' | b'~' => true,
+	_ => false,
+	}
+}
+
+/// Generate the basis 8.3 name for a long name
+///
+/// Returns the space-padded name, and true if it exactly represents the long name (and no LFN is needed)
+fn make_short_name(name: &[u8]) -> ([u8; 11], bool) {
+	let mut rv = [b' '; 11];
+	let (base, ext) = match name.iter().rposition(|&b| b == b'.')
+		{
+		Some(0) | None => (name, &name[..0]),
+		Some(p) => (&name[..p], &name[p+1..]),
+		};
+	let exact_base = fill_short_part(&mut rv[..8], base);
+	let exact_ext = fill_short_part(&mut rv[8..], ext);
+	if rv[0] == b' ' {
+		rv[0] = b'_';
+	}
+	(rv, exact_base && exact_ext && name.last() != Some(&b'.'))
+}
+/// Fill one component of a short name, returning false if information was lost
+fn fill_short_part(dst: &mut [u8], src: &[u8]) -> bool {
+	let mut exact = true;
+	let mut o = 0;
+	for &b in src
+	{
+		// Spaces and dots are dropped from short names
+		if b == b' ' || b == b'.' {
+			exact = false;
+			continue ;
+		}
+		if o == dst.len() {
+			return false;
+		}
+		dst[o] = if is_valid_short_char(b) {
+				b
+			}
+			else if is_valid_short_char(b.to_ascii_uppercase()) {
+				// Lower-case names are stored with a LFN to preserve case
+				exact = false;
+				b.to_ascii_uppercase()
+			}
+			else {
+				exact = false;
+				b'_'
+			};
+		o += 1;
+	}
+	exact
+}
+
+/// Replace the end of a short name's base with a numeric tail (`~N`)
+fn apply_numeric_tail(short_name: &mut [u8; 11], basis: &[u8; 11], n: usize) {
+	assert!(n > 0 && n < 1000000);
+	// Build "~N" backwards
+	let mut tail_buf = [0u8; 8];
+	let mut ofs = tail_buf.len();
+	let mut v = n;
+	while v > 0 {
+		ofs -= 1;
+		tail_buf[ofs] = b'0' + (v % 10) as u8;
+		v /= 10;
+	}
+	ofs -= 1;
+	tail_buf[ofs] = b'~';
+	let tail = &tail_buf[ofs..];
+	let base_len = basis[..8].iter().position(|&b| b == b' ').unwrap_or(8);
+	let keep = ::core::cmp::min(base_len, 8 - tail.len());
+	short_name[..8].clone_from_slice(&[b' '; 8]);
+	short_name[..keep].clone_from_slice(&basis[..keep]);
+	short_name[keep..][..tail.len()].clone_from_slice(tail);
+	short_name[8..].clone_from_slice(&basis[8..]);
+}
+
+/// Decode a space-padded 8.3 name into a NUL-padded `BASE.EXT` string
+fn decode_short_name(name: &[u8; 11]) -> [u8; 8+1+3] {
+	let mut rv = [0u8; 8+1+3];
+	let mut o = 0;
+	for &b in name[..8].iter().filter(|&&b| b != b' ') {
+		rv[o] = b;
+		o += 1;
+	}
+	if name[8] != b' ' {
+		rv[o] = b'.';
+		o += 1;
+		for &b in name[8..].iter().filter(|&&b| b != b' ') {
+			rv[o] = b;
+			o += 1;
+		}
+	}
+	rv
+}
+
+/// Checksum of a short name, stored in each of the associated LFN entries
+fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+	short_name.iter().fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
+}
+
+/// Build the on-disk LFN entries for a name (in on-disk order)
+fn make_lfn_entries(name: &[u16], checksum: u8) -> Vec<on_disk::DirEntLong> {
+	let count = ::kernel::lib::num::div_up(name.len(), LFN_CHARS_PER_ENT);
+	let mut rv = Vec::with_capacity(count);
+	for i in (0 .. count).rev()
+	{
+		// Name is NUL terminated (unless it fills the entry), and padded with 0xFFFF
+		let mut chars = [0xFFFFu16; LFN_CHARS_PER_ENT];
+		let part = &name[i * LFN_CHARS_PER_ENT ..];
+		let part = if part.len() > LFN_CHARS_PER_ENT { &part[..LFN_CHARS_PER_ENT] } else { part };
+		chars[..part.len()].clone_from_slice(part);
+		if part.len() < LFN_CHARS_PER_ENT {
+			chars[part.len()] = 0;
+		}
+
+		let mut ent = on_disk::DirEntLong::default();
+		ent.id = (i + 1) as u8 | if i == count - 1 { on_disk::LFN_LAST_ENTRY } else { 0 };
+		ent.attrib = on_disk::ATTR_LFN;
+		ent.checksum = checksum;
+		ent.name1.clone_from_slice(&chars[0..5]);
+		ent.name2.clone_from_slice(&chars[5..11]);
+		ent.name3.clone_from_slice(&chars[11..13]);
+		rv.push(ent);
+	}
+	rv
+}
+
+/// Build a short directory entry
+fn make_short_entry(name: [u8; 11], attribs: u8, cluster: u32) -> on_disk::DirEnt {
+	// TODO: Timestamps (there's no wall-clock time source yet)
+	on_disk::DirEnt {
+		name: name,
+		attribs: attribs,
+		cluster: cluster as u16,
+		cluster_hi: (cluster >> 16) as u16,
+		.. Default::default()
+	}
 }
 
 /// Iterator over directory entries
@@ -119,6 +644,8 @@ enum DirEnt {
 struct DirEntShort {
 	/// NUL-padded string with extention joined
 	name: [u8; 11+1],
+	/// On-disk (space-padded 8.3) name
+	short_name: [u8; 11],
 	cluster: u32,
 	size: u32,
 	attributes: u8,
@@ -229,6 +756,7 @@ impl<'a> ::core::iter::Iterator for DirEnts<'a> {
 				// 3. Cluster, Size, Attribs
 				Some( DirEnt::Short(DirEntShort{
 					name: outname,
+					short_name: ent.name,
 					cluster: (ent.cluster as u32) | (ent.cluster_hi as u32) << 16,
 					size: ent.size,
 					attributes: ent.attribs,
@@ -364,13 +892,131 @@ impl node::Dir for DirNode {
 		Ok( cur_ofs )
 	}
 	fn create(&self, name: &ByteStr, nodetype: node::NodeType) -> node::Result<node::InodeId> {
-		todo!("DirNode::create('{:?}', {:?})", name, nodetype);
+		let is_dir = match nodetype
+			{
+			node::NodeType::File => false,
+			node::NodeType::Dir => true,
+			node::NodeType::Symlink(_) => return Err(vfs::Error::Unknown("FAT doesn't support symbolic links")),
+			};
+		let long_name = try!(encode_long_name(name));
+
+		let _lh = self.fs.dir_lock.lock();
+		if try!(self.find_ent_by_name(name)).is_some() {
+			return Err(vfs::Error::AlreadyExists);
+		}
+
+		// 1. Pick a short name (with a numeric tail if the long name doesn't fit)
+		let (short_name, exact) = try!(self.pick_short_name(name));
+		let lfn_ents = if exact { Vec::new() } else { make_lfn_entries(&long_name, lfn_checksum(&short_name)) };
+
+		// 2. Allocate the first cluster of the new node.
+		// - Files always get a cluster, so the inode number (derived from the cluster) is unique and stable
+		let cluster = try!(self.fs.alloc_cluster(0));
+		if is_dir {
+			// Populate the `.` and `..` entries (`..` is zero when it refers to the root)
+			let parent = if self.start_cluster == self.fs.root_first_cluster { 0 } else { self.start_cluster };
+			let mut data = Vec::from_elem(self.fs.cluster_size, 0u8);
+			make_short_entry(*b".          ", on_disk::ATTR_DIRECTORY, cluster).write(&mut data[0..]);
+			make_short_entry(*b"..         ", on_disk::ATTR_DIRECTORY, parent).write(&mut data[32..]);
+			try!(self.fs.write_clusters(cluster, &data));
+		}
+		let short_ent = make_short_entry(short_name, if is_dir { on_disk::ATTR_DIRECTORY } else { on_disk::ATTR_ARCHIVE }, cluster);
+
+		// 3. Write the entries
+		let first = match self.write_entries(&lfn_ents, &short_ent)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				try!(self.fs.free_chain(cluster));
+				return Err(e);
+				},
+			};
+		log_debug!("create: {:?} = {:?} at {}+{} (cluster {:#x})", name, ::kernel::lib::RawString(&short_name), first, lfn_ents.len() + 1, cluster);
+
+		Ok( super::InodeRef::new(cluster, self.start_cluster).to_id() )
 	}
 	fn link(&self, name: &ByteStr, node: &node::NodeBase) -> node::Result<()> {
-		todo!("DirNode::link('{:?}', {:#x})", name, node.get_id());
+		// FAT stores file metadata in the directory entry, so a node can only have one name
+		log_notice!("DirNode::link('{:?}', {:#x}) - Hard links not supported by FAT", name, node.get_id());
+		Err(vfs::Error::Unknown("FAT doesn't support hard links"))
 	}
 	fn unlink(&self, name: &ByteStr) -> node::Result<()> {
-		todo!("DirNode::unlink('{:?}')", name);
+		if name == ByteStr::new(".") || name == ByteStr::new("..") {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let _lh = self.fs.dir_lock.lock();
+		let (first, short_idx, ent) = match try!(self.find_ent_by_name(name))
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::NotFound),
+			};
+		if ent.attributes & on_disk::ATTR_DIRECTORY != 0 {
+			if ! try!(DirNode::new(self.fs.reborrow(), ent.cluster).is_empty()) {
+				return Err(vfs::Error::Unknown("Directory not empty"));
+			}
+		}
+
+		// Mark the short entry and any associated LFN entries as deleted
+		try!(self.edit_entries(first, short_idx - first + 1, |_, data| data[0] = on_disk::DIRENT_DELETED));
+		// TODO: Defer freeing the clusters until open handles are closed
+		if ent.cluster != 0 {
+			try!(self.fs.free_chain(ent.cluster));
+		}
+		Ok( () )
 	}
-}
+	fn rename(&self, src_name: &ByteStr, dst_dir: &node::Dir, dst_name: &ByteStr) -> node::Result<()> {
+		if src_name == ByteStr::new(".") || src_name == ByteStr::new("..") {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let dst: &DirNode = match dst_dir.get_any().downcast_ref()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::CrossFilesystem),
+			};
+		if &*dst.fs as *const _ != &*self.fs as *const _ {
+			return Err(vfs::Error::CrossFilesystem);
+		}
+		let same_dir = dst.start_cluster == self.start_cluster;
+		let long_name = try!(encode_long_name(dst_name));
+
+		// A single lock covers all directories on the filesystem, so the move is atomic
+		let _lh = self.fs.dir_lock.lock();
+		let (first, short_idx, src_ent) = match try!(self.find_ent_by_name(src_name))
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::NotFound),
+			};
+		match try!(dst.find_ent_by_name(dst_name))
+		{
+		// Renaming to the same name (possibly with different case) just rewrites the entries
+		Some( (_, idx, _) ) if same_dir && idx == short_idx => {},
+		Some(_) => return Err(vfs::Error::AlreadyExists),
+		None => {},
+		}
+
+		// 1. Build the new entries, keeping the metadata from the existing short entry
+		let mut short_ent = try!(self.read_ent(short_idx));
+		let (short_name, exact) = try!(dst.pick_short_name(dst_name));
+		let lfn_ents = if exact { Vec::new() } else { make_lfn_entries(&long_name, lfn_checksum(&short_name)) };
+		short_ent.name = short_name;
+		short_ent.lcase = 0;
+
+		// 2. Write the new entries, then release the old ones (entries never move, so the indexes are still valid)
+		try!(dst.write_entries(&lfn_ents, &short_ent));
+		try!(self.edit_entries(first, short_idx - first + 1, |_, data| data[0] = on_disk::DIRENT_DELETED));
 
+		// 3. A moved directory's `..` must refer to its new parent (zero when that's the root)
+		if src_ent.attributes & on_disk::ATTR_DIRECTORY != 0 && !same_dir {
+			let parent = if dst.start_cluster == self.fs.root_first_cluster { 0 } else { dst.start_cluster };
+			let moved = DirNode::new(self.fs.reborrow(), src_ent.cluster);
+			try!(moved.edit_entries(1, 1, |_, data| {
+				let mut ent = on_disk::DirEnt::read(&mut &data[..]);
+				ent.cluster = parent as u16;
+				ent.cluster_hi = (parent >> 16) as u16;
+				ent.write(data);
+				}));
+		}
+		log_debug!("rename: {:?} -> {:?} ({:?})", src_name, dst_name, ::kernel::lib::RawString(&short_name));
+		Ok( () )
+	}
+}
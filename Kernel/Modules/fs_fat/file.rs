@@ -1,36 +1,172 @@
 // "Tifflin" Kernel
 // - By John Hodge (thePowersGang)
 //
-// Modules/fs_fat/dir.rs
+// Modules/fs_fat/file.rs
 use kernel::prelude::*;
 use kernel::lib::mem::aref::ArefBorrow;
 use kernel::vfs::{self, node};
+use kernel::sync::Mutex;
 use super::FilesystemInner;
+use super::dir::DirNode;
 
 const ERROR_SHORTCHAIN: vfs::Error = vfs::Error::Unknown("Cluster chain terminated early");
+/// Maximum size of a FAT file
+const MAX_FILE_SIZE: u64 = 0xFFFF_FFFF;
 
 pub struct FileNode
 {
 	fs: ArefBorrow<FilesystemInner>,
-	//parent_dir: u32,
+	/// First cluster of the containing directory
+	/// - Fixed, as the VFS doesn't move nodes to another directory while they're in use
+	parent_dir: u32,
+	/// Inode number the node was opened with (stays the same if a cluster is allocated later)
+	inode: node::InodeId,
+	state: Mutex<FileState>,
+}
+struct FileState
+{
 	first_cluster: u32,
 	size: u32,
+	/// Index of the file's (short) entry in the parent directory
+	/// - Only a hint, the entry can move when the file is renamed
+	dir_ent: usize,
+	/// Short name of the entry at `dir_ent` (used to validate the hint)
+	short_name: [u8; 11],
 }
 
 impl FileNode
 {
-	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, _parent: u32, first_cluster: u32, size: u32) -> Box<FileNode> {	
+	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, parent: u32, dir_ent: usize, short_name: [u8; 11], first_cluster: u32, size: u32) -> Box<FileNode> {	
 		Box::new(FileNode {
 			fs: fs,
-			//parent_dir: parent,
-			first_cluster: first_cluster,
-			size: size,
+			parent_dir: parent,
+			inode: super::InodeRef::new(first_cluster, parent).to_id(),
+			state: Mutex::new(FileState {
+				first_cluster: first_cluster,
+				size: size,
+				dir_ent: dir_ent,
+				short_name: short_name,
+				}),
 			})
 	}
+
+	/// Write the size and first cluster (`cluster`) back to the directory entry
+	///
+	/// The entry is located using the current first cluster, which is then updated to `cluster`
+	fn update_dirent(&self, st: &mut FileState, cluster: u32) -> node::Result<()> {
+		let dir = DirNode::new(self.fs.reborrow(), self.parent_dir);
+		let size = st.size;
+		let (idx, ent) = try!(dir.edit_ent(st.dir_ent, st.first_cluster, &st.short_name, |ent| {
+			ent.size = size;
+			ent.cluster = cluster as u16;
+			ent.cluster_hi = (cluster >> 16) as u16;
+			}));
+		st.first_cluster = cluster;
+		st.dir_ent = idx;
+		st.short_name = ent.name;
+		Ok( () )
+	}
+
+	/// Ensure that enough clusters are allocated to hold `size` bytes
+	fn reserve_clusters(&self, st: &mut FileState, size: u64) -> node::Result<()> {
+		let cs = self.fs.cluster_size as u64;
+		let needed = ::core::cmp::max(1, ::kernel::lib::num::div_up(size, cs));
+		if st.first_cluster == 0 {
+			// Empty file with no clusters (created by another driver)
+			let cluster = try!(self.fs.alloc_cluster(0));
+			if let Err(e) = self.update_dirent(st, cluster) {
+				try!(self.fs.free_chain(cluster));
+				return Err(e);
+			}
+		}
+		let mut count = 1;
+		let mut last = st.first_cluster;
+		while let Some(next) = try!(self.fs.get_next_cluster(last)) {
+			last = next;
+			count += 1;
+		}
+		while count < needed {
+			last = try!(self.fs.alloc_cluster(last));
+			count += 1;
+		}
+		Ok( () )
+	}
+
+	/// Write to already-allocated clusters
+	fn write_data(&self, st: &FileState, ofs: u64, buf: &[u8]) -> node::Result<()> {
+		let cs = self.fs.cluster_size;
+		// Seek to correct position in the cluster chain
+		let mut clusters = super::ClusterList::chained(self.fs.reborrow(), st.first_cluster);
+		for _ in 0 .. (ofs / cs as u64) {
+			clusters.next();
+		}
+		let ofs = (ofs % cs as u64) as usize;
+
+		// First incomplete cluster
+		let mut written = 0;
+		if ofs != 0 {
+			let cluster = match clusters.next()
+				{
+				Some(v) => v,
+				None => return Err( ERROR_SHORTCHAIN ),
+				};
+			let short_count = ::core::cmp::min(cs - ofs, buf.len());
+			try!(self.fs.edit_cluster(cluster, |data| data[ofs..][..short_count].clone_from_slice( &buf[..short_count] )));
+			written += short_count;
+		}
+		// Complete clusters
+		while buf.len() - written >= cs
+		{
+			let src = &buf[written..];
+			let (cluster, count) = match clusters.next_extent( src.len() / cs )
+				{
+				Some(v) => v,
+				None => return Err(ERROR_SHORTCHAIN),
+				};
+			let bytes = count * cs;
+			log_trace!("- Write cluster {}+{}", cluster, count);
+			try!(self.fs.write_clusters(cluster, &src[..bytes]));
+			written += bytes;
+		}
+		// Trailing partial cluster
+		if buf.len() - written > 0
+		{
+			let src = &buf[written..];
+			let cluster = match clusters.next()
+				{
+				Some(v) => v,
+				None => return Err(ERROR_SHORTCHAIN),
+				};
+			try!(self.fs.edit_cluster(cluster, |data| data[..src.len()].clone_from_slice(src)));
+		}
+		Ok( () )
+	}
+
+	/// Zero a range of allocated clusters
+	fn zero_range(&self, st: &FileState, mut ofs: u64, mut len: u64) -> node::Result<()> {
+		// Zero up to 16 clusters at a time
+		let zeroes = Vec::from_elem(self.fs.cluster_size * 16, 0u8);
+		while len > 0
+		{
+			let n = ::core::cmp::min(len, zeroes.len() as u64);
+			try!(self.write_data(st, ofs, &zeroes[..n as usize]));
+			ofs += n;
+			len -= n;
+		}
+		Ok( () )
+	}
 }
 impl node::NodeBase for FileNode {
 	fn get_id(&self) -> node::InodeId {
-		todo!("FileNode::get_id")
+		self.inode
+	}
+	fn get_info(&self) -> node::Result<node::NodeInfo> {
+		let mut st = self.state.lock();
+		let dir = DirNode::new(self.fs.reborrow(), self.parent_dir);
+		let (idx, ent) = try!(dir.find_ent(st.dir_ent, st.first_cluster, &st.short_name));
+		st.dir_ent = idx;
+		st.short_name = ent.name;
+		Ok( super::dir::ent_info(&ent, st.size) )
 	}
 	fn get_any(&self) -> &::core::any::Any {
 		self
@@ -38,29 +174,64 @@ impl node::NodeBase for FileNode {
 }
 impl node::File for FileNode {
 	fn size(&self) -> u64 {
-		self.size as u64
+		self.state.lock().size as u64
 	}
 	fn truncate(&self, newsize: u64) -> node::Result<u64> {
-		todo!("FileNode::truncate({:#x})", newsize);
+		if newsize > MAX_FILE_SIZE {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		let mut st = self.state.lock();
+		let oldsize = st.size as u64;
+		if newsize > oldsize {
+			try!(self.reserve_clusters(&mut st, newsize));
+			try!(self.zero_range(&st, oldsize, newsize - oldsize));
+		}
+		else if newsize < oldsize && st.first_cluster != 0 {
+			// Release clusters past the new end (keeping the first, as it identifies the file)
+			let keep = ::core::cmp::max(1, ::kernel::lib::num::div_up(newsize, self.fs.cluster_size as u64));
+			let mut last = st.first_cluster;
+			for _ in 1 .. keep {
+				last = match try!(self.fs.get_next_cluster(last))
+					{
+					Some(v) => v,
+					None => return Err(ERROR_SHORTCHAIN),
+					};
+			}
+			if let Some(next) = try!(self.fs.get_next_cluster(last)) {
+				try!(self.fs.end_chain(last));
+				try!(self.fs.free_chain(next));
+			}
+		}
+		if newsize != oldsize {
+			st.size = newsize as u32;
+			let cluster = st.first_cluster;
+			try!(self.update_dirent(&mut st, cluster));
+		}
+		Ok( newsize )
 	}
 	fn clear(&self, ofs: u64, size: u64) -> node::Result<()> {
-		todo!("FileNode::clear({:#x}+{:#x}", ofs, size);
+		let st = self.state.lock();
+		if ofs > st.size as u64 || size > st.size as u64 - ofs {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		self.zero_range(&st, ofs, size)
 	}
 	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		let st = self.state.lock();
 		// Sanity check and bound parameters
-		if ofs > self.size as u64 {
+		if ofs > st.size as u64 {
 			// out of range
 			return Err( vfs::Error::InvalidParameter );
 		}
-		if ofs == self.size as u64 {
+		if ofs == st.size as u64 {
 			return Ok(0);
 		}
-		let maxread = (self.size as u64 - ofs) as usize;
+		let maxread = (st.size as u64 - ofs) as usize;
 		let buf = if buf.len() > maxread { &mut buf[..maxread] } else { buf };
 		let read_length = buf.len();
 		
 		// Seek to correct position in the cluster chain
-		let mut clusters = super::ClusterList::chained(self.fs.reborrow(), self.first_cluster);
+		let mut clusters = super::ClusterList::chained(self.fs.reborrow(), st.first_cluster);
 		for _ in 0 .. (ofs/self.fs.cluster_size as u64) {
 			clusters.next();
 		}
@@ -150,7 +321,28 @@ impl node::File for FileNode {
 	}
 	/// Write data to the file, can only grow the file if ofs==size
 	fn write(&self, ofs: u64, buf: &[u8]) -> node::Result<usize> {
-		todo!("FileNode::write({:#x}, {:p})", ofs, ::kernel::lib::SlicePtr(buf));
+		let mut st = self.state.lock();
+		if ofs > st.size as u64 {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		if buf.len() == 0 {
+			return Ok(0);
+		}
+		let end = ofs + buf.len() as u64;
+		if end > MAX_FILE_SIZE {
+			return Err( vfs::Error::InvalidParameter );
+		}
+
+		if end > st.size as u64 {
+			try!(self.reserve_clusters(&mut st, end));
+		}
+		try!(self.write_data(&st, ofs, buf));
+		if end > st.size as u64 {
+			st.size = end as u32;
+			let cluster = st.first_cluster;
+			try!(self.update_dirent(&mut st, cluster));
+		}
+		Ok( buf.len() )
 	}
 }
 
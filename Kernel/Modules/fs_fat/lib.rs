@@ -13,6 +13,7 @@ use kernel::vfs::{self, mount, node};
 use kernel::metadevs::storage::{self,VolumeHandle,SizePrinter};
 use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
 use kernel::lib::mem::Arc;
+use kernel::sync::Mutex;
 
 extern crate utf16;
 extern crate blockcache;
@@ -26,9 +27,16 @@ const FAT32_MIN_CLUSTERS: usize = 65525;
 /// FAT Legacy (pre 32) root cluster base. Just has to be above the max cluster num for FAT16
 const FATL_ROOT_CLUSTER: u32 = 0x00FF0000;
 
+/// End-of-chain markers (written when terminating a chain)
 const FAT12_EOC: u16 = 0x0FFF;
 const FAT16_EOC: u16 = 0xFFFF;
-const FAT32_EOC: u32 = 0x00FFFFFF;
+const FAT32_EOC: u32 = 0x0FFFFFFF;
+/// Any FAT value at or above these values marks the end of a chain
+const FAT12_EOC_MIN: u32 = 0x0FF8;
+const FAT16_EOC_MIN: u32 = 0xFFF8;
+const FAT32_EOC_MIN: u32 = 0x0FFFFFF8;
+/// Only the low 28 bits of a FAT32 entry are used
+const FAT32_ENTRY_MASK: u32 = 0x0FFFFFFF;
 
 /// on-disk structures
 mod on_disk;
@@ -69,6 +77,13 @@ pub struct FilesystemInner
 	/// Total number of data clusters
 	cluster_count: usize,
 	first_fat_sector: usize,
+	/// Number of sectors in each FAT
+	fat_size: usize,
+	fat_count: usize,
+	/// FAT that is read from (FAT32 can disable mirroring)
+	active_fat: usize,
+	/// If false, only the active FAT is updated
+	fat_mirrored: bool,
 	first_data_sector: usize,
 	
 	root_first_cluster: u32,
@@ -78,6 +93,21 @@ pub struct FilesystemInner
 	// XXX: Should really use the above line for this, but BlockCache exists
 	/// A cache of metadata clusters (i.e. directories)
 	metadata_block_cache: ::blockcache::BlockCache,
+
+	/// FAT32 FSInfo sector (if present and valid)
+	fs_info_sector: Option<u64>,
+	/// Cluster allocation state, the lock is also held for all FAT updates
+	alloc_info: Mutex<AllocInfo>,
+	/// Lock held while modifying directory contents
+	dir_lock: Mutex<()>,
+}
+
+struct AllocInfo
+{
+	/// Number of free clusters (!0 if unknown)
+	free_count: u32,
+	/// Cluster to start the next free cluster search from
+	next_free: u32,
 }
 
 /// Inodes IDs destrucure into two 28-bit cluster IDs, and a 16-bit dir offset
@@ -125,7 +155,8 @@ impl mount::Driver for Driver
 			Ok(1)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle) -> vfs::Result<Box<mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, options: &[&str]) -> vfs::Result<Box<mount::Filesystem>> {
+		try!(mount::reject_options(options));
 		let vol = ::block_cache::CacheHandle::new(vol);
 
 		// Read the bootsector
@@ -179,6 +210,48 @@ impl mount::Driver for Driver
 			};
 		log_debug!("{:?} {} sectors, Size {}", fat_type, total_sectors,
 			SizePrinter((total_sectors*bs_c.bps as usize) as u64));
+
+		// FAT32 mirroring flags (bit 7 set = only the FAT in bits 0-3 is active)
+		let (active_fat, fat_mirrored) = match bs.info32()
+			{
+			Some(info) if info.ext_flags & 0x80 != 0 => ((info.ext_flags & 0xF) as usize, false),
+			_ => (0, true),
+			};
+		if active_fat >= bs_c.fat_count as usize {
+			return Err(vfs::Error::Unknown("Active FAT index out of range"));
+		}
+
+		// Load the free cluster hints from the FSInfo sector
+		let mut alloc_info = AllocInfo {
+			free_count: !0,
+			next_free: 2,
+			};
+		let fs_info_sector = match bs.info32()
+			{
+			Some(info) if info.fs_info != 0 && info.fs_info != 0xFFFF => {
+				let sector = info.fs_info as u64;
+				let blk = try!(vol.get_block(sector));
+				let ofs = (sector - blk.index()) as usize * vol.block_size();
+				match on_disk::FsInfo::read(&blk.data()[ofs..][..512])
+				{
+				Some(fi) => {
+					log_debug!("FSInfo: free_count={}, next_free={:#x}", fi.free_count, fi.next_free);
+					if fi.free_count as usize <= cluster_count {
+						alloc_info.free_count = fi.free_count;
+					}
+					if fi.next_free >= 2 && (fi.next_free as usize) < cluster_count + 2 {
+						alloc_info.next_free = fi.next_free;
+					}
+					Some(sector)
+					},
+				None => {
+					log_warning!("FAT32 FSInfo sector has invalid signatures, ignoring");
+					None
+					},
+				}
+				},
+			_ => None,
+			};
 		
 		Ok(Box::new(Filesystem {
 			// SAFE: Saving to a Box, so won't move
@@ -188,6 +261,10 @@ impl mount::Driver for Driver
 				cluster_size: spc * vol.block_size(),
 				cluster_count: cluster_count,
 				first_fat_sector: bs_c.reserved_sect_count as usize,
+				fat_size: fat_size,
+				fat_count: bs_c.fat_count as usize,
+				active_fat: active_fat,
+				fat_mirrored: fat_mirrored,
 				first_data_sector: first_data_sector,
 				root_first_cluster: match fat_type {
 					Size::Fat32 => bs.info32().unwrap().root_cluster,
@@ -197,6 +274,10 @@ impl mount::Driver for Driver
 				
 				metadata_block_cache: ::blockcache::BlockCache::new(),
 
+				fs_info_sector: fs_info_sector,
+				alloc_info: Mutex::new(alloc_info),
+				dir_lock: Mutex::new( () ),
+
 				vh: vol,
 				}) },
 			}))
@@ -207,6 +288,23 @@ type Cluster = Arc<[u8]>;
 
 impl FilesystemInner
 {
+	/// Get the first sector of a cluster
+	fn cluster_to_sector(&self, cluster: u32) -> u64 {
+		if !is!(self.ty, Size::Fat32) && cluster >= FATL_ROOT_CLUSTER {
+			// Root directory (for FAT12/16, where it was not a normal file)
+			let rc = cluster - FATL_ROOT_CLUSTER;
+			assert!( (rc as u64 * self.spc as u64) < self.root_sector_count as u64);
+			(self.first_data_sector - self.root_sector_count as usize) as u64
+			+ (rc * self.spc as u32) as u64
+		}
+		else {
+			// Anything else
+			assert!(cluster >= 2);
+			assert!(cluster - 2 < self.cluster_count as u32);
+			self.first_data_sector as u64 + (cluster as u64 - 2) * self.spc as u64
+		}
+	}
+
 	/// Load a cluster from disk
 	fn read_cluster(&self, cluster: u32, dst: &mut [u8]) -> Result<(), storage::IoError> {
 		assert_eq!(dst.len(), self.cluster_size);
@@ -216,24 +314,32 @@ impl FilesystemInner
 		log_trace!("Filesystem::read_clusters({:#x}, {})", cluster, dst.len() / self.cluster_size);
 		assert_eq!(dst.len() % self.cluster_size, 0);
 		// For now, just read the bytes, screw caching
-		let sector = if !is!(self.ty, Size::Fat32) && cluster >= FATL_ROOT_CLUSTER {
-				// Root directory (for FAT12/16, where it was not a normal file)
-				let rc = cluster - FATL_ROOT_CLUSTER;
-				assert!( (rc as u64 * self.spc as u64) < self.root_sector_count as u64);
-				(self.first_data_sector - self.root_sector_count as usize) as u64
-				+ (rc * self.spc as u32) as u64
-			}
-			else {
-				// Anything else
-				assert!(cluster >= 2);
-				assert!(cluster - 2 < self.cluster_count as u32);
-				self.first_data_sector as u64 + (cluster as u64 - 2) * self.spc as u64
-			};
+		let sector = self.cluster_to_sector(cluster);
 		log_debug!("read_clusters: cluster = {:#x}, sector = 0x{:x}", cluster, sector);
 		try!(self.vh.read_blocks(sector, dst));
 		//::kernel::logging::hex_dump("FAT Cluster", &buf);
 		Ok( () )
 	}
+	/// Write a run of contiguous clusters to disk
+	fn write_clusters(&self, cluster: u32, src: &[u8]) -> Result<(), storage::IoError> {
+		log_trace!("Filesystem::write_clusters({:#x}, {})", cluster, src.len() / self.cluster_size);
+		assert_eq!(src.len() % self.cluster_size, 0);
+		let sector = self.cluster_to_sector(cluster);
+		// The FAT12/16 root directory doesn't have to be a multiple of the cluster size, don't write past its end
+		let write_len = if !is!(self.ty, Size::Fat32) && cluster >= FATL_ROOT_CLUSTER {
+				let root_end = (self.first_data_sector as u64 - sector) as usize * self.vh.block_size();
+				::core::cmp::min(root_end, src.len())
+			}
+			else {
+				src.len()
+			};
+		try!(self.vh.write_blocks(sector, &src[..write_len]));
+		// Drop stale copies from the metadata cache
+		for i in 0 .. (src.len() / self.cluster_size) as u32 {
+			self.metadata_block_cache.invalidate(cluster + i);
+		}
+		Ok( () )
+	}
 
 	// TODO: Locking/Cache
 	// - Should this function lock the cluster somehow to prevent accidental overlap?
@@ -249,60 +355,234 @@ impl FilesystemInner
 				Ok( buf )
 			})
 	}
-	
-	/// Obtain the next cluster in a chain
-	fn get_next_cluster(&self, cluster: u32) -> Result< Option<u32>, storage::IoError > {
-		use kernel::lib::byteorder::{ReadBytesExt,LittleEndian};
-		// - Determine what sector contains the requested FAT entry
-		let bs = self.vh.block_size();
-		let (fat_sector,ofs) = match self.ty
-			{
-			Size::Fat12 => {
-				let cps = bs / 3 * 2;	// 2 per 3 bytes
-				(cluster as usize / cps, (cluster as usize % cps) / 2 * 3 )
-				},
-			Size::Fat16 => {
-				let cps = bs / 2;
-				(cluster as usize / cps, (cluster as usize % cps) * 2)
-				},
-			Size::Fat32 => {
-				let cps = bs / 4;
-				(cluster as usize / cps, (cluster as usize % cps) * 2)
-				},
-			};
+	/// Read-modify-write a single cluster
+	fn edit_cluster<F: FnOnce(&mut [u8])->R, R>(&self, cluster: u32, f: F) -> Result<R, storage::IoError>
+	{
+		let mut buf: Vec<u8> = Vec::from( &try!(self.load_cluster(cluster))[..] );
+		let rv = f(&mut buf);
+		try!(self.write_clusters(cluster, &buf));
+		Ok( rv )
+	}
+	/// Fill a cluster with zeroes
+	fn zero_cluster(&self, cluster: u32) -> Result<(), storage::IoError> {
+		self.write_clusters(cluster, &Vec::from_elem(self.cluster_size, 0u8))
+	}
+}
 
-		// - Read a single sector from the FAT
-		let sector_idx = (self.first_fat_sector + fat_sector) as u64;
+/// FAT access and cluster allocation
+impl FilesystemInner
+{
+	/// Byte offset of a cluster's entry within the FAT
+	fn fat_entry_ofs(&self, cluster: u32) -> usize {
+		match self.ty
+		{
+		Size::Fat12 => cluster as usize + cluster as usize / 2,	// 2 entries per 3 bytes
+		Size::Fat16 => cluster as usize * 2,
+		Size::Fat32 => cluster as usize * 4,
+		}
+	}
+	/// Read bytes from the active FAT (the range must not span a sector boundary)
+	fn read_fat_bytes(&self, byte_ofs: usize, dst: &mut [u8]) -> Result<(), storage::IoError> {
+		let bs = self.vh.block_size();
+		let ofs = byte_ofs % bs;
+		assert!(ofs + dst.len() <= bs);
+		let sector_idx = (self.first_fat_sector + self.active_fat * self.fat_size + byte_ofs / bs) as u64;
 		let sector_data_blk = try!(self.vh.get_block( sector_idx ));
 		let start_ofs = (sector_idx - sector_data_blk.index()) as usize * bs;
-		let sector_data = &sector_data_blk.data()[start_ofs .. ];
-		//log_debug!("Sector {} accessed via cached block at sector {}", sector_idx, sector_data_blk.index());
-		//::kernel::logging::hex_dump("FAT FAT", &sector_data[..bs]);
+		let len = dst.len();
+		dst.clone_from_slice( &sector_data_blk.data()[start_ofs + ofs ..][.. len] );
+		Ok( () )
+	}
+	/// Write bytes to the FAT (all copies if mirroring is enabled)
+	fn write_fat_bytes(&self, byte_ofs: usize, src: &[u8]) -> Result<(), storage::IoError> {
+		let bs = self.vh.block_size();
+		let ofs = byte_ofs % bs;
+		assert!(ofs + src.len() <= bs);
+		for fat in 0 .. self.fat_count
+		{
+			if !self.fat_mirrored && fat != self.active_fat {
+				continue ;
+			}
+			let sector_idx = (self.first_fat_sector + fat * self.fat_size + byte_ofs / bs) as u64;
+			try!(self.vh.edit(sector_idx, 1, |data| data[ofs..][..src.len()].clone_from_slice(src)));
+		}
+		Ok( () )
+	}
 
-		// - Extract the entry
+	/// Read a raw FAT entry
+	fn get_fat_entry(&self, cluster: u32) -> Result<u32, storage::IoError> {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		let ofs = self.fat_entry_ofs(cluster);
 		Ok(match self.ty
 		{
 		Size::Fat12 => {
-			// FAT12 has special handling because it packs 2 entries into 24 bytes
-			let val = {
-				let v24 = (&sector_data[ofs..]).read_uint::<LittleEndian>(3).unwrap();
-				if cluster % 2 == 0 { v24 & 0xFFF } else { v24 >> 12 }
-				} as u16;
-			if val == 0 { return Err(storage::IoError::Unknown("FAT: Zero FAT entry")); }
-			if val == FAT12_EOC { None } else { Some(val as u32) }
+			// FAT12 entries can straddle a sector boundary, so read each byte separately
+			let mut b = [0u8; 2];
+			try!(self.read_fat_bytes(ofs, &mut b[..1]));
+			try!(self.read_fat_bytes(ofs+1, &mut b[1..]));
+			let v16 = LittleEndian::read_u16(&b) as u32;
+			if cluster % 2 == 0 { v16 & 0xFFF } else { v16 >> 4 }
 			},
 		Size::Fat16 => {
-			let val = (&sector_data[ofs..]).read_u16::<LittleEndian>().unwrap();
-			if val == 0 { return Err(storage::IoError::Unknown("FAT: Zero FAT entry")); }
-			if val == FAT16_EOC { None } else { Some(val as u32) }
+			let mut b = [0u8; 2];
+			try!(self.read_fat_bytes(ofs, &mut b));
+			LittleEndian::read_u16(&b) as u32
 			},
 		Size::Fat32 => {
-			let val = (&sector_data[ofs..]).read_u32::<LittleEndian>().unwrap();
-			if val == 0 { return Err(storage::IoError::Unknown("FAT: Zero FAT entry")); }
-			if val == FAT32_EOC { None } else { Some(val as u32) }
+			let mut b = [0u8; 4];
+			try!(self.read_fat_bytes(ofs, &mut b));
+			LittleEndian::read_u32(&b) & FAT32_ENTRY_MASK
 			},
 		})
 	}
+	/// Update a FAT entry
+	///
+	/// NOTE: Caller must hold the `alloc_info` lock (FAT12 entries share bytes with their neighbours)
+	fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), storage::IoError> {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		let ofs = self.fat_entry_ofs(cluster);
+		match self.ty
+		{
+		Size::Fat12 => {
+			let mut b = [0u8; 2];
+			try!(self.read_fat_bytes(ofs, &mut b[..1]));
+			try!(self.read_fat_bytes(ofs+1, &mut b[1..]));
+			let v16 = LittleEndian::read_u16(&b);
+			let v16 = if cluster % 2 == 0 {
+					(v16 & 0xF000) | (value as u16 & 0xFFF)
+				}
+				else {
+					(v16 & 0x000F) | (value as u16) << 4
+				};
+			LittleEndian::write_u16(&mut b, v16);
+			try!(self.write_fat_bytes(ofs, &b[..1]));
+			try!(self.write_fat_bytes(ofs+1, &b[1..]));
+			},
+		Size::Fat16 => {
+			let mut b = [0u8; 2];
+			LittleEndian::write_u16(&mut b, value as u16);
+			try!(self.write_fat_bytes(ofs, &b));
+			},
+		Size::Fat32 => {
+			// The top four bits are reserved, and must be preserved
+			let mut b = [0u8; 4];
+			try!(self.read_fat_bytes(ofs, &mut b));
+			let v = (LittleEndian::read_u32(&b) & !FAT32_ENTRY_MASK) | (value & FAT32_ENTRY_MASK);
+			LittleEndian::write_u32(&mut b, v);
+			try!(self.write_fat_bytes(ofs, &b));
+			},
+		}
+		Ok( () )
+	}
+	fn is_eoc(&self, value: u32) -> bool {
+		match self.ty
+		{
+		Size::Fat12 => value >= FAT12_EOC_MIN,
+		Size::Fat16 => value >= FAT16_EOC_MIN,
+		Size::Fat32 => value >= FAT32_EOC_MIN,
+		}
+	}
+	fn eoc_marker(&self) -> u32 {
+		match self.ty
+		{
+		Size::Fat12 => FAT12_EOC as u32,
+		Size::Fat16 => FAT16_EOC as u32,
+		Size::Fat32 => FAT32_EOC,
+		}
+	}
+	
+	/// Obtain the next cluster in a chain
+	fn get_next_cluster(&self, cluster: u32) -> Result< Option<u32>, storage::IoError > {
+		let val = try!(self.get_fat_entry(cluster));
+		if val == 0 {
+			Err(storage::IoError::Unknown("FAT: Zero FAT entry"))
+		}
+		else if self.is_eoc(val) {
+			Ok( None )
+		}
+		else if val < 2 || val as usize >= self.cluster_count + 2 {
+			Err(storage::IoError::Unknown("FAT: Invalid FAT entry"))
+		}
+		else {
+			Ok( Some(val) )
+		}
+	}
+
+	/// Allocate a free cluster, appending it to the chain ending at `prev` (if non-zero)
+	fn alloc_cluster(&self, prev: u32) -> vfs::Result<u32> {
+		let mut info = self.alloc_info.lock();
+		if info.free_count == 0 {
+			return Err(vfs::Error::OutOfSpace);
+		}
+
+		let limit = self.cluster_count as u32 + 2;
+		let start = if info.next_free >= 2 && info.next_free < limit { info.next_free } else { 2 };
+		let mut cluster = start;
+		while try!(self.get_fat_entry(cluster)) != 0
+		{
+			cluster += 1;
+			if cluster == limit {
+				cluster = 2;
+			}
+			if cluster == start {
+				// Searched the entire FAT, the cached count was wrong
+				info.free_count = 0;
+				try!(self.sync_fs_info(&info));
+				return Err(vfs::Error::OutOfSpace);
+			}
+		}
+		log_trace!("alloc_cluster(prev={:#x}) = {:#x}", prev, cluster);
+
+		try!(self.set_fat_entry(cluster, self.eoc_marker()));
+		if prev != 0 {
+			try!(self.set_fat_entry(prev, cluster));
+		}
+
+		if info.free_count != !0 {
+			info.free_count -= 1;
+		}
+		info.next_free = if cluster + 1 == limit { 2 } else { cluster + 1 };
+		try!(self.sync_fs_info(&info));
+		Ok( cluster )
+	}
+	/// Mark `cluster` as the end of its chain
+	fn end_chain(&self, cluster: u32) -> vfs::Result<()> {
+		let _lh = self.alloc_info.lock();
+		try!(self.set_fat_entry(cluster, self.eoc_marker()));
+		Ok( () )
+	}
+	/// Release an entire cluster chain
+	fn free_chain(&self, first_cluster: u32) -> vfs::Result<()> {
+		let mut info = self.alloc_info.lock();
+		let mut cluster = first_cluster;
+		while cluster != 0
+		{
+			let next = match try!(self.get_next_cluster(cluster))
+				{
+				Some(v) => v,
+				None => 0,
+				};
+			try!(self.set_fat_entry(cluster, 0));
+			if info.free_count != !0 {
+				info.free_count += 1;
+			}
+			cluster = next;
+		}
+		try!(self.sync_fs_info(&info));
+		Ok( () )
+	}
+	/// Write the allocation hints back to the FSInfo sector (FAT32 only)
+	fn sync_fs_info(&self, info: &AllocInfo) -> Result<(), storage::IoError> {
+		if let Some(sector) = self.fs_info_sector
+		{
+			let fi = on_disk::FsInfo {
+				free_count: info.free_count,
+				next_free: info.next_free,
+				};
+			try!(self.vh.edit(sector, 1, |data| fi.write(data)));
+		}
+		Ok( () )
+	}
 }
 
 impl mount::Filesystem for Filesystem
@@ -314,6 +594,14 @@ impl mount::Filesystem for Filesystem
 			dir_offset: 0,
 			}).to_id()
 	}
+	fn sync(&self) -> vfs::Result<()> {
+		try!(self.vh.flush());
+		Ok( () )
+	}
+	fn parent_dependent_inodes(&self) -> bool {
+		// Nodes are located using their parent directory's first cluster
+		true
+	}
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		let r = InodeRef::from(id);
 		if r.first_cluster == self.root_first_cluster {
@@ -18,6 +18,15 @@ pub const ATTR_ARCHIVE  : u8 = 0x20;	// Flag set by user
 pub const CASE_LOWER_BASE: u8 = 0x08;	// Linux (maybe NT) flag
 pub const CASE_LOWER_EXT : u8 = 0x10;	// Linux (maybe NT) flag
 
+/// Marker in the first byte of a deleted directory entry
+pub const DIRENT_DELETED: u8 = 0xE5;
+/// Flag in `DirEntLong::id` marking the last (first on disk) entry of a long name
+pub const LFN_LAST_ENTRY: u8 = 0x40;
+
+const FSINFO_LEAD_SIG  : u32 = 0x41615252;
+const FSINFO_STRUCT_SIG: u32 = 0x61417272;
+const FSINFO_TRAIL_SIG : u32 = 0xAA550000;
+
 fn read_u8(s: &mut &[u8]) -> u8 {
 	use kernel::lib::byteorder::ReadBytesExt;
 	s.read_u8().unwrap()
@@ -37,6 +46,19 @@ fn read_arr<T: AsMut<[u8]>>(s: &mut &[u8]) -> T {
 	s.read(v.as_mut()).unwrap();
 	v
 }
+fn write_u16(dst: &mut [u8], ofs: usize, v: u16) {
+	use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+	LittleEndian::write_u16(&mut dst[ofs..], v)
+}
+fn write_u32(dst: &mut [u8], ofs: usize, v: u32) {
+	use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+	LittleEndian::write_u32(&mut dst[ofs..], v)
+}
+fn write_arr16(dst: &mut [u8], ofs: usize, v: &[u16]) {
+	for (i,&c) in v.iter().enumerate() {
+		write_u16(dst, ofs + i*2, c);
+	}
+}
 fn read_arr16<T: AsMut<[u16]>>(s: &mut &[u8]) -> T {
 	// (mostly) SAFE: 'T' should be POD... but can't enforce that easily
 	let mut v: T = unsafe { ::core::mem::zeroed() };
@@ -193,7 +215,40 @@ impl BootSect32Info {
 	}
 }
 
-#[derive(Debug)]
+/// FAT32 FSInfo sector (free cluster hints)
+pub struct FsInfo
+{
+	/// Last known free cluster count (0xFFFFFFFF if unknown)
+	pub free_count: u32,
+	/// Hint for where to start looking for free clusters (0xFFFFFFFF if unknown)
+	pub next_free: u32,
+}
+impl FsInfo {
+	/// Parse an FSInfo sector, returning None if the signatures are invalid
+	pub fn read(src: &[u8]) -> Option<FsInfo> {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		assert!(src.len() >= 512);
+		if LittleEndian::read_u32(&src[0..]) != FSINFO_LEAD_SIG
+			|| LittleEndian::read_u32(&src[484..]) != FSINFO_STRUCT_SIG
+			|| LittleEndian::read_u32(&src[508..]) != FSINFO_TRAIL_SIG
+		{
+			None
+		}
+		else {
+			Some(FsInfo {
+				free_count: LittleEndian::read_u32(&src[488..]),
+				next_free: LittleEndian::read_u32(&src[492..]),
+				})
+		}
+	}
+	/// Update the hint fields in an existing FSInfo sector
+	pub fn write(&self, dst: &mut [u8]) {
+		write_u32(dst, 488, self.free_count);
+		write_u32(dst, 492, self.next_free);
+	}
+}
+
+#[derive(Debug,Default)]
 pub struct DirEnt
 {
 	pub name: [u8; 11],
@@ -226,8 +281,23 @@ impl DirEnt {
 			size: read_u32(src),
 		}
 	}
+	pub fn write(&self, dst: &mut [u8]) {
+		assert!(dst.len() >= 32);
+		dst[0..11].clone_from_slice(&self.name);
+		dst[11] = self.attribs;
+		dst[12] = self.lcase;
+		dst[13] = self.creation_ds;
+		write_u16(dst, 14, self.creation_time);
+		write_u16(dst, 16, self.creation_date);
+		write_u16(dst, 18, self.accessed_date);
+		write_u16(dst, 20, self.cluster_hi);
+		write_u16(dst, 22, self.modified_time);
+		write_u16(dst, 24, self.modified_date);
+		write_u16(dst, 26, self.cluster);
+		write_u32(dst, 28, self.size);
+	}
 }
-#[derive(Debug)]
+#[derive(Debug,Default)]
 pub struct DirEntLong
 {
 	pub id: u8,
@@ -252,5 +322,16 @@ impl DirEntLong {
 			name3: read_arr16(src),
 		}
 	}
+	pub fn write(&self, dst: &mut [u8]) {
+		assert!(dst.len() >= 32);
+		dst[0] = self.id;
+		write_arr16(dst, 1, &self.name1);
+		dst[11] = self.attrib;
+		dst[12] = self.ty;
+		dst[13] = self.checksum;
+		write_arr16(dst, 14, &self.name2);
+		write_u16(dst, 26, self.first_cluster);
+		write_arr16(dst, 28, &self.name3);
+	}
 }
 
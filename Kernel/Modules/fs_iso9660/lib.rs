@@ -63,7 +63,8 @@ impl mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle) -> vfs::Result<Box<mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, options: &[&str]) -> vfs::Result<Box<mount::Filesystem>> {
+		try!(mount::reject_options(options));
 		// For this to work properly, the block size must evenly divide 2048
 		if 2048 % vol.block_size() != 0 {
 			return Err( vfs::Error::Unknown("Can't mount ISO9660 with sector size not a factor of 2048"/*, vol.block_size()*/) );
@@ -138,7 +139,17 @@ impl mount::Filesystem for Instance
 	}
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		if id == 0 {
-			Some(Dir::new_node(self.0.borrow(), self.root_lba, self.root_size) )
+			// - The root's metadata is in its "." entry
+			let info = match self.get_sector(self.root_lba)
+				{
+				Ok(blk) => match DirSector::new(&self.0, blk, 0).next()
+					{
+					Ok(Some(ent)) => ent.info,
+					_ => return None,
+					},
+				Err(_) => return None,
+				};
+			Some(Dir::new_node(self.0.borrow(), id, self.root_lba, self.root_size, info) )
 		}
 		else {
 			// Look up (or read) parent directory to obtain the info
@@ -164,13 +175,13 @@ impl mount::Filesystem for Instance
 					None
 				}
 				else if ent.flags & (1 << 1) != 0 {
-					Some(Dir::new_node(self.0.borrow(), ent.start, ent.size))
+					Some(Dir::new_node(self.0.borrow(), id, ent.start, ent.size, ent.info))
 				}
 				else if ent.flags & 0x64 != 0 {
 					None
 				}
 				else {
-					Some(File::new_node(self.0.borrow(), ent.start, ent.size))
+					Some(File::new_node(self.0.borrow(), id, ent.start, ent.size, ent.info))
 				}
 			}
 		}
@@ -209,23 +220,31 @@ impl InstanceInner
 struct File
 {
 	fs: ArefBorrow<InstanceInner>,
+	/// Inode number (byte offset of the directory entry, see `Dir::lookup`)
+	inode: node::InodeId,
 	first_lba: u32,
 	size: u32,
+	info: node::NodeInfo,
 }
 impl File
 {
-	fn new_node(fs: ArefBorrow<InstanceInner>, first_lba: u32, size: u32) -> node::Node {
+	fn new_node(fs: ArefBorrow<InstanceInner>, inode: node::InodeId, first_lba: u32, size: u32, info: node::NodeInfo) -> node::Node {
 		node::Node::File( Box::new( File {
 			fs: fs,
+			inode: inode,
 			first_lba: first_lba,
 			size: size,
+			info: info,
 			} ) )
 	}
 }
 impl node::NodeBase for File
 {
 	fn get_id(&self) -> node::InodeId {
-		todo!("File::get_id")
+		self.inode
+	}
+	fn get_info(&self) -> node::Result<node::NodeInfo> {
+		Ok( self.info.clone() )
 	}
 	fn get_any(&self) -> &::core::any::Any {
 		self
@@ -298,23 +317,31 @@ impl node::File for File
 struct Dir
 {
 	fs: ArefBorrow<InstanceInner>,
+	/// Inode number (byte offset of the directory entry, or 0 for the root)
+	inode: node::InodeId,
 	first_lba: u32,
 	size: u32,
+	info: node::NodeInfo,
 }
 impl Dir
 {
-	fn new_node(fs: ArefBorrow<InstanceInner>, first_lba: u32, size: u32) -> node::Node {
+	fn new_node(fs: ArefBorrow<InstanceInner>, inode: node::InodeId, first_lba: u32, size: u32, info: node::NodeInfo) -> node::Node {
 		node::Node::Dir( Box::new( Dir {
 			fs: fs,
+			inode: inode,
 			first_lba: first_lba,
 			size: size,
+			info: info,
 			} ) )
 	}
 }
 impl node::NodeBase for Dir
 {
 	fn get_id(&self) -> node::InodeId {
-		todo!("Dir::get_id")
+		self.inode
+	}
+	fn get_info(&self) -> node::Result<node::NodeInfo> {
+		Ok( self.info.clone() )
 	}
 	fn get_any(&self) -> &::core::any::Any {
 		self
@@ -383,6 +410,10 @@ impl node::Dir for Dir
 		// ISO9660 is readonly
 		Err( vfs::Error::ReadOnlyFilesystem )
 	}
+	fn rename(&self, _src_name: &ByteStr, _dst_dir: &node::Dir, _dst_name: &ByteStr) -> node::Result<()> {
+		// ISO9660 is readonly
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
 }
 
 
@@ -397,6 +428,7 @@ struct DirEnt<'a>
 	size: u32,
 	name: &'a [u8],
 	sys_use: &'a [u8],
+	info: node::NodeInfo,
 }
 impl<'a> ::core::fmt::Debug for DirEnt<'a> {
 	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
@@ -463,6 +495,18 @@ impl<'a> DirSector<'a>
 
 				let mut name = &ent[33..][..namelen];
 
+				// Without RockRidge, there's no permission information (and the disc isn't writable)
+				let recorded = decode_short_timestamp(&ent[18..25]);
+				let mut info = node::NodeInfo {
+					size: LittleEndian::read_u32(&ent[10..]) as u64,
+					mtime: recorded,
+					ctime: recorded,
+					atime: recorded,
+					mode: 0o555,
+					uid: 0,
+					gid: 0,
+					};
+
 				if let Some(skip) = self.fs.susp_len_skip {
 					let skip = skip as usize;
 					if su.len() < skip {
@@ -473,9 +517,17 @@ impl<'a> DirSector<'a>
 					for ent in SuspIterator(&su[skip..])
 					{
 						//log_trace!("ent={:?}", ent);
+						match ent
+						{
 						// TODO: Need to handle this _FAR_ better
-						if let SuspItem::AlternateName(0, new_name) = ent {
-							name = new_name;
+						SuspItem::AlternateName(0, new_name) => name = new_name,
+						SuspItem::PosixMode { mode, uid, gid, .. } => {
+							info.mode = mode & 0o7777;
+							info.uid = uid;
+							info.gid = gid;
+							},
+						SuspItem::Timestamps { flags, data } => apply_timestamps(&mut info, flags, data),
+						_ => {},
 						}
 					}
 				}
@@ -488,12 +540,57 @@ impl<'a> DirSector<'a>
 					size: LittleEndian::read_u32(&ent[10..]),
 					name: name,
 					sys_use: su,
+					info: info,
 					}))
 			}
 		}
 	}
 }
 
+/// Decode a 7-byte timestamp (years since 1900, month, day, hour, minute, second, GMT offset in 15 minute units)
+fn decode_short_timestamp(d: &[u8]) -> ::kernel::time::Timestamp {
+	if d[1] == 0 {
+		// Month zero = not recorded
+		return 0;
+	}
+	::kernel::time::timestamp_from_date(1900 + d[0] as i64, d[1], d[2], d[3], d[4], d[5]) - d[6] as i8 as i64 * 15 * 60
+}
+/// Decode a 17-byte timestamp ("YYYYMMDDHHMMSScc" in ASCII, then the GMT offset in 15 minute units)
+fn decode_long_timestamp(d: &[u8]) -> ::kernel::time::Timestamp {
+	fn dec(d: &[u8]) -> i64 {
+		d.iter().fold(0, |v, &c| v * 10 + c.wrapping_sub(b'0') as i64)
+	}
+	if dec(&d[4..6]) == 0 {
+		return 0;
+	}
+	::kernel::time::timestamp_from_date(dec(&d[0..4]), dec(&d[4..6]) as u8, dec(&d[6..8]) as u8, dec(&d[8..10]) as u8, dec(&d[10..12]) as u8, dec(&d[12..14]) as u8)
+		- d[16] as i8 as i64 * 15 * 60
+}
+/// Apply a RockRidge "TF" entry to the node info
+fn apply_timestamps(info: &mut node::NodeInfo, flags: u8, mut data: &[u8]) {
+	const TF_LONG_FORM: u8 = 1 << 7;
+	let len = if flags & TF_LONG_FORM != 0 { 17 } else { 7 };
+	// Stamps are present in bit order: creation, modify, access, attributes (remaining bits are ignored)
+	for bit in 0 .. 4
+	{
+		if flags & (1 << bit) == 0 {
+			continue ;
+		}
+		if data.len() < len {
+			break ;
+		}
+		let ts = if len == 17 { decode_long_timestamp(&data[..len]) } else { decode_short_timestamp(&data[..len]) };
+		data = &data[len..];
+		match bit
+		{
+		1 => info.mtime = ts,
+		2 => info.atime = ts,
+		3 => info.ctime = ts,
+		_ => {},
+		}
+	}
+}
+
 struct SuspIterator<'a>(&'a [u8]);
 
 #[derive(Debug)]
@@ -0,0 +1,275 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/arp.rs
+//! Address Resolution Protocol (IPv4 to Ethernet)
+use kernel::prelude::*;
+use kernel::sync::Mutex;
+use nic::{MacAddr,PacketReader,SparsePacket};
+use ipv4::Address;
+
+/// Time (in ms) before a cache entry is considered stale
+const CACHE_TIMEOUT: u64 = 5*60*1000;
+/// Time (in ms) between retransmits of an unanswered request
+const REQUEST_INTERVAL: u64 = 1000;
+/// Time (in ms) before packets waiting on an unanswered request are dropped
+const RESOLVE_TIMEOUT: u64 = 3000;
+/// Maximum number of packets queued for each unresolved address (oldest are dropped)
+const MAX_QUEUED: usize = 8;
+
+const OPER_REQUEST: u16 = 1;
+const OPER_REPLY: u16 = 2;
+
+struct CacheEnt
+{
+	/// MAC address of the interface this entry was learnt on
+	interface: MacAddr,
+	addr: Address,
+	mac: MacAddr,
+	timestamp: u64,
+}
+
+/// An address being resolved, with the packets waiting on it
+struct PendingEnt
+{
+	interface: MacAddr,
+	local_ip: Address,
+	addr: Address,
+	/// Time the first request was sent
+	start: u64,
+	last_request: u64,
+	/// Queued IPv4 packets (sent once the reply arrives)
+	packets: Vec<Vec<u8>>,
+}
+
+static CACHE: Mutex<Vec<CacheEnt>> = Mutex::new(Vec::new_const());
+static PENDING: Mutex<Vec<PendingEnt>> = Mutex::new(Vec::new_const());
+
+/// Handle an incoming ARP packet
+pub fn handle_packet(local_mac: MacAddr, _src_mac: MacAddr, mut r: PacketReader)
+{
+	// 1. Parse the header, only interested in Ethernet+IPv4
+	let (hw_ty, proto_ty, hw_size, proto_size, oper) = match (r.read_u16n(), r.read_u16n(), r.read_u8(), r.read_u8(), r.read_u16n())
+		{
+		(Ok(a), Ok(b), Ok(c), Ok(d), Ok(e)) => (a, b, c, d, e),
+		_ => {
+			log_notice!("ARP: Truncated header");
+			return ;
+			},
+		};
+	if hw_ty != 1 || proto_ty != ::nic::ether_type::IPV4 || hw_size != 6 || proto_size != 4 {
+		log_debug!("ARP: Unsupported packet hw={}/{} proto={:#x}/{}", hw_ty, hw_size, proto_ty, proto_size);
+		return ;
+	}
+	let mut sender_mac = [0; 6];
+	let mut sender_ip = [0; 4];
+	let mut target_mac = [0; 6];
+	let mut target_ip = [0; 4];
+	match (r.read(&mut sender_mac), r.read(&mut sender_ip), r.read(&mut target_mac), r.read(&mut target_ip))
+	{
+	(Ok(_), Ok(_), Ok(_), Ok(_)) => {},
+	_ => {
+		log_notice!("ARP: Truncated body");
+		return ;
+		},
+	}
+	let sender_ip = Address(sender_ip);
+	let target_ip = Address(target_ip);
+	log_trace!("ARP: oper={} {}/{:x} -> {}", oper, sender_ip, ::kernel::lib::FmtSlice(&sender_mac), target_ip);
+
+	// 2. Update the cache with the sender's information
+	if !sender_ip.is_zero() {
+		learn(local_mac, sender_ip, sender_mac);
+	}
+
+	// 3. If this is a request for one of our addresses, reply
+	if oper == OPER_REQUEST
+	{
+		match ::ipv4::get_interface_address(local_mac)
+		{
+		Some(local_ip) if !local_ip.is_zero() && local_ip == target_ip => {
+			log_debug!("ARP: Replying to {} ({:x})", sender_ip, ::kernel::lib::FmtSlice(&sender_mac));
+			send(local_mac, OPER_REPLY, local_ip, sender_mac, sender_ip);
+			},
+		_ => {},
+		}
+	}
+}
+
+/// Record a IPv4->MAC mapping (called for received ARP packets, and by the IPv4 layer for incoming packets)
+///
+/// Sends any packets that were waiting for the address to be resolved.
+pub fn learn(local_mac: MacAddr, addr: Address, mac: MacAddr)
+{
+	update_cache(local_mac, addr, mac);
+	flush_pending(local_mac, addr, mac);
+}
+fn update_cache(local_mac: MacAddr, addr: Address, mac: MacAddr)
+{
+	let now = ::kernel::time::ticks();
+	let mut lh = CACHE.lock();
+	for e in lh.iter_mut()
+	{
+		if e.interface == local_mac && e.addr == addr {
+			e.mac = mac;
+			e.timestamp = now;
+			return ;
+		}
+	}
+	// Clear out stale entries before adding a new one
+	let mut i = 0;
+	while i < lh.len()
+	{
+		if now - lh[i].timestamp > CACHE_TIMEOUT {
+			lh.remove(i);
+		}
+		else {
+			i += 1;
+		}
+	}
+	lh.push(CacheEnt {
+		interface: local_mac,
+		addr: addr,
+		mac: mac,
+		timestamp: now,
+		});
+}
+
+/// Look up an address in the cache (without sending a request)
+pub fn peer_lookup(local_mac: MacAddr, addr: Address) -> Option<MacAddr>
+{
+	let now = ::kernel::time::ticks();
+	let lh = CACHE.lock();
+	lh.iter()
+		.find(|e| e.interface == local_mac && e.addr == addr && now - e.timestamp <= CACHE_TIMEOUT)
+		.map(|e| e.mac)
+}
+
+/// Send an IPv4 packet to a host on the local network
+///
+/// If the address isn't cached, the packet is queued and a request is sent. This never blocks (it's also called on
+/// the receive path), queued packets are sent by `learn` once the reply arrives, or dropped by `handle_timers`.
+pub fn send_or_queue(local_mac: MacAddr, local_ip: Address, addr: Address, pkt: SparsePacket) -> Result<(), ::nic::Error>
+{
+	if let Some(m) = peer_lookup(local_mac, addr) {
+		return ::nic::send_from(local_mac, m, ::nic::ether_type::IPV4, pkt);
+	}
+
+	let mut data = Vec::with_capacity(pkt.total_len());
+	for span in &pkt {
+		data.extend_from_slice(span);
+	}
+	let now = ::kernel::time::ticks();
+	let new_request = {
+		let mut lh = PENDING.lock();
+		match lh.iter_mut().find(|e| e.interface == local_mac && e.addr == addr)
+		{
+		Some(e) => {
+			if e.packets.len() >= MAX_QUEUED {
+				log_debug!("ARP: Too many packets waiting on {}, dropping oldest", addr);
+				e.packets.remove(0);
+			}
+			e.packets.push(data);
+			false
+			},
+		None => {
+			lh.push(PendingEnt {
+				interface: local_mac,
+				local_ip: local_ip,
+				addr: addr,
+				start: now,
+				last_request: now,
+				packets: vec![data],
+				});
+			true
+			},
+		}
+		};
+	if new_request {
+		send(local_mac, OPER_REQUEST, local_ip, [0; 6], addr);
+		::wake_timers();
+	}
+	// - The reply could have arrived before the packet was queued
+	if let Some(m) = peer_lookup(local_mac, addr) {
+		flush_pending(local_mac, addr, m);
+	}
+	Ok( () )
+}
+
+/// Send packets that were waiting for `addr` to be resolved
+fn flush_pending(local_mac: MacAddr, addr: Address, mac: MacAddr)
+{
+	let ent = {
+		let mut lh = PENDING.lock();
+		match lh.iter().position(|e| e.interface == local_mac && e.addr == addr)
+		{
+		Some(i) => lh.remove(i),
+		None => return ,
+		}
+		};
+	log_debug!("ARP: {} resolved, sending {} queued packets", addr, ent.packets.len());
+	for p in ent.packets
+	{
+		if let Err(e) = ::nic::send_from(local_mac, mac, ::nic::ether_type::IPV4, SparsePacket::new_root(&p)) {
+			log_warning!("ARP: Failed to send queued packet to {} - {:?}", addr, e);
+		}
+	}
+}
+
+/// Retransmit unanswered requests, and drop packets waiting on requests that have timed out
+///
+/// Returns the time of the next retransmit or timeout.
+pub fn handle_timers(now: u64) -> Option<u64>
+{
+	let mut requests = Vec::new();
+	let next = {
+		let mut lh = PENDING.lock();
+		let mut i = 0;
+		while i < lh.len()
+		{
+			if now - lh[i].start >= RESOLVE_TIMEOUT {
+				let e = lh.remove(i);
+				log_notice!("ARP: Timeout resolving {}, dropping {} packets", e.addr, e.packets.len());
+				continue ;
+			}
+			if now - lh[i].last_request >= REQUEST_INTERVAL {
+				lh[i].last_request = now;
+				requests.push( (lh[i].interface, lh[i].local_ip, lh[i].addr) );
+			}
+			i += 1;
+		}
+		lh.iter()
+			.map(|e| ::core::cmp::min(e.last_request + REQUEST_INTERVAL, e.start + RESOLVE_TIMEOUT))
+			.min()
+		};
+	// - Sent with the lock released, as sending can block
+	for (local_mac, local_ip, addr) in requests
+	{
+		send(local_mac, OPER_REQUEST, local_ip, [0; 6], addr);
+	}
+	next
+}
+
+fn send(local_mac: MacAddr, oper: u16, local_ip: Address, dest_mac: MacAddr, dest_ip: Address)
+{
+	let mut pkt = [0u8; 2+2+1+1+2 + 6+4+6+4];
+	pkt[0] = 0; pkt[1] = 1;	// Ethernet
+	pkt[2] = (::nic::ether_type::IPV4 >> 8) as u8;
+	pkt[3] = (::nic::ether_type::IPV4 & 0xFF) as u8;
+	pkt[4] = 6;
+	pkt[5] = 4;
+	pkt[6] = (oper >> 8) as u8;
+	pkt[7] = (oper & 0xFF) as u8;
+	pkt[8..14].copy_from_slice(&local_mac);
+	pkt[14..18].copy_from_slice(&local_ip.0);
+	pkt[18..24].copy_from_slice(&dest_mac);
+	pkt[24..28].copy_from_slice(&dest_ip.0);
+
+	let eth_dest = if oper == OPER_REQUEST { ::nic::MAC_BROADCAST } else { dest_mac };
+	match ::nic::send_from(local_mac, eth_dest, ::nic::ether_type::ARP, SparsePacket::new_root(&pkt))
+	{
+	Ok(_) => {},
+	Err(e) => log_warning!("ARP: Failed to send packet - {:?}", e),
+	}
+}
+
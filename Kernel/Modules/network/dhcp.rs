@@ -0,0 +1,494 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/dhcp.rs
+//! Dynamic Host Configuration Protocol client (RFC 2131)
+//!
+//! Runs entirely from the network timer thread, one state machine per registered interface.
+use kernel::prelude::*;
+use kernel::sync::{Mutex,RwLock};
+use ipv4::Address;
+use nic::MacAddr;
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Initial retransmit interval (doubled on each retry, up to `MAX_RETRY_INTERVAL`)
+const INITIAL_RETRY_INTERVAL: u64 = 4*1000;
+const MAX_RETRY_INTERVAL: u64 = 64*1000;
+/// Number of REQUEST retransmits before restarting with DISCOVER
+const MAX_REQUEST_RETRIES: u32 = 4;
+
+const MSG_DISCOVER: u8 = 1;
+const MSG_OFFER: u8 = 2;
+const MSG_REQUEST: u8 = 3;
+const MSG_ACK: u8 = 5;
+const MSG_NAK: u8 = 6;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAM_REQUEST: u8 = 55;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_END: u8 = 255;
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+enum State
+{
+	/// Sending DISCOVER, waiting for an OFFER
+	Selecting,
+	/// Sent REQUEST for an offer, waiting for ACK/NAK
+	Requesting,
+	/// Lease active, waiting for T1
+	Bound,
+	/// Sent REQUEST to extend the lease
+	Renewing,
+}
+
+/// Configuration obtained from a server
+#[derive(Copy,Clone,Debug,Default)]
+struct Lease
+{
+	address: Address,
+	server: Address,
+	mask_len: u8,
+	router: Option<Address>,
+	/// Lease duration (ms)
+	duration: u64,
+	/// Renewal (T1) time (ms)
+	renew: u64,
+}
+
+struct Client
+{
+	local_mac: MacAddr,
+	state: State,
+	xid: u32,
+	/// Time at which the next action is taken
+	next_event: u64,
+	retry_interval: u64,
+	retries: u32,
+	/// Offer/lease being requested (or held)
+	lease: Option<Lease>,
+	/// Time at which the current lease expires
+	lease_expiry: u64,
+	/// Is the lease's configuration applied to the interface
+	applied: bool,
+}
+
+struct ClientList
+{
+	socket: ::udp::Socket,
+	clients: Vec<Client>,
+}
+
+static CLIENTS: Mutex<Option<ClientList>> = Mutex::new(None);
+static DNS_SERVERS: RwLock<Vec<Address>> = RwLock::new(Vec::new_const());
+
+/// Begin address configuration for a newly registered interface
+pub fn start(local_mac: MacAddr)
+{
+	let mut lh = CLIENTS.lock();
+	if lh.is_none()
+	{
+		match ::udp::Socket::bind(Address::zero(), CLIENT_PORT)
+		{
+		Ok(s) => *lh = Some(ClientList { socket: s, clients: Vec::new() }),
+		Err(e) => {
+			log_error!("DHCP: Unable to bind client port - {:?}", e);
+			return ;
+			},
+		}
+	}
+	let list = lh.as_mut().unwrap();
+	if list.clients.iter().any(|c| c.local_mac == local_mac) {
+		return ;
+	}
+	log_log!("DHCP: Starting on {:x}", ::kernel::lib::FmtSlice(&local_mac));
+	list.clients.push(Client {
+		local_mac: local_mac,
+		state: State::Selecting,
+		xid: make_xid(local_mac),
+		next_event: ::kernel::time::ticks(),
+		retry_interval: INITIAL_RETRY_INTERVAL,
+		retries: 0,
+		lease: None,
+		lease_expiry: 0,
+		applied: false,
+		});
+	::wake_timers();
+}
+
+/// Stop managing an interface (removes any configuration applied)
+pub fn stop(local_mac: MacAddr)
+{
+	let mut lh = CLIENTS.lock();
+	if let Some(ref mut list) = *lh
+	{
+		if let Some(i) = list.clients.iter().position(|c| c.local_mac == local_mac) {
+			let mut c = list.clients.remove(i);
+			c.unconfigure();
+		}
+	}
+}
+
+/// DNS servers supplied by DHCP servers
+pub fn dns_servers() -> Vec<Address>
+{
+	DNS_SERVERS.read().clone()
+}
+
+/// Register the timer thread to be woken when a DHCP packet arrives
+pub fn bind_wait(obj: &mut ::kernel::threads::SleepObject)
+{
+	if let Some(ref list) = *CLIENTS.lock() {
+		list.socket.bind_wait(obj);
+	}
+}
+pub fn clear_wait(obj: &mut ::kernel::threads::SleepObject)
+{
+	if let Some(ref list) = *CLIENTS.lock() {
+		list.socket.clear_wait(obj);
+	}
+}
+
+/// Process received replies and timeouts (called from the network timer thread)
+///
+/// Returns the time of the next client timeout.
+pub fn handle_timers(now: u64) -> Option<u64>
+{
+	let mut lh = CLIENTS.lock();
+	let list = match *lh
+		{
+		Some(ref mut l) => l,
+		None => return None,
+		};
+
+	// 1. Handle all received packets
+	let mut buf = [0u8; 576];
+	while let Some( (len, src, src_port) ) = list.socket.recv_from(&mut buf)
+	{
+		if src_port != SERVER_PORT {
+			continue ;
+		}
+		let pkt = &buf[..len];
+		match Packet::parse(pkt)
+		{
+		Some(p) => {
+			if let Some(c) = list.clients.iter_mut().find(|c| c.local_mac == p.chaddr && c.xid == p.xid) {
+				c.handle_packet(&list.socket, &p, now);
+			}
+			},
+		None => log_debug!("DHCP: Malformed packet from {}", src),
+		}
+	}
+
+	// 2. Handle timeouts
+	for c in list.clients.iter_mut()
+	{
+		if now >= c.next_event {
+			c.handle_timeout(&list.socket, now);
+		}
+	}
+	list.clients.iter().map(|c| c.next_event).min()
+}
+
+fn make_xid(mac: MacAddr) -> u32
+{
+	let t = ::kernel::time::ticks() as u32;
+	t ^ ((mac[2] as u32) << 24 | (mac[3] as u32) << 16 | (mac[4] as u32) << 8 | mac[5] as u32)
+}
+
+impl Client
+{
+	fn handle_timeout(&mut self, sock: &::udp::Socket, now: u64)
+	{
+		match self.state
+		{
+		State::Selecting => {
+			self.send(sock, MSG_DISCOVER);
+			self.schedule_retry(now);
+			},
+		State::Requesting => {
+			if self.retries >= MAX_REQUEST_RETRIES {
+				log_notice!("DHCP: No reply to REQUEST, restarting");
+				self.restart(now);
+			}
+			else {
+				self.send(sock, MSG_REQUEST);
+				self.schedule_retry(now);
+			}
+			},
+		State::Bound => {
+			log_debug!("DHCP: Renewing lease");
+			self.state = State::Renewing;
+			self.retry_interval = INITIAL_RETRY_INTERVAL;
+			self.retries = 0;
+			self.send(sock, MSG_REQUEST);
+			self.schedule_retry(now);
+			},
+		State::Renewing => {
+			if now >= self.lease_expiry {
+				log_notice!("DHCP: Lease expired on {:x}", ::kernel::lib::FmtSlice(&self.local_mac));
+				self.unconfigure();
+				self.restart(now);
+			}
+			else {
+				self.send(sock, MSG_REQUEST);
+				self.schedule_retry(now);
+				if self.next_event > self.lease_expiry {
+					self.next_event = self.lease_expiry;
+				}
+			}
+			},
+		}
+	}
+
+	fn schedule_retry(&mut self, now: u64) {
+		self.next_event = now + self.retry_interval;
+		self.retry_interval = ::core::cmp::min(self.retry_interval * 2, MAX_RETRY_INTERVAL);
+		self.retries += 1;
+	}
+
+	fn restart(&mut self, now: u64) {
+		self.state = State::Selecting;
+		self.xid = make_xid(self.local_mac).wrapping_add(1);
+		self.lease = None;
+		self.retry_interval = INITIAL_RETRY_INTERVAL;
+		self.retries = 0;
+		self.next_event = now;
+	}
+
+	fn handle_packet(&mut self, sock: &::udp::Socket, p: &Packet, now: u64)
+	{
+		match (self.state, p.msg_type)
+		{
+		(State::Selecting, MSG_OFFER) => {
+			let server = match p.server_id
+				{
+				Some(s) => s,
+				None => {
+					log_notice!("DHCP: OFFER without a server identifier");
+					return ;
+					},
+				};
+			log_log!("DHCP: Offer of {} from {}", p.yiaddr, server);
+			self.lease = Some(Lease {
+				address: p.yiaddr,
+				server: server,
+				..Default::default()
+				});
+			self.state = State::Requesting;
+			self.retry_interval = INITIAL_RETRY_INTERVAL;
+			self.retries = 0;
+			self.send(sock, MSG_REQUEST);
+			self.schedule_retry(now);
+			},
+		(State::Requesting, MSG_ACK) | (State::Renewing, MSG_ACK) => {
+			let duration = p.lease_time.unwrap_or(3600) as u64 * 1000;
+			let lease = Lease {
+				address: p.yiaddr,
+				server: p.server_id.unwrap_or(self.lease.map(|l| l.server).unwrap_or(Address::zero())),
+				mask_len: p.mask_len.unwrap_or(24),
+				router: p.router,
+				duration: duration,
+				renew: p.renewal_time.map(|v| v as u64 * 1000).unwrap_or(duration / 2),
+				};
+			self.state = State::Bound;
+			self.lease_expiry = now + lease.duration;
+			self.next_event = now + lease.renew;
+			let changed = match self.lease
+				{
+				Some(ref l) => !self.applied || l.address != lease.address || l.mask_len != lease.mask_len || l.router != lease.router,
+				None => true,
+				};
+			self.lease = Some(lease);
+			if changed {
+				self.configure(p);
+			}
+			},
+		(State::Requesting, MSG_NAK) | (State::Renewing, MSG_NAK) => {
+			log_notice!("DHCP: Server NAKed request");
+			self.unconfigure();
+			self.restart(now);
+			},
+		(s, t) => log_debug!("DHCP: Ignoring message type {} in state {:?}", t, s),
+		}
+	}
+
+	fn configure(&mut self, p: &Packet)
+	{
+		let lease = self.lease.unwrap();
+		log_notice!("DHCP: {:x} configured as {}/{} router={:?} lease={}s",
+			::kernel::lib::FmtSlice(&self.local_mac), lease.address, lease.mask_len, lease.router, lease.duration / 1000);
+		::ipv4::add_interface(self.local_mac, lease.address, lease.mask_len);
+		if let Some(r) = lease.router {
+			::ipv4::add_route(Address::zero(), 0, r);
+		}
+		if p.dns_servers.len() > 0 {
+			let mut lh = DNS_SERVERS.write();
+			for s in p.dns_servers.iter() {
+				if !lh.iter().any(|e| e == s) {
+					lh.push(*s);
+				}
+			}
+		}
+		self.applied = true;
+	}
+	fn unconfigure(&mut self)
+	{
+		if self.applied
+		{
+			if let Some(l) = self.lease {
+				if l.router.is_some() {
+					::ipv4::remove_route(Address::zero(), 0);
+				}
+			}
+			::ipv4::remove_interface(self.local_mac);
+			self.applied = false;
+		}
+	}
+
+	fn send(&self, sock: &::udp::Socket, msg_type: u8)
+	{
+		let mut buf = [0u8; 300];
+		buf[0] = 1;	// BOOTREQUEST
+		buf[1] = 1;	// Ethernet
+		buf[2] = 6;
+		buf[3] = 0;
+		buf[4] = (self.xid >> 24) as u8;
+		buf[5] = (self.xid >> 16) as u8;
+		buf[6] = (self.xid >> 8) as u8;
+		buf[7] = self.xid as u8;
+		// secs = 0
+		// flags: Request broadcast replies (we can't receive unicast before configuration)
+		if self.state != State::Renewing {
+			buf[10] = 0x80;
+		}
+		// ciaddr (only when renewing)
+		if let (State::Renewing, Some(l)) = (self.state, self.lease) {
+			buf[12..16].copy_from_slice(&l.address.0);
+		}
+		buf[28..34].copy_from_slice(&self.local_mac);
+		buf[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+		let mut ofs = 240;
+		{
+			let mut push_opt = |code: u8, data: &[u8]| {
+				buf[ofs] = code;
+				buf[ofs+1] = data.len() as u8;
+				buf[ofs+2..][..data.len()].copy_from_slice(data);
+				ofs += 2 + data.len();
+			};
+			push_opt(OPT_MESSAGE_TYPE, &[msg_type]);
+			if msg_type == MSG_REQUEST && self.state == State::Requesting {
+				if let Some(l) = self.lease {
+					push_opt(OPT_REQUESTED_IP, &l.address.0);
+					push_opt(OPT_SERVER_ID, &l.server.0);
+				}
+			}
+			push_opt(OPT_PARAM_REQUEST, &[OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS, OPT_LEASE_TIME, OPT_RENEWAL_TIME]);
+		}
+		buf[ofs] = OPT_END;
+		// NOTE: The rest of the buffer is left as padding, some servers reject messages shorter than the BOOTP minimum (300 bytes)
+
+		let dest = match (self.state, self.lease)
+			{
+			(State::Renewing, Some(l)) => l.server,
+			_ => Address::broadcast(),
+			};
+		let rv = if dest == Address::broadcast() {
+				sock.send_via(self.local_mac, dest, SERVER_PORT, &buf)
+			}
+			else {
+				sock.send_to(dest, SERVER_PORT, &buf)
+			};
+		if let Err(e) = rv {
+			log_notice!("DHCP: Error sending message {} - {:?}", msg_type, e);
+		}
+	}
+}
+
+/// Parsed server message
+struct Packet
+{
+	xid: u32,
+	yiaddr: Address,
+	chaddr: MacAddr,
+	msg_type: u8,
+	server_id: Option<Address>,
+	mask_len: Option<u8>,
+	router: Option<Address>,
+	dns_servers: Vec<Address>,
+	lease_time: Option<u32>,
+	renewal_time: Option<u32>,
+}
+impl Packet
+{
+	fn parse(buf: &[u8]) -> Option<Packet>
+	{
+		if buf.len() < 240 || buf[0] != 2 || buf[1] != 1 || buf[2] != 6 || buf[236..240] != MAGIC_COOKIE {
+			return None;
+		}
+		let get_addr = |d: &[u8]| Address([d[0], d[1], d[2], d[3]]);
+		let get_u32 = |d: &[u8]| (d[0] as u32) << 24 | (d[1] as u32) << 16 | (d[2] as u32) << 8 | d[3] as u32;
+		let mut rv = Packet {
+			xid: get_u32(&buf[4..8]),
+			yiaddr: get_addr(&buf[16..20]),
+			chaddr: [buf[28], buf[29], buf[30], buf[31], buf[32], buf[33]],
+			msg_type: 0,
+			server_id: None,
+			mask_len: None,
+			router: None,
+			dns_servers: Vec::new(),
+			lease_time: None,
+			renewal_time: None,
+			};
+
+		let mut opts = &buf[240..];
+		while opts.len() > 0
+		{
+			let code = opts[0];
+			if code == OPT_END {
+				break;
+			}
+			if code == OPT_PAD {
+				opts = &opts[1..];
+				continue ;
+			}
+			if opts.len() < 2 || opts.len() < 2 + opts[1] as usize {
+				return None;
+			}
+			let data = &opts[2..][..opts[1] as usize];
+			match (code, data.len())
+			{
+			(OPT_MESSAGE_TYPE, 1) => rv.msg_type = data[0],
+			(OPT_SERVER_ID, 4) => rv.server_id = Some(get_addr(data)),
+			(OPT_SUBNET_MASK, 4) => rv.mask_len = Some(get_u32(data).count_ones() as u8),
+			(OPT_ROUTER, l) if l >= 4 => rv.router = Some(get_addr(data)),
+			(OPT_DNS, l) if l >= 4 => {
+				for a in data.chunks(4).filter(|c| c.len() == 4) {
+					rv.dns_servers.push(get_addr(a));
+				}
+				},
+			(OPT_LEASE_TIME, 4) => rv.lease_time = Some(get_u32(data)),
+			(OPT_RENEWAL_TIME, 4) => rv.renewal_time = Some(get_u32(data)),
+			_ => {},
+			}
+			opts = &opts[2 + data.len() ..];
+		}
+		if rv.msg_type == 0 {
+			None
+		}
+		else {
+			Some(rv)
+		}
+	}
+}
+
@@ -0,0 +1,566 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/ipv4.rs
+//! Internet Protocol version 4 (Layer 3)
+use kernel::prelude::*;
+use kernel::sync::{Mutex,RwLock};
+use core::sync::atomic::{AtomicUsize,Ordering};
+use nic::{MacAddr,PacketReader,SparsePacket};
+
+/// Maximum payload size that will be sent (Ethernet MTU minus the IPv4 header)
+pub const MAX_PAYLOAD: usize = 1500 - 20;
+/// Time (in ms) before an incomplete fragmented packet is discarded
+const REASSEMBLY_TIMEOUT: u64 = 30*1000;
+/// Maximum number of packets being reassembled at one time
+const MAX_REASSEMBLY: usize = 16;
+
+#[derive(Debug)]
+pub enum Error
+{
+	/// No interface matches the requested source address
+	NoInterface,
+	/// No route to the destination
+	NoRoute,
+	/// Address resolution failed
+	UnreachableHost,
+	/// Payload too large to send in one packet
+	TooLarge,
+}
+impl_from! {
+	From<::nic::Error>(v) for Error {
+		match v
+		{
+		::nic::Error::MtuExceeded => Error::TooLarge,
+		_ => Error::NoInterface,
+		}
+	}
+}
+
+/// An IPv4 address
+#[derive(Copy,Clone,PartialEq,Eq,PartialOrd,Ord,Default)]
+pub struct Address(pub [u8; 4]);
+impl Address
+{
+	pub const fn zero() -> Address {
+		Address([0; 4])
+	}
+	pub const fn broadcast() -> Address {
+		Address([255; 4])
+	}
+	pub fn from_u32(v: u32) -> Address {
+		Address([ (v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8 ])
+	}
+	pub fn as_u32(&self) -> u32 {
+		(self.0[0] as u32) << 24 | (self.0[1] as u32) << 16 | (self.0[2] as u32) << 8 | (self.0[3] as u32)
+	}
+	pub fn is_zero(&self) -> bool {
+		self.0 == [0; 4]
+	}
+	/// Returns this address with only the top `bits` bits retained
+	pub fn mask(&self, bits: u8) -> Address {
+		if bits == 0 {
+			Address::zero()
+		}
+		else if bits >= 32 {
+			*self
+		}
+		else {
+			Address::from_u32( self.as_u32() & !(!0u32 >> bits as usize) )
+		}
+	}
+	/// Returns true if `other` is in the same `bits`-sized subnet as this address
+	pub fn same_net(&self, other: Address, bits: u8) -> bool {
+		self.mask(bits) == other.mask(bits)
+	}
+}
+impl_fmt! {
+	Debug(self, f) for Address {
+		write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+	}
+	Display(self, f) for Address {
+		write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+	}
+}
+
+/// An IPv4 address assigned to an ethernet interface
+#[derive(Copy,Clone,Debug)]
+pub struct Interface
+{
+	local_mac: MacAddr,
+	address: Address,
+	mask_len: u8,
+}
+impl Interface
+{
+	pub fn mac(&self) -> MacAddr {
+		self.local_mac
+	}
+	pub fn addr(&self) -> Address {
+		self.address
+	}
+	pub fn mask_len(&self) -> u8 {
+		self.mask_len
+	}
+	/// Returns true if the passed address is the broadcast address for this interface's subnet
+	fn is_broadcast(&self, addr: Address) -> bool {
+		addr == Address::broadcast() || (self.mask_len < 32 && addr == Address::from_u32(self.address.as_u32() | (!0u32 >> self.mask_len as usize)))
+	}
+}
+
+/// Handler for a layer 4 protocol
+///
+/// Called with the receiving interface, source address, destination address, and a reader over the payload
+pub type ProtocolHandler = fn(&Interface, Address, Address, PacketReader);
+
+/// A route to a non-local network via a gateway
+#[derive(Copy,Clone,Debug)]
+struct Route
+{
+	network: Address,
+	mask_len: u8,
+	gateway: Address,
+}
+
+static INTERFACES: RwLock<Vec<Interface>> = RwLock::new(Vec::new_const());
+static ROUTES: RwLock<Vec<Route>> = RwLock::new(Vec::new_const());
+static PROTOCOLS: RwLock<Vec<(u8, ProtocolHandler)>> = RwLock::new(Vec::new_const());
+static REASSEMBLY: Mutex<Vec<ReassemblyBuffer>> = Mutex::new(Vec::new_const());
+static NEXT_IDENT: AtomicUsize = AtomicUsize::new(1);
+
+/// Add (or replace) the IPv4 address bound to an interface
+pub fn add_interface(local_mac: MacAddr, addr: Address, mask_len: u8)
+{
+	let mut lh = INTERFACES.write();
+	for e in lh.iter_mut()
+	{
+		if e.local_mac == local_mac {
+			log_notice!("IPv4: Interface {:x} changed from {}/{} to {}/{}", ::kernel::lib::FmtSlice(&local_mac), e.address, e.mask_len, addr, mask_len);
+			e.address = addr;
+			e.mask_len = mask_len;
+			return ;
+		}
+	}
+	log_notice!("IPv4: Interface {:x} added with {}/{}", ::kernel::lib::FmtSlice(&local_mac), addr, mask_len);
+	lh.push(Interface {
+		local_mac: local_mac,
+		address: addr,
+		mask_len: mask_len,
+		});
+}
+
+/// Obtain the address assigned to the interface with the specified MAC address
+pub fn get_interface_address(local_mac: MacAddr) -> Option<Address>
+{
+	INTERFACES.read().iter().find(|e| e.local_mac == local_mac).map(|e| e.address)
+}
+
+/// Remove the IPv4 address from an interface (e.g. when a DHCP lease expires)
+pub fn remove_interface(local_mac: MacAddr)
+{
+	let mut lh = INTERFACES.write();
+	if let Some(i) = lh.iter().position(|e| e.local_mac == local_mac) {
+		let e = lh.remove(i);
+		log_notice!("IPv4: Interface {:x} ({}/{}) removed", ::kernel::lib::FmtSlice(&local_mac), e.address, e.mask_len);
+	}
+}
+
+/// Add a route to `network`/`mask_len` via `gateway` (a zero-length mask is the default route)
+///
+/// Replaces any existing route to the same network
+pub fn add_route(network: Address, mask_len: u8, gateway: Address)
+{
+	let network = network.mask(mask_len);
+	let mut lh = ROUTES.write();
+	if let Some(r) = lh.iter_mut().find(|r| r.network == network && r.mask_len == mask_len) {
+		log_notice!("IPv4: Route {}/{} changed from {} to {}", network, mask_len, r.gateway, gateway);
+		r.gateway = gateway;
+		return ;
+	}
+	log_notice!("IPv4: Route {}/{} via {}", network, mask_len, gateway);
+	lh.push(Route { network: network, mask_len: mask_len, gateway: gateway });
+}
+
+/// Remove a previously added route
+pub fn remove_route(network: Address, mask_len: u8)
+{
+	let network = network.mask(mask_len);
+	let mut lh = ROUTES.write();
+	if let Some(i) = lh.iter().position(|r| r.network == network && r.mask_len == mask_len) {
+		lh.remove(i);
+	}
+}
+
+/// Determine the outgoing interface and next hop for a packet
+fn route(source: Address, dest: Address) -> Result<(Interface, Address), Error>
+{
+	let lh = INTERFACES.read();
+	let usable = |e: &&Interface| !e.address.is_zero() && (source.is_zero() || e.address == source);
+	if !source.is_zero() && !lh.iter().any(|e| e.address == source) {
+		return Err(Error::NoInterface);
+	}
+
+	// 1. Directly connected
+	if let Some(i) = lh.iter().filter(&usable).find(|e| e.address.same_net(dest, e.mask_len) || e.is_broadcast(dest)) {
+		return Ok( (*i, dest) );
+	}
+	// 2. Via a gateway (longest prefix first)
+	let routes = ROUTES.read();
+	let mut best: Option<(Interface, Address, u8)> = None;
+	for r in routes.iter().filter(|r| r.network == dest.mask(r.mask_len))
+	{
+		if best.map(|b| b.2 >= r.mask_len).unwrap_or(false) {
+			continue ;
+		}
+		if let Some(i) = lh.iter().filter(&usable).find(|e| e.address.same_net(r.gateway, e.mask_len)) {
+			best = Some( (*i, r.gateway, r.mask_len) );
+		}
+	}
+	match best
+	{
+	Some( (i, gw, _) ) => Ok( (i, gw) ),
+	None => Err(Error::NoRoute),
+	}
+}
+
+/// Determine the local address that would be used to send to `dest`
+pub fn route_source(dest: Address) -> Option<Address>
+{
+	route(Address::zero(), dest).ok().map(|(i, _)| i.address)
+}
+
+/// Register a handler for a layer 4 protocol
+pub fn register_handler(proto: u8, handler: ProtocolHandler) -> Result<(), ()>
+{
+	let mut lh = PROTOCOLS.write();
+	if lh.iter().any(|e| e.0 == proto) {
+		Err( () )
+	}
+	else {
+		lh.push( (proto, handler) );
+		Ok( () )
+	}
+}
+
+/// Calculate the internet checksum over a sequence of big-endian words
+pub fn calculate_checksum<I: Iterator<Item=u16>>(words: I) -> u16
+{
+	!fold_checksum(sum_words(words))
+}
+/// Sum words for a ones-complement checksum (without folding)
+pub fn sum_words<I: Iterator<Item=u16>>(words: I) -> u32
+{
+	words.fold(0u32, |acc, v| acc + v as u32)
+}
+/// Fold a 32-bit sum into a 16-bit ones-complement sum
+pub fn fold_checksum(mut sum: u32) -> u16
+{
+	while sum > 0xFFFF {
+		sum = (sum & 0xFFFF) + (sum >> 16);
+	}
+	sum as u16
+}
+/// Iterate a byte slice as big-endian words (padding with a zero byte if the length is odd)
+pub fn words_be(data: &[u8]) -> WordsBe {
+	WordsBe(data)
+}
+pub struct WordsBe<'a>(&'a [u8]);
+impl<'a> Iterator for WordsBe<'a>
+{
+	type Item = u16;
+	fn next(&mut self) -> Option<u16> {
+		match self.0.len()
+		{
+		0 => None,
+		1 => {
+			let rv = (self.0[0] as u16) << 8;
+			self.0 = &[];
+			Some(rv)
+			},
+		_ => {
+			let rv = (self.0[0] as u16) << 8 | self.0[1] as u16;
+			self.0 = &self.0[2..];
+			Some(rv)
+			},
+		}
+	}
+}
+
+/// Handle an incoming IPv4 packet (called by the ethernet layer)
+pub fn handle_rx_ethernet(local_mac: MacAddr, src_mac: MacAddr, mut r: PacketReader)
+{
+	// 1. Read and validate the header
+	let mut hdr = [0u8; 60];
+	if r.read(&mut hdr[..20]).is_err() {
+		log_notice!("IPv4: Short packet");
+		return ;
+	}
+	if hdr[0] >> 4 != 4 {
+		log_notice!("IPv4: Bad version {}", hdr[0] >> 4);
+		return ;
+	}
+	let hdr_len = (hdr[0] & 0xF) as usize * 4;
+	if hdr_len < 20 {
+		log_notice!("IPv4: Bad header length {}", hdr_len);
+		return ;
+	}
+	if r.read(&mut hdr[20..hdr_len]).is_err() {
+		log_notice!("IPv4: Short packet (options)");
+		return ;
+	}
+	let hdr = &hdr[..hdr_len];
+	if fold_checksum(sum_words(words_be(hdr))) != 0xFFFF {
+		log_notice!("IPv4: Header checksum failed");
+		return ;
+	}
+	let total_len = (hdr[2] as usize) << 8 | hdr[3] as usize;
+	let ident = (hdr[4] as u16) << 8 | hdr[5] as u16;
+	let flags_frag = (hdr[6] as u16) << 8 | hdr[7] as u16;
+	let proto = hdr[9];
+	let source = Address([hdr[12], hdr[13], hdr[14], hdr[15]]);
+	let dest = Address([hdr[16], hdr[17], hdr[18], hdr[19]]);
+
+	// - Strip ethernet padding
+	if total_len < hdr_len || r.limit(total_len - hdr_len).is_err() {
+		log_notice!("IPv4: Bad total length {} (hdr={}, avail={})", total_len, hdr_len, r.remain() + hdr_len);
+		return ;
+	}
+
+	// 2. Check that it's addressed to this interface
+	let iface = match INTERFACES.read().iter().find(|e| e.local_mac == local_mac)
+		{
+		Some(i) => *i,
+		// Interface not (yet) configured, still accept broadcasts (e.g. for DHCP)
+		None => Interface { local_mac: local_mac, address: Address::zero(), mask_len: 0 },
+		};
+	if dest != iface.address && !iface.is_broadcast(dest) && !iface.address.is_zero() {
+		log_trace!("IPv4: Packet for {} not for us ({})", dest, iface.address);
+		return ;
+	}
+
+	// Learn the sender's MAC (avoids ARP traffic when replying)
+	if !source.is_zero() && iface.address.same_net(source, iface.mask_len) {
+		::arp::learn(local_mac, source, src_mac);
+	}
+
+	// 3. Handle fragmentation
+	let more_fragments = flags_frag & 0x2000 != 0;
+	let frag_ofs = (flags_frag & 0x1FFF) as usize * 8;
+	if more_fragments || frag_ofs != 0
+	{
+		if let Some(pkt) = reassemble(source, dest, proto, ident, frag_ofs, more_fragments, r)
+		{
+			dispatch(&iface, source, dest, proto, PacketReader::new(&pkt));
+		}
+	}
+	else
+	{
+		dispatch(&iface, source, dest, proto, r);
+	}
+}
+
+fn dispatch(iface: &Interface, source: Address, dest: Address, proto: u8, r: PacketReader)
+{
+	// Copy the handler out so the lock isn't held while it runs
+	let handler = PROTOCOLS.read().iter().find(|e| e.0 == proto).map(|e| e.1);
+	match handler
+	{
+	Some(h) => h(iface, source, dest, r),
+	None => log_debug!("IPv4: Unhandled protocol {} from {}", proto, source),
+	}
+}
+
+/// A fully reassembled packet
+struct ReassembledPacket(Vec<u8>);
+impl ::nic::RxPacket for ReassembledPacket
+{
+	fn len(&self) -> usize {
+		self.0.len()
+	}
+	fn num_regions(&self) -> usize {
+		1
+	}
+	fn get_region(&self, idx: usize) -> &[u8] {
+		assert!(idx == 0);
+		&self.0
+	}
+	fn get_slice(&self, range: ::core::ops::Range<usize>) -> Option<&[u8]> {
+		if range.start <= range.end && range.end <= self.0.len() {
+			Some(&self.0[range])
+		}
+		else {
+			None
+		}
+	}
+}
+
+struct ReassemblyBuffer
+{
+	source: Address,
+	dest: Address,
+	proto: u8,
+	ident: u16,
+	timestamp: u64,
+	/// Total length (known once the final fragment is seen)
+	total_len: Option<usize>,
+	data: Vec<u8>,
+	/// Sorted list of received (start,end) ranges
+	ranges: Vec<(usize,usize)>,
+}
+impl ReassemblyBuffer
+{
+	fn add_range(&mut self, start: usize, end: usize)
+	{
+		let pos = self.ranges.iter().position(|r| r.0 > start).unwrap_or(self.ranges.len());
+		self.ranges.insert(pos, (start, end));
+		// Merge overlapping/adjacent ranges
+		let mut i = 0;
+		while i + 1 < self.ranges.len()
+		{
+			if self.ranges[i].1 >= self.ranges[i+1].0 {
+				let e = self.ranges.remove(i+1).1;
+				if e > self.ranges[i].1 {
+					self.ranges[i].1 = e;
+				}
+			}
+			else {
+				i += 1;
+			}
+		}
+	}
+	fn is_complete(&self) -> bool {
+		match self.total_len
+		{
+		Some(l) => self.ranges.len() == 1 && self.ranges[0] == (0, l),
+		None => false,
+		}
+	}
+}
+
+fn reassemble(source: Address, dest: Address, proto: u8, ident: u16, ofs: usize, more: bool, mut r: PacketReader) -> Option<ReassembledPacket>
+{
+	let len = r.remain();
+	if ofs + len > 0xFFFF {
+		log_notice!("IPv4: Fragment from {} exceeds maximum size ({}+{})", source, ofs, len);
+		return None;
+	}
+	let now = ::kernel::time::ticks();
+	let mut lh = REASSEMBLY.lock();
+
+	// Expire stale buffers
+	let mut i = 0;
+	while i < lh.len()
+	{
+		if now - lh[i].timestamp > REASSEMBLY_TIMEOUT {
+			let b = lh.remove(i);
+			log_debug!("IPv4: Discarding incomplete packet {} -> {} #{}", b.source, b.dest, b.ident);
+		}
+		else {
+			i += 1;
+		}
+	}
+
+	let idx = match lh.iter().position(|b| b.source == source && b.dest == dest && b.proto == proto && b.ident == ident)
+		{
+		Some(i) => i,
+		None => {
+			if lh.len() >= MAX_REASSEMBLY {
+				log_notice!("IPv4: Too many packets being reassembled, dropping fragment from {}", source);
+				return None;
+			}
+			lh.push(ReassemblyBuffer {
+				source: source,
+				dest: dest,
+				proto: proto,
+				ident: ident,
+				timestamp: now,
+				total_len: None,
+				data: Vec::new(),
+				ranges: Vec::new(),
+				});
+			lh.len() - 1
+			},
+		};
+
+	{
+		let buf = &mut lh[idx];
+		if !more {
+			buf.total_len = Some(ofs + len);
+		}
+		if buf.data.len() < ofs + len {
+			buf.data.resize(ofs + len, 0);
+		}
+		r.read(&mut buf.data[ofs..][..len]).expect("Reading fragment body");
+		buf.add_range(ofs, ofs + len);
+		if !buf.is_complete() {
+			return None;
+		}
+	}
+
+	let mut buf = lh.remove(idx);
+	let total_len = buf.total_len.unwrap();
+	buf.data.truncate(total_len);
+	Some( ReassembledPacket(buf.data) )
+}
+
+/// Send a packet to the specified address
+///
+/// If `source` is the zero address, the interface is selected using the routing table
+pub fn send_packet(source: Address, dest: Address, proto: u8, pkt: SparsePacket) -> Result<(), Error>
+{
+	let (iface, next_hop) = try!(route(source, dest));
+	send_packet_int(&iface, dest, next_hop, proto, pkt)
+}
+
+/// Send a packet out a specific interface (bypassing routing)
+///
+/// Works on unconfigured interfaces (with a zero source address), as needed for DHCP
+pub fn send_packet_via(local_mac: MacAddr, dest: Address, proto: u8, pkt: SparsePacket) -> Result<(), Error>
+{
+	let iface = INTERFACES.read().iter().find(|e| e.local_mac == local_mac).map(|e| *e)
+		.unwrap_or(Interface { local_mac: local_mac, address: Address::zero(), mask_len: 0 });
+	send_packet_int(&iface, dest, dest, proto, pkt)
+}
+
+fn send_packet_int(iface: &Interface, dest: Address, next_hop: Address, proto: u8, pkt: SparsePacket) -> Result<(), Error>
+{
+	let payload_len = pkt.total_len();
+	if payload_len > MAX_PAYLOAD {
+		return Err(Error::TooLarge);
+	}
+
+	let is_broadcast = iface.is_broadcast(next_hop);
+	if !is_broadcast && iface.address.is_zero() {
+		// Can't ARP without an address
+		return Err(Error::NoRoute);
+	}
+
+	let mut hdr = [0u8; 20];
+	let total_len = 20 + payload_len;
+	let ident = NEXT_IDENT.fetch_add(1, Ordering::Relaxed) as u16;
+	hdr[0] = 0x45;	// Version 4, IHL=5
+	hdr[1] = 0;	// DSCP/ECN
+	hdr[2] = (total_len >> 8) as u8;
+	hdr[3] = total_len as u8;
+	hdr[4] = (ident >> 8) as u8;
+	hdr[5] = ident as u8;
+	hdr[6] = 0x40;	// Don't fragment
+	hdr[7] = 0;
+	hdr[8] = 64;	// TTL
+	hdr[9] = proto;
+	hdr[12..16].copy_from_slice(&iface.address.0);
+	hdr[16..20].copy_from_slice(&dest.0);
+	let cksum = calculate_checksum(words_be(&hdr));
+	hdr[10] = (cksum >> 8) as u8;
+	hdr[11] = cksum as u8;
+
+	let pkt = SparsePacket::new_chained(&hdr, &pkt);
+	if is_broadcast {
+		try!(::nic::send_from(iface.local_mac, ::nic::MAC_BROADCAST, ::nic::ether_type::IPV4, pkt));
+	}
+	else {
+		// - Queued if the next hop's MAC address isn't known yet
+		try!(::arp::send_or_queue(iface.local_mac, iface.address, next_hop, pkt));
+	}
+	Ok( () )
+}
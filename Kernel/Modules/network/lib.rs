@@ -14,9 +14,63 @@ extern crate stack_dst;
 module_define!{Network, [], init}
 
 pub mod nic;
+pub mod arp;
+pub mod ipv4;
 pub mod tcp;
+pub mod udp;
+pub mod dhcp;
+
+static S_TIMER_THREAD: ::kernel::sync::mutex::LazyMutex<::kernel::threads::WorkerThread> = lazymutex_init!();
+/// Sleep object of the timer thread (signalled when a new timer is started)
+static S_TIMER_WAKE: ::kernel::sync::Spinlock<Option<::kernel::threads::SleepObjectRef>> = ::kernel::sync::Spinlock::new(None);
 
 fn init()
 {
+	tcp::init();
+	udp::init();
+	S_TIMER_THREAD.init( || ::kernel::threads::WorkerThread::new("Network Timers", timer_thread) );
+}
+
+/// Wake the timer thread to re-check timers (called when a timer is started)
+fn wake_timers()
+{
+	if let Some(ref r) = *S_TIMER_WAKE.lock() {
+		r.signal();
+	}
+}
+
+fn timer_thread()
+{
+	let mut so = ::kernel::threads::SleepObject::new("Network Timers");
+	*S_TIMER_WAKE.lock() = Some(so.get_ref());
+	loop
+	{
+		// - Received DHCP packets are handled on this thread too
+		dhcp::bind_wait(&mut so);
+
+		let now = ::kernel::time::ticks();
+		let next = [tcp::handle_timers(now), dhcp::handle_timers(now), arp::handle_timers(now)].iter()
+			.filter_map(|&t| t)
+			.min();
+
+		// Sleep until the earliest deadline, or until woken by a new timer/packet
+		let timer = match next
+			{
+			Some(t) => match ::kernel::time::Timer::new(t, so.get_ref())
+				{
+				Some(h) => Some(h),
+				None => {
+					log_warning!("Network timers: No kernel timer available, polling");
+					::kernel::threads::yield_time();
+					dhcp::clear_wait(&mut so);
+					continue ;
+					},
+				},
+			None => None,
+			};
+		so.wait();
+		drop(timer);
+		dhcp::clear_wait(&mut so);
+	}
 }
 
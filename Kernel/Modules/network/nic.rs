@@ -16,6 +16,8 @@ pub enum Error
 	MtuExceeded,
 	/// Not enough space avaliable for the packet
 	BufferUnderrun,
+	/// No interface with the requested address
+	NoInterface,
 }
 
 /// Chain of wrapping packet information
@@ -24,6 +26,27 @@ pub struct SparsePacket<'a>
 	head: &'a [u8],
 	next: Option<&'a SparsePacket<'a>>,
 }
+impl<'a> SparsePacket<'a>
+{
+	/// Create a packet from a single buffer (the innermost layer)
+	pub fn new_root(data: &'a [u8]) -> SparsePacket<'a> {
+		SparsePacket {
+			head: data,
+			next: None,
+			}
+	}
+	/// Wrap an existing packet in a new header
+	pub fn new_chained(data: &'a [u8], next: &'a SparsePacket<'a>) -> SparsePacket<'a> {
+		SparsePacket {
+			head: data,
+			next: Some(next),
+			}
+	}
+	/// Total length of the packet (all layers)
+	pub fn total_len(&self) -> usize {
+		self.into_iter().fold(0, |acc, v| acc + v.len())
+	}
+}
 impl<'a> IntoIterator for &'a SparsePacket<'a>
 {
 	type IntoIter = SparsePacketIter<'a>;
@@ -56,6 +79,110 @@ pub trait RxPacket
 	fn get_slice(&self, range: ::core::ops::Range<usize>) -> Option<&[u8]>;
 }
 
+/// Cursor over a received packet, hiding the region boundaries
+pub struct PacketReader<'a>
+{
+	pkt: &'a RxPacket,
+	/// Offset from the start of the packet
+	ofs: usize,
+	/// Logical end of the packet (can be shortened by upper layers, e.g. to strip padding)
+	end: usize,
+}
+impl<'a> PacketReader<'a>
+{
+	pub fn new(pkt: &'a RxPacket) -> PacketReader<'a> {
+		PacketReader {
+			pkt: pkt,
+			ofs: 0,
+			end: pkt.len(),
+			}
+	}
+	/// Number of bytes remaining in the packet
+	pub fn remain(&self) -> usize {
+		self.end - self.ofs
+	}
+	/// Current offset from the start of the packet
+	pub fn pos(&self) -> usize {
+		self.ofs
+	}
+	/// Restrict the reader to the next `len` bytes
+	pub fn limit(&mut self, len: usize) -> Result<(), ()> {
+		if len > self.remain() {
+			Err( () )
+		}
+		else {
+			self.end = self.ofs + len;
+			Ok( () )
+		}
+	}
+	/// Read exactly `dst.len()` bytes (fails without consuming anything if there's not enough data)
+	pub fn read(&mut self, dst: &mut [u8]) -> Result<(), ()> {
+		if dst.len() > self.remain() {
+			return Err( () );
+		}
+		let mut ofs = self.ofs;
+		let mut dst_ofs = 0;
+		// Locate the first region, then copy from it (and subsequent regions)
+		let mut region_start = 0;
+		for i in 0 .. self.pkt.num_regions()
+		{
+			if dst_ofs == dst.len() {
+				break;
+			}
+			let r = self.pkt.get_region(i);
+			let region_end = region_start + r.len();
+			if ofs < region_end
+			{
+				let r = &r[ofs - region_start ..];
+				let len = ::core::cmp::min(r.len(), dst.len() - dst_ofs);
+				dst[dst_ofs ..][..len].copy_from_slice( &r[..len] );
+				dst_ofs += len;
+				ofs += len;
+			}
+			region_start = region_end;
+		}
+		assert_eq!(dst_ofs, dst.len());
+		self.ofs = ofs;
+		Ok( () )
+	}
+	/// Skip `count` bytes
+	pub fn skip(&mut self, count: usize) -> Result<(), ()> {
+		if count > self.remain() {
+			Err( () )
+		}
+		else {
+			self.ofs += count;
+			Ok( () )
+		}
+	}
+	pub fn read_u8(&mut self) -> Result<u8, ()> {
+		let mut b = [0];
+		try!(self.read(&mut b));
+		Ok(b[0])
+	}
+	/// Read a big-endian (network order) u16
+	pub fn read_u16n(&mut self) -> Result<u16, ()> {
+		let mut b = [0; 2];
+		try!(self.read(&mut b));
+		Ok( (b[0] as u16) << 8 | (b[1] as u16) )
+	}
+	/// Read a big-endian (network order) u32
+	pub fn read_u32n(&mut self) -> Result<u32, ()> {
+		let mut b = [0; 4];
+		try!(self.read(&mut b));
+		Ok( (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | (b[3] as u32) )
+	}
+}
+impl<'a> Clone for PacketReader<'a> {
+	fn clone(&self) -> Self {
+		PacketReader {
+			pkt: self.pkt,
+			ofs: self.ofs,
+			end: self.end,
+			}
+	}
+}
+
 /// Network interface API
 pub trait Interface: 'static + Send + Sync
 {
@@ -70,9 +197,14 @@ pub trait Interface: 'static + Send + Sync
 	fn rx_packet(&self) -> Result<PacketHandle, Error>;
 }
 
+/// Ethernet (MAC) address
+pub type MacAddr = [u8; 6];
+/// Broadcast MAC address
+pub const MAC_BROADCAST: MacAddr = [0xFF; 6];
+
 struct InterfaceData
 {
-	#[allow(dead_code)]	// Never read, just exists to hold the handle
+	mac_addr: MacAddr,
 	base_interface: Aref<Interface+'static>,
 	thread: ::kernel::threads::WorkerThread,
 }
@@ -88,6 +220,12 @@ pub struct Registration<T> {
 }
 impl<T> Drop for Registration<T> {
 	fn drop(&mut self) {
+		// Release any DHCP lease first (DHCP sends with its own lock held, so can't be called with the list locked)
+		let mac = INTERFACES_LIST.lock()[self.index].as_ref().map(|e| e.mac_addr);
+		if let Some(mac) = mac {
+			::dhcp::stop(mac);
+		}
+
 		let mut lh = INTERFACES_LIST.lock();
 		assert!( self.index < lh.len() );
 		if let Some(ref int_ent) = lh[self.index] {
@@ -107,24 +245,14 @@ impl<T> ::core::ops::Deref for Registration<T> {
 	}
 }
 
-pub fn register<T: Interface>(mac_addr: [u8; 6], int: T) -> Registration<T> {
+pub fn register<T: Interface>(mac_addr: MacAddr, int: T) -> Registration<T> {
 	let reg = Aref::new(int);
 	let b = reg.borrow();
 
-	// HACK: Send a dummy packet
-	// - An ICMP Echo request to qemu's user network router
-	{
-		let mut pkt = 
-			// MAC Dst                MAC Src     EtherTy IP      TotalLen Identif Frag   TTL Prot CkSum  Source          Dest            ICMP
-			*b"\xFF\xFF\xFF\xFF\xFF\xFF\0\0\0\0\0\0\x08\x00\x45\x00\x00\x23\x00\x00\x00\x00\xFF\x01\xa3\xca\x0A\x00\x02\x0F\x0A\x00\x02\x01\x08\x00\x7d\x0d\x00\x00\x00\x00Hello World"
-			;
-		pkt[6..][..6].copy_from_slice( &mac_addr );
-		reg.tx_raw(SparsePacket { head: &pkt, next: None });
-	}
-
 	let worker_reg = reg.borrow();
 	let reg = InterfaceData {
-		thread: ::kernel::threads::WorkerThread::new("Network Rx", move || rx_thread(&*worker_reg)),
+		mac_addr: mac_addr,
+		thread: ::kernel::threads::WorkerThread::new("Network Rx", move || rx_thread(mac_addr, &*worker_reg)),
 		base_interface: reg,
 		};
 
@@ -139,6 +267,9 @@ pub fn register<T: Interface>(mac_addr: [u8; 6], int: T) -> Registration<T> {
 		return list.len() - 1;
 	}
 	let idx = insert_opt(&mut INTERFACES_LIST.lock(), reg);
+
+	// Request an address for the new interface
+	::dhcp::start(mac_addr);
 	
 	Registration {
 		pd: ::core::marker::PhantomData,
@@ -147,19 +278,86 @@ pub fn register<T: Interface>(mac_addr: [u8; 6], int: T) -> Registration<T> {
 		}
 }
 
-fn rx_thread(int: &Interface)
+/// Ethernet frame types handled by the stack
+pub mod ether_type
+{
+	pub const IPV4: u16 = 0x0800;
+	pub const ARP : u16 = 0x0806;
+}
+
+/// Send a packet using the interface with the specified MAC address
+///
+/// Prepends the Ethernet II header
+pub fn send_from(local_addr: MacAddr, dest_addr: MacAddr, ether_ty: u16, pkt: SparsePacket) -> Result<(), Error>
 {
+	let mut hdr = [0u8; 6+6+2];
+	hdr[0..6].copy_from_slice(&dest_addr);
+	hdr[6..12].copy_from_slice(&local_addr);
+	hdr[12] = (ether_ty >> 8) as u8;
+	hdr[13] = (ether_ty & 0xFF) as u8;
+	let pkt = SparsePacket::new_chained(&hdr, &pkt);
+
+	let lh = INTERFACES_LIST.lock();
+	for ent in lh.iter()
+	{
+		if let Some(ref ent) = *ent
+		{
+			if ent.mac_addr == local_addr
+			{
+				ent.base_interface.tx_raw(pkt);
+				return Ok( () );
+			}
+		}
+	}
+	Err( Error::NoInterface )
+}
+
+fn rx_thread(mac_addr: MacAddr, int: &Interface)
+{
+	let so = ::kernel::threads::SleepObject::new("rx_thread");
+	int.rx_wait_register(&so);
 	loop
 	{
-		let so = ::kernel::threads::SleepObject::new("rx_thread");
-		int.rx_wait_register(&so);
 		so.wait();
-		match int.rx_packet()
+		// Drain all pending packets before sleeping again
+		loop
 		{
-		Ok(pkt) => todo!("Received packet - len={}", pkt.len()),
-		Err(Error::NoPacket) => {},
-		Err(e) => todo!("{:?}", e),
+			match int.rx_packet()
+			{
+			Ok(pkt) => handle_rx_ethernet(mac_addr, &*pkt),
+			Err(Error::NoPacket) => break,
+			Err(e) => {
+				log_warning!("Error receiving packet: {:?}", e);
+				break;
+				},
+			}
 		}
 	}
 }
 
+/// Handle an incoming Ethernet II frame
+fn handle_rx_ethernet(mac_addr: MacAddr, pkt: &RxPacket)
+{
+	let mut reader = PacketReader::new(pkt);
+	let mut dest = [0; 6];
+	let mut src = [0; 6];
+	let ether_ty = match (reader.read(&mut dest), reader.read(&mut src), reader.read_u16n())
+		{
+		(Ok(_), Ok(_), Ok(v)) => v,
+		_ => {
+			log_notice!("Short packet ({} < {})", pkt.len(), 6+6+2);
+			return ;
+			},
+		};
+	if dest != mac_addr && dest != MAC_BROADCAST && dest[0] & 1 == 0 {
+		// Not for us (and not broadcast/multicast), ignore
+		return ;
+	}
+
+	match ether_ty
+	{
+	ether_type::IPV4 => ::ipv4::handle_rx_ethernet(mac_addr, src, reader),
+	ether_type::ARP => ::arp::handle_packet(mac_addr, src, reader),
+	_ => log_debug!("Unhandled ethernet type {:#06x} from {:x}", ether_ty, ::kernel::lib::FmtSlice(&src)),
+	}
+}
@@ -3,10 +3,1148 @@
 //
 // Modules/network/tcp.rs
 //! Transmission Control Protocol (Layer 4)
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::sync::Mutex;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use ipv4::Address;
+use nic::{PacketReader,SparsePacket};
 
-#[repr(C)]
+const IPV4_PROTO_TCP: u8 = 6;
+
+/// Size of the per-connection receive buffer (also the maximum advertised window)
+const RX_BUFFER_SIZE: usize = 0x4000;
+/// Size of the per-connection transmit buffer
+const TX_BUFFER_SIZE: usize = 0x4000;
+/// Maximum number of out-of-order segments held per connection
+const MAX_OOO_SEGMENTS: usize = 32;
+/// Default MSS (used if the remote doesn't specify one)
+const DEFAULT_MSS: usize = 536;
+/// Local MSS (Ethernet MTU - IPv4 header - TCP header)
+const LOCAL_MSS: usize = ::ipv4::MAX_PAYLOAD - 20;
+/// Initial retransmission timeout (ms)
+const INITIAL_RTO: u64 = 1000;
+/// Maximum retransmission timeout (ms)
+const MAX_RTO: u64 = 60*1000;
+/// Number of retransmissions before the connection is dropped
+const MAX_RETRANSMITS: u32 = 8;
+/// Time spent in TIME-WAIT (2*MSL)
+const TIME_WAIT_DURATION: u64 = 2*30*1000;
+/// First port used for outgoing connections
+const EPHEMERAL_PORT_BASE: u16 = 49152;
+
+const FLAG_FIN: u8 = 0x01;
+const FLAG_SYN: u8 = 0x02;
+const FLAG_RST: u8 = 0x04;
+const FLAG_PSH: u8 = 0x08;
+const FLAG_ACK: u8 = 0x10;
+
+static CONNECTIONS: Mutex<Vec<Arc<ConnectionShared>>> = Mutex::new(Vec::new_const());
+static SERVERS: Mutex<Vec<Arc<ServerShared>>> = Mutex::new(Vec::new_const());
+static NEXT_EPHEMERAL: AtomicUsize = AtomicUsize::new(0);
+static ISS_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init()
+{
+	::ipv4::register_handler(IPV4_PROTO_TCP, rx_handler_v4).expect("Unable to register TCP with IPv4");
+}
+
+/// Errors from TCP operations
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum ConnError
+{
+	/// No route to the remote host
+	NoRoute,
+	/// The local side has closed the connection
+	LocalClosed,
+	/// The remote host refused the connection (RST in response to SYN)
+	RemoteRefused,
+	/// The remote host reset the connection
+	RemoteReset,
+	/// The remote host stopped responding
+	TimedOut,
+	/// The requested local port is already in use
+	AddressInUse,
+	/// No free ephemeral ports
+	NoPorts,
+}
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+struct Quad
+{
+	local_addr: Address,
+	local_port: u16,
+	remote_addr: Address,
+	remote_port: u16,
+}
+
+/// Connection states (RFC 793, minus LISTEN which is handled by `Server`)
+#[derive(Copy,Clone,PartialEq,Debug)]
+enum ConnectionState
+{
+	SynSent,
+	SynReceived,
+	Established,
+	FinWait1,
+	FinWait2,
+	CloseWait,
+	Closing,
+	LastAck,
+	TimeWait,
+	Closed,
+}
+
+/// Sequence number comparison (a < b, with wrapping)
+fn seq_lt(a: u32, b: u32) -> bool {
+	(a.wrapping_sub(b) as i32) < 0
+}
+/// Sequence number comparison (a <= b, with wrapping)
+fn seq_le(a: u32, b: u32) -> bool {
+	a == b || seq_lt(a, b)
+}
+
+fn wake_all(src: &::kernel::async::queue::Source) {
+	while src.wake_one() {
+	}
+}
+
+/// Remove `count` bytes from the front of a buffer
+fn drain_front(buf: &mut Vec<u8>, count: usize) {
+	let remain = buf.len() - count;
+	for i in 0 .. remain {
+		buf[i] = buf[count + i];
+	}
+	buf.truncate(remain);
+}
+
+/// Decoded TCP header
 struct PktHeader
 {
+	source_port: u16,
+	dest_port: u16,
+	sequence_number: u32,
+	acknowledgement_number: u32,
+	/// Header length in 32-bit words
+	data_offset: u8,
+	flags: u8,
+	window_size: u16,
+	checksum: u16,
+	urgent_pointer: u16,
+}
+impl PktHeader
+{
+	fn parse(buf: &[u8]) -> Option<PktHeader> {
+		if buf.len() < 20 {
+			return None;
+		}
+		Some(PktHeader {
+			source_port: (buf[0] as u16) << 8 | buf[1] as u16,
+			dest_port: (buf[2] as u16) << 8 | buf[3] as u16,
+			sequence_number: (buf[4] as u32) << 24 | (buf[5] as u32) << 16 | (buf[6] as u32) << 8 | buf[7] as u32,
+			acknowledgement_number: (buf[8] as u32) << 24 | (buf[9] as u32) << 16 | (buf[10] as u32) << 8 | buf[11] as u32,
+			data_offset: buf[12] >> 4,
+			flags: buf[13],
+			window_size: (buf[14] as u16) << 8 | buf[15] as u16,
+			checksum: (buf[16] as u16) << 8 | buf[17] as u16,
+			urgent_pointer: (buf[18] as u16) << 8 | buf[19] as u16,
+			})
+	}
+	fn encode(&self, dst: &mut [u8]) {
+		dst[0] = (self.source_port >> 8) as u8;
+		dst[1] = self.source_port as u8;
+		dst[2] = (self.dest_port >> 8) as u8;
+		dst[3] = self.dest_port as u8;
+		dst[4] = (self.sequence_number >> 24) as u8;
+		dst[5] = (self.sequence_number >> 16) as u8;
+		dst[6] = (self.sequence_number >> 8) as u8;
+		dst[7] = self.sequence_number as u8;
+		dst[8] = (self.acknowledgement_number >> 24) as u8;
+		dst[9] = (self.acknowledgement_number >> 16) as u8;
+		dst[10] = (self.acknowledgement_number >> 8) as u8;
+		dst[11] = self.acknowledgement_number as u8;
+		dst[12] = self.data_offset << 4;
+		dst[13] = self.flags;
+		dst[14] = (self.window_size >> 8) as u8;
+		dst[15] = self.window_size as u8;
+		dst[16] = (self.checksum >> 8) as u8;
+		dst[17] = self.checksum as u8;
+		dst[18] = (self.urgent_pointer >> 8) as u8;
+		dst[19] = self.urgent_pointer as u8;
+	}
+}
+
+/// Compute the TCP checksum (including the IPv4 pseudo-header)
+fn calculate_checksum(src: Address, dest: Address, hdr: &[u8], data: &[u8]) -> u16
+{
+	let tcp_len = hdr.len() + data.len();
+	let pseudo = [
+		src.0[0], src.0[1], src.0[2], src.0[3],
+		dest.0[0], dest.0[1], dest.0[2], dest.0[3],
+		0, IPV4_PROTO_TCP, (tcp_len >> 8) as u8, tcp_len as u8,
+		];
+	// NOTE: Header is always a multiple of four bytes, so concatenating the word iterators is valid
+	let sum = ::ipv4::sum_words(::ipv4::words_be(&pseudo))
+		+ ::ipv4::sum_words(::ipv4::words_be(hdr))
+		+ ::ipv4::sum_words(::ipv4::words_be(data));
+	!::ipv4::fold_checksum(sum)
+}
+
+/// Send a single segment
+fn send_packet(quad: &Quad, seq: u32, ack: u32, flags: u8, window: u16, data: &[u8])
+{
+	let mut hdr_buf = [0u8; 24];
+	// Include a MSS option on SYN
+	let hdr_len = if flags & FLAG_SYN != 0 {
+			hdr_buf[20] = 2;	// MSS
+			hdr_buf[21] = 4;
+			hdr_buf[22] = (LOCAL_MSS >> 8) as u8;
+			hdr_buf[23] = LOCAL_MSS as u8;
+			24
+		}
+		else {
+			20
+		};
+	let hdr = PktHeader {
+		source_port: quad.local_port,
+		dest_port: quad.remote_port,
+		sequence_number: seq,
+		acknowledgement_number: if flags & FLAG_ACK != 0 { ack } else { 0 },
+		data_offset: (hdr_len / 4) as u8,
+		flags: flags,
+		window_size: window,
+		checksum: 0,
+		urgent_pointer: 0,
+		};
+	hdr.encode(&mut hdr_buf);
+	let cksum = calculate_checksum(quad.local_addr, quad.remote_addr, &hdr_buf[..hdr_len], data);
+	hdr_buf[16] = (cksum >> 8) as u8;
+	hdr_buf[17] = cksum as u8;
+
+	let pkt_data = SparsePacket::new_root(data);
+	let pkt = SparsePacket::new_chained(&hdr_buf[..hdr_len], &pkt_data);
+	match ::ipv4::send_packet(quad.local_addr, quad.remote_addr, IPV4_PROTO_TCP, pkt)
+	{
+	Ok(_) => {},
+	Err(e) => log_notice!("TCP: Failed to send to {}:{} - {:?}", quad.remote_addr, quad.remote_port, e),
+	}
+}
+
+/// Generate an initial sequence number
+fn get_iss() -> u32
+{
+	// Clock-based, as suggested by RFC 793 (with a counter to avoid duplicates within a tick)
+	(::kernel::time::ticks() as u32).wrapping_mul(250).wrapping_add( ISS_COUNTER.fetch_add(0x10000, Ordering::Relaxed) as u32 )
 }
 
+fn rx_handler_v4(_int: &::ipv4::Interface, src_addr: Address, dest_addr: Address, mut r: PacketReader)
+{
+	// Read the entire segment so the checksum can be validated
+	let mut buf: Vec<u8> = vec![0; r.remain()];
+	r.read(&mut buf).expect("Reading TCP segment");
+	let hdr = match PktHeader::parse(&buf)
+		{
+		Some(h) => h,
+		None => {
+			log_notice!("TCP: Short packet from {}", src_addr);
+			return ;
+			},
+		};
+	let hdr_len = hdr.data_offset as usize * 4;
+	if hdr_len < 20 || hdr_len > buf.len() {
+		log_notice!("TCP: Bad header length {} from {}", hdr_len, src_addr);
+		return ;
+	}
+	// Checksum is mandatory for TCP (unlike UDP, zero doesn't mean "not present")
+	{
+		let (h, d) = buf.split_at(hdr_len);
+		let mut h2 = [0u8; 60];
+		h2[..hdr_len].copy_from_slice(h);
+		h2[16] = 0;
+		h2[17] = 0;
+		if calculate_checksum(src_addr, dest_addr, &h2[..hdr_len], d) != hdr.checksum {
+			log_notice!("TCP: Bad checksum from {}:{}", src_addr, hdr.source_port);
+			return ;
+		}
+	}
+	// Parse options (only MSS is used)
+	let mut mss = None;
+	{
+		let mut opts = &buf[20 .. hdr_len];
+		while opts.len() > 0
+		{
+			match opts[0]
+			{
+			0 => break,
+			1 => { opts = &opts[1..]; },
+			kind => {
+				if opts.len() < 2 || (opts[1] as usize) < 2 || opts.len() < opts[1] as usize {
+					break;
+				}
+				if kind == 2 && opts[1] == 4 {
+					mss = Some( (opts[2] as usize) << 8 | opts[3] as usize );
+				}
+				opts = &opts[opts[1] as usize ..];
+				},
+			}
+		}
+	}
+	let data = &buf[hdr_len..];
+
+	let quad = Quad {
+		local_addr: dest_addr,
+		local_port: hdr.dest_port,
+		remote_addr: src_addr,
+		remote_port: hdr.source_port,
+		};
+	log_trace!("TCP: {:?} seq={:#x} ack={:#x} flags={:#x} len={}", quad, hdr.sequence_number, hdr.acknowledgement_number, hdr.flags, data.len());
+
+	// 1. Check for an existing connection
+	let conn = CONNECTIONS.lock().iter().find(|c| c.quad == quad).cloned();
+	if let Some(conn) = conn
+	{
+		handle_packet(&conn, &hdr, data);
+		return ;
+	}
+
+	// 2. Check for a listening server (only for SYN)
+	if hdr.flags & (FLAG_SYN|FLAG_ACK|FLAG_RST) == FLAG_SYN
+	{
+		let server = SERVERS.lock().iter()
+			.find(|s| s.port == quad.local_port && (s.local_addr.is_zero() || s.local_addr == quad.local_addr))
+			.cloned();
+		if let Some(server) = server
+		{
+			if server.accept_queue.lock().len() >= server.backlog {
+				log_notice!("TCP: Listen queue full for port {}, dropping SYN from {}", server.port, quad.remote_addr);
+				return ;
+			}
+			let iss = get_iss();
+			let mut conn = Connection::new(ConnectionState::SynReceived, iss);
+			conn.rx_next = hdr.sequence_number.wrapping_add(1);
+			conn.tx_window = hdr.window_size as u32;
+			conn.tx_mss = ::core::cmp::min(mss.unwrap_or(DEFAULT_MSS), LOCAL_MSS);
+			conn.listener = Some(server);
+			send_packet(&quad, iss, conn.rx_next, FLAG_SYN|FLAG_ACK, conn.rx_window(), &[]);
+			conn.tx_next = iss.wrapping_add(1);
+			conn.start_retransmit_timer();
+			CONNECTIONS.lock().push( Arc::new(ConnectionShared::new(quad, conn)) );
+			return ;
+		}
+	}
+
+	// 3. Otherwise, reply with RST (unless this is itself a RST)
+	if hdr.flags & FLAG_RST == 0
+	{
+		log_debug!("TCP: No connection for {:?}, sending RST", quad);
+		if hdr.flags & FLAG_ACK != 0 {
+			send_packet(&quad, hdr.acknowledgement_number, 0, FLAG_RST, 0, &[]);
+		}
+		else {
+			let seg_len = data.len() as u32 + if hdr.flags & FLAG_SYN != 0 { 1 } else { 0 } + if hdr.flags & FLAG_FIN != 0 { 1 } else { 0 };
+			send_packet(&quad, 0, hdr.sequence_number.wrapping_add(seg_len), FLAG_RST|FLAG_ACK, 0, &[]);
+		}
+	}
+}
+
+/// Handle a segment for an existing connection
+fn handle_packet(conn: &Arc<ConnectionShared>, hdr: &PktHeader, data: &[u8])
+{
+	let (became_established, closed) = {
+		let mut lh = conn.conn.lock();
+		let was_established = lh.is_synchronised();
+		lh.handle_packet(&conn.quad, hdr, data);
+		(!was_established && lh.is_synchronised(), lh.state == ConnectionState::Closed)
+		};
+
+	// Notify the listening server of a newly opened connection
+	if became_established
+	{
+		let listener = conn.conn.lock().listener.take();
+		if let Some(server) = listener
+		{
+			server.accept_queue.lock().push(conn.clone());
+			server.waiters.wake_one();
+		}
+	}
+	if closed
+	{
+		remove_connection(&conn.quad);
+	}
+	wake_all(&conn.rx_waiters);
+	wake_all(&conn.tx_waiters);
+}
+
+fn remove_connection(quad: &Quad)
+{
+	let mut lh = CONNECTIONS.lock();
+	if let Some(i) = lh.iter().position(|c| c.quad == *quad) {
+		lh.remove(i);
+	}
+}
+
+/// Process retransmission/TIME-WAIT timers (called from the network timer thread)
+pub fn handle_timers(now: u64) -> Option<u64>
+{
+	let mut next: Option<u64> = None;
+	let conns: Vec<Arc<ConnectionShared>> = CONNECTIONS.lock().iter().cloned().collect();
+	for c in conns
+	{
+		let (fired, closed) = {
+			let mut lh = c.conn.lock();
+			let fired = lh.check_timers(&c.quad, now);
+			for t in lh.retransmit_at.iter().chain(lh.time_wait_until.iter()) {
+				next = Some(next.map(|n| ::core::cmp::min(n, *t)).unwrap_or(*t));
+			}
+			(fired, lh.state == ConnectionState::Closed)
+			};
+		if closed {
+			remove_connection(&c.quad);
+		}
+		if fired || closed {
+			wake_all(&c.rx_waiters);
+			wake_all(&c.tx_waiters);
+		}
+	}
+	next
+}
+
+struct ConnectionShared
+{
+	quad: Quad,
+	conn: Mutex<Connection>,
+	rx_waiters: ::kernel::async::queue::Source,
+	tx_waiters: ::kernel::async::queue::Source,
+}
+impl ConnectionShared
+{
+	fn new(quad: Quad, conn: Connection) -> ConnectionShared {
+		ConnectionShared {
+			quad: quad,
+			conn: Mutex::new(conn),
+			rx_waiters: Default::default(),
+			tx_waiters: Default::default(),
+		}
+	}
+}
+
+struct Connection
+{
+	state: ConnectionState,
+	/// Error to report to the user once the connection has failed
+	error: Option<ConnError>,
+	/// Listening server to notify once the connection is established
+	listener: Option<Arc<ServerShared>>,
+
+	/// SND.UNA - Oldest unacknowledged sequence number
+	tx_unacked: u32,
+	/// SND.NXT - Next sequence number to be sent
+	tx_next: u32,
+	/// SND.WND - Window advertised by the remote
+	tx_window: u32,
+	/// Maximum segment size for transmitted segments
+	tx_mss: usize,
+	/// Sequence number of the first byte in `tx_buffer`
+	tx_buffer_seq: u32,
+	/// Data queued by the user that has not yet been acknowledged
+	tx_buffer: Vec<u8>,
+	/// The user has closed the send side (FIN to be sent once the buffer drains)
+	tx_closed: bool,
+	/// The FIN has been sent (and occupies the last sequence number before `tx_next`)
+	tx_fin_sent: bool,
+
+	/// RCV.NXT - Next expected sequence number
+	rx_next: u32,
+	/// In-order data waiting for the user
+	rx_buffer: Vec<u8>,
+	/// Out-of-order segments (sorted by sequence number)
+	rx_ooo: Vec<(u32, Vec<u8>)>,
+	/// The remote has sent a FIN
+	rx_closed: bool,
+
+	/// Current retransmission timeout
+	rto: u64,
+	/// Time at which the retransmission timer expires
+	retransmit_at: Option<u64>,
+	retransmit_count: u32,
+	/// Time at which TIME-WAIT ends
+	time_wait_until: Option<u64>,
+}
+impl Connection
+{
+	fn new(state: ConnectionState, iss: u32) -> Connection {
+		Connection {
+			state: state,
+			error: None,
+			listener: None,
+			tx_unacked: iss,
+			tx_next: iss,
+			tx_window: 0,
+			tx_mss: DEFAULT_MSS,
+			tx_buffer_seq: iss.wrapping_add(1),
+			tx_buffer: Vec::new(),
+			tx_closed: false,
+			tx_fin_sent: false,
+			rx_next: 0,
+			rx_buffer: Vec::new(),
+			rx_ooo: Vec::new(),
+			rx_closed: false,
+			rto: INITIAL_RTO,
+			retransmit_at: None,
+			retransmit_count: 0,
+			time_wait_until: None,
+		}
+	}
+
+	/// Returns true once the three-way handshake has completed
+	fn is_synchronised(&self) -> bool {
+		match self.state
+		{
+		ConnectionState::SynSent | ConnectionState::SynReceived => false,
+		ConnectionState::Closed => self.error.is_none(),
+		_ => true,
+		}
+	}
+
+	/// Current receive window
+	fn rx_window(&self) -> u16 {
+		(RX_BUFFER_SIZE - self.rx_buffer.len()) as u16
+	}
+
+	fn start_retransmit_timer(&mut self) {
+		if self.retransmit_at.is_none() {
+			self.retransmit_at = Some(::kernel::time::ticks() + self.rto);
+			::wake_timers();
+		}
+	}
+
+	fn fail(&mut self, err: ConnError) {
+		log_notice!("TCP: Connection failed - {:?} (state {:?})", err, self.state);
+		self.state = ConnectionState::Closed;
+		self.error = Some(err);
+		self.retransmit_at = None;
+		self.tx_buffer.truncate(0);
+	}
+
+	fn send_ack(&self, quad: &Quad) {
+		send_packet(quad, self.tx_next, self.rx_next, FLAG_ACK, self.rx_window(), &[]);
+	}
+
+	/// Number of bytes of `tx_buffer` that have been sent at least once
+	fn tx_sent_len(&self) -> usize {
+		if self.tx_fin_sent {
+			self.tx_buffer.len()
+		}
+		else if seq_lt(self.tx_buffer_seq, self.tx_next) {
+			self.tx_next.wrapping_sub(self.tx_buffer_seq) as usize
+		}
+		else {
+			0
+		}
+	}
+
+	/// Transmit as much queued data as the remote window allows (and the FIN if pending)
+	fn flush_tx(&mut self, quad: &Quad)
+	{
+		match self.state
+		{
+		ConnectionState::Established | ConnectionState::CloseWait => {},
+		_ => return,
+		}
+
+		loop
+		{
+			let sent = self.tx_sent_len();
+			let in_flight = self.tx_next.wrapping_sub(self.tx_unacked);
+			let window_left = self.tx_window.saturating_sub(in_flight) as usize;
+			let len = ::core::cmp::min( ::core::cmp::min(self.tx_buffer.len() - sent, window_left), self.tx_mss );
+			if len == 0 {
+				break;
+			}
+			send_packet(quad, self.tx_next, self.rx_next, FLAG_ACK|FLAG_PSH, self.rx_window(), &self.tx_buffer[sent..][..len]);
+			self.tx_next = self.tx_next.wrapping_add(len as u32);
+			self.start_retransmit_timer();
+		}
+
+		if self.tx_closed && !self.tx_fin_sent && self.tx_sent_len() == self.tx_buffer.len()
+		{
+			send_packet(quad, self.tx_next, self.rx_next, FLAG_ACK|FLAG_FIN, self.rx_window(), &[]);
+			self.tx_next = self.tx_next.wrapping_add(1);
+			self.tx_fin_sent = true;
+			self.state = match self.state
+				{
+				ConnectionState::Established => ConnectionState::FinWait1,
+				ConnectionState::CloseWait => ConnectionState::LastAck,
+				s => s,
+				};
+			self.start_retransmit_timer();
+		}
+		else if self.tx_window == 0 && self.tx_sent_len() < self.tx_buffer.len()
+		{
+			// Zero window, ensure that the timer is running so a probe is sent
+			self.start_retransmit_timer();
+		}
+	}
+
+	/// Handle timer expiry, returns true if the state changed
+	fn check_timers(&mut self, quad: &Quad, now: u64) -> bool
+	{
+		if let Some(t) = self.time_wait_until
+		{
+			if now >= t {
+				self.time_wait_until = None;
+				self.state = ConnectionState::Closed;
+				return true;
+			}
+		}
+
+		match self.retransmit_at
+		{
+		Some(t) if now >= t => {},
+		_ => return false,
+		}
+		self.retransmit_at = None;
+
+		self.retransmit_count += 1;
+		if self.retransmit_count > MAX_RETRANSMITS {
+			send_packet(quad, self.tx_next, 0, FLAG_RST, 0, &[]);
+			self.fail(ConnError::TimedOut);
+			return true;
+		}
+		self.rto = ::core::cmp::min(self.rto * 2, MAX_RTO);
+		log_debug!("TCP: Retransmit #{} for {:?} (state {:?}, rto={})", self.retransmit_count, quad, self.state, self.rto);
+
+		match self.state
+		{
+		ConnectionState::SynSent => {
+			send_packet(quad, self.tx_unacked, 0, FLAG_SYN, self.rx_window(), &[]);
+			self.start_retransmit_timer();
+			},
+		ConnectionState::SynReceived => {
+			send_packet(quad, self.tx_unacked, self.rx_next, FLAG_SYN|FLAG_ACK, self.rx_window(), &[]);
+			self.start_retransmit_timer();
+			},
+		ConnectionState::Closed | ConnectionState::TimeWait => {},
+		_ => {
+			let sent = self.tx_sent_len();
+			if sent > 0
+			{
+				// Resend the oldest unacknowledged segment
+				let len = ::core::cmp::min(sent, self.tx_mss);
+				send_packet(quad, self.tx_buffer_seq, self.rx_next, FLAG_ACK|FLAG_PSH, self.rx_window(), &self.tx_buffer[..len]);
+				self.start_retransmit_timer();
+			}
+			else if self.tx_fin_sent && self.tx_unacked != self.tx_next
+			{
+				send_packet(quad, self.tx_next.wrapping_sub(1), self.rx_next, FLAG_ACK|FLAG_FIN, self.rx_window(), &[]);
+				self.start_retransmit_timer();
+			}
+			else if self.tx_buffer.len() > 0
+			{
+				// Zero-window probe (a single byte beyond the window)
+				send_packet(quad, self.tx_next, self.rx_next, FLAG_ACK, self.rx_window(), &self.tx_buffer[..1]);
+				self.tx_next = self.tx_next.wrapping_add(1);
+				self.start_retransmit_timer();
+			}
+			},
+		}
+		false
+	}
+
+	/// Process a received segment (RFC 793 "SEGMENT ARRIVES")
+	fn handle_packet(&mut self, quad: &Quad, hdr: &PktHeader, data: &[u8])
+	{
+		let seq = hdr.sequence_number;
+		let ack = hdr.acknowledgement_number;
+
+		// --- Reset handling ---
+		if hdr.flags & FLAG_RST != 0
+		{
+			match self.state
+			{
+			ConnectionState::SynSent => {
+				if hdr.flags & FLAG_ACK != 0 && ack == self.tx_next {
+					self.fail(ConnError::RemoteRefused);
+				}
+				},
+			ConnectionState::Closed => {},
+			_ => {
+				// Only accept resets within the receive window
+				if seq_le(self.rx_next, seq) && seq_lt(seq, self.rx_next.wrapping_add(self.rx_window() as u32 + 1)) {
+					self.fail(ConnError::RemoteReset);
+				}
+				},
+			}
+			return ;
+		}
+
+		match self.state
+		{
+		ConnectionState::Closed => return,
+		ConnectionState::SynSent => {
+			if hdr.flags & FLAG_ACK != 0 && ack != self.tx_next {
+				// Unacceptable ACK
+				send_packet(quad, ack, 0, FLAG_RST, 0, &[]);
+				return ;
+			}
+			if hdr.flags & (FLAG_SYN|FLAG_ACK) == (FLAG_SYN|FLAG_ACK) {
+				self.rx_next = seq.wrapping_add(1);
+				self.tx_unacked = ack;
+				self.tx_window = hdr.window_size as u32;
+				self.retransmit_at = None;
+				self.retransmit_count = 0;
+				self.rto = INITIAL_RTO;
+				self.state = ConnectionState::Established;
+				self.send_ack(quad);
+				self.flush_tx(quad);
+			}
+			// NOTE: Simultaneous open (SYN without ACK) is not supported
+			return ;
+			},
+		_ => {},
+		}
+
+		// --- Sequence number acceptability ---
+		if hdr.flags & FLAG_SYN != 0
+		{
+			if self.state == ConnectionState::SynReceived && seq.wrapping_add(1) == self.rx_next {
+				// Retransmitted SYN, resend the SYN-ACK
+				send_packet(quad, self.tx_unacked, self.rx_next, FLAG_SYN|FLAG_ACK, self.rx_window(), &[]);
+			}
+			else {
+				self.send_ack(quad);
+			}
+			return ;
+		}
+		let seg_end = seq.wrapping_add(data.len() as u32);
+		if data.len() > 0 && seq_le(seg_end, self.rx_next) {
+			// Old duplicate, the remote missed our ACK
+			self.send_ack(quad);
+			return ;
+		}
+		if seq_le(self.rx_next.wrapping_add(self.rx_window() as u32), seq) && !(data.len() == 0 && seq == self.rx_next) {
+			// Beyond the window
+			self.send_ack(quad);
+			return ;
+		}
+
+		// --- ACK processing ---
+		if hdr.flags & FLAG_ACK == 0 {
+			return ;
+		}
+		if self.state == ConnectionState::SynReceived
+		{
+			if ack != self.tx_next {
+				send_packet(quad, ack, 0, FLAG_RST, 0, &[]);
+				return ;
+			}
+			self.state = ConnectionState::Established;
+		}
+		if seq_lt(self.tx_next, ack) {
+			// Acknowledging data not yet sent
+			self.send_ack(quad);
+			return ;
+		}
+		if seq_lt(self.tx_unacked, ack)
+		{
+			// Release acknowledged data from the buffer
+			if seq_lt(self.tx_buffer_seq, ack) {
+				let n = ::core::cmp::min(ack.wrapping_sub(self.tx_buffer_seq) as usize, self.tx_buffer.len());
+				drain_front(&mut self.tx_buffer, n);
+				self.tx_buffer_seq = self.tx_buffer_seq.wrapping_add(n as u32);
+			}
+			self.tx_unacked = ack;
+			// Restart the retransmit timer for the remaining data
+			self.retransmit_at = None;
+			self.retransmit_count = 0;
+			self.rto = INITIAL_RTO;
+			if self.tx_unacked != self.tx_next {
+				self.start_retransmit_timer();
+			}
+		}
+		self.tx_window = hdr.window_size as u32;
+
+		let fin_acked = self.tx_fin_sent && self.tx_unacked == self.tx_next;
+		match self.state
+		{
+		ConnectionState::FinWait1 if fin_acked => { self.state = ConnectionState::FinWait2; },
+		ConnectionState::Closing if fin_acked => { self.enter_time_wait(); },
+		ConnectionState::LastAck if fin_acked => {
+			self.state = ConnectionState::Closed;
+			return ;
+			},
+		ConnectionState::TimeWait => {
+			// Retransmitted FIN, re-acknowledge and restart the timer
+			if hdr.flags & FLAG_FIN != 0 {
+				self.send_ack(quad);
+				self.enter_time_wait();
+			}
+			return ;
+			},
+		_ => {},
+		}
+
+		// --- Segment data ---
+		let mut need_ack = false;
+		if data.len() > 0
+		{
+			match self.state
+			{
+			ConnectionState::Established | ConnectionState::FinWait1 | ConnectionState::FinWait2 => {
+				self.accept_data(seq, data);
+				need_ack = true;
+				},
+			_ => {},
+			}
+		}
+
+		// --- FIN ---
+		if hdr.flags & FLAG_FIN != 0
+		{
+			if seg_end == self.rx_next && !self.rx_closed
+			{
+				self.rx_next = self.rx_next.wrapping_add(1);
+				self.rx_closed = true;
+				match self.state
+				{
+				ConnectionState::Established => { self.state = ConnectionState::CloseWait; },
+				ConnectionState::FinWait1 => { self.state = ConnectionState::Closing; },
+				ConnectionState::FinWait2 => { self.enter_time_wait(); },
+				_ => {},
+				}
+			}
+			need_ack = true;
+		}
+
+		if need_ack {
+			self.send_ack(quad);
+		}
+		self.flush_tx(quad);
+	}
+
+	fn enter_time_wait(&mut self) {
+		self.state = ConnectionState::TimeWait;
+		self.retransmit_at = None;
+		self.time_wait_until = Some(::kernel::time::ticks() + TIME_WAIT_DURATION);
+		::wake_timers();
+	}
+
+	/// Append in-order data to the receive buffer (returns the number of bytes accepted)
+	fn push_rx(&mut self, seq: u32, data: &[u8]) -> usize {
+		// Trim data that has already been received
+		let skip = self.rx_next.wrapping_sub(seq) as usize;
+		if skip >= data.len() {
+			return 0;
+		}
+		let data = &data[skip..];
+		let len = ::core::cmp::min(data.len(), RX_BUFFER_SIZE - self.rx_buffer.len());
+		self.rx_buffer.push_all(&data[..len]);
+		self.rx_next = self.rx_next.wrapping_add(len as u32);
+		len
+	}
+
+	fn accept_data(&mut self, seq: u32, data: &[u8])
+	{
+		if seq_le(seq, self.rx_next)
+		{
+			self.push_rx(seq, data);
+			// Pull in any out-of-order segments that are now contiguous
+			while let Some(i) = self.rx_ooo.iter().position(|e| seq_le(e.0, self.rx_next))
+			{
+				let (s, d) = self.rx_ooo.remove(i);
+				self.push_rx(s, &d);
+			}
+		}
+		else
+		{
+			// Out of order, hold until the gap is filled
+			if self.rx_ooo.iter().any(|e| e.0 == seq) {
+				return ;
+			}
+			if self.rx_ooo.len() >= MAX_OOO_SEGMENTS {
+				log_debug!("TCP: Out-of-order queue full, dropping segment {:#x}", seq);
+				return ;
+			}
+			let pos = self.rx_ooo.iter().position(|e| seq_lt(seq, e.0)).unwrap_or(self.rx_ooo.len());
+			self.rx_ooo.insert(pos, (seq, Vec::from(data)));
+		}
+	}
+}
+
+/// Handle to a TCP connection
+pub struct ConnectionHandle(Arc<ConnectionShared>);
+impl ConnectionHandle
+{
+	/// Open a connection to a remote host (blocks until the connection is established or fails)
+	pub fn connect(addr: Address, port: u16) -> Result<ConnectionHandle, ConnError>
+	{
+		let local_addr = match ::ipv4::route_source(addr)
+			{
+			Some(a) => a,
+			None => return Err(ConnError::NoRoute),
+			};
+		let handle = {
+			let mut lh = CONNECTIONS.lock();
+			let local_port = match allocate_port(&lh, local_addr, addr, port)
+				{
+				Some(p) => p,
+				None => return Err(ConnError::NoPorts),
+				};
+			let quad = Quad {
+				local_addr: local_addr,
+				local_port: local_port,
+				remote_addr: addr,
+				remote_port: port,
+				};
+			let iss = get_iss();
+			let mut conn = Connection::new(ConnectionState::SynSent, iss);
+			conn.tx_next = iss.wrapping_add(1);
+			conn.start_retransmit_timer();
+			let shared = Arc::new(ConnectionShared::new(quad, conn));
+			lh.push(shared.clone());
+			ConnectionHandle(shared)
+			};
+		log_debug!("TCP: Connecting {:?}", handle.0.quad);
+		{
+			let c = handle.0.conn.lock();
+			send_packet(&handle.0.quad, c.tx_unacked, 0, FLAG_SYN, c.rx_window(), &[]);
+		}
+
+		// Wait for the handshake to complete
+		let mut so = ::kernel::threads::SleepObject::new("TCP connect");
+		loop
+		{
+			handle.0.rx_waiters.wait_upon(&mut so);
+			let done = {
+				let c = handle.0.conn.lock();
+				match c.state
+				{
+				ConnectionState::SynSent => None,
+				ConnectionState::Closed => Some( Err(c.error.unwrap_or(ConnError::RemoteReset)) ),
+				_ => Some( Ok(()) ),
+				}
+				};
+			if let Some(rv) = done {
+				handle.0.rx_waiters.clear_wait(&mut so);
+				return rv.map(|_| handle);
+			}
+			so.wait();
+			handle.0.rx_waiters.clear_wait(&mut so);
+		}
+	}
+
+	/// Local address and port
+	pub fn local_addr(&self) -> (Address, u16) {
+		(self.0.quad.local_addr, self.0.quad.local_port)
+	}
+	/// Remote address and port
+	pub fn remote_addr(&self) -> (Address, u16) {
+		(self.0.quad.remote_addr, self.0.quad.remote_port)
+	}
+
+	/// Queue data for transmission (non-blocking)
+	///
+	/// Returns the number of bytes queued (zero if the transmit buffer is full)
+	pub fn send_data(&self, buf: &[u8]) -> Result<usize, ConnError>
+	{
+		let mut lh = self.0.conn.lock();
+		if let Some(e) = lh.error {
+			return Err(e);
+		}
+		if lh.tx_closed {
+			return Err(ConnError::LocalClosed);
+		}
+		match lh.state
+		{
+		ConnectionState::SynSent | ConnectionState::SynReceived
+		| ConnectionState::Established | ConnectionState::CloseWait => {},
+		_ => return Err(ConnError::LocalClosed),
+		}
+		let len = ::core::cmp::min(buf.len(), TX_BUFFER_SIZE - lh.tx_buffer.len());
+		lh.tx_buffer.push_all(&buf[..len]);
+		lh.flush_tx(&self.0.quad);
+		Ok(len)
+	}
+
+	/// Read received data (non-blocking)
+	///
+	/// Returns `Ok(0)` if no data is available, check `is_eof` to distinguish the remote closing
+	pub fn recv_data(&self, buf: &mut [u8]) -> Result<usize, ConnError>
+	{
+		let mut lh = self.0.conn.lock();
+		if lh.rx_buffer.len() == 0 {
+			if let Some(e) = lh.error {
+				return Err(e);
+			}
+		}
+		let old_window = lh.rx_window() as usize;
+		let len = ::core::cmp::min(buf.len(), lh.rx_buffer.len());
+		buf[..len].copy_from_slice(&lh.rx_buffer[..len]);
+		drain_front(&mut lh.rx_buffer, len);
+		// Window update if the window was (almost) closed
+		if len > 0 && old_window < lh.tx_mss && lh.is_synchronised() && !lh.rx_closed {
+			lh.send_ack(&self.0.quad);
+		}
+		Ok(len)
+	}
+
+	/// Returns true if the remote has closed its side and all data has been read
+	pub fn is_eof(&self) -> bool {
+		let lh = self.0.conn.lock();
+		lh.rx_buffer.len() == 0 && (lh.rx_closed || lh.state == ConnectionState::Closed)
+	}
+	/// Returns true if a call to `recv_data` would not return `Ok(0)` due to no data
+	pub fn is_readable(&self) -> bool {
+		let lh = self.0.conn.lock();
+		lh.rx_buffer.len() > 0 || lh.rx_closed || lh.state == ConnectionState::Closed
+	}
+	/// Returns true if `send_data` would accept data (or return an error)
+	pub fn is_writable(&self) -> bool {
+		let lh = self.0.conn.lock();
+		match lh.state
+		{
+		ConnectionState::Established | ConnectionState::CloseWait => lh.tx_buffer.len() < TX_BUFFER_SIZE || lh.tx_closed,
+		ConnectionState::SynSent | ConnectionState::SynReceived => false,
+		_ => true,
+		}
+	}
+
+	/// Close the sending side of the connection (sends FIN once all queued data is sent)
+	pub fn close(&self)
+	{
+		let mut lh = self.0.conn.lock();
+		if lh.tx_closed {
+			return ;
+		}
+		lh.tx_closed = true;
+		let state = lh.state;
+		match state
+		{
+		ConnectionState::SynSent => {
+			lh.state = ConnectionState::Closed;
+			lh.error = Some(ConnError::LocalClosed);
+			::core::mem::drop(lh);
+			remove_connection(&self.0.quad);
+			},
+		_ => lh.flush_tx(&self.0.quad),
+		}
+	}
+
+	pub fn bind_wait_rx(&self, obj: &mut ::kernel::threads::SleepObject) {
+		self.0.rx_waiters.wait_upon(obj);
+		if self.is_readable() {
+			obj.signal();
+		}
+	}
+	pub fn clear_wait_rx(&self, obj: &mut ::kernel::threads::SleepObject) {
+		self.0.rx_waiters.clear_wait(obj);
+	}
+	pub fn bind_wait_tx(&self, obj: &mut ::kernel::threads::SleepObject) {
+		self.0.tx_waiters.wait_upon(obj);
+		if self.is_writable() {
+			obj.signal();
+		}
+	}
+	pub fn clear_wait_tx(&self, obj: &mut ::kernel::threads::SleepObject) {
+		self.0.tx_waiters.clear_wait(obj);
+	}
+}
+impl ::core::ops::Drop for ConnectionHandle
+{
+	fn drop(&mut self)
+	{
+		self.close();
+	}
+}
+
+/// Pick an unused local port for an outgoing connection
+fn allocate_port(conns: &[Arc<ConnectionShared>], local_addr: Address, remote_addr: Address, remote_port: u16) -> Option<u16>
+{
+	let count = (0x10000 - EPHEMERAL_PORT_BASE as usize) as usize;
+	for _ in 0 .. count
+	{
+		let port = EPHEMERAL_PORT_BASE + (NEXT_EPHEMERAL.fetch_add(1, Ordering::Relaxed) % count) as u16;
+		let in_use = conns.iter().any(|c| c.quad.local_addr == local_addr && c.quad.local_port == port && c.quad.remote_addr == remote_addr && c.quad.remote_port == remote_port)
+			|| SERVERS.lock().iter().any(|s| s.port == port);
+		if !in_use {
+			return Some(port);
+		}
+	}
+	None
+}
+
+struct ServerShared
+{
+	local_addr: Address,
+	port: u16,
+	backlog: usize,
+	accept_queue: Mutex<Vec<Arc<ConnectionShared>>>,
+	waiters: ::kernel::async::queue::Source,
+}
+
+/// A listening TCP socket
+pub struct Server(Arc<ServerShared>);
+impl Server
+{
+	/// Listen for connections on the specified port (`addr` may be zero to listen on all interfaces)
+	pub fn listen(addr: Address, port: u16) -> Result<Server, ConnError>
+	{
+		let mut lh = SERVERS.lock();
+		if lh.iter().any(|s| s.port == port && (s.local_addr.is_zero() || addr.is_zero() || s.local_addr == addr)) {
+			return Err(ConnError::AddressInUse);
+		}
+		let shared = Arc::new(ServerShared {
+			local_addr: addr,
+			port: port,
+			backlog: 16,
+			accept_queue: Default::default(),
+			waiters: Default::default(),
+			});
+		lh.push(shared.clone());
+		log_debug!("TCP: Listening on {}:{}", addr, port);
+		Ok( Server(shared) )
+	}
+
+	pub fn port(&self) -> u16 {
+		self.0.port
+	}
+
+	/// Accept a pending connection (non-blocking)
+	pub fn accept(&self) -> Option<ConnectionHandle>
+	{
+		let mut lh = self.0.accept_queue.lock();
+		if lh.len() > 0 {
+			Some( ConnectionHandle(lh.remove(0)) )
+		}
+		else {
+			None
+		}
+	}
+
+	pub fn bind_wait(&self, obj: &mut ::kernel::threads::SleepObject) {
+		self.0.waiters.wait_upon(obj);
+		if self.0.accept_queue.lock().len() > 0 {
+			obj.signal();
+		}
+	}
+	pub fn clear_wait(&self, obj: &mut ::kernel::threads::SleepObject) -> bool {
+		self.0.waiters.clear_wait(obj);
+		self.0.accept_queue.lock().len() > 0
+	}
+}
+impl ::core::ops::Drop for Server
+{
+	fn drop(&mut self)
+	{
+		{
+			let mut lh = SERVERS.lock();
+			if let Some(i) = lh.iter().position(|s| s.port == self.0.port && s.local_addr == self.0.local_addr) {
+				lh.remove(i);
+			}
+		}
+		// Close any connections that were never accepted
+		while let Some(c) = self.0.accept_queue.lock().pop() {
+			::core::mem::drop( ConnectionHandle(c) );
+		}
+	}
+}
 
@@ -0,0 +1,270 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/udp.rs
+//! User Datagram Protocol (Layer 4)
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::sync::Mutex;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use ipv4::Address;
+use nic::{MacAddr,PacketReader,SparsePacket};
+
+const IPV4_PROTO_UDP: u8 = 17;
+/// Maximum number of datagrams queued on a socket before new ones are dropped
+const MAX_QUEUED: usize = 32;
+/// Maximum payload of a single (unfragmented) datagram
+pub const MAX_PAYLOAD: usize = ::ipv4::MAX_PAYLOAD - 8;
+/// First port used for automatically-bound sockets
+const EPHEMERAL_PORT_BASE: u16 = 49152;
+
+static SOCKETS: Mutex<Vec<Arc<SocketShared>>> = Mutex::new(Vec::new_const());
+static NEXT_EPHEMERAL: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init()
+{
+	::ipv4::register_handler(IPV4_PROTO_UDP, rx_handler_v4).expect("Unable to register UDP with IPv4");
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Error
+{
+	/// The requested local port is already bound
+	AddressInUse,
+	/// No free ephemeral ports
+	NoPorts,
+	/// Datagram too large to send
+	TooLarge,
+	/// No route to the destination
+	NoRoute,
+	/// Address resolution for the destination failed
+	UnreachableHost,
+}
+impl_from! {
+	From<::ipv4::Error>(v) for Error {
+		match v
+		{
+		::ipv4::Error::TooLarge => Error::TooLarge,
+		::ipv4::Error::UnreachableHost => Error::UnreachableHost,
+		::ipv4::Error::NoInterface | ::ipv4::Error::NoRoute => Error::NoRoute,
+		}
+	}
+}
+
+/// A received datagram
+struct Datagram
+{
+	source: Address,
+	source_port: u16,
+	data: Vec<u8>,
+}
+
+struct SocketShared
+{
+	local_addr: Address,
+	local_port: u16,
+	queue: Mutex<Vec<Datagram>>,
+	waiters: ::kernel::async::queue::Source,
+}
+
+fn calculate_checksum(src: Address, dest: Address, hdr: &[u8], data: &[u8]) -> u16
+{
+	let len = hdr.len() + data.len();
+	let pseudo = [
+		src.0[0], src.0[1], src.0[2], src.0[3],
+		dest.0[0], dest.0[1], dest.0[2], dest.0[3],
+		0, IPV4_PROTO_UDP, (len >> 8) as u8, len as u8,
+		];
+	let sum = ::ipv4::sum_words(::ipv4::words_be(&pseudo))
+		+ ::ipv4::sum_words(::ipv4::words_be(hdr))
+		+ ::ipv4::sum_words(::ipv4::words_be(data));
+	match !::ipv4::fold_checksum(sum)
+	{
+	0 => 0xFFFF,	// Zero means "no checksum", so send all-ones instead
+	v => v,
+	}
+}
+
+fn rx_handler_v4(_int: &::ipv4::Interface, src_addr: Address, dest_addr: Address, mut r: PacketReader)
+{
+	let mut hdr = [0u8; 8];
+	if r.read(&mut hdr).is_err() {
+		log_notice!("UDP: Short packet from {}", src_addr);
+		return ;
+	}
+	let source_port = (hdr[0] as u16) << 8 | hdr[1] as u16;
+	let dest_port = (hdr[2] as u16) << 8 | hdr[3] as u16;
+	let length = (hdr[4] as usize) << 8 | hdr[5] as usize;
+	let checksum = (hdr[6] as u16) << 8 | hdr[7] as u16;
+	if length < 8 || r.limit(length - 8).is_err() {
+		log_notice!("UDP: Bad length {} from {}", length, src_addr);
+		return ;
+	}
+	let mut data: Vec<u8> = vec![0; length - 8];
+	r.read(&mut data).expect("Reading UDP payload");
+
+	if checksum != 0
+	{
+		hdr[6] = 0;
+		hdr[7] = 0;
+		if calculate_checksum(src_addr, dest_addr, &hdr, &data) != checksum {
+			log_notice!("UDP: Bad checksum from {}:{}", src_addr, source_port);
+			return ;
+		}
+	}
+
+	let sock = SOCKETS.lock().iter()
+		.find(|s| s.local_port == dest_port && (s.local_addr.is_zero() || s.local_addr == dest_addr))
+		.cloned();
+	match sock
+	{
+	Some(sock) => {
+		{
+			let mut lh = sock.queue.lock();
+			if lh.len() >= MAX_QUEUED {
+				log_debug!("UDP: Queue full on port {}, dropping datagram from {}", dest_port, src_addr);
+				return ;
+			}
+			lh.push(Datagram {
+				source: src_addr,
+				source_port: source_port,
+				data: data,
+				});
+		}
+		sock.waiters.wake_one();
+		},
+	None => log_trace!("UDP: No socket for port {} (from {}:{})", dest_port, src_addr, source_port),
+	}
+}
+
+/// A bound UDP socket
+pub struct Socket(Arc<SocketShared>);
+impl Socket
+{
+	/// Bind a socket to a local address/port (`addr` may be zero for all interfaces, `port` zero for an ephemeral port)
+	pub fn bind(addr: Address, port: u16) -> Result<Socket, Error>
+	{
+		let mut lh = SOCKETS.lock();
+		let port = if port == 0 {
+				let count = 0x10000 - EPHEMERAL_PORT_BASE as usize;
+				let mut found = None;
+				for _ in 0 .. count
+				{
+					let p = EPHEMERAL_PORT_BASE + (NEXT_EPHEMERAL.fetch_add(1, Ordering::Relaxed) % count) as u16;
+					if !lh.iter().any(|s| s.local_port == p) {
+						found = Some(p);
+						break;
+					}
+				}
+				match found
+				{
+				Some(p) => p,
+				None => return Err(Error::NoPorts),
+				}
+			}
+			else {
+				if lh.iter().any(|s| s.local_port == port && (s.local_addr.is_zero() || addr.is_zero() || s.local_addr == addr)) {
+					return Err(Error::AddressInUse);
+				}
+				port
+			};
+		let shared = Arc::new(SocketShared {
+			local_addr: addr,
+			local_port: port,
+			queue: Default::default(),
+			waiters: Default::default(),
+			});
+		lh.push(shared.clone());
+		Ok( Socket(shared) )
+	}
+
+	pub fn local_addr(&self) -> (Address, u16) {
+		(self.0.local_addr, self.0.local_port)
+	}
+
+	/// Send a datagram (routed using the bound address, or the destination if unbound)
+	pub fn send_to(&self, dest: Address, port: u16, data: &[u8]) -> Result<(), Error>
+	{
+		let source = if self.0.local_addr.is_zero() {
+				match ::ipv4::route_source(dest)
+				{
+				Some(a) => a,
+				None => return Err(Error::NoRoute),
+				}
+			}
+			else {
+				self.0.local_addr
+			};
+		let hdr = self.build_header(source, dest, port, data);
+		let pkt_data = SparsePacket::new_root(data);
+		try!(::ipv4::send_packet(source, dest, IPV4_PROTO_UDP, SparsePacket::new_chained(&hdr, &pkt_data)));
+		Ok( () )
+	}
+
+	/// Send a datagram out a specific interface (usable before the interface has an address, e.g. for DHCP)
+	pub fn send_via(&self, local_mac: MacAddr, dest: Address, port: u16, data: &[u8]) -> Result<(), Error>
+	{
+		let source = ::ipv4::get_interface_address(local_mac).unwrap_or(Address::zero());
+		let hdr = self.build_header(source, dest, port, data);
+		let pkt_data = SparsePacket::new_root(data);
+		try!(::ipv4::send_packet_via(local_mac, dest, IPV4_PROTO_UDP, SparsePacket::new_chained(&hdr, &pkt_data)));
+		Ok( () )
+	}
+
+	fn build_header(&self, source: Address, dest: Address, port: u16, data: &[u8]) -> [u8; 8]
+	{
+		let len = 8 + data.len();
+		let mut hdr = [
+			(self.0.local_port >> 8) as u8, self.0.local_port as u8,
+			(port >> 8) as u8, port as u8,
+			(len >> 8) as u8, len as u8,
+			0, 0,
+			];
+		let cksum = calculate_checksum(source, dest, &hdr, data);
+		hdr[6] = (cksum >> 8) as u8;
+		hdr[7] = cksum as u8;
+		hdr
+	}
+
+	/// Receive a datagram (non-blocking)
+	///
+	/// Returns the number of bytes copied (the rest of the datagram is discarded) and the source address/port
+	pub fn recv_from(&self, buf: &mut [u8]) -> Option<(usize, Address, u16)>
+	{
+		let mut lh = self.0.queue.lock();
+		if lh.len() == 0 {
+			return None;
+		}
+		let dg = lh.remove(0);
+		let len = ::core::cmp::min(buf.len(), dg.data.len());
+		buf[..len].copy_from_slice(&dg.data[..len]);
+		Some( (len, dg.source, dg.source_port) )
+	}
+
+	/// Returns true if a datagram is waiting
+	pub fn is_readable(&self) -> bool {
+		self.0.queue.lock().len() > 0
+	}
+
+	pub fn bind_wait(&self, obj: &mut ::kernel::threads::SleepObject) {
+		self.0.waiters.wait_upon(obj);
+		if self.is_readable() {
+			obj.signal();
+		}
+	}
+	pub fn clear_wait(&self, obj: &mut ::kernel::threads::SleepObject) -> bool {
+		self.0.waiters.clear_wait(obj);
+		self.is_readable()
+	}
+}
+impl ::core::ops::Drop for Socket
+{
+	fn drop(&mut self)
+	{
+		let mut lh = SOCKETS.lock();
+		if let Some(i) = lh.iter().position(|s| s.local_port == self.0.local_port && s.local_addr == self.0.local_addr) {
+			lh.remove(i);
+		}
+	}
+}
+
@@ -65,4 +65,8 @@ pub const FLAG_ISR_ROK   : u16 = 0x0001;	// Rx OK
 
 pub const FLAG_TSD_TOK: u32 = 0x8000;
 
+pub const FLAG_CMD_BUFE: u8 = 0x01;	// Rx buffer empty
+
+pub const FLAG_RXS_ROK: u16 = 0x0001;	// Rx packet header: Received OK
+
 
@@ -7,7 +7,7 @@
 #![feature(linkage)]	// for module_define!
 #![feature(integer_atomics)]	// AtomicU8
 use kernel::prelude::*;
-use kernel::sync::Mutex;
+use kernel::sync::Spinlock;
 use core::sync::atomic::{Ordering,AtomicU8,AtomicU16};
 use network::nic;
 use hw::Regs;
@@ -15,6 +15,7 @@ use hw::Regs;
 #[macro_use]
 extern crate kernel;
 extern crate network;
+extern crate stack_dst;
 
 mod hw;
 
@@ -29,8 +30,11 @@ fn init()
 	::kernel::device_manager::register_driver(&PCI_DRIVER);
 }
 
-const RX_BUFFER_LENGTH: usize = 0x2000+16;
+/// Size of the receive ring (packets start within the ring, but can run past the end - RCR.WRAP is set)
+const RX_RING_SIZE: usize = 0x2000;
 const RX_BUFFER_LIMIT : usize = 0x3000;
+/// Largest valid received frame (including the CRC)
+const MAX_FRAME_LEN: usize = 1514 + 4;
 
 struct BusDev( nic::Registration<Card>, ::kernel::irqs::ObjectHandle );
 struct Card
@@ -39,9 +43,10 @@ struct Card
 	
 	// Buffer: Three contigious pages
 	rx_buffer: ::kernel::memory::virt::ArrayHandle<u8>,
+	/// Offset of the next unread packet header in the receive ring
 	rx_seen_ofs: AtomicU16,
 
-	waiter_handle: Mutex<Option<::kernel::threads::SleepObjectRef>>,
+	waiter_handle: Spinlock<Option<::kernel::threads::SleepObjectRef>>,
 
 	// Transmit Buffers
 	tx_buffer_handles: [ ::kernel::memory::virt::ArrayHandle<u8>; 2 ],
@@ -101,7 +106,7 @@ impl BusDev
 			io_base: io,
 			rx_buffer: rx_buffer,
 			rx_seen_ofs: AtomicU16::new(0),
-			waiter_handle: Default::default(),
+			waiter_handle: Spinlock::new(None),
 			tx_buffer_handles: tx_buffer_handles,
 			tx_slots: buffer_ring::BufferRing::new(tx_slots),
 			tx_slots_active: AtomicU8::new(0),
@@ -122,8 +127,8 @@ impl BusDev
 
 			// Receive buffer
 			card.write_32(Regs::RBSTART, ::kernel::memory::virt::get_phys(&card.rx_buffer[0]) as u32);
-			card.write_32(Regs::CBA, 0);
-			card.write_32(Regs::CAPR, 0);
+			// - CAPR is 16 bytes behind the read offset
+			card.write_16(Regs::CAPR, (0u16).wrapping_sub(16));
 			// Transmit buffers
 			// - TODO: These need protected access
 			card.write_32(Regs::TSAD0, ::kernel::memory::virt::get_phys(&card.tx_buffer_handles[0][    0]) as u32);
@@ -219,49 +224,24 @@ impl Card
 			status_clear |= hw::FLAG_ISR_TOK;
 		}
 		// ---
-		// Receive OK
+		// Receive OK/Error/Overflow - Wake the Rx thread, which reads packets from the ring (see `rx_packet`)
 		// ---
-		if status & hw::FLAG_ISR_ROK != 0
+		let rx_flags = hw::FLAG_ISR_ROK | hw::FLAG_ISR_RER | hw::FLAG_ISR_RXOVW | hw::FLAG_ISR_FOVW;
+		if status & rx_flags != 0
 		{
-			// Starting at the last known Rx address, enumerate packets
-			let mut read_ofs = self.rx_seen_ofs.load(Ordering::Relaxed);
-			let end_ofs = self.read_16(Regs::CBA);
-
-			let mut num_packets = 0;
-			if read_ofs > end_ofs
-			{
-				// Rx buffer has wrapped around, read until the end of the buffer and reset read_ofs to 0
-				while read_ofs < RX_BUFFER_LENGTH as u16
-				{
-					// NOTE: The maximum valid address is RX_BUFFER_LIMIT (larger)
-					let (size, _hdr, _data) = self.get_packet(read_ofs as usize, RX_BUFFER_LIMIT);
-					num_packets += 1;
-					read_ofs += size as u16;
-				}
-				read_ofs = 0;
-			}
-
-			while read_ofs < end_ofs
-			{
-				let (size, _hdr, _data) = self.get_packet(read_ofs as usize, end_ofs as usize);
-				num_packets += 1;
-				read_ofs += size as u16;
+			if status & (hw::FLAG_ISR_RER | hw::FLAG_ISR_RXOVW | hw::FLAG_ISR_FOVW) != 0 {
+				log_notice!("RTL8139: Rx error/overflow, status=0x{:04x}", status);
 			}
-
-			self.rx_seen_ofs.store(read_ofs, Ordering::Relaxed);
-			// NOTE: Don't write back CAPR here - It's updated once the packet has been seen by the network stack
-			status_clear |= hw::FLAG_ISR_ROK;
-			
-			if num_packets > 0
-			{
-				todo!("Wake waiting thread");
+			if let Some(ref w) = *self.waiter_handle.lock() {
+				w.signal();
 			}
+			status_clear |= status & rx_flags;
 		}
-		
 
 		if status & !status_clear != 0
 		{
-			todo!("Handle other status bits - 0x{:04x}", status & !status_clear);
+			log_notice!("RTL8139: Unhandled status bits 0x{:04x}", status & !status_clear);
+			status_clear |= status;
 		}
 
 		// SAFE: No memory triggered by this, only thread active
@@ -271,17 +251,60 @@ impl Card
 	}
 
 
-	fn get_packet(&self, ofs: usize, max_ofs: usize) -> (usize, u16, &[u8]) {
-		assert!(ofs < max_ofs);
-		assert!(ofs+4 < max_ofs);
+	/// Read the header of the packet at `ofs` in the receive ring, returns (flags, length including CRC)
+	fn get_packet_header(&self, ofs: usize) -> (u16, usize) {
+		assert!(ofs < RX_RING_SIZE);
 		assert!(ofs%4 == 0);
 		
 		let pkt_flags = self.rx_buffer[ofs+0] as u16 | (self.rx_buffer[ofs+1] as u16 * 256);
 		let raw_len   = self.rx_buffer[ofs+2] as u16 | (self.rx_buffer[ofs+3] as u16 * 256);
+		log_trace!("get_packet_header({}): len={} flags=0x{:04x}", ofs, raw_len, pkt_flags);
+		(pkt_flags, raw_len as usize)
+	}
+	/// Mark everything before `ofs` in the receive ring as read (releasing the space to the card)
+	fn set_rx_ofs(&self, ofs: usize) {
+		self.rx_seen_ofs.store(ofs as u16, Ordering::Relaxed);
+		// SAFE: Only releases ring space that's no longer referenced
+		unsafe { self.write_16(Regs::CAPR, (ofs as u16).wrapping_sub(16)); }
+	}
+}
 
-		let size = (raw_len + 4 + 3) & !4;
-		log_trace!("get_packet({}): len={} flags=0x{:04x}", ofs, raw_len, pkt_flags);
-		(size as usize, pkt_flags, &self.rx_buffer[ofs+4..][..raw_len as usize])
+/// Received packet, releases its space in the receive ring when dropped
+struct Packet<'a>
+{
+	card: &'a Card,
+	/// Offset of the packet data in the receive buffer
+	ofs: usize,
+	/// Length of the packet (excluding the CRC)
+	len: usize,
+	/// Ring offset of the following packet
+	next_ofs: usize,
+}
+impl<'a> nic::RxPacket for Packet<'a>
+{
+	fn len(&self) -> usize {
+		self.len
+	}
+	fn num_regions(&self) -> usize {
+		1
+	}
+	fn get_region(&self, idx: usize) -> &[u8] {
+		assert!(idx == 0);
+		&self.card.rx_buffer[self.ofs ..][.. self.len]
+	}
+	fn get_slice(&self, range: ::core::ops::Range<usize>) -> Option<&[u8]> {
+		if range.start <= range.end && range.end <= self.len {
+			Some( &self.card.rx_buffer[self.ofs ..][range] )
+		}
+		else {
+			None
+		}
+	}
+}
+impl<'a> ::core::ops::Drop for Packet<'a>
+{
+	fn drop(&mut self) {
+		self.card.set_rx_ofs(self.next_ofs);
 	}
 }
 
@@ -303,7 +326,30 @@ impl nic::Interface for Card
 		*self.waiter_handle.lock() = Some(channel.get_ref());
 	}
 	fn rx_packet(&self) -> Result<nic::PacketHandle, nic::Error> {
-		todo!("rx_packet");
+		if self.read_8(Regs::CMD) & hw::FLAG_CMD_BUFE != 0 {
+			return Err( nic::Error::NoPacket );
+		}
+		let ofs = self.rx_seen_ofs.load(Ordering::Relaxed) as usize;
+		let (flags, raw_len) = self.get_packet_header(ofs);
+		if flags & hw::FLAG_RXS_ROK == 0 || raw_len < 4 || raw_len > MAX_FRAME_LEN || ofs + 4 + raw_len > RX_BUFFER_LIMIT {
+			// Bad packet (or the ring is out of sync), drop everything the card has written so far
+			log_warning!("RTL8139: Bad Rx header at {:#x} (flags=0x{:04x}, len={}), dropping pending packets", ofs, flags, raw_len);
+			let end_ofs = self.read_16(Regs::CBA) as usize % RX_RING_SIZE;
+			self.set_rx_ofs(end_ofs & !3);
+			return Err( nic::Error::NoPacket );
+		}
+
+		let pkt = Packet {
+			card: self,
+			ofs: ofs + 4,
+			len: raw_len - 4,
+			next_ofs: ((ofs + 4 + raw_len + 3) & !3) % RX_RING_SIZE,
+			};
+		match ::stack_dst::ValueA::new(pkt)
+		{
+		Ok(v) => Ok(v),
+		Err(_) => panic!("RTL8139: Packet handle doesn't fit in PacketHandle"),
+		}
 	}
 }
 #[allow(dead_code)]
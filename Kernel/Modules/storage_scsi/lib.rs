@@ -124,6 +124,13 @@ impl<I: ScsiInterface> storage::PhysicalVolume for Volume<I>
 	fn name(&self) -> &str { self.int.name() }
 	fn blocksize(&self) -> usize { self.size.expect("Calling blocksize on no-media volume").0 }
 	fn capacity(&self) -> Option<u64> { self.size.map(|x| x.1) }
+	fn is_readonly(&self) -> bool {
+		match self.class
+		{
+		VolumeClass::CdDvd => true,
+		_ => false,
+		}
+	}
 	
 	fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
 	{
@@ -164,10 +171,10 @@ impl<I: ScsiInterface> storage::PhysicalVolume for Volume<I>
 		match self.class
 		{
 		VolumeClass::CdDvd => Box::new(async::NullResultWaiter::new( || Err(storage::IoError::ReadOnly) )),
-		VolumeClass::DirectAccessBlock => {
-			todo!("Volume::write(idx={},num={},len={})", idx, num, src.len());
+		_ => {
+			log_notice!("TODO: SCSI Volume::write(idx={},num={},len={})", idx, num, src.len());
+			Box::new(async::NullResultWaiter::new( || Err(storage::IoError::Unknown("TODO: Write support")) ))
 			},
-		_ => Box::new(async::NullResultWaiter::new( || Err(storage::IoError::Unknown("TODO: Write support")) )),
 		}
 	}
 	
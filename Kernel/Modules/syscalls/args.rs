@@ -32,6 +32,8 @@ unsafe impl Pod for u32 {}
 unsafe impl Pod for ::values::WaitItem {}
 unsafe impl Pod for ::values::GuiEvent {}	// Kinda lies, but meh
 unsafe impl Pod for ::values::RpcMessage {}
+unsafe impl Pod for ::values::SocketAddrV4 {}
+unsafe impl Pod for ::values::VFSNodeInfo {}
 
 impl<T: Pod> SyscallArg for Freeze<T>
 {
@@ -86,6 +88,7 @@ impl<T: Pod> SyscallArg for FreezeMut<T>
 			return Err( ::Error::TooManyArgs );
 		}
 		let ptr = args[0] as *mut T;
+		*args = &args[1..];
 
 		// SAFE: Performs data validation, and only accepts user pointers (which are checkable)
 		unsafe { 
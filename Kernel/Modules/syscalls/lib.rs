@@ -15,6 +15,7 @@ use args::Args;
 #[macro_use]
 extern crate kernel;
 extern crate gui;
+extern crate network;
 extern crate stack_dst;
 
 mod objects;
@@ -25,6 +26,7 @@ mod threads;
 mod gui_calls;
 mod vfs;
 mod ipc_calls;
+mod net;
 
 pub type ObjectHandle = u32;
 
@@ -248,6 +250,41 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 			Err( () ) => !0
 			}
 			},
+		// === 5: Network
+		NET_CONNECT => {
+			let addr: u32 = try!(args.get());
+			let port: u16 = try!(args.get());
+			from_result(net::connect(addr, port))
+			},
+		NET_LISTEN => {
+			let addr: u32 = try!(args.get());
+			let port: u16 = try!(args.get());
+			from_result(net::listen(addr, port))
+			},
+		NET_BIND_UDP => {
+			let addr: u32 = try!(args.get());
+			let port: u16 = try!(args.get());
+			from_result(net::bind_udp(addr, port))
+			},
+		// === 5: VFS mount management
+		VFS_MOUNT => {
+			let path: Freeze<[u8]> = try!(args.get());
+			let volume: Freeze<str> = try!(args.get());
+			let options: Freeze<str> = try!(args.get());
+			from_result(vfs::mount(&path, &volume, &options))
+			},
+		VFS_UNMOUNT => {
+			let path: Freeze<[u8]> = try!(args.get());
+			from_result(vfs::unmount(&path))
+			},
+		VFS_REMOUNT => {
+			let path: Freeze<[u8]> = try!(args.get());
+			let options: Freeze<str> = try!(args.get());
+			from_result(vfs::remount(&path, &options))
+			},
+		VFS_SYNC => {
+			from_result(vfs::sync())
+			},
 		// === *: Default
 		_ => {
 			log_error!("Unknown syscall {:05x}", call_id);
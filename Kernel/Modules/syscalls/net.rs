@@ -0,0 +1,257 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/syscalls/net.rs
+//! Userland interface to the network stack
+use kernel::prelude::*;
+
+use kernel::memory::freeze::{Freeze,FreezeMut};
+use super::{values,objects};
+use super::{Error,ObjectHandle};
+use args::Args;
+use network::ipv4::Address;
+use values::{NetError,SocketAddrV4};
+
+impl_from! {
+	From<::network::tcp::ConnError>(v) for NetError {{
+		use network::tcp::ConnError;
+		match v
+		{
+		ConnError::NoRoute => NetError::NoRoute,
+		ConnError::LocalClosed => NetError::LocalClosed,
+		ConnError::RemoteRefused => NetError::ConnectionRefused,
+		ConnError::RemoteReset => NetError::ConnectionReset,
+		ConnError::TimedOut => NetError::TimedOut,
+		ConnError::AddressInUse => NetError::AddressInUse,
+		ConnError::NoPorts => NetError::NoPorts,
+		}
+	}}
+	From<::network::udp::Error>(v) for NetError {{
+		use network::udp::Error;
+		match v
+		{
+		Error::AddressInUse => NetError::AddressInUse,
+		Error::NoPorts => NetError::NoPorts,
+		Error::TooLarge => NetError::TooLarge,
+		Error::NoRoute => NetError::NoRoute,
+		Error::UnreachableHost => NetError::HostUnreachable,
+		}
+	}}
+}
+
+/// Convert a network result into an encoded syscall result
+fn to_result<T, E: Into<NetError>>(r: Result<T, E>) -> Result<T, u32> {
+	r.map_err( |e| { let e: NetError = e.into(); e.into() } )
+}
+fn encode_addr(addr: (Address, u16)) -> SocketAddrV4 {
+	SocketAddrV4 { addr: (addr.0).0, port: addr.1 }
+}
+fn would_block() -> u64 {
+	super::from_result::<u32,_>( Err(NetError::WouldBlock) )
+}
+
+#[inline(never)]
+pub fn connect(addr: u32, port: u16) -> Result<ObjectHandle,u32> {
+	let addr = Address::from_u32(addr);
+	log_debug!("NET_CONNECT({}:{})", addr, port);
+	to_result( ::network::tcp::ConnectionHandle::connect(addr, port) )
+		.map(|h| objects::new_object(TcpConnection(h)))
+}
+#[inline(never)]
+pub fn listen(addr: u32, port: u16) -> Result<ObjectHandle,u32> {
+	let addr = Address::from_u32(addr);
+	log_debug!("NET_LISTEN({}:{})", addr, port);
+	to_result( ::network::tcp::Server::listen(addr, port) )
+		.map(|h| objects::new_object(TcpListener(h)))
+}
+#[inline(never)]
+pub fn bind_udp(addr: u32, port: u16) -> Result<ObjectHandle,u32> {
+	let addr = Address::from_u32(addr);
+	log_debug!("NET_BIND_UDP({}:{})", addr, port);
+	to_result( ::network::udp::Socket::bind(addr, port) )
+		.map(|h| objects::new_object(UdpSocket(h)))
+}
+
+/// Listening TCP port
+struct TcpListener(::network::tcp::Server);
+impl objects::Object for TcpListener
+{
+	fn class(&self) -> u16 { values::CLASS_NET_TCPLISTEN }
+	fn as_any(&self) -> &Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		None
+	}
+	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error> {
+		match call
+		{
+		values::NET_TCPLISTEN_ACCEPT => {
+			match self.0.accept()
+			{
+			Some(h) => Ok( objects::new_object(TcpConnection(h)) as u64 ),
+			None => Ok( would_block() ),
+			}
+			},
+		values::NET_TCPLISTEN_GETPORT => {
+			Ok( self.0.port() as u64 )
+			},
+		_ => ::objects::object_has_no_such_method_ref("net::TcpListener", call),
+		}
+	}
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_NET_TCPLISTEN_CONNECT != 0 {
+			self.0.bind_wait(obj);
+			ret += 1;
+		}
+		ret
+	}
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_NET_TCPLISTEN_CONNECT != 0 {
+			if self.0.clear_wait(obj) {
+				ret |= values::EV_NET_TCPLISTEN_CONNECT;
+			}
+		}
+		ret
+	}
+}
+
+/// TCP connection
+struct TcpConnection(::network::tcp::ConnectionHandle);
+impl objects::Object for TcpConnection
+{
+	fn class(&self) -> u16 { values::CLASS_NET_TCPCONN }
+	fn as_any(&self) -> &Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		None
+	}
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
+		match call
+		{
+		values::NET_TCPCONN_SEND => {
+			let data: Freeze<[u8]> = try!(args.get());
+			log_debug!("NET_TCPCONN_SEND({:p}+{})", data.as_ptr(), data.len());
+			Ok(match self.0.send_data(&data)
+			{
+			Ok(0) if data.len() > 0 => would_block(),
+			r => super::from_result( to_result(r).map(|v| v as u32) ),
+			})
+			},
+		values::NET_TCPCONN_RECV => {
+			let mut data: FreezeMut<[u8]> = try!(args.get());
+			log_debug!("NET_TCPCONN_RECV({:p}+{})", data.as_ptr(), data.len());
+			Ok(match self.0.recv_data(&mut data)
+			{
+			Ok(0) if data.len() > 0 && !self.0.is_eof() => would_block(),
+			r => super::from_result( to_result(r).map(|v| v as u32) ),
+			})
+			},
+		values::NET_TCPCONN_SHUTDOWN => {
+			log_debug!("NET_TCPCONN_SHUTDOWN()");
+			self.0.close();
+			Ok(0)
+			},
+		values::NET_TCPCONN_GETLOCAL => {
+			let mut dst: FreezeMut<SocketAddrV4> = try!(args.get());
+			*dst = encode_addr(self.0.local_addr());
+			Ok(0)
+			},
+		values::NET_TCPCONN_GETREMOTE => {
+			let mut dst: FreezeMut<SocketAddrV4> = try!(args.get());
+			*dst = encode_addr(self.0.remote_addr());
+			Ok(0)
+			},
+		_ => ::objects::object_has_no_such_method_ref("net::TcpConnection", call),
+		}
+	}
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_NET_TCPCONN_RECV != 0 {
+			self.0.bind_wait_rx(obj);
+			ret += 1;
+		}
+		if flags & values::EV_NET_TCPCONN_SEND != 0 {
+			self.0.bind_wait_tx(obj);
+			ret += 1;
+		}
+		ret
+	}
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_NET_TCPCONN_RECV != 0 {
+			self.0.clear_wait_rx(obj);
+			if self.0.is_readable() {
+				ret |= values::EV_NET_TCPCONN_RECV;
+			}
+		}
+		if flags & values::EV_NET_TCPCONN_SEND != 0 {
+			self.0.clear_wait_tx(obj);
+			if self.0.is_writable() {
+				ret |= values::EV_NET_TCPCONN_SEND;
+			}
+		}
+		ret
+	}
+}
+
+/// UDP socket
+struct UdpSocket(::network::udp::Socket);
+impl objects::Object for UdpSocket
+{
+	fn class(&self) -> u16 { values::CLASS_NET_UDP }
+	fn as_any(&self) -> &Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		None
+	}
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
+		match call
+		{
+		values::NET_UDP_SENDTO => {
+			let addr: u32 = try!(args.get());
+			let port: u16 = try!(args.get());
+			let data: Freeze<[u8]> = try!(args.get());
+			let addr = Address::from_u32(addr);
+			log_debug!("NET_UDP_SENDTO({}:{}, {:p}+{})", addr, port, data.as_ptr(), data.len());
+			let rv = to_result( self.0.send_to(addr, port, &data) ).map(|_| data.len() as u32);
+			Ok( super::from_result(rv) )
+			},
+		values::NET_UDP_RECVFROM => {
+			let mut data: FreezeMut<[u8]> = try!(args.get());
+			let mut src: FreezeMut<SocketAddrV4> = try!(args.get());
+			log_debug!("NET_UDP_RECVFROM({:p}+{})", data.as_ptr(), data.len());
+			match self.0.recv_from(&mut data)
+			{
+			Some( (len, addr, port) ) => {
+				*src = encode_addr( (addr, port) );
+				Ok( len as u64 )
+				},
+			None => Ok( would_block() ),
+			}
+			},
+		values::NET_UDP_GETLOCAL => {
+			let mut dst: FreezeMut<SocketAddrV4> = try!(args.get());
+			*dst = encode_addr(self.0.local_addr());
+			Ok(0)
+			},
+		_ => ::objects::object_has_no_such_method_ref("net::UdpSocket", call),
+		}
+	}
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_NET_UDP_RECV != 0 {
+			self.0.bind_wait(obj);
+			ret += 1;
+		}
+		ret
+	}
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_NET_UDP_RECV != 0 {
+			if self.0.clear_wait(obj) {
+				ret |= values::EV_NET_UDP_RECV;
+			}
+		}
+		ret
+	}
+}
+
@@ -37,10 +37,42 @@ impl_from! {
 		Error::PermissionDenied => VFSError::PermissionDenied,
 		Error::Locked => VFSError::FileLocked,
 		Error::MalformedPath => VFSError::MalformedPath,
-		Error::Unknown(reason) => todo!("VFS Error Unknown - '{}'", reason),
-		_ => todo!("VFS Error - {:?}", v),
+		Error::AlreadyExists => VFSError::AlreadyExists,
+		Error::InvalidParameter => VFSError::InvalidParameter,
+		Error::NonDirComponent => VFSError::NotADirectory,
+		Error::ReadOnlyFilesystem => VFSError::ReadOnlyFilesystem,
+		Error::CrossFilesystem => VFSError::CrossFilesystem,
+		Error::Busy => VFSError::Busy,
+		Error::RecursionDepthExceeded => VFSError::RecursionDepthExceeded,
+		Error::BlockIoError(_) => VFSError::IoError,
+		Error::InconsistentFilesystem => VFSError::InconsistentFilesystem,
+		Error::OutOfSpace => VFSError::OutOfSpace,
+		Error::OutOfMemory => VFSError::OutOfMemory,
+		Error::TransientError => VFSError::TransientError,
+		Error::Unknown(reason) => {
+			log_notice!("VFS Error Unknown - '{}'", reason);
+			VFSError::Unknown
+			},
 		}
 	}}
+	From<::kernel::vfs::mount::MountError>(v) for ::values::VFSMountError {{
+		use kernel::vfs::mount::MountError;
+		use values::VFSMountError;
+		map_enums!(
+			(MountError, VFSMountError)
+			match (v) {
+				(UnknownFilesystem),
+				(NoHandler),
+				(InvalidMountpoint),
+				(MountpointUsed),
+				(CallFailed),
+				(BadOption),
+				(NotMounted),
+				(Busy),
+				(ReadOnlyVolume),
+			}
+		)
+	}}
 	From<node::NodeClass>(v) for ::values::VFSNodeType {
 		match v
 		{
@@ -72,6 +104,52 @@ impl_from! {
 fn to_result<T>(r: Result<T, ::kernel::vfs::Error>) -> Result<T, u32> {
 	r.map_err( |e| Into::into( <::values::VFSError as From<_>>::from(e) ) )
 }
+/// Convert a mount result into an encoded syscall result
+fn to_mount_result(r: Result<(), ::kernel::vfs::mount::MountError>) -> Result<u32, u32> {
+	r.map(|_| 0).map_err( |e| Into::into( <::values::VFSMountError as From<_>>::from(e) ) )
+}
+
+#[inline(never)]
+pub fn mount(path: &[u8], volume: &str, options: &str) -> Result<u32,u32> {
+	log_debug!("VFS_MOUNT({:?}, {:?}, {:?})", ::kernel::lib::byte_str::ByteStr::new(path), volume, options);
+	let vh = match ::kernel::metadevs::storage::VolumeHandle::open_named(volume)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			log_log!("VFS_MOUNT - Unable to open '{}': {}", volume, e);
+			return Err( ::values::VFSMountError::NoSuchVolume.into() );
+			},
+		};
+	// - `fs=<name>` selects the driver, the rest are passed to the VFS
+	let mut fs = "";
+	let mut mount_options = Vec::new();
+	for o in options.split(',')
+	{
+		if o.starts_with("fs=") {
+			fs = &o[3..];
+		}
+		else if o != "" {
+			mount_options.push(o);
+		}
+	}
+	to_mount_result( ::kernel::vfs::mount::mount(Path::new(path), vh, fs, &mount_options) )
+}
+#[inline(never)]
+pub fn unmount(path: &[u8]) -> Result<u32,u32> {
+	log_debug!("VFS_UNMOUNT({:?})", ::kernel::lib::byte_str::ByteStr::new(path));
+	to_mount_result( ::kernel::vfs::mount::unmount(Path::new(path)) )
+}
+#[inline(never)]
+pub fn remount(path: &[u8], options: &str) -> Result<u32,u32> {
+	log_debug!("VFS_REMOUNT({:?}, {:?})", ::kernel::lib::byte_str::ByteStr::new(path), options);
+	let mount_options: Vec<&str> = options.split(',').filter(|o| *o != "").collect();
+	to_mount_result( ::kernel::vfs::mount::remount(Path::new(path), &mount_options) )
+}
+#[inline(never)]
+pub fn sync() -> Result<u32,u32> {
+	log_debug!("VFS_SYNC()");
+	to_result( ::kernel::vfs::mount::sync_all() ).map(|_| 0)
+}
 
 pub fn init_handles(loader_handle: ::kernel::vfs::handle::File, init_handle: ::kernel::vfs::handle::File) {
 	use kernel::vfs::handle;
@@ -105,7 +183,7 @@ impl objects::Object for Node
 	fn try_clone(&self) -> Option<u32> {
 		Some( ::objects::new_object( Node(self.0.clone()) ) )
 	}
-	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error> {
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		match call
 		{
 		values::VFS_NODE_GETTYPE => {
@@ -113,6 +191,25 @@ impl objects::Object for Node
 			let v32: u32 = ::values::VFSNodeType::from( self.0.get_class() ).into();
 			Ok( v32 as u64 )
 			},
+		values::VFS_NODE_GETINFO => {
+			let mut dst: FreezeMut<::values::VFSNodeInfo> = try!(args.get());
+			log_debug!("VFS_NODE_GETINFO({:p})", &*dst);
+			let res = to_result(self.0.get_info())
+				.map(|info| {
+					*dst = ::values::VFSNodeInfo {
+						size: info.size,
+						mtime: info.mtime,
+						ctime: info.ctime,
+						atime: info.atime,
+						mode: info.mode,
+						uid: info.uid,
+						gid: info.gid,
+						_pad: 0,
+						};
+					0
+					});
+			Ok( super::from_result::<u32,_>(res) )
+			},
 		_ => ::objects::object_has_no_such_method_ref("vfs::Node", call),
 		}
 	}
@@ -176,21 +273,13 @@ impl objects::Object for File
 			let ofs: u64 = try!(args.get());
 			let mut dest: FreezeMut<[u8]> = try!(args.get());
 			log_debug!("File::readat({}, {:p}+{} bytes)", ofs, dest.as_ptr(), dest.len());
-			match self.0.read(ofs, &mut dest)
-			{
-			Ok(count) => Ok(count as u64),
-			Err(e) => todo!("File::handle_syscall READAT Error {:?}", e),
-			}
+			Ok( super::from_result::<u32,_>( to_result(self.0.read(ofs, &mut dest)).map(|count| count as u32) ) )
 			},
 		values::VFS_FILE_WRITEAT => {
 			let ofs: u64 = try!(args.get());
 			let src: Freeze<[u8]> = try!(args.get());
 			log_debug!("File::writeat({}, {:p}+{} bytes)", ofs, src.as_ptr(), src.len());
-			match self.0.write(ofs, &src)
-			{
-			Ok(count) => Ok(count as u64),
-			Err(e) => todo!("File::handle_syscall WRITEAT Error {:?}", e),
-			}
+			Ok( super::from_result::<u32,_>( to_result(self.0.write(ofs, &src)).map(|count| count as u32) ) )
 			},
 		values::VFS_FILE_MEMMAP => {
 			let ofs: u64 = try!(args.get());
@@ -209,18 +298,15 @@ impl objects::Object for File
 				};
 			log_debug!("VFS_FILE_MEMMAP({:#x}, {:#x}+{}, {:?})", ofs, addr, size, mode);
 			
-			match self.0.memory_map(addr, ofs, size, mode)
-			{
-			Ok(h) => {
-				// TODO: I would like the map handle to be avaliable, but I'd like the user to be able to "forget" it
-				// (so it becomes an indelible part of the address space).
-				// - That would likely need a new system call similar to Drop
-				// - XXX: The handle here has borrow of the file handle, so can't be stored as-is
-				::core::mem::forget(h);
-				Ok(0)
-				},
-			Err(e) => todo!("File::handle_syscall MEMMAP Error {:?}", e),
-			}
+			// NOTE: Userland can "forget" the returned handle, making the mapping a permanent part of the address space
+			Ok( super::from_result(
+				to_result( self.0.memory_map(addr, ofs, size, mode) )
+					.map( |h| objects::new_object(MemoryMap(h)) )
+				) )
+			},
+		values::VFS_FILE_SYNC => {
+			log_debug!("File::sync()");
+			Ok( super::from_result::<u32,_>( to_result(self.0.sync()).map(|_| 0) ) )
 			},
 		_ => ::objects::object_has_no_such_method_ref("vfs::File", call),
 		}
@@ -279,6 +365,21 @@ impl objects::Object for Dir
 		values::VFS_DIR_ENUMERATE => {
 			objects::new_object( DirIter::new( self.handle.clone() ) ) as u64
 			},
+		values::VFS_DIR_RENAME => {
+			let src: Freeze<[u8]> = try!(args.get());
+			let dst: Freeze<[u8]> = try!(args.get());
+
+			let (src, dst) = (Path::new(&src), Path::new(&dst));
+			log_debug!("VFS_DIR_RENAME({:?}, {:?})", src, dst);
+			super::from_result::<u32,_>(
+				to_result( self.handle.rename(src, dst) )
+					.map( |_| 0 )
+				)
+			},
+		values::VFS_DIR_WATCH => {
+			log_debug!("VFS_DIR_WATCH()");
+			objects::new_object( Watch( self.handle.watch() ) ) as u64
+			},
 		_ => return ::objects::object_has_no_such_method_ref("vfs::Dir", call),
 		})
 	}
@@ -434,10 +535,88 @@ impl objects::Object for Link
 
 
 
-// -
-// -
-//impl objects::Object for ::kernel::vfs::handle::MemoryMapHandle<'a>
-//{
-//}
+// --------------------------------------------------------------------
+//
+// --------------------------------------------------------------------
 
+struct MemoryMap(::kernel::vfs::handle::MemoryMapHandle);
+impl objects::Object for MemoryMap
+{
+	fn class(&self) -> u16 { values::CLASS_VFS_MEMMAP }
+	fn as_any(&self) -> &Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		None
+	}
+	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error> {
+		::objects::object_has_no_such_method_ref("vfs::MemoryMap", call)
+	}
+	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
+	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
+}
+
+
+// --------------------------------------------------------------------
+//
+// --------------------------------------------------------------------
+
+struct Watch(::kernel::vfs::watch::Watch);
+impl objects::Object for Watch
+{
+	fn class(&self) -> u16 { values::CLASS_VFS_WATCH }
+	fn as_any(&self) -> &Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		None
+	}
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
+		match call
+		{
+		values::VFS_WATCH_READ => {
+			let mut name: FreezeMut<[u8]> = try!(args.get());
+			let mut kind: FreezeMut<u32> = try!(args.get());
+			log_debug!("VFS_WATCH_READ({:p}+{})", name.as_ptr(), name.len());
+			match self.0.pop_event()
+			{
+			Some(ev) => {
+				use kernel::vfs::watch::EventKind;
+				*kind = match ev.kind
+					{
+					EventKind::Created     => values::VFSWatchEvent::Created,
+					EventKind::Removed     => values::VFSWatchEvent::Removed,
+					EventKind::RenamedFrom => values::VFSWatchEvent::RenamedFrom,
+					EventKind::RenamedTo   => values::VFSWatchEvent::RenamedTo,
+					EventKind::Modified    => values::VFSWatchEvent::Modified,
+					EventKind::Overflow    => values::VFSWatchEvent::Overflow,
+					}.into();
+				// Names longer than the buffer are truncated, the full length is returned
+				let len = ::core::cmp::min(name.len(), ev.name.len());
+				name[..len].copy_from_slice( &ev.name.as_bytes()[..len] );
+				Ok( ev.name.len() as u64 )
+				},
+			None => {
+				*kind = 0;
+				Ok(0)
+				},
+			}
+			},
+		_ => ::objects::object_has_no_such_method_ref("vfs::Watch", call),
+		}
+	}
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_VFS_WATCH_EVENT != 0 {
+			self.0.bind_wait(obj);
+			ret += 1;
+		}
+		ret
+	}
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_VFS_WATCH_EVENT != 0 {
+			if self.0.clear_wait(obj) {
+				ret |= values::EV_VFS_WATCH_EVENT;
+			}
+		}
+		ret
+	}
+}
 
@@ -29,6 +29,7 @@ struct Volume<I: Interface>
 {
 	interface: I,
 	capacity: u64,
+	read_only: bool,
 	requestq: Queue,
 }
 
@@ -42,14 +43,16 @@ impl BlockDevice
 		let requestq = int.get_queue(0, 0).expect("Queue #0 'requestq' missing on virtio block device");
 	
 		let features = int.negotiate_features( VIRTIO_BLK_F_RO );
-		if features & VIRTIO_BLK_F_RO != 0 {
-			// TODO: Need a way of indicating to the upper layers that a volume is read-only
+		let read_only = features & VIRTIO_BLK_F_RO != 0;
+		if read_only {
+			log_debug!("- Read-only");
 		}
 		int.set_driver_ok();
 
 		let mut vol = Box::new(Volume {
 			requestq: requestq,
 			capacity: capacity,
+			read_only: read_only,
 			interface: int,
 			});
 
@@ -82,6 +85,7 @@ impl<I: Interface+Send+'static> storage::PhysicalVolume for Volume<I>
 	fn name(&self) -> &str { "virtio0" }
 	fn blocksize(&self) -> usize { BLOCK_SIZE }
 	fn capacity(&self) -> Option<u64> { Some(self.capacity) }
+	fn is_readonly(&self) -> bool { self.read_only }
 	
 	fn read<'a>(&'a self, prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
 	{
@@ -115,6 +119,9 @@ impl<I: Interface+Send+'static> storage::PhysicalVolume for Volume<I>
 	fn write<'a>(&'a self, prio: u8, idx: u64, num: usize, src: &'a [u8]) -> storage::AsyncIoResult<'a, usize>
 	{
 		assert_eq!( src.len(), num * BLOCK_SIZE );
+		if self.read_only {
+			return Box::new(async::NullResultWaiter::new( || Err(storage::IoError::ReadOnly) ));
+		}
 		let cmd = VirtioBlockReq {
 			type_: VIRTIO_BLK_T_OUT,
 			ioprio: (255 - prio) as u32,
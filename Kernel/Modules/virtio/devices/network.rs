@@ -0,0 +1,278 @@
+//
+//
+//
+//! VirtIO network device
+use kernel::prelude::*;
+use kernel::sync::{Mutex,Spinlock};
+use interface::Interface;
+use queue::{Queue,Buffer};
+use network::nic;
+
+#[allow(dead_code)]
+mod defs {
+pub const VIRTIO_NET_F_CSUM	: u32 = 1 << 0;
+pub const VIRTIO_NET_F_MAC	: u32 = 1 << 5;
+pub const VIRTIO_NET_F_STATUS	: u32 = 1 << 16;
+// TODO: Other feature flags (offloads, mergable buffers)
+
+pub const VIRTIO_NET_S_LINK_UP	: u16 = 1;
+}
+use self::defs::*;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+/// Size of the header prepended to all packets (legacy, without mergable buffers)
+const NET_HDR_SIZE: usize = 10;
+/// Size of a single RX slot (header padded to 16 bytes, followed by the packet)
+const RX_SLOT_SIZE: usize = 2048;
+const RX_DATA_OFS: usize = 16;
+/// Number of pages of RX buffers (two slots per page)
+const RX_BUFFER_PAGES: usize = 8;
+const RX_SLOTS: usize = RX_BUFFER_PAGES * (::kernel::PAGE_SIZE / RX_SLOT_SIZE);
+/// Largest frame that will be sent (Ethernet header + 1500 byte MTU)
+const MAX_FRAME_SIZE: usize = 1514;
+
+pub struct NetDevice<I: Interface+Send+'static>
+{
+	_nic_handle: nic::Registration<Card<I>>,
+}
+
+/// `nic::Interface` implementation (wraps the boxed state, so the IRQ handler has a stable pointer)
+struct Card<I: Interface+Send+'static>(Box<Inner<I>>);
+
+struct Inner<I: Interface+Send+'static>
+{
+	interface: I,
+	rx_queue: Queue,
+	tx_queue: Queue,
+
+	/// Backing memory for received packets, split into `RX_SLOTS` slots
+	rx_buffers: ::kernel::memory::virt::ArrayHandle<u8>,
+	/// Slots currently owned by the device (first descriptor, slot index)
+	rx_posted: Mutex<Vec<(u16, usize)>>,
+	/// Slots filled by the device but not yet handed to the network stack (slot index, packet length)
+	rx_complete: Mutex<Vec<(usize, usize)>>,
+
+	waiter_handle: Spinlock<Option<::kernel::threads::SleepObjectRef>>,
+}
+// SAFE: Queue accesses are internally locked, and the interface is only used for register accesses after init
+unsafe impl<I: Interface+Send+'static> Sync for Inner<I> {}
+
+#[repr(C)]
+#[derive(Default)]
+struct VirtioNetHdr
+{
+	flags: u8,
+	gso_type: u8,
+	hdr_len: u16,
+	gso_size: u16,
+	csum_start: u16,
+	csum_offset: u16,
+}
+unsafe impl ::kernel::lib::POD for VirtioNetHdr {}
+
+impl<I: Interface+Send+'static> NetDevice<I>
+{
+	pub fn new(mut int: I) -> Self {
+		let features = int.negotiate_features( VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS );
+
+		let mac = if features & VIRTIO_NET_F_MAC != 0 {
+				// SAFE: Readable registers
+				let (w0, w1) = unsafe { (int.cfg_read_32(0), int.cfg_read_32(4)) };
+				[ w0 as u8, (w0 >> 8) as u8, (w0 >> 16) as u8, (w0 >> 24) as u8, w1 as u8, (w1 >> 8) as u8 ]
+			}
+			else {
+				// No MAC supplied, use a locally-administered address
+				log_notice!("VirtIO network device did not provide a MAC address, using a fixed local address");
+				[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]
+			};
+		if features & VIRTIO_NET_F_STATUS != 0 {
+			// SAFE: Readable register
+			let status = unsafe { (int.cfg_read_32(4) >> 16) as u16 };
+			if status & VIRTIO_NET_S_LINK_UP == 0 {
+				log_notice!("VirtIO network device reports link down");
+			}
+		}
+		log_notice!("VirtIO Network Device: MAC={:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+			mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]);
+
+		let rx_queue = int.get_queue(RECEIVEQ, 0).expect("Queue #0 'receiveq' missing on virtio network device");
+		let tx_queue = int.get_queue(TRANSMITQ, 0).expect("Queue #1 'transmitq' missing on virtio network device");
+
+		let rx_buffers = ::kernel::memory::virt::alloc_dma(64, RX_BUFFER_PAGES, "VirtIO").expect("TODO: Handle alloc failure VirtIO net RX").into_array();
+
+		let mut inner = Box::new(Inner {
+			interface: int,
+			rx_queue: rx_queue,
+			tx_queue: tx_queue,
+			rx_buffers: rx_buffers,
+			rx_posted: Mutex::new(Vec::with_capacity(RX_SLOTS)),
+			rx_complete: Mutex::new(Vec::with_capacity(RX_SLOTS)),
+			waiter_handle: Spinlock::new(None),
+			});
+
+		struct SPtr<T>(*const T);
+		unsafe impl<T> Send for SPtr<T> {}
+		let sp = SPtr(&*inner);
+		// SAFE: Now boxed, won't be invalidated until after Drop is called
+		inner.interface.bind_interrupt( Box::new(move || unsafe { (*sp.0).handle_irq() }) );
+
+		// Hand all RX buffers to the device before marking the driver as ready
+		for slot in 0 .. RX_SLOTS {
+			inner.post_rx(slot);
+		}
+		inner.interface.set_driver_ok();
+
+		NetDevice {
+			_nic_handle: nic::register(mac, Card(inner)),
+			}
+	}
+}
+impl<I: Interface+Send+'static> ::kernel::device_manager::DriverInstance for NetDevice<I> {
+}
+
+impl<I: Interface+Send+'static> Inner<I>
+{
+	fn handle_irq(&self) -> bool {
+		let isr = self.interface.get_isr();
+		if isr == 0 {
+			return false;
+		}
+		if isr & 1 != 0 {
+			// Used ring updated, let the RX thread poll the queue
+			if let Some(ref w) = *self.waiter_handle.lock() {
+				w.signal();
+			}
+		}
+		true
+	}
+
+	/// Give an RX slot (back) to the device
+	fn post_rx(&self, slot: usize) {
+		assert!(slot < RX_SLOTS);
+		let mut posted = self.rx_posted.lock();
+		// SAFE: The slot is not referenced by anything else (not posted, and not held by a `Packet`)
+		// SAFE: Buffers are part of `self`, so remain valid until the device is reset
+		unsafe {
+			let base = (&self.rx_buffers[slot * RX_SLOT_SIZE]) as *const u8 as *mut u8;
+			let hdr = ::core::slice::from_raw_parts_mut(base, NET_HDR_SIZE);
+			let data = ::core::slice::from_raw_parts_mut(base.offset(RX_DATA_OFS as isize), RX_SLOT_SIZE - RX_DATA_OFS);
+			let desc = self.rx_queue.send_buffers_detached(&self.interface, &mut [
+				Buffer::Write(hdr),
+				Buffer::Write(data),
+				]);
+			posted.push( (desc, slot) );
+		}
+	}
+
+	/// Move completed RX slots from the used ring to the completed list
+	fn poll_rx(&self) {
+		let mut posted = self.rx_posted.lock();
+		let mut complete = self.rx_complete.lock();
+		self.rx_queue.pop_used(|desc, len| {
+			match posted.iter().position(|e| e.0 == desc)
+			{
+			Some(i) => {
+				let (_, slot) = posted.remove(i);
+				if len < NET_HDR_SIZE {
+					log_warning!("VirtIO Net: Short RX completion ({} bytes) on slot {}", len, slot);
+					complete.push( (slot, 0) );
+				}
+				else {
+					complete.push( (slot, len - NET_HDR_SIZE) );
+				}
+				},
+			None => log_error!("VirtIO Net: RX completion for unknown descriptor {}", desc),
+			}
+			});
+	}
+
+	fn rx_data(&self, slot: usize, len: usize) -> &[u8] {
+		&self.rx_buffers[slot * RX_SLOT_SIZE + RX_DATA_OFS ..][..len]
+	}
+}
+
+impl<I: Interface+Send+'static> nic::Interface for Card<I>
+{
+	fn tx_raw(&self, pkt: nic::SparsePacket) {
+		let total_len = pkt.total_len();
+		if total_len > MAX_FRAME_SIZE {
+			log_warning!("VirtIO Net: Dropping oversized packet ({} > {})", total_len, MAX_FRAME_SIZE);
+			return ;
+		}
+		let hdr = VirtioNetHdr::default();
+		let mut buffers = Vec::with_capacity(4);
+		buffers.push( Buffer::Read(::kernel::lib::as_byte_slice(&hdr)) );
+		for span in &pkt {
+			if span.len() > 0 {
+				buffers.push( Buffer::Read(span) );
+			}
+		}
+		let h = self.0.tx_queue.send_buffers(&self.0.interface, &mut buffers);
+		match h.wait_for_completion()
+		{
+		Ok(_) => {},
+		Err( () ) => log_warning!("VirtIO Net: TX failed"),
+		}
+	}
+	fn rx_wait_register(&self, channel: &::kernel::threads::SleepObject) {
+		*self.0.waiter_handle.lock() = Some(channel.get_ref());
+	}
+	fn rx_packet(&self) -> Result<nic::PacketHandle, nic::Error> {
+		self.0.poll_rx();
+		let ent = {
+			let mut lh = self.0.rx_complete.lock();
+			if lh.len() == 0 {
+				return Err( nic::Error::NoPacket );
+			}
+			lh.remove(0)
+			};
+		let pkt = Packet {
+			dev: &*self.0,
+			slot: ent.0,
+			len: ent.1,
+			};
+		match ::stack_dst::ValueA::new(pkt)
+		{
+		Ok(v) => Ok(v),
+		Err(_) => panic!("VirtIO Net: Packet handle doesn't fit in PacketHandle"),
+		}
+	}
+}
+
+/// Received packet, returns the slot to the device when dropped
+struct Packet<'a, I: Interface+Send+'static>
+{
+	dev: &'a Inner<I>,
+	slot: usize,
+	len: usize,
+}
+impl<'a, I: Interface+Send+'static> nic::RxPacket for Packet<'a, I>
+{
+	fn len(&self) -> usize {
+		self.len
+	}
+	fn num_regions(&self) -> usize {
+		1
+	}
+	fn get_region(&self, idx: usize) -> &[u8] {
+		assert!(idx == 0);
+		self.dev.rx_data(self.slot, self.len)
+	}
+	fn get_slice(&self, range: ::core::ops::Range<usize>) -> Option<&[u8]> {
+		if range.start <= range.end && range.end <= self.len {
+			Some( &self.dev.rx_data(self.slot, self.len)[range] )
+		}
+		else {
+			None
+		}
+	}
+}
+impl<'a, I: Interface+Send+'static> ::core::ops::Drop for Packet<'a, I>
+{
+	fn drop(&mut self) {
+		self.dev.post_rx(self.slot);
+	}
+}
+
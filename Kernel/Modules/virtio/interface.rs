@@ -18,6 +18,8 @@ pub trait Interface
 	fn set_driver_ok(&mut self);
 
 	fn notify_queue(&self, idx: usize);
+	/// Read and acknowledge the interrupt status (bit 0 = used ring update, bit 1 = config change)
+	fn get_isr(&self) -> u32;
 
 	//fn cfg_read_8(&self, ofs: usize) -> u8;
 	//fn cfg_read_16(&self, ofs: usize) -> u16;
@@ -106,6 +108,15 @@ impl Interface for Mmio
 		}
 	}
 
+	fn get_isr(&self) -> u32 {
+		// SAFE: Status read has no side-effects, ACK only clears the bits read
+		unsafe {
+			let v = self.io.read_32(0x60);
+			self.io.write_32(0x64, v);
+			v
+		}
+	}
+
 	unsafe fn cfg_read_32(&self, ofs: usize) -> u32 {
 		assert!(ofs + 4 <= 0x100);
 		self.io.read_32(0x100 + ofs)
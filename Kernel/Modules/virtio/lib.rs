@@ -7,8 +7,10 @@
 #![feature(linkage)]
 
 #[macro_use] extern crate kernel;
+extern crate network;
+extern crate stack_dst;
 
-module_define!{VirtIO, [DeviceManager, Storage], init}
+module_define!{VirtIO, [DeviceManager, Storage, Network], init}
 
 mod drivers;
 mod interface;
@@ -104,21 +104,56 @@ impl Queue
 
 	pub fn check_interrupt(&self) {
 		while self.last_seen_used.load(Ordering::Relaxed) as u16 != self.used_ring().idx {
-			let idx = self.last_seen_used.fetch_add(1, Ordering::Relaxed) & 0xFFFF;
+			let idx = (self.last_seen_used.fetch_add(1, Ordering::Relaxed) & 0xFFFF) % self.size;
 			log_debug!("idx={}, desc={:?}", idx, self.used_ring().ents[idx]);
 			let UsedElem { id, len } = self.used_ring().ents[idx];
 
-			assert!(len > 0);
-			self.avail_ring_res[id as usize].store(len as usize, Ordering::Release);
+			// NOTE: Stored as len+1, as zero is a valid length (for device-read-only requests)
+			self.avail_ring_res[id as usize].store(len as usize + 1, Ordering::Release);
 			self.interrupt_flag.release();
 		}
 	}
 
+	/// Pop completed descriptor chains from the used ring, for queues that don't use `Request`
+	///
+	/// Calls `cb` with the first descriptor of each chain and the number of bytes written by the device,
+	/// after the chain's descriptors have been released.
+	pub fn pop_used<F: FnMut(u16, usize)>(&self, mut cb: F) {
+		while self.last_seen_used.load(Ordering::Relaxed) as u16 != self.used_ring().idx {
+			let idx = (self.last_seen_used.fetch_add(1, Ordering::Relaxed) & 0xFFFF) % self.size;
+			let UsedElem { id, len } = self.used_ring().ents[idx];
+			log_trace!("pop_used: idx={}, id={}, len={}", idx, id, len);
+
+			self.release_descriptors(id as u16);
+			cb(id as u16, len as usize);
+		}
+	}
+
 	pub fn phys_addr(&self) -> u64 {
 		::kernel::memory::virt::get_phys(self.buffer.as_ref::<u8>(0)) as u64
 	}
 
 	pub fn send_buffers<'a, I: Interface>(&'a self, interface: &I, buffers: &mut [Buffer<'a>]) -> Request<'a> {
+		let descriptor = self.build_chain(buffers);
+
+		// Add to the active queue
+		self.dispatch_descriptor(interface, descriptor)
+	}
+
+	/// Hand a set of buffers to the device without a `Request` to track completion (see `pop_used`)
+	///
+	/// Returns the index of the first descriptor in the chain.
+	///
+	/// UNSAFE: The caller must ensure that the buffers remain valid until the device has returned them
+	pub unsafe fn send_buffers_detached<'a, I: Interface>(&self, interface: &I, buffers: &mut [Buffer<'a>]) -> u16 {
+		let descriptor = self.build_chain(buffers);
+		let idx = descriptor.idx;
+		self.avail_ring().push(idx);
+		interface.notify_queue(self.idx);
+		idx
+	}
+
+	fn build_chain<'a>(&self, buffers: &mut [Buffer<'a>]) -> DescriptorHandle<'a> {
 		assert!(buffers.len() > 0);
 
 		// Allocate a descriptor for each buffer (backwards to build up linked list)
@@ -128,9 +163,7 @@ impl Queue
 		{
 			descriptor = self.allocate_descriptor(Some(descriptor), buf);
 		}
-
-		// Add to the active queue
-		self.dispatch_descriptor(interface, descriptor)
+		descriptor
 	}
 
 	fn allocate_descriptor<'a>(&self, mut next: Option<DescriptorHandle<'a>>, buffer: &mut Buffer<'a>) -> DescriptorHandle<'a> {
@@ -169,6 +202,21 @@ impl Queue
 			}
 	}
 
+	/// Release a chain of descriptors (marking them as free for `allocate_descriptor_raw`)
+	fn release_descriptors(&self, first: u16) {
+		let mut d = self.descriptors();
+		let mut idx = first as usize;
+		loop
+		{
+			log_trace!("- Desc {}: Release", idx);
+			d[idx].length = 0;
+			if d[idx].flags & VRING_DESC_F_NEXT == 0 {
+				break ;
+			}
+			idx = d[idx].next as usize;
+		}
+	}
+
 	/// Return a lock handle to the "avaliable" ring buffer (the list of descriptors handed to the device)
 	fn avail_ring(&self) -> LockedAvailRing {
 		LockedAvailRing {
@@ -220,7 +268,7 @@ impl AvailRing {
 	fn push(&mut self, val: u16) {
 		let count = self.ents.len();
 		self.ents[self.idx as usize % count] = val;
-		self.idx += 1;
+		self.idx = self.idx.wrapping_add(1);
 		//log_debug!("AvailRing = {:?}", self);
 	}
 }
@@ -267,7 +315,7 @@ impl<'a> Request<'a>
 		{
 			let v = self.queue.avail_ring_res[self.first_desc as usize].swap(0, Ordering::Acquire);
 			if v != 0 {
-				return Ok(v);
+				return Ok(v - 1);
 			}
 			self.queue.interrupt_flag.release();
 			// HACK: Yield here to prevent this wait from instantly waking
@@ -279,17 +327,7 @@ impl<'a> Request<'a>
 impl<'a> ::core::ops::Drop for Request<'a>
 {
 	fn drop(&mut self) {
-		let mut d = self.queue.descriptors();
-		let mut idx = self.first_desc as usize;
-		loop
-		{
-			log_trace!("- Desc {}: Release", idx);
-			d[idx].length = 0;
-			if d[idx].flags & VRING_DESC_F_NEXT == 0 {
-				break ;
-			}
-			idx = d[idx].next as usize;
-		}
+		self.queue.release_descriptors(self.first_desc);
 	}
 }
 
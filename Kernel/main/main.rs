@@ -143,12 +143,50 @@ fn vfs_test()
 		ls(Path::new("/system"));
 	}
 	
+	// *. Create, unlink, and look up a file on the ramfs scratch directory
+	match unlink_test()
+	{
+	Ok(_) => log_log!("VFS unlink test passed"),
+	Err(e) => log_error!("VFS unlink test failed: {}", e),
+	}
+	
 	// *. TEST Automount
 	// - Probably shouldn't be included in the final version, but works for testing filesystem and storage drivers
 	automount();
 
 	ls(Path::new("/mount/ahci?-0p0"));
 }
+fn unlink_test() -> Result<(), &'static str>
+{
+	use kernel::vfs::{self,handle};
+	use kernel::vfs::Path;
+	
+	let dir = try!( handle::Dir::open(Path::new("/temp")).map_err(|_| "/temp can't be opened") );
+	let file = try!( dir.create_file("unlink_test", handle::FileOpenMode::ExclRW).map_err(|_| "Create failed") );
+	try!( file.write(0, b"test").map_err(|_| "Write failed") );
+	try!( dir.unlink("unlink_test".as_ref()).map_err(|_| "Unlink failed") );
+	
+	// - The name should be gone (from both the directory and the lookup caches)
+	match dir.open_child("unlink_test".as_ref())
+	{
+	Err(vfs::Error::NotFound) => {},
+	Err(_) => return Err("Lookup after unlink failed"),
+	Ok(_) => return Err("Unlinked file can still be opened"),
+	}
+	match handle::File::open(Path::new("/temp/unlink_test"), handle::FileOpenMode::SharedRO)
+	{
+	Err(vfs::Error::NotFound) => {},
+	Err(_) => return Err("Path lookup after unlink failed"),
+	Ok(_) => return Err("Unlinked file can still be opened by path"),
+	}
+	// - But the existing handle should still work
+	let mut buf = [0; 4];
+	match file.read(0, &mut buf)
+	{
+	Ok(4) if &buf == b"test" => Ok( () ),
+	_ => Err("Read from unlinked file failed"),
+	}
+}
 fn automount()
 {
 	use kernel::metadevs::storage::VolumeHandle;
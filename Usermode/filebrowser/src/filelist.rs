@@ -15,8 +15,10 @@ pub struct FileList<'a>
 	on_chdir: Box<Fn(&mut WindowTrait, &Path) + 'a>,
 
 	cur_paths: RefCell<Vec<OsString>>,
+	/// Currently displayed directory, and a watch used to refresh the listing
+	cur_dir: RefCell<Option<(::syscalls::vfs::Dir, ::syscalls::vfs::Watch)>>,
 	
-	list: ListView<[&'static str; 2], FileEnt>,
+	list: ListView<[&'static str; 4], FileEnt>,
 }
 
 impl<'a> FileList<'a>
@@ -28,12 +30,51 @@ impl<'a> FileList<'a>
 			on_open: Box::new(|_,_,_|()),
 			on_chdir: Box::new(|_,_|()),
 			cur_paths: Default::default(),
-			list: ListView::new(["T", "Filename"]),
+			cur_dir: RefCell::new(None),
+			list: ListView::new(["T", "Filename", "Size", "Modified"]),
 		}
 	}
 	
 
 	pub fn populate(&self, dir: &::syscalls::vfs::Dir) {
+		*self.cur_dir.borrow_mut() = match dir.watch()
+			{
+			Ok(w) => Some( (dir.clone(), w) ),
+			Err(e) => {
+				kernel_log!("Unable to watch directory - {:?}", e);
+				None
+				},
+			};
+		self.fill(dir);
+	}
+
+	/// Obtain the wait item for changes to the current directory (if it's being watched)
+	pub fn watch_wait(&self) -> Option<::syscalls::WaitItem> {
+		self.cur_dir.borrow().as_ref().map(|&(_, ref w)| w.wait_event())
+	}
+	/// Handle change notifications, returns true if the listing was refreshed
+	pub fn handle_watch(&self) -> bool {
+		let cd = self.cur_dir.borrow();
+		let &(ref dir, ref watch) = match *cd
+			{
+			Some(ref v) => v,
+			None => return false,
+			};
+		let mut namebuf = [0; 512];
+		let mut changed = false;
+		while let Some( (ev, name) ) = watch.read_event(&mut namebuf)
+		{
+			kernel_log!("Directory change: {:?} {:?}", ev, ::std::str::from_utf8(name));
+			changed = true;
+		}
+		// TODO: Update only the affected entries instead of re-reading the entire directory
+		if changed {
+			self.fill(dir);
+		}
+		changed
+	}
+
+	fn fill(&self, dir: &::syscalls::vfs::Dir) {
 		let mut iter = match dir.enumerate()
 			{
 			Ok(v) => v,
@@ -133,12 +174,17 @@ struct FileEnt
 	ty_str: &'static str,
 	name: OsString,
 	display_name: Option<String>,
+	size_str: String,
+	mtime_str: String,
 }
 impl FileEnt
 {
 	fn new(dir: &::syscalls::vfs::Dir, name: &[u8]) -> FileEnt {
-		let node = dir.open_child(name);
-		let node_ty = match node { Ok(n) => Some(n.class()), Err(_) => None };
+		let (node_ty, info) = match dir.open_child(name)
+			{
+			Ok(n) => (Some(n.class()), n.get_info().ok()),
+			Err(_) => (None, None),
+			};
 		FileEnt {
 			ty_str: match node_ty
 				{
@@ -155,12 +201,39 @@ impl FileEnt
 				else {
 					Some(String::from_utf8_lossy(name).into_owned())
 				},
+			size_str: match (&node_ty, &info)
+				{
+				(&Some(::syscalls::vfs::NodeType::File), &Some(ref i)) => format!("{}", i.size),
+				_ => String::new(),
+				},
+			mtime_str: match info
+				{
+				Some(ref i) if i.mtime != 0 => format_timestamp(i.mtime),
+				_ => String::new(),
+				},
 		}
 	}
 }
+
+/// Format a timestamp (seconds since 1970) as "YYYY-MM-DD HH:MM"
+fn format_timestamp(ts: i64) -> String {
+	let days = (if ts >= 0 { ts } else { ts - 86399 }) / 86400;
+	let secs = ts - days * 86400;
+	// Convert days since 1970-01-01 into a date (counting years from March, so the leap day is last)
+	let z = days + 719468;
+	let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+	let day_of_era = z - era * 146097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+	format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, secs / 3600, secs / 60 % 60)
+}
 impl ::listview::Row for FileEnt {
 	fn count(&self) -> usize {
-		2
+		4
 	}
 	fn value(&self, col: usize) -> &str {
 		match col
@@ -172,6 +245,8 @@ impl ::listview::Row for FileEnt {
 			else {
 				self.name.to_str().unwrap()
 			},
+		2 => &self.size_str,
+		3 => &self.mtime_str,
 		_ => "",
 		}
 	}
@@ -4,6 +4,7 @@
 ///
 
 extern crate wtk;
+extern crate async;
 extern crate vec_ring;
 #[macro_use(kernel_log)]
 extern crate syscalls;
@@ -37,7 +38,36 @@ fn main()
 	window.focus(&fl);
 	window.show();
 
-	window.idle_loop();
+	::async::idle_loop(&mut [ &mut Browser { window: window, list: &fl } ]);
+}
+
+/// Wraps the window to also refresh the listing when the current directory changes
+struct Browser<'a>
+{
+	window: ::wtk::Window<'a, ::wtk::decorator::Standard>,
+	list: &'a ::filelist::FileList<'a>,
+}
+impl<'a> ::async::WaitController for Browser<'a>
+{
+	fn get_count(&self) -> usize {
+		::async::WaitController::get_count(&self.window) + if self.list.watch_wait().is_some() { 1 } else { 0 }
+	}
+	fn populate(&self, cb: &mut FnMut(::syscalls::WaitItem)) {
+		::async::WaitController::populate(&self.window, cb);
+		if let Some(wi) = self.list.watch_wait() {
+			cb(wi);
+		}
+	}
+	fn handle(&mut self, events: &[::syscalls::WaitItem]) {
+		let (win_events, watch_events) = events.split_at( ::async::WaitController::get_count(&self.window) );
+		// - Handle the watch first, as window events can change the current directory
+		if watch_events.len() > 0 && watch_events[0].flags != 0 {
+			if self.list.handle_watch() {
+				self.window.redraw();
+			}
+		}
+		::async::WaitController::handle(&mut self.window, win_events);
+	}
 }
 
 fn get_app_exe(name: &[u8]) -> Result<::syscalls::vfs::File, ()> {
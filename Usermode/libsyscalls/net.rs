@@ -0,0 +1,198 @@
+// Tifflin OS - System Calls
+// - By John Hodge (thePowersGang)
+//
+// net.rs
+//! Network sockets (TCP and UDP over IPv4)
+//!
+//! All send/receive operations are non-blocking, returning `Error::WouldBlock` when they can't
+//! complete. Use the `wait_*` methods with `threads::wait` to sleep until they can.
+pub use ::values::NetError as Error;
+pub use ::values::SocketAddrV4;
+
+/// Listening TCP port
+pub struct TcpListener(super::ObjectHandle);
+/// Connected TCP stream
+pub struct TcpStream(super::ObjectHandle);
+/// Bound UDP socket
+pub struct UdpSocket(super::ObjectHandle);
+
+impl SocketAddrV4
+{
+	pub fn new(addr: [u8; 4], port: u16) -> SocketAddrV4 {
+		SocketAddrV4 { addr: addr, port: port }
+	}
+	fn addr_u32(&self) -> u32 {
+		(self.addr[0] as u32) << 24 | (self.addr[1] as u32) << 16 | (self.addr[2] as u32) << 8 | self.addr[3] as u32
+	}
+}
+impl ::core::fmt::Display for SocketAddrV4 {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		write!(f, "{}.{}.{}.{}:{}", self.addr[0], self.addr[1], self.addr[2], self.addr[3], self.port)
+	}
+}
+
+#[inline]
+fn to_obj(val: usize) -> Result<super::ObjectHandle, Error> {
+	super::ObjectHandle::new(val).map_err(|code| Error::try_from(code).expect("Bad network error"))
+}
+#[inline]
+fn to_result(val: usize) -> Result<u32, Error> {
+	super::to_result(val).map_err(|code| Error::try_from(code).expect("Bad network error"))
+}
+
+impl TcpListener
+{
+	/// Start listening on the specified local address and port (address may be 0.0.0.0 for all interfaces)
+	pub fn bind(addr: SocketAddrV4) -> Result<TcpListener, Error> {
+		// SAFE: Syscall
+		to_obj( unsafe { syscall!(NET_LISTEN, addr.addr_u32() as usize, addr.port as usize) } as usize )
+			.map(|h| TcpListener(h))
+	}
+
+	/// Obtain the local port number
+	pub fn port(&self) -> u16 {
+		// SAFE: Syscall with no side-effects
+		unsafe { self.0.call_0(::values::NET_TCPLISTEN_GETPORT) as u16 }
+	}
+
+	/// Accept a waiting connection
+	pub fn accept(&self) -> Result<TcpStream, Error> {
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_0(::values::NET_TCPLISTEN_ACCEPT) } as usize )
+			.map(|h| TcpStream(h))
+	}
+
+	pub fn wait_accept(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_NET_TCPLISTEN_CONNECT)
+	}
+}
+impl ::Object for TcpListener
+{
+	const CLASS: u16 = ::values::CLASS_NET_TCPLISTEN;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: super::ObjectHandle) -> Self {
+		TcpListener(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+
+	type Waits = TcpListenerWaits;
+}
+define_waits!{ TcpListenerWaits => (
+	connect:has_connect = ::values::EV_NET_TCPLISTEN_CONNECT,
+)}
+
+impl TcpStream
+{
+	/// Open a connection to a remote host (blocks until the connection is established or fails)
+	pub fn connect(addr: SocketAddrV4) -> Result<TcpStream, Error> {
+		// SAFE: Syscall
+		to_obj( unsafe { syscall!(NET_CONNECT, addr.addr_u32() as usize, addr.port as usize) } as usize )
+			.map(|h| TcpStream(h))
+	}
+
+	/// Queue data for sending, returns the number of bytes accepted
+	pub fn send(&self, data: &[u8]) -> Result<usize, Error> {
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_2(::values::NET_TCPCONN_SEND, data.as_ptr() as usize, data.len()) } as usize )
+			.map(|v| v as usize)
+	}
+	/// Read received data, `Ok(0)` indicates that the remote has closed the connection
+	pub fn recv(&self, data: &mut [u8]) -> Result<usize, Error> {
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_2(::values::NET_TCPCONN_RECV, data.as_mut_ptr() as usize, data.len()) } as usize )
+			.map(|v| v as usize)
+	}
+	/// Close the sending half of the connection
+	pub fn shutdown(&self) {
+		// SAFE: Syscall
+		unsafe { self.0.call_0(::values::NET_TCPCONN_SHUTDOWN); }
+	}
+
+	pub fn local_addr(&self) -> SocketAddrV4 {
+		let mut rv = SocketAddrV4::default();
+		// SAFE: Syscall
+		unsafe { self.0.call_1(::values::NET_TCPCONN_GETLOCAL, &mut rv as *mut _ as usize); }
+		rv
+	}
+	pub fn remote_addr(&self) -> SocketAddrV4 {
+		let mut rv = SocketAddrV4::default();
+		// SAFE: Syscall
+		unsafe { self.0.call_1(::values::NET_TCPCONN_GETREMOTE, &mut rv as *mut _ as usize); }
+		rv
+	}
+
+	pub fn wait_recv(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_NET_TCPCONN_RECV)
+	}
+	pub fn wait_send(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_NET_TCPCONN_SEND)
+	}
+}
+impl ::Object for TcpStream
+{
+	const CLASS: u16 = ::values::CLASS_NET_TCPCONN;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: super::ObjectHandle) -> Self {
+		TcpStream(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+
+	type Waits = TcpStreamWaits;
+}
+define_waits!{ TcpStreamWaits => (
+	recv:has_recv = ::values::EV_NET_TCPCONN_RECV,
+	send:has_send = ::values::EV_NET_TCPCONN_SEND,
+)}
+
+impl UdpSocket
+{
+	/// Bind to a local address and port (port 0 selects a free port)
+	pub fn bind(addr: SocketAddrV4) -> Result<UdpSocket, Error> {
+		// SAFE: Syscall
+		to_obj( unsafe { syscall!(NET_BIND_UDP, addr.addr_u32() as usize, addr.port as usize) } as usize )
+			.map(|h| UdpSocket(h))
+	}
+
+	/// Send a single datagram
+	pub fn send_to(&self, data: &[u8], dest: SocketAddrV4) -> Result<usize, Error> {
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_4(::values::NET_UDP_SENDTO, dest.addr_u32() as usize, dest.port as usize, data.as_ptr() as usize, data.len()) } as usize )
+			.map(|v| v as usize)
+	}
+	/// Receive a single datagram (excess data is discarded)
+	pub fn recv_from(&self, data: &mut [u8]) -> Result<(usize, SocketAddrV4), Error> {
+		let mut src = SocketAddrV4::default();
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_3(::values::NET_UDP_RECVFROM, data.as_mut_ptr() as usize, data.len(), &mut src as *mut _ as usize) } as usize )
+			.map(|v| (v as usize, src))
+	}
+
+	pub fn local_addr(&self) -> SocketAddrV4 {
+		let mut rv = SocketAddrV4::default();
+		// SAFE: Syscall
+		unsafe { self.0.call_1(::values::NET_UDP_GETLOCAL, &mut rv as *mut _ as usize); }
+		rv
+	}
+
+	pub fn wait_recv(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_NET_UDP_RECV)
+	}
+}
+impl ::Object for UdpSocket
+{
+	const CLASS: u16 = ::values::CLASS_NET_UDP;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: super::ObjectHandle) -> Self {
+		UdpSocket(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+
+	type Waits = UdpSocketWaits;
+}
+define_waits!{ UdpSocketWaits => (
+	recv:has_recv = ::values::EV_NET_UDP_RECV,
+)}
+
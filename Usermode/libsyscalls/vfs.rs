@@ -13,11 +13,18 @@ pub struct Dir(super::ObjectHandle);
 pub struct DirIter(::ObjectHandle);
 /// Symbolic link
 pub struct Symlink(super::ObjectHandle);
+/// Mapped region of a file (unmapped when dropped, use `mem::forget` to keep the mapping)
+pub struct MemoryMap(super::ObjectHandle);
+/// Change notifications for a directory
+pub struct Watch(super::ObjectHandle);
 
 pub use ::values::VFSError as Error;
 pub use ::values::VFSNodeType as NodeType;
 pub use ::values::VFSFileOpenMode as FileOpenMode;
 pub use ::values::VFSMemoryMapMode as MemoryMapMode;
+pub use ::values::VFSMountError as MountError;
+pub use ::values::VFSNodeInfo as NodeInfo;
+pub use ::values::VFSWatchEvent as WatchEvent;
 
 pub static ROOT: Dir = Dir( ::ObjectHandle(2) );
 
@@ -30,6 +37,37 @@ fn to_obj(val: usize) -> Result<super::ObjectHandle, Error> {
 fn to_result(val: usize) -> Result<u32, Error> {
 	super::to_result(val).map_err(|code| Error::try_from(code).expect("Bad VFS Error"))
 }
+#[inline]
+fn to_mount_result(val: usize) -> Result<(), MountError> {
+	super::to_result(val).map(|_| ()).map_err(|code| MountError::try_from(code).expect("Bad VFS Mount Error"))
+}
+
+/// Mount the named volume at the specified path
+///
+/// `options` is a comma-separated list, `fs=<name>` selects the filesystem driver (otherwise it's autodetected)
+#[inline]
+pub fn mount(path: &[u8], volume: &str, options: &str) -> Result<(), MountError> {
+	// SAFE: Syscall with correct args
+	to_mount_result( unsafe { syscall!(VFS_MOUNT, path.as_ptr() as usize, path.len(), volume.as_ptr() as usize, volume.len(), options.as_ptr() as usize, options.len()) } as usize )
+}
+/// Unmount the volume mounted at the specified path
+#[inline]
+pub fn unmount(path: &[u8]) -> Result<(), MountError> {
+	// SAFE: Syscall with correct args
+	to_mount_result( unsafe { syscall!(VFS_UNMOUNT, path.as_ptr() as usize, path.len()) } as usize )
+}
+/// Change the options (e.g. `ro`/`rw`) of an existing mount
+#[inline]
+pub fn remount(path: &[u8], options: &str) -> Result<(), MountError> {
+	// SAFE: Syscall with correct args
+	to_mount_result( unsafe { syscall!(VFS_REMOUNT, path.as_ptr() as usize, path.len(), options.as_ptr() as usize, options.len()) } as usize )
+}
+/// Write cached data back to disk for all mounted volumes
+#[inline]
+pub fn sync() -> Result<(), Error> {
+	// SAFE: Syscall with no args
+	to_result( unsafe { syscall!(VFS_SYNC) } as usize ).map(|_| ())
+}
 
 impl Node
 {
@@ -40,6 +78,15 @@ impl Node
 		NodeType::try_from( unsafe { self.0.call_0(::values::VFS_NODE_GETTYPE) } as u32 ).expect("Bad VFS Node Type")
 	}
 
+	/// Obtain the node's metadata (size, timestamps, permissions and owner)
+	#[inline]
+	pub fn get_info(&self) -> Result<NodeInfo,Error> {
+		let mut info = NodeInfo::default();
+		// SAFE: Syscall with correct args
+		try!(to_result( unsafe { self.0.call_1(::values::VFS_NODE_GETINFO, &mut info as *mut _ as usize) } as usize ));
+		Ok( info )
+	}
+
 	/// Convert handle to a directory handle
 	#[inline]
 	pub fn into_dir(self) -> Result<Dir,Error> {
@@ -115,11 +162,20 @@ impl File
 	
 	// Actualy safe, as it uses the aliasing restrictions from the file, and ensures that the provided address is free
 	/// Map a portion of this file into this process's address space.
+	///
+	/// The address and offset must have the same alignment within a page (the mapping covers whole pages)
 	#[inline]
-	pub fn memory_map(&self, ofs: u64, read_size: usize, mem_addr: *const ::Void, mode: MemoryMapMode) -> Result<(),Error> {
+	pub fn memory_map(&self, ofs: u64, read_size: usize, mem_addr: *const ::Void, mode: MemoryMapMode) -> Result<MemoryMap,Error> {
 		// SAFE: Passes valid arguments to MEMMAP
-		to_result( unsafe { self.0.call_4l(::values::VFS_FILE_MEMMAP, ofs, read_size, mem_addr as usize, mode as u8 as usize) } as usize )
-			.map( |_| () )
+		to_obj( unsafe { self.0.call_4l(::values::VFS_FILE_MEMMAP, ofs, read_size, mem_addr as usize, mode as u8 as usize) } as usize )
+			.map( |h| MemoryMap(h) )
+	}
+
+	/// Write cached data for the file's volume back to disk
+	#[inline]
+	pub fn sync(&self) -> Result<(),Error> {
+		// SAFE: Syscall with no args
+		to_result( unsafe { self.0.call_0(::values::VFS_FILE_SYNC) } as usize ).map(|_| ())
 	}
 }
 impl ::Object for File {
@@ -170,6 +226,23 @@ impl Dir
 		Err(code) => Err( Error::try_from(code).expect("Bad VFS Error") ),
 		}
 	}
+
+	/// Rename (or move) a node, both paths are relative to this directory
+	#[inline]
+	pub fn rename<P: ?Sized+AsRef<[u8]>, Q: ?Sized+AsRef<[u8]>>(&self, src: &P, dst: &Q) -> Result<(), Error> {
+		let (src, dst) = (src.as_ref(), dst.as_ref());
+		// SAFE: Syscall
+		try!(to_result(unsafe { self.0.call_4(::values::VFS_DIR_RENAME, src.as_ptr() as usize, src.len(), dst.as_ptr() as usize, dst.len()) } as usize));
+		Ok( () )
+	}
+
+	/// Start watching this directory for changes
+	#[inline]
+	pub fn watch(&self) -> Result<Watch, Error> {
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_0(::values::VFS_DIR_WATCH) } as usize )
+			.map(|h| Watch(h))
+	}
 }
 impl ::Object for Dir {
 	const CLASS: u16 = ::values::CLASS_VFS_DIR;
@@ -240,3 +313,50 @@ impl ::Object for Symlink {
 
 	type Waits = ();
 }
+
+impl ::Object for MemoryMap {
+	const CLASS: u16 = ::values::CLASS_VFS_MEMMAP;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: ::ObjectHandle) -> Self {
+		MemoryMap(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+
+	type Waits = ();
+}
+
+impl Watch
+{
+	/// Read the oldest queued event, returning the event and the affected name
+	///
+	/// If the buffer is too small, the name is truncated.
+	pub fn read_event<'a>(&self, namebuf: &'a mut [u8]) -> Option<(WatchEvent, &'a [u8])> {
+		let mut kind = 0u32;
+		// SAFE: Syscall
+		let len = unsafe { self.0.call_3(::values::VFS_WATCH_READ, namebuf.as_mut_ptr() as usize, namebuf.len(), &mut kind as *mut _ as usize) } as usize;
+		match WatchEvent::try_from(kind)
+		{
+		Ok(ev) => Some( (ev, &namebuf[.. ::core::cmp::min(len, namebuf.len())]) ),
+		Err(_) => None,
+		}
+	}
+
+	pub fn wait_event(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_VFS_WATCH_EVENT)
+	}
+}
+impl ::Object for Watch {
+	const CLASS: u16 = ::values::CLASS_VFS_WATCH;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: ::ObjectHandle) -> Self {
+		Watch(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+
+	type Waits = WatchWaits;
+}
+define_waits!{ WatchWaits => (
+	event:has_event = ::values::EV_VFS_WATCH_EVENT,
+)}
@@ -173,6 +173,11 @@ impl<'a, D: 'a + Decorator> Window<'a, D>
 	pub fn rerender(&mut self)  {
 		WindowTrait::rerender(self)
 	}
+	/// Re-render the window and present it (for changes made outside of event handling)
+	pub fn redraw(&mut self) {
+		WindowTrait::rerender(self);
+		self.win.redraw();
+	}
 
 	/// Obtain the states of all "modifier" keys
 	pub fn get_modifiers(&self) -> &ModifierStates {
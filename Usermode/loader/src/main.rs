@@ -161,7 +161,8 @@ fn load_binary(path: &::std::ffi::OsStr, fh: ::syscalls::vfs::File) -> usize
 				};
 			let fp = segments_it.get_file();
 			if aligned > 0 {
-				let mm = fp.memory_map(segment.file_addr, aligned, segment.load_addr as *mut _, map_mode);
+				let mm = fp.memory_map(segment.file_addr, aligned, segment.load_addr as *mut _, map_mode).expect("Failed to map segment");
+				// - Segments are never unmapped, so drop the handle without unmapping
 				::std::mem::forget(mm);
 			}
 			if tail > 0 {
@@ -84,6 +84,28 @@ def_grp!( 3: GROUP_IPC = {
 	=0: IPC_NEWPAIR,
 });
 
+/// Networking
+def_grp!( 4: GROUP_NET = {
+	/// Open a TCP connection to a remote address/port (blocks until established or failed)
+	=0: NET_CONNECT,
+	/// Start listening for TCP connections on a local address/port
+	=1: NET_LISTEN,
+	/// Bind a UDP socket to a local address/port
+	=2: NET_BIND_UDP,
+});
+
+/// Filesystem management
+def_grp!( 5: GROUP_VFS = {
+	/// Mount a named volume at a path (options are comma-separated, `fs=<name>` selects the driver)
+	=0: VFS_MOUNT,
+	/// Unmount the volume mounted at a path
+	=1: VFS_UNMOUNT,
+	/// Change the options of an existing mount
+	=2: VFS_REMOUNT,
+	/// Write cached data back to disk for all mounted volumes
+	=3: VFS_SYNC,
+});
+
 pub fn get_class_name(class_idx: u16) -> &'static str {
 	CLASS_NAMES.get(class_idx as usize).unwrap_or(&"UNK")
 }
@@ -163,6 +185,8 @@ def_classes! {
 	/// Opened node
 	=3: CLASS_VFS_NODE = {
 		=0: VFS_NODE_GETTYPE,
+		/// Obtain the node's metadata (writes a VFSNodeInfo)
+		=1: VFS_NODE_GETINFO,
 		--
 		=0: VFS_NODE_TOFILE,
 		=1: VFS_NODE_TODIR,
@@ -177,8 +201,10 @@ def_classes! {
 		=1: VFS_FILE_READAT,
 		/// Write to the specified position in the file
 		=2: VFS_FILE_WRITEAT,
-		/// Map part of the file into the current address space
+		/// Map part of the file into the current address space (returns a CLASS_VFS_MEMMAP handle)
 		=3: VFS_FILE_MEMMAP,
+		/// Write cached data for the file's volume back to disk
+		=4: VFS_FILE_SYNC,
 		--
 	}|{
 	},
@@ -190,6 +216,10 @@ def_classes! {
 		=1: VFS_DIR_OPENCHILD,
 		/// Open a sub-path
 		=2: VFS_DIR_OPENPATH,
+		/// Rename/move a node (source and destination paths are relative to this directory)
+		=3: VFS_DIR_RENAME,
+		/// Watch the directory for changes (returns a CLASS_VFS_WATCH handle)
+		=4: VFS_DIR_WATCH,
 		--
 	}|{
 	},
@@ -254,6 +284,64 @@ def_classes! {
 	}|{
 		/// Fires when the channel has a message waiting
 		=0: EV_IPC_RPC_RECV,
+	},
+
+	/// TCP listening port
+	=11: CLASS_NET_TCPLISTEN = {
+		/// Accept a pending connection (returns a new CLASS_NET_TCPCONN handle)
+		=0: NET_TCPLISTEN_ACCEPT,
+		/// Obtain the local port number
+		=1: NET_TCPLISTEN_GETPORT,
+		--
+	}|{
+		/// Fires when a connection is waiting to be accepted
+		=0: EV_NET_TCPLISTEN_CONNECT,
+	},
+	/// TCP connection (stream)
+	=12: CLASS_NET_TCPCONN = {
+		/// Queue data for transmission, returns the number of bytes accepted
+		=0: NET_TCPCONN_SEND,
+		/// Read received data, returns zero on end of stream
+		=1: NET_TCPCONN_RECV,
+		/// Close the sending side of the connection (remote sees end of stream)
+		=2: NET_TCPCONN_SHUTDOWN,
+		/// Obtain the local address/port (as a SocketAddrV4)
+		=3: NET_TCPCONN_GETLOCAL,
+		/// Obtain the remote address/port (as a SocketAddrV4)
+		=4: NET_TCPCONN_GETREMOTE,
+		--
+	}|{
+		/// Fires when data (or end of stream) is available
+		=0: EV_NET_TCPCONN_RECV,
+		/// Fires when there is space to send
+		=1: EV_NET_TCPCONN_SEND,
+	},
+	/// UDP socket
+	=13: CLASS_NET_UDP = {
+		/// Send a datagram to the specified address/port
+		=0: NET_UDP_SENDTO,
+		/// Receive a datagram (returns length, writes the source to a SocketAddrV4)
+		=1: NET_UDP_RECVFROM,
+		/// Obtain the local address/port (as a SocketAddrV4)
+		=2: NET_UDP_GETLOCAL,
+		--
+	}|{
+		/// Fires when a datagram is waiting
+		=0: EV_NET_UDP_RECV,
+	},
+	/// Mapped region of a file (dropping the handle unmaps the region)
+	=14: CLASS_VFS_MEMMAP = {
+		--
+	}|{
+	},
+	/// Change notifications for a directory
+	=15: CLASS_VFS_WATCH = {
+		/// Read the oldest queued event (returns the name length, writes the event kind to a u32)
+		=0: VFS_WATCH_READ,
+		--
+	}|{
+		/// Fires when an event is queued
+		=0: EV_VFS_WATCH_EVENT,
 	}
 }
 
@@ -302,6 +390,31 @@ enum_to_from!{ VFSError => u32:
 	PermissionDenied = 2,
 	FileLocked = 3,
 	MalformedPath = 4,
+	AlreadyExists = 5,
+	InvalidParameter = 6,
+	NotADirectory = 7,
+	ReadOnlyFilesystem = 8,
+	CrossFilesystem = 9,
+	IoError = 10,
+	RecursionDepthExceeded = 11,
+	InconsistentFilesystem = 12,
+	OutOfSpace = 13,
+	OutOfMemory = 14,
+	TransientError = 15,
+	Unknown = 16,	// Miscellaneous error (details are logged by the kernel)
+	Busy = 17,	// Node is in use (e.g. a mountpoint)
+}
+enum_to_from!{ VFSMountError => u32:
+	UnknownFilesystem = 0,
+	NoHandler = 1,
+	InvalidMountpoint = 2,
+	MountpointUsed = 3,
+	CallFailed = 4,
+	BadOption = 5,
+	NotMounted = 6,
+	Busy = 7,
+	NoSuchVolume = 8,
+	ReadOnlyVolume = 9,
 }
 enum_to_from!{ VFSNodeType => u32:
 	File = 0,
@@ -329,6 +442,74 @@ enum_to_from!{ VFSMemoryMapMode => u8:
 	WriteBack = 3,
 }
 
+enum_to_from!{ VFSWatchEvent => u32:
+	// /// An entry was created
+	Created = 1,
+	// /// An entry was removed
+	Removed = 2,
+	// /// An entry was renamed away from this name
+	RenamedFrom = 3,
+	// /// An entry was renamed to this name
+	RenamedTo = 4,
+	// /// A file's contents changed
+	Modified = 5,
+	// /// Events were lost, the directory should be re-read
+	Overflow = 6,
+}
+
+enum_to_from!{ NetError => u32:
+	// /// Operation would block (no data/space/connection available)
+	WouldBlock = 0,
+	// /// No route to the destination
+	NoRoute = 1,
+	// /// Remote host did not respond to address resolution
+	HostUnreachable = 2,
+	// /// Remote refused the connection
+	ConnectionRefused = 3,
+	// /// Connection was reset by the remote
+	ConnectionReset = 4,
+	// /// Connection timed out
+	TimedOut = 5,
+	// /// Local side of the connection has been closed
+	LocalClosed = 6,
+	// /// Requested local address/port is already in use
+	AddressInUse = 7,
+	// /// No free ephemeral ports
+	NoPorts = 8,
+	// /// Datagram was too large to send
+	TooLarge = 9,
+}
+
+#[repr(C)]
+#[derive(Copy,Clone,Debug,Default)]
+/// Node metadata, as returned by VFS_NODE_GETINFO
+pub struct VFSNodeInfo
+{
+	/// Size of the node's data in bytes
+	pub size: u64,
+	/// Last modification time (seconds since 1970-01-01 00:00 UTC)
+	pub mtime: i64,
+	/// Last metadata change time
+	pub ctime: i64,
+	/// Last access time
+	pub atime: i64,
+	/// Unix-style permission bits
+	pub mode: u32,
+	pub uid: u32,
+	pub gid: u32,
+	/// Explicit tail padding (always zero, so no kernel data is copied into it)
+	pub _pad: u32,
+}
+
+#[repr(C)]
+#[derive(Copy,Clone,Debug,Default)]
+/// IPv4 address and port, as passed to/from network calls
+pub struct SocketAddrV4
+{
+	/// Address in network order
+	pub addr: [u8; 4],
+	pub port: u16,
+}
 
 enum_to_from!{ GuiWinFlag => u8:
 	Visible = 0,